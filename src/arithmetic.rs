@@ -1,4 +1,7 @@
-use crate::gate::*;
+use crate::{
+    gate::*,
+    netlist::{NetlistBuilder, NetlistError},
+};
 
 #[derive(Debug)]
 pub struct HalfAdder {
@@ -26,6 +29,12 @@ impl Gate for HalfAdder {
         self.and.re_compute();
         self.xor.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("and", &self.and)?;
+        builder.part("xor", &self.xor)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -58,192 +67,653 @@ impl Gate for FullAdder {
         self.half_adder2.re_compute();
         self.or.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("half_adder1", &self.half_adder1)?;
+        builder.part("half_adder2", &self.half_adder2)?;
+        builder.part("or", &self.or)?;
+        Ok(())
+    }
 }
 
+pub type Add16 = Add<16>;
+
+/// Nbitの加算器。一番下の半加算器から全加算器へ順に繰り上がりを伝える
 #[derive(Debug)]
-pub struct Add16 {
-    pub out: SharedBus<16>,
+pub struct Add<const N: usize> {
+    pub out: SharedBus<N>,
     half_adder: HalfAdder,
-    full_adder1: FullAdder,
-    full_adder2: FullAdder,
-    full_adder3: FullAdder,
-    full_adder4: FullAdder,
-    full_adder5: FullAdder,
-    full_adder6: FullAdder,
-    full_adder7: FullAdder,
-    full_adder8: FullAdder,
-    full_adder9: FullAdder,
-    full_adder10: FullAdder,
-    full_adder11: FullAdder,
-    full_adder12: FullAdder,
-    full_adder13: FullAdder,
-    full_adder14: FullAdder,
-    full_adder15: FullAdder,
-}
-
-impl Add16 {
-    pub fn new(a: SharedBus<16>, b: SharedBus<16>) -> Add16 {
-        // FIXME もうちょっとスッキリ書きたい
-        let a0 = a.reconnect([0]);
-        let a1 = a.reconnect([1]);
-        let a2 = a.reconnect([2]);
-        let a3 = a.reconnect([3]);
-        let a4 = a.reconnect([4]);
-        let a5 = a.reconnect([5]);
-        let a6 = a.reconnect([6]);
-        let a7 = a.reconnect([7]);
-        let a8 = a.reconnect([8]);
-        let a9 = a.reconnect([9]);
-        let a10 = a.reconnect([10]);
-        let a11 = a.reconnect([11]);
-        let a12 = a.reconnect([12]);
-        let a13 = a.reconnect([13]);
-        let a14 = a.reconnect([14]);
-        let a15 = a.reconnect([15]);
-
-        let b0 = b.reconnect([0]);
-        let b1 = b.reconnect([1]);
-        let b2 = b.reconnect([2]);
-        let b3 = b.reconnect([3]);
-        let b4 = b.reconnect([4]);
-        let b5 = b.reconnect([5]);
-        let b6 = b.reconnect([6]);
-        let b7 = b.reconnect([7]);
-        let b8 = b.reconnect([8]);
-        let b9 = b.reconnect([9]);
-        let b10 = b.reconnect([10]);
-        let b11 = b.reconnect([11]);
-        let b12 = b.reconnect([12]);
-        let b13 = b.reconnect([13]);
-        let b14 = b.reconnect([14]);
-        let b15 = b.reconnect([15]);
-
-        let half_adder = HalfAdder::new(a0, b0);
-        let full_adder1 = FullAdder::new(a1, b1, half_adder.carry.clone());
-        let full_adder2 = FullAdder::new(a2, b2, full_adder1.carry.clone());
-        let full_adder3 = FullAdder::new(a3, b3, full_adder2.carry.clone());
-        let full_adder4 = FullAdder::new(a4, b4, full_adder3.carry.clone());
-        let full_adder5 = FullAdder::new(a5, b5, full_adder4.carry.clone());
-        let full_adder6 = FullAdder::new(a6, b6, full_adder5.carry.clone());
-        let full_adder7 = FullAdder::new(a7, b7, full_adder6.carry.clone());
-        let full_adder8 = FullAdder::new(a8, b8, full_adder7.carry.clone());
-        let full_adder9 = FullAdder::new(a9, b9, full_adder8.carry.clone());
-        let full_adder10 = FullAdder::new(a10, b10, full_adder9.carry.clone());
-        let full_adder11 = FullAdder::new(a11, b11, full_adder10.carry.clone());
-        let full_adder12 = FullAdder::new(a12, b12, full_adder11.carry.clone());
-        let full_adder13 = FullAdder::new(a13, b13, full_adder12.carry.clone());
-        let full_adder14 = FullAdder::new(a14, b14, full_adder13.carry.clone());
-        let full_adder15 = FullAdder::new(a15, b15, full_adder14.carry.clone());
-
-        let out = Bus::new([
-            half_adder.sum.get_shared_bit(0),
-            full_adder1.sum.get_shared_bit(0),
-            full_adder2.sum.get_shared_bit(0),
-            full_adder3.sum.get_shared_bit(0),
-            full_adder4.sum.get_shared_bit(0),
-            full_adder5.sum.get_shared_bit(0),
-            full_adder6.sum.get_shared_bit(0),
-            full_adder7.sum.get_shared_bit(0),
-            full_adder8.sum.get_shared_bit(0),
-            full_adder9.sum.get_shared_bit(0),
-            full_adder10.sum.get_shared_bit(0),
-            full_adder11.sum.get_shared_bit(0),
-            full_adder12.sum.get_shared_bit(0),
-            full_adder13.sum.get_shared_bit(0),
-            full_adder14.sum.get_shared_bit(0),
-            full_adder15.sum.get_shared_bit(0),
-        ])
-        .to_shared_bus();
-
-        Add16 {
-            out,
+    full_adders: Vec<FullAdder>,
+}
+
+impl<const N: usize> Add<N> {
+    pub fn new(a: SharedBus<N>, b: SharedBus<N>) -> Add<N> {
+        let half_adder = HalfAdder::new(a.bit::<0>(), b.bit::<0>());
+        let mut full_adders: Vec<FullAdder> = vec![];
+        for i in 1..N {
+            let carry = match full_adders.last() {
+                Some(full_adder) => full_adder.carry.clone(),
+                None => half_adder.carry.clone(),
+            };
+            full_adders.push(FullAdder::new(a.reconnect([i]), b.reconnect([i]), carry));
+        }
+
+        let mut out = half_adder.sum.shared_bits();
+        out.extend(
+            full_adders
+                .iter()
+                .map(|full_adder| full_adder.sum.get_shared_bit(0)),
+        );
+
+        Add {
+            out: bus(&out),
             half_adder,
-            full_adder1,
-            full_adder2,
-            full_adder3,
-            full_adder4,
-            full_adder5,
-            full_adder6,
-            full_adder7,
-            full_adder8,
-            full_adder9,
-            full_adder10,
-            full_adder11,
-            full_adder12,
-            full_adder13,
-            full_adder14,
-            full_adder15,
-        }
-    }
-}
-
-impl Gate for Add16 {
+            full_adders,
+        }
+    }
+}
+
+impl<const N: usize> Gate for Add<N> {
     fn re_compute(&self) -> () {
         self.half_adder.re_compute();
-        self.full_adder1.re_compute();
-        self.full_adder2.re_compute();
-        self.full_adder3.re_compute();
-        self.full_adder4.re_compute();
-        self.full_adder5.re_compute();
-        self.full_adder6.re_compute();
-        self.full_adder7.re_compute();
-        self.full_adder8.re_compute();
-        self.full_adder9.re_compute();
-        self.full_adder10.re_compute();
-        self.full_adder11.re_compute();
-        self.full_adder12.re_compute();
-        self.full_adder13.re_compute();
-        self.full_adder14.re_compute();
-        self.full_adder15.re_compute();
+        for full_adder in self.full_adders.iter() {
+            full_adder.re_compute();
+        }
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("half_adder", &self.half_adder)?;
+        for (i, full_adder) in self.full_adders.iter().enumerate() {
+            builder.part(&format!("full_adder{}", i + 1), full_adder)?;
+        }
+        Ok(())
     }
 }
 
+pub type Inc16 = Inc<16>;
+
+/// 1を足す
 #[derive(Debug)]
-pub struct Inc16 {
-    pub out: SharedBus<16>,
-    add16: Add16,
+pub struct Inc<const N: usize> {
+    pub out: SharedBus<N>,
+    add: Add<N>,
 }
 
-impl Inc16 {
-    pub fn new(input: SharedBus<16>) -> Inc16 {
+impl<const N: usize> Inc<N> {
+    pub fn new(input: SharedBus<N>) -> Inc<N> {
         let all0 = Bus::<1>::all0().to_shared_bus();
         let all1 = Bus::<1>::all1().to_shared_bus();
-        let one = Bus::new([
-            all1.get_shared_bit(0), // ここだけ1
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-            all0.get_shared_bit(0),
-        ])
-        .to_shared_bus();
-
-        let add16 = Add16::new(input.clone(), one.clone());
-
-        Inc16 {
-            out: add16.out.clone(),
-            add16,
-        }
-    }
-}
-
-impl Gate for Inc16 {
+        // 0bit目だけ1
+        let one: Vec<SharedBit> = (0..N)
+            .map(|i| match i {
+                0 => all1.get_shared_bit(0),
+                _ => all0.get_shared_bit(0),
+            })
+            .collect();
+
+        let add = Add::new(input.clone(), bus(&one));
+
+        Inc {
+            out: add.out.clone(),
+            add,
+        }
+    }
+}
+
+impl<const N: usize> Gate for Inc<N> {
+    fn re_compute(&self) -> () {
+        self.add.re_compute();
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("add", &self.add)?;
+        Ok(())
+    }
+}
+
+// bitを並べてbusにする
+fn bus<const N: usize>(bits: &[SharedBit]) -> SharedBus<N> {
+    Bus::new(std::array::from_fn(|i| bits[i].clone())).to_shared_bus()
+}
+
+// 1bitの値を2つずつgateでまとめて1つにする。段数が少なくなるように木にする
+fn tree<G: Gate>(
+    mut bits: Vec<SharedBus<1>>,
+    gates: &mut Vec<G>,
+    gate: impl Fn(SharedBus<1>, SharedBus<1>) -> (G, SharedBus<1>),
+) -> SharedBus<1> {
+    while bits.len() > 1 {
+        let mut next = vec![];
+        for pair in bits.chunks(2) {
+            match pair {
+                [a, b] => {
+                    let (g, out) = gate(a.clone(), b.clone());
+                    gates.push(g);
+                    next.push(out);
+                }
+                _ => next.push(pair[0].clone()),
+            }
+        }
+        bits = next;
+    }
+    bits.pop().unwrap()
+}
+
+/// 積和。termsのそれぞれをAndでまとめてから、それをOrでまとめる
+#[derive(Debug)]
+struct SumOfProducts {
+    out: SharedBus<1>,
+    ands: Vec<And<1>>,
+    ors: Vec<Or<1>>,
+}
+
+impl SumOfProducts {
+    fn new(terms: Vec<Vec<SharedBus<1>>>) -> SumOfProducts {
+        let mut ands = vec![];
+        let mut ors = vec![];
+        let products = terms
+            .into_iter()
+            .map(|term| {
+                tree(term, &mut ands, |a, b| {
+                    let and = And::new(a, b);
+                    let out = and.out.clone();
+                    (and, out)
+                })
+            })
+            .collect();
+        let out = tree(products, &mut ors, |a, b| {
+            let or = Or::new(a, b);
+            let out = or.out.clone();
+            (or, out)
+        });
+        SumOfProducts { out, ands, ors }
+    }
+
+    // 下位から並んだgenerateとpropagateで、一番上から出る繰り上がりの項
+    // g[j-1] + p[j-1]g[j-2] + ... + p[j-1]...p[0]carry
+    fn carry(
+        g: &[SharedBus<1>],
+        p: &[SharedBus<1>],
+        carry: Option<&SharedBus<1>>,
+    ) -> SumOfProducts {
+        let mut terms = vec![];
+        for m in (0..g.len()).rev() {
+            let mut term = p[m + 1..].to_vec();
+            term.push(g[m].clone());
+            terms.push(term);
+        }
+        if let Some(carry) = carry {
+            let mut term = p.to_vec();
+            term.push(carry.clone());
+            terms.push(term);
+        }
+        SumOfProducts::new(terms)
+    }
+}
+
+impl Gate for SumOfProducts {
+    // Andは入力しか見ないので先にまとめて計算できる
+    fn re_compute(&self) -> () {
+        for and in self.ands.iter() {
+            and.re_compute();
+        }
+        for or in self.ors.iter() {
+            or.re_compute();
+        }
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        for (i, and) in self.ands.iter().enumerate() {
+            builder.part(&format!("and{}", i + 1), and)?;
+        }
+        for (i, or) in self.ors.iter().enumerate() {
+            builder.part(&format!("or{}", i + 1), or)?;
+        }
+        Ok(())
+    }
+}
+
+/// 4bitごとに繰り上がりを先読みする加算器
+/// 4bitのまとまりのgenerate/propagateから4bitごとの繰り上がりを求め、そこから各bitの繰り上がりを求める
+#[derive(Debug)]
+pub struct CarryLookaheadAdd16 {
+    pub out: SharedBus<16>,
+    // 各bitのgenerateとpropagate
+    and: And<16>,
+    xor1: Xor<16>,
+    group_generate: Vec<SumOfProducts>,
+    group_propagate: Vec<SumOfProducts>,
+    group_carry: Vec<SumOfProducts>,
+    carry: Vec<SumOfProducts>,
+    xor2: Xor<16>,
+}
+
+impl CarryLookaheadAdd16 {
+    pub fn new(a: SharedBus<16>, b: SharedBus<16>) -> CarryLookaheadAdd16 {
+        let and = And::new(a.clone(), b.clone());
+        let xor1 = Xor::new(a.clone(), b.clone());
+        let g: Vec<SharedBus<1>> = (0..16).map(|i| and.out.reconnect([i])).collect();
+        let p: Vec<SharedBus<1>> = (0..16).map(|i| xor1.out.reconnect([i])).collect();
+
+        // 一番上のまとまりのgenerateと、一番下と一番上のまとまりのpropagateは使わない
+        let group_generate: Vec<SumOfProducts> = (0..3)
+            .map(|k| SumOfProducts::carry(&g[4 * k..4 * k + 4], &p[4 * k..4 * k + 4], None))
+            .collect();
+        let group_propagate: Vec<SumOfProducts> = (1..3)
+            .map(|k| SumOfProducts::new(vec![p[4 * k..4 * k + 4].to_vec()]))
+            .collect();
+        // 4bitごとの繰り上がり。4bit目へはgroup_generate[0]がそのまま入る
+        let big_g: Vec<SharedBus<1>> = group_generate.iter().map(|s| s.out.clone()).collect();
+        // 一番下のまとまりのpropagateは使わないので0にしておく
+        let mut big_p = vec![Bus::<1>::all0().to_shared_bus()];
+        big_p.extend(group_propagate.iter().map(|s| s.out.clone()));
+        let group_carry: Vec<SumOfProducts> = (2..4)
+            .map(|k| SumOfProducts::carry(&big_g[..k], &big_p[..k], None))
+            .collect();
+        let mut group_carries = vec![big_g[0].clone()];
+        group_carries.extend(group_carry.iter().map(|s| s.out.clone()));
+
+        let mut carry = vec![];
+        let mut carries = vec![Bus::<1>::all0().get_shared_bit(0)];
+        for k in 0..4 {
+            if k > 0 {
+                carries.push(group_carries[k - 1].get_shared_bit(0));
+            }
+            let group_in = match k {
+                0 => None,
+                _ => Some(&group_carries[k - 1]),
+            };
+            for j in 1..4 {
+                let bits = 4 * k..4 * k + j;
+                let sum = SumOfProducts::carry(&g[bits.clone()], &p[bits], group_in);
+                carries.push(sum.out.get_shared_bit(0));
+                carry.push(sum);
+            }
+        }
+        let xor2 = Xor::new(xor1.out.clone(), bus(&carries));
+
+        CarryLookaheadAdd16 {
+            out: xor2.out.clone(),
+            and,
+            xor1,
+            group_generate,
+            group_propagate,
+            group_carry,
+            carry,
+            xor2,
+        }
+    }
+}
+
+impl Gate for CarryLookaheadAdd16 {
+    fn re_compute(&self) -> () {
+        self.and.re_compute();
+        self.xor1.re_compute();
+        for sum in self
+            .group_generate
+            .iter()
+            .chain(self.group_propagate.iter())
+            .chain(self.group_carry.iter())
+            .chain(self.carry.iter())
+        {
+            sum.re_compute();
+        }
+        self.xor2.re_compute();
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("and", &self.and)?;
+        builder.part("xor1", &self.xor1)?;
+        for (name, sums) in [
+            ("group_generate", &self.group_generate),
+            ("group_propagate", &self.group_propagate),
+            ("group_carry", &self.group_carry),
+            ("carry", &self.carry),
+        ] {
+            for (i, sum) in sums.iter().enumerate() {
+                builder.part(&format!("{}{}", name, i + 1), sum)?;
+            }
+        }
+        builder.part("xor2", &self.xor2)?;
+        Ok(())
+    }
+}
+
+/// 4bitの加算器。Carry-selectの部品
+#[derive(Debug)]
+struct RippleAdd4 {
+    sum: SharedBus<4>,
+    carry: SharedBus<1>,
+    full_adders: Vec<FullAdder>,
+}
+
+impl RippleAdd4 {
+    fn new(a: SharedBus<4>, b: SharedBus<4>, c: SharedBus<1>) -> RippleAdd4 {
+        let mut full_adders: Vec<FullAdder> = vec![];
+        for i in 0..4 {
+            let carry = match full_adders.last() {
+                Some(full_adder) => full_adder.carry.clone(),
+                None => c.clone(),
+            };
+            full_adders.push(FullAdder::new(a.reconnect([i]), b.reconnect([i]), carry));
+        }
+        let sum: Vec<SharedBit> = full_adders
+            .iter()
+            .map(|full_adder| full_adder.sum.get_shared_bit(0))
+            .collect();
+        RippleAdd4 {
+            sum: bus(&sum),
+            carry: full_adders[3].carry.clone(),
+            full_adders,
+        }
+    }
+}
+
+impl Gate for RippleAdd4 {
     fn re_compute(&self) -> () {
-        self.add16.re_compute();
+        for full_adder in self.full_adders.iter() {
+            full_adder.re_compute();
+        }
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        for (i, full_adder) in self.full_adders.iter().enumerate() {
+            builder.part(&format!("full_adder{}", i + 1), full_adder)?;
+        }
+        Ok(())
     }
 }
 
+/// 上位の4bitずつを繰り上がりが0の場合と1の場合の両方で足しておき、下から来た繰り上がりで選ぶ加算器
 #[derive(Debug)]
+pub struct CarrySelectAdd16 {
+    pub out: SharedBus<16>,
+    add4: RippleAdd4,
+    // 下から2つ目から上の4bitずつ
+    add4_carry0: Vec<RippleAdd4>,
+    add4_carry1: Vec<RippleAdd4>,
+    carry_muxes: Vec<Mux<1>>,
+    sum_muxes: Vec<Mux<4>>,
+}
+
+impl CarrySelectAdd16 {
+    pub fn new(a: SharedBus<16>, b: SharedBus<16>) -> CarrySelectAdd16 {
+        let a4 = [
+            a.slice_len::<0, 4>(),
+            a.slice_len::<4, 4>(),
+            a.slice_len::<8, 4>(),
+            a.slice_len::<12, 4>(),
+        ];
+        let b4 = [
+            b.slice_len::<0, 4>(),
+            b.slice_len::<4, 4>(),
+            b.slice_len::<8, 4>(),
+            b.slice_len::<12, 4>(),
+        ];
+        let add4 = RippleAdd4::new(a4[0].clone(), b4[0].clone(), Bus::all0().to_shared_bus());
+        let add4_carry0: Vec<RippleAdd4> = (1..4)
+            .map(|k| RippleAdd4::new(a4[k].clone(), b4[k].clone(), Bus::all0().to_shared_bus()))
+            .collect();
+        let add4_carry1: Vec<RippleAdd4> = (1..4)
+            .map(|k| RippleAdd4::new(a4[k].clone(), b4[k].clone(), Bus::all1().to_shared_bus()))
+            .collect();
+
+        // 一番上の4bitから出る繰り上がりは使わない
+        let mut carry_muxes: Vec<Mux<1>> = vec![];
+        let mut sum_muxes = vec![];
+        for k in 0..3 {
+            let carry = match carry_muxes.last() {
+                Some(mux) => mux.out.clone(),
+                None => add4.carry.clone(),
+            };
+            let (carry0, carry1) = (&add4_carry0[k], &add4_carry1[k]);
+            let sum_mux = Mux::new(carry0.sum.clone(), carry1.sum.clone(), carry.clone());
+            sum_muxes.push(sum_mux);
+            if k < 2 {
+                carry_muxes.push(Mux::new(carry0.carry.clone(), carry1.carry.clone(), carry));
+            }
+        }
+
+        let out8: SharedBus<8> = add4.sum.concat(&sum_muxes[0].out);
+        let out12: SharedBus<12> = out8.concat(&sum_muxes[1].out);
+
+        CarrySelectAdd16 {
+            out: out12.concat(&sum_muxes[2].out),
+            add4,
+            add4_carry0,
+            add4_carry1,
+            carry_muxes,
+            sum_muxes,
+        }
+    }
+}
+
+impl Gate for CarrySelectAdd16 {
+    fn re_compute(&self) -> () {
+        self.add4.re_compute();
+        for add4 in self.add4_carry0.iter().chain(self.add4_carry1.iter()) {
+            add4.re_compute();
+        }
+        for mux in self.carry_muxes.iter() {
+            mux.re_compute();
+        }
+        for mux in self.sum_muxes.iter() {
+            mux.re_compute();
+        }
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("add4", &self.add4)?;
+        for (i, add4) in self.add4_carry0.iter().enumerate() {
+            builder.part(&format!("add4_carry0_{}", i + 1), add4)?;
+        }
+        for (i, add4) in self.add4_carry1.iter().enumerate() {
+            builder.part(&format!("add4_carry1_{}", i + 1), add4)?;
+        }
+        for (i, mux) in self.carry_muxes.iter().enumerate() {
+            builder.part(&format!("carry_mux{}", i + 1), mux)?;
+        }
+        for (i, mux) in self.sum_muxes.iter().enumerate() {
+            builder.part(&format!("sum_mux{}", i + 1), mux)?;
+        }
+        Ok(())
+    }
+}
+
+/// 上位の(generate, propagate)に下位のものをまとめる
+/// g = g_hi + p_hi g_lo、p = p_hi p_lo。下位が0bit目まで届いていればpはもう使わないので作らない
+#[derive(Debug)]
+struct CarryOperator {
+    g: SharedBus<1>,
+    p: SharedBus<1>,
+    and1: And<1>,
+    or: Or<1>,
+    and2: Option<And<1>>,
+}
+
+impl CarryOperator {
+    fn new(
+        (g_hi, p_hi): (SharedBus<1>, SharedBus<1>),
+        (g_lo, p_lo): (SharedBus<1>, SharedBus<1>),
+        needs_p: bool,
+    ) -> CarryOperator {
+        let and1 = And::new(p_hi.clone(), g_lo);
+        let or = Or::new(g_hi, and1.out.clone());
+        let and2 = needs_p.then(|| And::new(p_hi.clone(), p_lo));
+        CarryOperator {
+            g: or.out.clone(),
+            p: and2.as_ref().map_or(p_hi, |and2| and2.out.clone()),
+            and1,
+            or,
+            and2,
+        }
+    }
+}
+
+impl Gate for CarryOperator {
+    fn re_compute(&self) -> () {
+        self.and1.re_compute();
+        self.or.re_compute();
+        if let Some(and2) = &self.and2 {
+            and2.re_compute();
+        }
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("and1", &self.and1)?;
+        builder.part("or", &self.or)?;
+        if let Some(and2) = &self.and2 {
+            builder.part("and2", and2)?;
+        }
+        Ok(())
+    }
+}
+
+/// Kogge-Stoneの加算器。1, 2, 4, 8bit離れたbitの(generate, propagate)を順にまとめて、
+/// 4段で全bitの繰り上がりを求める
+#[derive(Debug)]
+pub struct KoggeStoneAdd16 {
+    pub out: SharedBus<16>,
+    and: And<16>,
+    xor1: Xor<16>,
+    // 段ごとに、まとめる相手のあるbitだけ並ぶ
+    levels: Vec<Vec<CarryOperator>>,
+    xor2: Xor<16>,
+}
+
+impl KoggeStoneAdd16 {
+    pub fn new(a: SharedBus<16>, b: SharedBus<16>) -> KoggeStoneAdd16 {
+        let and = And::new(a.clone(), b.clone());
+        let xor1 = Xor::new(a.clone(), b.clone());
+        // bitごとの、そこから下のbitをまとめた(generate, propagate)
+        let mut gp: Vec<(SharedBus<1>, SharedBus<1>)> = (0..16)
+            .map(|i| (and.out.reconnect([i]), xor1.out.reconnect([i])))
+            .collect();
+        let mut levels = vec![];
+        for distance in [1, 2, 4, 8] {
+            let mut level = vec![];
+            let mut next = gp.clone();
+            for i in distance..16 {
+                let operator =
+                    CarryOperator::new(gp[i].clone(), gp[i - distance].clone(), i >= 2 * distance);
+                next[i] = (operator.g.clone(), operator.p.clone());
+                level.push(operator);
+            }
+            levels.push(level);
+            gp = next;
+        }
+
+        // i bit目への繰り上がりは i-1 bit目から下をまとめたgenerate
+        let mut carries = vec![Bus::<1>::all0().get_shared_bit(0)];
+        carries.extend(gp[..15].iter().map(|(g, _)| g.get_shared_bit(0)));
+        let xor2 = Xor::new(xor1.out.clone(), bus(&carries));
+
+        KoggeStoneAdd16 {
+            out: xor2.out.clone(),
+            and,
+            xor1,
+            levels,
+            xor2,
+        }
+    }
+}
+
+impl Gate for KoggeStoneAdd16 {
+    fn re_compute(&self) -> () {
+        self.and.re_compute();
+        self.xor1.re_compute();
+        for operator in self.levels.iter().flatten() {
+            operator.re_compute();
+        }
+        self.xor2.re_compute();
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("and", &self.and)?;
+        builder.part("xor1", &self.xor1)?;
+        for (level, operators) in self.levels.iter().enumerate() {
+            // 段ごとに上から何bitかだけあるので、名前には何bit目のものかを付ける
+            for (i, operator) in operators.iter().enumerate() {
+                let bit = 16 - operators.len() + i;
+                builder.part(&format!("level{}_{}", level + 1, bit), operator)?;
+            }
+        }
+        builder.part("xor2", &self.xor2)?;
+        Ok(())
+    }
+}
+
+/// ALUで使う16bitの加算器の種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdderKind {
+    /// Add16。全加算器を16個つないだもの
+    RippleCarry,
+    CarryLookahead,
+    CarrySelect,
+    KoggeStone,
+}
+
+/// AdderKindで選んだ16bitの加算器
+#[derive(Debug)]
+pub enum Adder16 {
+    RippleCarry(Add16),
+    CarryLookahead(CarryLookaheadAdd16),
+    CarrySelect(CarrySelectAdd16),
+    KoggeStone(KoggeStoneAdd16),
+}
+
+impl Adder16 {
+    pub fn new(kind: AdderKind, a: SharedBus<16>, b: SharedBus<16>) -> Adder16 {
+        match kind {
+            AdderKind::RippleCarry => Adder16::RippleCarry(Add16::new(a, b)),
+            AdderKind::CarryLookahead => Adder16::CarryLookahead(CarryLookaheadAdd16::new(a, b)),
+            AdderKind::CarrySelect => Adder16::CarrySelect(CarrySelectAdd16::new(a, b)),
+            AdderKind::KoggeStone => Adder16::KoggeStone(KoggeStoneAdd16::new(a, b)),
+        }
+    }
+
+    pub fn out(&self) -> SharedBus<16> {
+        match self {
+            Adder16::RippleCarry(adder) => adder.out.clone(),
+            Adder16::CarryLookahead(adder) => adder.out.clone(),
+            Adder16::CarrySelect(adder) => adder.out.clone(),
+            Adder16::KoggeStone(adder) => adder.out.clone(),
+        }
+    }
+
+    /// 中身の加算器のチップ名
+    pub fn name(&self) -> &'static str {
+        match self {
+            Adder16::RippleCarry(_) => "Add16",
+            Adder16::CarryLookahead(_) => "CarryLookaheadAdd16",
+            Adder16::CarrySelect(_) => "CarrySelectAdd16",
+            Adder16::KoggeStone(_) => "KoggeStoneAdd16",
+        }
+    }
+
+    fn gate(&self) -> &dyn Gate {
+        match self {
+            Adder16::RippleCarry(adder) => adder,
+            Adder16::CarryLookahead(adder) => adder,
+            Adder16::CarrySelect(adder) => adder,
+            Adder16::KoggeStone(adder) => adder,
+        }
+    }
+}
+
+impl Gate for Adder16 {
+    fn re_compute(&self) -> () {
+        self.gate().re_compute();
+    }
+
+    // 中身の加算器の部品をそのまま並べる
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        self.gate().flatten(builder)
+    }
+}
+
+#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)] // nand2tetrisのチップ名に合わせる
 pub struct ALU {
     pub out: SharedBus<16>,
     pub zr: SharedBus<1>,
@@ -255,7 +725,7 @@ pub struct ALU {
     not2: Not<16>,
     mux4: Mux<16>,
     and1: And<16>,
-    add1: Add16,
+    add1: Adder16,
     mux5: Mux<16>,
     not3: Not<16>,
     mux6: Mux<16>,
@@ -266,6 +736,8 @@ pub struct ALU {
 }
 
 impl ALU {
+    // 引数はALU.hdlのピンと同じ並び
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         x: SharedBus<16>,
         y: SharedBus<16>,
@@ -275,6 +747,22 @@ impl ALU {
         ny: SharedBus<1>,
         f: SharedBus<1>,
         no: SharedBus<1>,
+    ) -> ALU {
+        ALU::with_adder(x, y, zx, nx, zy, ny, f, no, AdderKind::RippleCarry)
+    }
+
+    /// 加算器を選んで作る
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_adder(
+        x: SharedBus<16>,
+        y: SharedBus<16>,
+        zx: SharedBus<1>,
+        nx: SharedBus<1>,
+        zy: SharedBus<1>,
+        ny: SharedBus<1>,
+        f: SharedBus<1>,
+        no: SharedBus<1>,
+        adder: AdderKind,
     ) -> ALU {
         let mux1 = Mux::new(x.clone(), Bus::all0().to_shared_bus(), zx.clone());
         let mux2 = Mux::new(y.clone(), Bus::all0().to_shared_bus(), zy.clone());
@@ -286,18 +774,18 @@ impl ALU {
         let mux4 = Mux::new(mux2.out.clone(), not2.out.clone(), ny.clone());
 
         let and1 = And::new(mux3.out.clone(), mux4.out.clone());
-        let add1 = Add16::new(mux3.out.clone(), mux4.out.clone());
-        let mux5 = Mux::new(and1.out.clone(), add1.out.clone(), f.clone());
+        let add1 = Adder16::new(adder, mux3.out.clone(), mux4.out.clone());
+        let mux5 = Mux::new(and1.out.clone(), add1.out(), f.clone());
 
         let not3 = Not::new(mux5.out.clone());
         let mux6 = Mux::new(mux5.out.clone(), not3.out.clone(), no.clone());
 
-        let or8way1 = Or8Way::new(mux6.out.reconnect([0, 1, 2, 3, 4, 5, 6, 7]));
-        let or8way2 = Or8Way::new(mux6.out.reconnect([8, 9, 10, 11, 12, 13, 14, 15]));
+        let or8way1 = Or8Way::new(mux6.out.slice_len::<0, 8>());
+        let or8way2 = Or8Way::new(mux6.out.slice_len::<8, 8>());
         let or1 = Or::new(or8way1.out.clone(), or8way2.out.clone());
         let not4 = Not::new(or1.out.clone());
 
-        let ng = Bus::new([mux6.out.get_shared_bit(15)]).to_shared_bus();
+        let ng = mux6.out.bit::<15>();
 
         ALU {
             out: mux6.out.clone(),
@@ -340,11 +828,31 @@ impl Gate for ALU {
         self.or1.re_compute();
         self.not4.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("mux1", &self.mux1)?;
+        builder.part("mux2", &self.mux2)?;
+        builder.part("not1", &self.not1)?;
+        builder.part("mux3", &self.mux3)?;
+        builder.part("not2", &self.not2)?;
+        builder.part("mux4", &self.mux4)?;
+        builder.part("and1", &self.and1)?;
+        builder.named_part("add1", self.add1.name(), &self.add1)?;
+        builder.part("mux5", &self.mux5)?;
+        builder.part("not3", &self.not3)?;
+        builder.part("mux6", &self.mux6)?;
+        builder.part("or8way1", &self.or8way1)?;
+        builder.part("or8way2", &self.or8way2)?;
+        builder.part("or1", &self.or1)?;
+        builder.part("not4", &self.not4)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::netlist::{GateCount, Netlist, Twin};
 
     #[test]
     fn half_adder_re_compute() {
@@ -361,9 +869,14 @@ mod tests {
             let sum = case[2].parse::<Bus<1>>().unwrap().to_shared_bus();
             let carry = case[3].parse::<Bus<1>>().unwrap().to_shared_bus();
 
-            let half_adder = HalfAdder::new(a, b);
+            let half_adder = HalfAdder::new(a.clone(), b.clone());
 
-            half_adder.re_compute();
+            Twin::new(
+                &half_adder,
+                &[a.shared_bits(), b.shared_bits()],
+                &[half_adder.sum.shared_bits(), half_adder.carry.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(half_adder.carry, carry);
             assert_eq!(half_adder.sum, sum);
         }
@@ -389,9 +902,14 @@ mod tests {
             let sum = case[3].parse::<Bus<1>>().unwrap().to_shared_bus();
             let carry = case[4].parse::<Bus<1>>().unwrap().to_shared_bus();
 
-            let full_adder = FullAdder::new(a, b, c);
+            let full_adder = FullAdder::new(a.clone(), b.clone(), c.clone());
 
-            full_adder.re_compute();
+            Twin::new(
+                &full_adder,
+                &[a.shared_bits(), b.shared_bits(), c.shared_bits()],
+                &[full_adder.sum.shared_bits(), full_adder.carry.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(full_adder.sum, sum);
             assert_eq!(full_adder.carry, carry);
         }
@@ -413,12 +931,127 @@ mod tests {
             let b = case[1].parse::<Bus<16>>().unwrap().to_shared_bus();
             let out = case[2].parse::<Bus<16>>().unwrap().to_shared_bus();
 
-            let add16 = Add16::new(a, b);
-            add16.re_compute();
+            let add16 = Add16::new(a.clone(), b.clone());
+            Twin::new(
+                &add16,
+                &[a.shared_bits(), b.shared_bits()],
+                &[add16.out.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(add16.out, out);
         }
     }
 
+    const ADDERS: [AdderKind; 4] = [
+        AdderKind::RippleCarry,
+        AdderKind::CarryLookahead,
+        AdderKind::CarrySelect,
+        AdderKind::KoggeStone,
+    ];
+
+    #[test]
+    fn adders_re_compute() {
+        let bus = |value: u16| format!("{:016b}", value).parse::<Bus<16>>().unwrap();
+        for kind in ADDERS {
+            let a = Bus::<16>::all0().to_shared_bus();
+            let b = Bus::<16>::all0().to_shared_bus();
+            let adder = Adder16::new(kind, a.clone(), b.clone());
+            let chip = Twin::new(
+                &adder,
+                &[a.shared_bits(), b.shared_bits()],
+                &[adder.out().shared_bits()],
+            );
+            // 繰り上がりが下から上まで伝わるものも含める
+            let values = [0, 1, 0x7fff, 0x8000, 0xffff, 0x1234, 0x9876, 0x5555];
+            for x in values.iter().copied().chain((0..40).map(|i| i * 1637)) {
+                for y in values {
+                    a.overwrite(&bus(x));
+                    b.overwrite(&bus(y));
+                    // 部品は依存順に並んでいるので1回で決まる
+                    chip.re_compute();
+                    assert_eq!(adder.out().to_u16(), x.wrapping_add(y), "{:?}", kind);
+                }
+            }
+
+            // D-A
+            let x = bus(1000).to_shared_bus();
+            let y = bus(3000).to_shared_bus();
+            let bit = |value: &str| value.parse::<Bus<1>>().unwrap().to_shared_bus();
+            let alu = ALU::with_adder(
+                x,
+                y,
+                bit("0"),
+                bit("1"),
+                bit("0"),
+                bit("0"),
+                bit("1"),
+                bit("1"),
+                kind,
+            );
+            alu.re_compute();
+            assert_eq!(alu.out.to_u16() as i16, -2000, "{:?}", kind);
+            assert_eq!(alu.ng, bit("1"));
+        }
+    }
+
+    #[test]
+    fn adders_depth() {
+        let mut results = vec![];
+        for kind in ADDERS {
+            let a = Bus::<16>::all0().to_shared_bus();
+            let b = Bus::<16>::all0().to_shared_bus();
+            let adder = Adder16::new(kind, a.clone(), b.clone());
+            let netlist = Netlist::from_gate(
+                &adder,
+                &[("a", a.shared_bits()), ("b", b.shared_bits())],
+                &[("out", adder.out().shared_bits())],
+            )
+            .unwrap();
+            let count = GateCount::of(adder.name(), &adder).unwrap();
+            assert_eq!(count.nands, netlist.nands().len());
+            results.push((count.nands, netlist.critical_path().levels));
+        }
+        // (Nandの数, 段数)。ripple carryが一番少なくて一番深い
+        assert_eq!(results, [(231, 62), (383, 28), (532, 34), (473, 20)]);
+    }
+
+    // 幅の違う加算器。8bitと32bitで繰り上がりが一番上まで伝わるもの
+    #[test]
+    fn add_n_re_compute() {
+        fn bus<const N: usize>(value: u64) -> SharedBus<N> {
+            format!("{:0width$b}", value, width = N)
+                .parse::<Bus<N>>()
+                .unwrap()
+                .to_shared_bus()
+        }
+        fn value<const N: usize>(bus: &SharedBus<N>) -> u64 {
+            (0..N)
+                .map(|i| ((bus.get_shared_bit(i).get() == I) as u64) << i)
+                .sum()
+        }
+
+        let add8 = Add::<8>::new(bus(0b1111_1111), bus(1));
+        add8.re_compute();
+        assert_eq!(value(&add8.out), 0);
+        let add8 = Add::<8>::new(bus(100), bus(27));
+        add8.re_compute();
+        assert_eq!(value(&add8.out), 127);
+
+        let add32 = Add::<32>::new(bus(0x8765_4321), bus(0x1234_5678));
+        add32.re_compute();
+        assert_eq!(value(&add32.out), 0x9999_9999);
+        let inc32 = Inc::<32>::new(bus(0xffff_ffff));
+        inc32.re_compute();
+        assert_eq!(value(&inc32.out), 0);
+        let inc8 = Inc::<8>::new(bus(0x7f));
+        inc8.re_compute();
+        assert_eq!(value(&inc8.out), 0x80);
+
+        let add1 = Add::<1>::new(bus(1), bus(1));
+        add1.re_compute();
+        assert_eq!(value(&add1.out), 0);
+    }
+
     #[test]
     fn inc16_re_compute() {
         let cases = vec![
@@ -432,8 +1065,8 @@ mod tests {
             let input = case[0].parse::<Bus<16>>().unwrap().to_shared_bus();
             let out = case[1].parse::<Bus<16>>().unwrap().to_shared_bus();
 
-            let inc16 = Inc16::new(input);
-            inc16.re_compute();
+            let inc16 = Inc16::new(input.clone());
+            Twin::new(&inc16, &[input.shared_bits()], &[inc16.out.shared_bits()]).re_compute();
             assert_eq!(inc16.out, out);
         }
     }
@@ -925,8 +1558,35 @@ mod tests {
             let zr = case[9].parse::<Bus<1>>().unwrap().to_shared_bus();
             let ng = case[10].parse::<Bus<1>>().unwrap().to_shared_bus();
 
-            let alu = ALU::new(x, y, zx, nx, zy, ny, f, no);
-            alu.re_compute();
+            let alu = ALU::new(
+                x.clone(),
+                y.clone(),
+                zx.clone(),
+                nx.clone(),
+                zy.clone(),
+                ny.clone(),
+                f.clone(),
+                no.clone(),
+            );
+            Twin::new(
+                &alu,
+                &[
+                    x.shared_bits(),
+                    y.shared_bits(),
+                    zx.shared_bits(),
+                    nx.shared_bits(),
+                    zy.shared_bits(),
+                    ny.shared_bits(),
+                    f.shared_bits(),
+                    no.shared_bits(),
+                ],
+                &[
+                    alu.out.shared_bits(),
+                    alu.zr.shared_bits(),
+                    alu.ng.shared_bits(),
+                ],
+            )
+            .re_compute();
             assert_eq!(alu.out, out);
             assert_eq!(alu.zr, zr);
             assert_eq!(alu.ng, ng);
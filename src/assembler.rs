@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{computer::ROM32KBuiltIn, gate::SharedBus};
+
+/// ROMに載せられる命令数の上限
+pub const ROM_SIZE: usize = 32768;
+
+/// 変数の割り当てを始めるアドレス
+const VARIABLE_BASE: u16 = 16;
+
+/// 変数に使えるのはSCREENの手前 (16383番地) まで
+const VARIABLE_END: u16 = 16384;
+
+// a c1 c2 c3 c4 c5 c6 の7bit
+pub(crate) const COMP_TABLE: [(&str, u16); 28] = [
+    ("0", 0b0101010),
+    ("1", 0b0111111),
+    ("-1", 0b0111010),
+    ("D", 0b0001100),
+    ("A", 0b0110000),
+    ("!D", 0b0001101),
+    ("!A", 0b0110001),
+    ("-D", 0b0001111),
+    ("-A", 0b0110011),
+    ("D+1", 0b0011111),
+    ("A+1", 0b0110111),
+    ("D-1", 0b0001110),
+    ("A-1", 0b0110010),
+    ("D+A", 0b0000010),
+    ("D-A", 0b0010011),
+    ("A-D", 0b0000111),
+    ("D&A", 0b0000000),
+    ("D|A", 0b0010101),
+    ("M", 0b1110000),
+    ("!M", 0b1110001),
+    ("-M", 0b1110011),
+    ("M+1", 0b1110111),
+    ("M-1", 0b1110010),
+    ("D+M", 0b1000010),
+    ("D-M", 0b1010011),
+    ("M-D", 0b1000111),
+    ("D&M", 0b1000000),
+    ("D|M", 0b1010101),
+];
+
+pub(crate) const JUMP_TABLE: [(&str, u16); 8] = [
+    ("", 0b000),
+    ("JGT", 0b001),
+    ("JEQ", 0b010),
+    ("JGE", 0b011),
+    ("JLT", 0b100),
+    ("JNE", 0b101),
+    ("JLE", 0b110),
+    ("JMP", 0b111),
+];
+
+const PREDEFINED_SYMBOLS: [(&str, u16); 23] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("R0", 0),
+    ("R1", 1),
+    ("R2", 2),
+    ("R3", 3),
+    ("R4", 4),
+    ("R5", 5),
+    ("R6", 6),
+    ("R7", 7),
+    ("R8", 8),
+    ("R9", 9),
+    ("R10", 10),
+    ("R11", 11),
+    ("R12", 12),
+    ("R13", 13),
+    ("R14", 14),
+    ("R15", 15),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+
+#[derive(Debug, PartialEq)]
+pub enum AssembleErrorKind {
+    InvalidSymbol(String),
+    AddressOutOfRange(String),
+    InvalidDest(String),
+    InvalidComp(String),
+    InvalidJump(String),
+    InvalidLabel(String),
+    DuplicateLabel(String),
+    TooManyInstructions,
+    /// 割り当てようとした変数
+    TooManyVariables(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AssembleError {
+    /// 1始まりの行番号
+    pub line: usize,
+    pub kind: AssembleErrorKind,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            AssembleErrorKind::InvalidSymbol(s) => write!(f, "invalid symbol `{}`", s),
+            AssembleErrorKind::AddressOutOfRange(s) => {
+                write!(f, "address `{}` is out of range (0..=32767)", s)
+            }
+            AssembleErrorKind::InvalidDest(s) => write!(f, "invalid dest `{}`", s),
+            AssembleErrorKind::InvalidComp(s) => write!(f, "invalid comp `{}`", s),
+            AssembleErrorKind::InvalidJump(s) => write!(f, "invalid jump `{}`", s),
+            AssembleErrorKind::InvalidLabel(s) => write!(f, "invalid label `{}`", s),
+            AssembleErrorKind::DuplicateLabel(s) => write!(f, "duplicate label `{}`", s),
+            AssembleErrorKind::TooManyInstructions => {
+                write!(f, "program exceeds {} instructions", ROM_SIZE)
+            }
+            AssembleErrorKind::TooManyVariables(s) => write!(
+                f,
+                "no room for variable `{}` (variables must fit in {}..={})",
+                s,
+                VARIABLE_BASE,
+                VARIABLE_END - 1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+enum Line<'a> {
+    Label(&'a str),
+    A(&'a str),
+    C(String),
+}
+
+/// Hackアセンブリを機械語に変換する
+pub fn assemble(source: &str) -> Result<Vec<u16>, AssembleError> {
+    let lines = parse_lines(source)?;
+
+    // 1パス目: ラベルの位置を記録する
+    let mut symbols: HashMap<String, u16> = PREDEFINED_SYMBOLS
+        .iter()
+        .map(|(name, value)| (name.to_string(), *value))
+        .collect();
+    let mut rom_address = 0;
+    for (line_number, line) in lines.iter() {
+        match line {
+            Line::Label(label) => {
+                if symbols.contains_key(*label) {
+                    return Err(AssembleError {
+                        line: *line_number,
+                        kind: AssembleErrorKind::DuplicateLabel(label.to_string()),
+                    });
+                }
+                // ROMの外を指すラベルは@で0x8000以上になり、C命令と区別できない
+                if rom_address >= ROM_SIZE {
+                    return Err(AssembleError {
+                        line: *line_number,
+                        kind: AssembleErrorKind::TooManyInstructions,
+                    });
+                }
+                symbols.insert(label.to_string(), rom_address as u16);
+            }
+            _ => {
+                if rom_address >= ROM_SIZE {
+                    return Err(AssembleError {
+                        line: *line_number,
+                        kind: AssembleErrorKind::TooManyInstructions,
+                    });
+                }
+                rom_address += 1;
+            }
+        }
+    }
+
+    // 2パス目: 命令を変換する。未知のシンボルは16番地から変数として割り当てる
+    let mut next_variable = VARIABLE_BASE;
+    let mut words = vec![];
+    for (line_number, line) in lines.iter() {
+        let error = |kind| AssembleError {
+            line: *line_number,
+            kind,
+        };
+        match line {
+            Line::Label(_) => {}
+            Line::A(value) => {
+                if value.starts_with(|c: char| c.is_ascii_digit()) {
+                    let address = value
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|v| *v < 0x8000)
+                        .ok_or_else(|| {
+                            error(AssembleErrorKind::AddressOutOfRange(value.to_string()))
+                        })?;
+                    words.push(address);
+                } else {
+                    let address = match symbols.get(*value) {
+                        Some(address) => *address,
+                        None if next_variable >= VARIABLE_END => {
+                            return Err(error(AssembleErrorKind::TooManyVariables(
+                                value.to_string(),
+                            )));
+                        }
+                        None => {
+                            let address = next_variable;
+                            symbols.insert(value.to_string(), address);
+                            next_variable += 1;
+                            address
+                        }
+                    };
+                    words.push(address);
+                }
+            }
+            Line::C(instruction) => words.push(encode_c_instruction(instruction).map_err(error)?),
+        }
+    }
+
+    Ok(words)
+}
+
+/// アセンブルした結果をそのままROMにする
+pub fn assemble_to_rom(
+    source: &str,
+    address: SharedBus<15>,
+) -> Result<ROM32KBuiltIn, AssembleError> {
+    let words = assemble(source)?;
+    Ok(ROM32KBuiltIn::from_words(&words, address))
+}
+
+fn parse_lines(source: &str) -> Result<Vec<(usize, Line<'_>)>, AssembleError> {
+    let mut lines = vec![];
+    for (index, raw) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let code = raw.split("//").next().unwrap_or("").trim();
+        if code.is_empty() {
+            continue;
+        }
+
+        if let Some(rest) = code.strip_prefix('(') {
+            let label = rest.strip_suffix(')').map(|l| l.trim());
+            match label {
+                Some(label) if is_valid_symbol(label) => {
+                    lines.push((line_number, Line::Label(label)))
+                }
+                _ => {
+                    return Err(AssembleError {
+                        line: line_number,
+                        kind: AssembleErrorKind::InvalidLabel(code.to_string()),
+                    })
+                }
+            }
+        } else if let Some(value) = code.strip_prefix('@') {
+            let value = value.trim();
+            let is_number = value.chars().all(|c| c.is_ascii_digit());
+            if value.is_empty() || (!is_number && !is_valid_symbol(value)) {
+                return Err(AssembleError {
+                    line: line_number,
+                    kind: AssembleErrorKind::InvalidSymbol(value.to_string()),
+                });
+            }
+            lines.push((line_number, Line::A(value)));
+        } else {
+            // "D = D + A ; JGT" のような空白入りの書き方も許す
+            let instruction: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+            lines.push((line_number, Line::C(instruction)));
+        }
+    }
+    Ok(lines)
+}
+
+fn is_valid_symbol(symbol: &str) -> bool {
+    let is_symbol_char = |c: char| c.is_ascii_alphanumeric() || "_.$:".contains(c);
+    !symbol.is_empty()
+        && !symbol.starts_with(|c: char| c.is_ascii_digit())
+        && symbol.chars().all(is_symbol_char)
+}
+
+// dest=comp;jump
+fn encode_c_instruction(instruction: &str) -> Result<u16, AssembleErrorKind> {
+    let (dest, rest) = match instruction.split_once('=') {
+        Some((dest, rest)) => (dest, rest),
+        None => ("", instruction),
+    };
+    let (comp, jump) = match rest.split_once(';') {
+        Some((comp, jump)) => (comp, jump),
+        None => (rest, ""),
+    };
+
+    let dest = encode_dest(dest).ok_or_else(|| AssembleErrorKind::InvalidDest(dest.to_string()))?;
+    let comp = encode_comp(comp).ok_or_else(|| AssembleErrorKind::InvalidComp(comp.to_string()))?;
+    let jump = JUMP_TABLE
+        .iter()
+        .find(|(name, _)| *name == jump)
+        .map(|(_, bits)| *bits)
+        .ok_or_else(|| AssembleErrorKind::InvalidJump(jump.to_string()))?;
+
+    Ok(0b111 << 13 | comp << 6 | dest << 3 | jump)
+}
+
+// A, D, Mの組み合わせ。順番は問わないが重複はエラー
+fn encode_dest(dest: &str) -> Option<u16> {
+    let mut bits = 0;
+    for c in dest.chars() {
+        let bit = match c {
+            'A' => 0b100,
+            'D' => 0b010,
+            'M' => 0b001,
+            _ => return None,
+        };
+        if bits & bit != 0 {
+            return None;
+        }
+        bits |= bit;
+    }
+    Some(bits)
+}
+
+fn encode_comp(comp: &str) -> Option<u16> {
+    let lookup = |comp: &str| {
+        COMP_TABLE
+            .iter()
+            .find(|(name, _)| *name == comp)
+            .map(|(_, bits)| *bits)
+    };
+    if let Some(bits) = lookup(comp) {
+        return Some(bits);
+    }
+
+    // A+D, M|D のように可換な演算子の左右が逆になっているものも受け付ける
+    for op in ['+', '&', '|'] {
+        if let Some((x, y)) = comp.split_once(op) {
+            return lookup(&format!("{}{}{}", y, op, x));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gate::{Bus, Gate};
+
+    #[test]
+    fn a_instruction() {
+        assert_eq!(assemble("@0").unwrap(), vec![0]);
+        assert_eq!(assemble("@12345").unwrap(), vec![12345]);
+        assert_eq!(assemble("@32767").unwrap(), vec![32767]);
+        assert_eq!(
+            assemble("@32768"),
+            Err(AssembleError {
+                line: 1,
+                kind: AssembleErrorKind::AddressOutOfRange("32768".to_string())
+            })
+        );
+    }
+
+    #[test]
+    fn c_instruction() {
+        let cases = vec![
+            // asm, machine code
+            ("D=A", "1110110000010000"),
+            ("D=D+A", "1110000010010000"),
+            ("M=D", "1110001100001000"),
+            ("0;JMP", "1110101010000111"),
+            ("D;JGT", "1110001100000001"),
+            ("AMD=M-1", "1111110010111000"),
+            ("MD=D|M;JLE", "1111010101011110"),
+            ("D = D + M ; JNE", "1111000010010101"),
+            // 左右が逆でも同じ命令
+            ("D=A+D", "1110000010010000"),
+            ("DM=D", "1110001100011000"),
+        ];
+        for (asm, code) in cases {
+            let expected = u16::from_str_radix(code, 2).unwrap();
+            assert_eq!(assemble(asm).unwrap(), vec![expected], "{}", asm);
+        }
+    }
+
+    #[test]
+    fn symbols() {
+        let source = "// 変数とラベル
+                      @i
+                      M=1
+                      (LOOP)
+                      @sum
+                      M=0
+                      @LOOP
+                      0;JMP
+                      (END)
+                      @END
+                      @SCREEN
+                      @KBD
+                      @R15
+                      @i";
+        assert_eq!(
+            assemble(source).unwrap(),
+            vec![16, 0xefc8, 17, 0xea88, 2, 0xea87, 6, 16384, 24576, 15, 16]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let cases = vec![
+            (
+                "@1\nD=X",
+                2,
+                AssembleErrorKind::InvalidComp("X".to_string()),
+            ),
+            (
+                "\n\nQ=D",
+                3,
+                AssembleErrorKind::InvalidDest("Q".to_string()),
+            ),
+            ("DD=D", 1, AssembleErrorKind::InvalidDest("DD".to_string())),
+            (
+                "D;JXX",
+                1,
+                AssembleErrorKind::InvalidJump("JXX".to_string()),
+            ),
+            (
+                "@1abc",
+                1,
+                AssembleErrorKind::InvalidSymbol("1abc".to_string()),
+            ),
+            ("@", 1, AssembleErrorKind::InvalidSymbol("".to_string())),
+            (
+                "(LOOP",
+                1,
+                AssembleErrorKind::InvalidLabel("(LOOP".to_string()),
+            ),
+            (
+                "(A)\n(A)",
+                2,
+                AssembleErrorKind::DuplicateLabel("A".to_string()),
+            ),
+            (
+                "(SP)",
+                1,
+                AssembleErrorKind::DuplicateLabel("SP".to_string()),
+            ),
+        ];
+        for (source, line, kind) in cases {
+            assert_eq!(assemble(source), Err(AssembleError { line, kind }));
+        }
+
+        let too_long = "D=0\n".repeat(ROM_SIZE + 1);
+        assert_eq!(
+            assemble(&too_long),
+            Err(AssembleError {
+                line: ROM_SIZE + 1,
+                kind: AssembleErrorKind::TooManyInstructions
+            })
+        );
+        // ROMをちょうど使い切ったあとのラベルは32768を指してしまう
+        let label_after_full = format!("{}(END)\n", "D=0\n".repeat(ROM_SIZE));
+        assert_eq!(
+            assemble(&label_after_full),
+            Err(AssembleError {
+                line: ROM_SIZE + 1,
+                kind: AssembleErrorKind::TooManyInstructions
+            })
+        );
+        assert_eq!(assemble(&"D=0\n".repeat(ROM_SIZE)).unwrap().len(), ROM_SIZE);
+
+        // 16番地から16383番地まで使い切ると、次の変数は置けない
+        let variables: String = (VARIABLE_BASE..=VARIABLE_END)
+            .map(|i| format!("@v{}\n", i))
+            .collect();
+        let error = assemble(&variables).unwrap_err();
+        assert_eq!(
+            error,
+            AssembleError {
+                line: (VARIABLE_END - VARIABLE_BASE) as usize + 1,
+                kind: AssembleErrorKind::TooManyVariables("v16384".to_string())
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "line 16369: no room for variable `v16384` (variables must fit in 16..=16383)"
+        );
+        assert!(assemble(&variables[..variables.len() - "@v16384\n".len()]).is_ok());
+    }
+
+    #[test]
+    fn to_rom() {
+        let address = Bus::<15>::all0().to_shared_bus();
+        let rom = assemble_to_rom("@2\nD=A", address.clone()).unwrap();
+        rom.re_compute();
+        assert_eq!(rom.out.to_u16(), 2);
+
+        address.overwrite(&"000000000000001".parse().unwrap());
+        rom.re_compute();
+        assert_eq!(rom.out.to_u16(), 0b1110110000010000);
+    }
+}
@@ -0,0 +1,520 @@
+use std::{any::Any, rc::Rc};
+
+use crate::{
+    arithmetic::{Add16, Adder16, AdderKind, FullAdder, HalfAdder, Inc16, ALU},
+    computer::{Computer, KeyboardBuiltIn, MemoryBuiltIn, ROM32KBuiltIn, ScreenBuiltIn, CPU},
+    gate::*,
+    sequential::{OneBitRegister, RAM16KBuiltIn, Register, PC, RAM4K, RAM512, RAM64, RAM8},
+};
+
+/// HDLから使えるRust実装のチップのピン構成
+#[derive(Debug)]
+pub struct BuiltInSpec {
+    pub name: &'static str,
+    pub inputs: &'static [(&'static str, usize)],
+    pub outputs: &'static [(&'static str, usize)],
+    /// 出力が組み合わせ回路として依存する入力。ここにない入力はclockでしか効かない
+    pub combinational: &'static [&'static str],
+}
+
+impl BuiltInSpec {
+    pub fn is_clocked(&self) -> bool {
+        self.combinational.len() < self.inputs.len()
+    }
+}
+
+const fn spec(
+    name: &'static str,
+    inputs: &'static [(&'static str, usize)],
+    outputs: &'static [(&'static str, usize)],
+    combinational: &'static [&'static str],
+) -> BuiltInSpec {
+    BuiltInSpec {
+        name,
+        inputs,
+        outputs,
+        combinational,
+    }
+}
+
+const RAM_INPUTS: [(&str, usize); 3] = [("in", 16), ("load", 1), ("address", 0)];
+
+const ADD16_INPUTS: [(&str, usize); 2] = [("a", 16), ("b", 16)];
+
+const ALU_INPUTS: [(&str, usize); 8] = [
+    ("x", 16),
+    ("y", 16),
+    ("zx", 1),
+    ("nx", 1),
+    ("zy", 1),
+    ("ny", 1),
+    ("f", 1),
+    ("no", 1),
+];
+const ALU_OUTPUTS: [(&str, usize); 3] = [("out", 16), ("zr", 1), ("ng", 1)];
+const ALU_COMBINATIONAL: [&str; 8] = ["x", "y", "zx", "nx", "zy", "ny", "f", "no"];
+
+// Nand と DFF は回路の最小単位として circuit 側で作る
+// CarryLookaheadAdd16 などは nand2tetris にはない、Add16 と加算器だけが違うチップ
+pub const BUILTINS: [BuiltInSpec; 44] = [
+    spec("Nand", &[("a", 1), ("b", 1)], &[("out", 1)], &["a", "b"]),
+    spec("DFF", &[("in", 1)], &[("out", 1)], &[]),
+    spec("Not", &[("in", 1)], &[("out", 1)], &["in"]),
+    spec("And", &[("a", 1), ("b", 1)], &[("out", 1)], &["a", "b"]),
+    spec("Or", &[("a", 1), ("b", 1)], &[("out", 1)], &["a", "b"]),
+    spec("Xor", &[("a", 1), ("b", 1)], &[("out", 1)], &["a", "b"]),
+    spec(
+        "Mux",
+        &[("a", 1), ("b", 1), ("sel", 1)],
+        &[("out", 1)],
+        &["a", "b", "sel"],
+    ),
+    spec(
+        "DMux",
+        &[("in", 1), ("sel", 1)],
+        &[("a", 1), ("b", 1)],
+        &["in", "sel"],
+    ),
+    spec("Not16", &[("in", 16)], &[("out", 16)], &["in"]),
+    spec(
+        "And16",
+        &[("a", 16), ("b", 16)],
+        &[("out", 16)],
+        &["a", "b"],
+    ),
+    spec("Or16", &[("a", 16), ("b", 16)], &[("out", 16)], &["a", "b"]),
+    spec(
+        "Mux16",
+        &[("a", 16), ("b", 16), ("sel", 1)],
+        &[("out", 16)],
+        &["a", "b", "sel"],
+    ),
+    spec("Or8Way", &[("in", 8)], &[("out", 1)], &["in"]),
+    spec(
+        "Mux4Way16",
+        &[("a", 16), ("b", 16), ("c", 16), ("d", 16), ("sel", 2)],
+        &[("out", 16)],
+        &["a", "b", "c", "d", "sel"],
+    ),
+    spec(
+        "Mux8Way16",
+        &[
+            ("a", 16),
+            ("b", 16),
+            ("c", 16),
+            ("d", 16),
+            ("e", 16),
+            ("f", 16),
+            ("g", 16),
+            ("h", 16),
+            ("sel", 3),
+        ],
+        &[("out", 16)],
+        &["a", "b", "c", "d", "e", "f", "g", "h", "sel"],
+    ),
+    spec(
+        "DMux4Way",
+        &[("in", 1), ("sel", 2)],
+        &[("a", 1), ("b", 1), ("c", 1), ("d", 1)],
+        &["in", "sel"],
+    ),
+    spec(
+        "DMux8Way",
+        &[("in", 1), ("sel", 3)],
+        &[
+            ("a", 1),
+            ("b", 1),
+            ("c", 1),
+            ("d", 1),
+            ("e", 1),
+            ("f", 1),
+            ("g", 1),
+            ("h", 1),
+        ],
+        &["in", "sel"],
+    ),
+    spec(
+        "HalfAdder",
+        &[("a", 1), ("b", 1)],
+        &[("sum", 1), ("carry", 1)],
+        &["a", "b"],
+    ),
+    spec(
+        "FullAdder",
+        &[("a", 1), ("b", 1), ("c", 1)],
+        &[("sum", 1), ("carry", 1)],
+        &["a", "b", "c"],
+    ),
+    spec("Add16", &ADD16_INPUTS, &[("out", 16)], &["a", "b"]),
+    spec(
+        "CarryLookaheadAdd16",
+        &ADD16_INPUTS,
+        &[("out", 16)],
+        &["a", "b"],
+    ),
+    spec(
+        "CarrySelectAdd16",
+        &ADD16_INPUTS,
+        &[("out", 16)],
+        &["a", "b"],
+    ),
+    spec(
+        "KoggeStoneAdd16",
+        &ADD16_INPUTS,
+        &[("out", 16)],
+        &["a", "b"],
+    ),
+    spec("Inc16", &[("in", 16)], &[("out", 16)], &["in"]),
+    spec("ALU", &ALU_INPUTS, &ALU_OUTPUTS, &ALU_COMBINATIONAL),
+    spec(
+        "CarryLookaheadALU",
+        &ALU_INPUTS,
+        &ALU_OUTPUTS,
+        &ALU_COMBINATIONAL,
+    ),
+    spec(
+        "CarrySelectALU",
+        &ALU_INPUTS,
+        &ALU_OUTPUTS,
+        &ALU_COMBINATIONAL,
+    ),
+    spec(
+        "KoggeStoneALU",
+        &ALU_INPUTS,
+        &ALU_OUTPUTS,
+        &ALU_COMBINATIONAL,
+    ),
+    spec("Bit", &[("in", 1), ("load", 1)], &[("out", 1)], &[]),
+    spec("Register", &[("in", 16), ("load", 1)], &[("out", 16)], &[]),
+    spec("ARegister", &[("in", 16), ("load", 1)], &[("out", 16)], &[]),
+    spec("DRegister", &[("in", 16), ("load", 1)], &[("out", 16)], &[]),
+    spec(
+        "PC",
+        &[("in", 16), ("load", 1), ("inc", 1), ("reset", 1)],
+        &[("out", 16)],
+        &[],
+    ),
+    spec("RAM8", &ram_inputs::<3>(), &[("out", 16)], &["address"]),
+    spec("RAM64", &ram_inputs::<6>(), &[("out", 16)], &["address"]),
+    spec("RAM512", &ram_inputs::<9>(), &[("out", 16)], &["address"]),
+    spec("RAM4K", &ram_inputs::<12>(), &[("out", 16)], &["address"]),
+    spec("RAM16K", &ram_inputs::<14>(), &[("out", 16)], &["address"]),
+    spec("ROM32K", &[("address", 15)], &[("out", 16)], &["address"]),
+    spec(
+        "Screen",
+        &[("in", 16), ("load", 1), ("address", 13)],
+        &[("out", 16)],
+        &["address"],
+    ),
+    spec("Keyboard", &[], &[("out", 16)], &[]),
+    spec(
+        "Memory",
+        &[("in", 16), ("load", 1), ("address", 15)],
+        &[("out", 16)],
+        &["address"],
+    ),
+    spec(
+        "CPU",
+        &[("inM", 16), ("instruction", 16), ("reset", 1)],
+        &[("outM", 16), ("writeM", 1), ("addressM", 15), ("pc", 15)],
+        &["inM", "instruction"],
+    ),
+    spec("Computer", &[("reset", 1)], &[], &[]),
+];
+
+// RAMnのaddressの幅だけ違う入力
+const fn ram_inputs<const ADDRESS: usize>() -> [(&'static str, usize); 3] {
+    let mut inputs = RAM_INPUTS;
+    inputs[2].1 = ADDRESS;
+    inputs
+}
+
+pub fn find(name: &str) -> Option<&'static BuiltInSpec> {
+    BUILTINS.iter().find(|spec| spec.name == name)
+}
+
+fn bus<const N: usize>(bits: &[SharedBit]) -> SharedBus<N> {
+    Bus::new(std::array::from_fn(|i| bits[i].clone())).to_shared_bus()
+}
+
+// Add16 と ALU で使う加算器をチップ名の頭から決める
+fn adder_kind(name: &str) -> AdderKind {
+    if name.starts_with("CarryLookahead") {
+        AdderKind::CarryLookahead
+    } else if name.starts_with("CarrySelect") {
+        AdderKind::CarrySelect
+    } else if name.starts_with("KoggeStone") {
+        AdderKind::KoggeStone
+    } else {
+        AdderKind::RippleCarry
+    }
+}
+
+/// 組み込みチップ、その中身をdowncastするためのAny、specの出力の順に並んだチップ側の出力bit
+pub type BuiltIn = (Rc<dyn Gate>, Rc<dyn Any>, Vec<Vec<SharedBit>>);
+
+fn shared<G: Gate + 'static>(gate: G, outputs: Vec<Vec<SharedBit>>) -> BuiltIn {
+    let gate = Rc::new(gate);
+    (gate.clone(), gate, outputs)
+}
+
+/// Nand と DFF 以外の組み込みチップを作る。inputsはspecの入力の順
+pub fn build(name: &str, inputs: &[Vec<SharedBit>]) -> Option<BuiltIn> {
+    let i = |index: usize| &inputs[index][..];
+    let built: BuiltIn = match name {
+        "Not" => {
+            let gate = Not::<1>::new(bus(i(0)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "And" => {
+            let gate = And::<1>::new(bus(i(0)), bus(i(1)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Or" => {
+            let gate = Or::<1>::new(bus(i(0)), bus(i(1)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Xor" => {
+            let gate = Xor::<1>::new(bus(i(0)), bus(i(1)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Mux" => {
+            let gate = Mux::<1>::new(bus(i(0)), bus(i(1)), bus(i(2)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "DMux" => {
+            let gate = DMux::new(bus(i(0)), bus(i(1)));
+            let outputs = vec![gate.out1.shared_bits(), gate.out2.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Not16" => {
+            let gate = Not::<16>::new(bus(i(0)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "And16" => {
+            let gate = And::<16>::new(bus(i(0)), bus(i(1)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Or16" => {
+            let gate = Or::<16>::new(bus(i(0)), bus(i(1)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Mux16" => {
+            let gate = Mux::<16>::new(bus(i(0)), bus(i(1)), bus(i(2)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Or8Way" => {
+            let gate = Or8Way::new(bus(i(0)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Mux4Way16" => {
+            let gate = Mux4Way16::new(bus(i(0)), bus(i(1)), bus(i(2)), bus(i(3)), bus(i(4)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Mux8Way16" => {
+            let gate = Mux8Way16::new(
+                bus(i(0)),
+                bus(i(1)),
+                bus(i(2)),
+                bus(i(3)),
+                bus(i(4)),
+                bus(i(5)),
+                bus(i(6)),
+                bus(i(7)),
+                bus(i(8)),
+            );
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "DMux4Way" => {
+            let gate = DMux4Way::new(bus(i(0)), bus(i(1)));
+            let outputs = vec![
+                gate.out1.shared_bits(),
+                gate.out2.shared_bits(),
+                gate.out3.shared_bits(),
+                gate.out4.shared_bits(),
+            ];
+            shared(gate, outputs)
+        }
+        "DMux8Way" => {
+            let gate = DMux8Way::new(bus(i(0)), bus(i(1)));
+            let outputs = vec![
+                gate.out1.shared_bits(),
+                gate.out2.shared_bits(),
+                gate.out3.shared_bits(),
+                gate.out4.shared_bits(),
+                gate.out5.shared_bits(),
+                gate.out6.shared_bits(),
+                gate.out7.shared_bits(),
+                gate.out8.shared_bits(),
+            ];
+            shared(gate, outputs)
+        }
+        "HalfAdder" => {
+            let gate = HalfAdder::new(bus(i(0)), bus(i(1)));
+            let outputs = vec![gate.sum.shared_bits(), gate.carry.shared_bits()];
+            shared(gate, outputs)
+        }
+        "FullAdder" => {
+            let gate = FullAdder::new(bus(i(0)), bus(i(1)), bus(i(2)));
+            let outputs = vec![gate.sum.shared_bits(), gate.carry.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Add16" => {
+            let gate = Add16::new(bus(i(0)), bus(i(1)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "CarryLookaheadAdd16" | "CarrySelectAdd16" | "KoggeStoneAdd16" => {
+            let gate = Adder16::new(adder_kind(name), bus(i(0)), bus(i(1)));
+            let outputs = vec![gate.out().shared_bits()];
+            shared(gate, outputs)
+        }
+        "Inc16" => {
+            let gate = Inc16::new(bus(i(0)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "ALU" | "CarryLookaheadALU" | "CarrySelectALU" | "KoggeStoneALU" => {
+            let gate = ALU::with_adder(
+                bus(i(0)),
+                bus(i(1)),
+                bus(i(2)),
+                bus(i(3)),
+                bus(i(4)),
+                bus(i(5)),
+                bus(i(6)),
+                bus(i(7)),
+                adder_kind(name),
+            );
+            let outputs = vec![
+                gate.out.shared_bits(),
+                gate.zr.shared_bits(),
+                gate.ng.shared_bits(),
+            ];
+            shared(gate, outputs)
+        }
+        "Bit" => {
+            let gate = OneBitRegister::new(bus(i(0)), bus(i(1)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Register" | "ARegister" | "DRegister" => {
+            let gate = Register::<16>::new(bus(i(0)), bus(i(1)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "PC" => {
+            let gate = PC::new(bus(i(0)), bus(i(1)), bus(i(2)), bus(i(3)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "RAM8" => {
+            let gate = RAM8::new(bus(i(0)), bus(i(1)), bus(i(2)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "RAM64" => {
+            let gate = RAM64::new(bus(i(0)), bus(i(1)), bus(i(2)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "RAM512" => {
+            let gate = RAM512::new(bus(i(0)), bus(i(1)), bus(i(2)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "RAM4K" => {
+            let gate = RAM4K::new(bus(i(0)), bus(i(1)), bus(i(2)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "RAM16K" => {
+            let gate = RAM16KBuiltIn::new(bus(i(0)), bus(i(1)), bus(i(2)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "ROM32K" => {
+            let gate = ROM32KBuiltIn::new(Box::new([0; 32768]), bus(i(0)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Screen" => {
+            let gate = ScreenBuiltIn::new(bus(i(0)), bus(i(1)), bus(i(2)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Keyboard" => {
+            let gate = KeyboardBuiltIn::new();
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "Memory" => {
+            let gate = MemoryBuiltIn::new(bus(i(0)), bus(i(1)), bus(i(2)));
+            let outputs = vec![gate.out.shared_bits()];
+            shared(gate, outputs)
+        }
+        "CPU" => {
+            let gate = CPU::new(bus(i(0)), bus(i(1)), bus(i(2)));
+            let outputs = vec![
+                gate.out_m.shared_bits(),
+                gate.write_m.shared_bits(),
+                gate.address_m.shared_bits(),
+                gate.pc.shared_bits(),
+            ];
+            shared(gate, outputs)
+        }
+        "Computer" => {
+            let rom = ROM32KBuiltIn::new(Box::new([0; 32768]), Bus::all0().to_shared_bus());
+            let gate = Computer::new(bus(i(0)), rom);
+            shared(gate, vec![])
+        }
+        _ => return None,
+    };
+    Some(built)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_spec_can_be_built() {
+        for spec in BUILTINS
+            .iter()
+            .filter(|s| s.name != "Nand" && s.name != "DFF")
+        {
+            let inputs: Vec<Vec<SharedBit>> = spec
+                .inputs
+                .iter()
+                .map(|(_, width)| {
+                    (0..*width)
+                        .map(|_| Bus::<1>::all0().bits[0].clone())
+                        .collect()
+                })
+                .collect();
+            let (_, _, outputs) = build(spec.name, &inputs).unwrap();
+            let widths: Vec<usize> = outputs.iter().map(|o| o.len()).collect();
+            let expected: Vec<usize> = spec.outputs.iter().map(|(_, w)| *w).collect();
+            assert_eq!(widths, expected, "{}", spec.name);
+            for pin in spec.combinational {
+                assert!(spec.inputs.iter().any(|(name, _)| name == pin));
+            }
+        }
+        assert!(find("Register").unwrap().is_clocked());
+        assert!(!find("ALU").unwrap().is_clocked());
+        assert_eq!(find("RAM4K").unwrap().inputs[2], ("address", 12));
+        assert!(find("Foo").is_none());
+    }
+}
@@ -0,0 +1,1224 @@
+use std::{
+    any::Any,
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    fmt, fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    builtin::{self, BuiltInSpec},
+    gate::*,
+    hdl::*,
+    netlist::{Netlist, NetlistBuilder, NetlistError},
+    sequential::DFF,
+};
+
+const FALSE_NET: usize = 0;
+const TRUE_NET: usize = 1;
+
+/// 読み込んだチップの定義と、そのファイル
+#[derive(Debug)]
+struct Loaded {
+    def: ChipDef,
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+enum Resolved {
+    Hdl(Rc<Loaded>),
+    BuiltIn(&'static BuiltInSpec),
+}
+
+/// チップの入出力ピン
+#[derive(Debug, Clone)]
+struct Interface {
+    inputs: Vec<PinDecl>,
+    outputs: Vec<PinDecl>,
+}
+
+impl Interface {
+    fn from_spec(spec: &BuiltInSpec) -> Interface {
+        let pins = |pins: &[(&str, usize)]| {
+            pins.iter()
+                .map(|(name, width)| PinDecl {
+                    name: name.to_string(),
+                    width: *width,
+                })
+                .collect()
+        };
+        Interface {
+            inputs: pins(spec.inputs),
+            outputs: pins(spec.outputs),
+        }
+    }
+
+    fn input(&self, name: &str) -> Option<&PinDecl> {
+        self.inputs.iter().find(|pin| pin.name == name)
+    }
+
+    fn output(&self, name: &str) -> Option<&PinDecl> {
+        self.outputs.iter().find(|pin| pin.name == name)
+    }
+}
+
+/// .hdlを探すディレクトリと、読み込んだチップのキャッシュ
+/// .hdlが見つからないチップは組み込みの実装を使う
+#[derive(Debug)]
+pub struct Library {
+    dirs: Vec<PathBuf>,
+    loaded: HashMap<String, Rc<Loaded>>,
+}
+
+impl Library {
+    pub fn new<P: AsRef<Path>>(dirs: &[P]) -> Library {
+        Library {
+            dirs: dirs.iter().map(|dir| dir.as_ref().to_path_buf()).collect(),
+            loaded: HashMap::new(),
+        }
+    }
+
+    fn resolve(&mut self, name: &str) -> Result<Resolved, HdlError> {
+        if name == "Nand" || name == "DFF" {
+            return Ok(Resolved::BuiltIn(builtin::find(name).unwrap()));
+        }
+        if let Some(loaded) = self.loaded.get(name) {
+            return Ok(Resolved::Hdl(loaded.clone()));
+        }
+        let file = self
+            .dirs
+            .iter()
+            .map(|dir| dir.join(format!("{}.hdl", name)))
+            .find(|file| file.is_file());
+        if let Some(file) = file {
+            let file = Some(file);
+            let source = fs::read_to_string(file.as_ref().unwrap())
+                .map_err(|e| HdlError::new(0, HdlErrorKind::Io(e.to_string())).in_file(&file))?;
+            let def = parse(&source).map_err(|e| e.in_file(&file))?;
+            let loaded = Rc::new(Loaded { def, file });
+            self.loaded.insert(name.to_string(), loaded.clone());
+            return Ok(Resolved::Hdl(loaded));
+        }
+        match builtin::find(name) {
+            Some(spec) => Ok(Resolved::BuiltIn(spec)),
+            None => Err(HdlError::new(
+                0,
+                HdlErrorKind::UnknownChip(name.to_string()),
+            )),
+        }
+    }
+
+    fn interface(&mut self, name: &str) -> Result<Interface, HdlError> {
+        match self.resolve(name)? {
+            Resolved::BuiltIn(spec) => Ok(Interface::from_spec(spec)),
+            Resolved::Hdl(loaded) => Ok(Interface {
+                inputs: loaded.def.inputs.clone(),
+                outputs: loaded.def.outputs.clone(),
+            }),
+        }
+    }
+
+    /// チップを Nand / DFF と組み込みチップまで展開して組み立てる
+    pub fn build(&mut self, name: &str) -> Result<Chip, HdlError> {
+        let interface = self.interface(name)?;
+        let mut builder = Builder {
+            library: self,
+            parent: vec![FALSE_NET, TRUE_NET],
+            names: vec!["false".to_string(), "true".to_string()],
+            nodes: vec![],
+            node_parts: vec![],
+            parts: vec![],
+            stack: vec![],
+        };
+
+        let mut pins = HashMap::new();
+        let mut top = |pins_decl: &[PinDecl]| {
+            pins_decl
+                .iter()
+                .map(|pin| {
+                    let nets = builder.new_nets(name, &pin.name, pin.width);
+                    pins.insert(pin.name.clone(), nets.clone());
+                    (pin.name.clone(), nets)
+                })
+                .collect::<Vec<_>>()
+        };
+        let inputs = top(&interface.inputs);
+        let outputs = top(&interface.outputs);
+        builder.instantiate(name, pins, name)?;
+        builder.finish(name, inputs, outputs)
+    }
+}
+
+/// .hdlファイルからチップを作る。同じディレクトリの.hdlも部品として使う
+pub fn load_chip<P: AsRef<Path>>(path: P) -> Result<Chip, HdlError> {
+    let path = path.as_ref();
+    let file = Some(path.to_path_buf());
+    let source = fs::read_to_string(path)
+        .map_err(|e| HdlError::new(0, HdlErrorKind::Io(e.to_string())).in_file(&file))?;
+    let def = parse(&source).map_err(|e| e.in_file(&file))?;
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut library = Library::new(&[dir]);
+    let name = def.name.clone();
+    library
+        .loaded
+        .insert(name.clone(), Rc::new(Loaded { def, file }));
+    library.build(&name)
+}
+
+#[derive(Debug)]
+enum NodeSpec {
+    Nand {
+        a: usize,
+        b: usize,
+        out: usize,
+    },
+    Dff {
+        input: usize,
+        out: usize,
+    },
+    BuiltIn {
+        spec: &'static BuiltInSpec,
+        inputs: Vec<Vec<usize>>,
+        outputs: Vec<Vec<usize>>,
+    },
+}
+
+// 展開しながらnetを番号で管理する。出力が複数の外側のピンにつながるとnetを併合する
+struct Builder<'a> {
+    library: &'a mut Library,
+    // union-find
+    parent: Vec<usize>,
+    names: Vec<String>,
+    nodes: Vec<NodeSpec>,
+    // nodesと同じ並び。そのノードに行き着くまでにたどった部品の (ファイル, 行)
+    node_parts: Vec<Vec<PartLocation>>,
+    // 展開中の部品の (ファイル, 行)
+    parts: Vec<PartLocation>,
+    // 展開中のチップ。自分自身を部品にしていないか調べる
+    stack: Vec<String>,
+}
+
+type PartLocation = (Option<PathBuf>, usize);
+
+impl Builder<'_> {
+    fn new_nets(&mut self, scope: &str, pin: &str, width: usize) -> Vec<usize> {
+        (0..width)
+            .map(|i| {
+                let net = self.parent.len();
+                self.parent.push(net);
+                self.names.push(match width {
+                    1 => format!("{}.{}", scope, pin),
+                    _ => format!("{}.{}[{}]", scope, pin, i),
+                });
+                net
+            })
+            .collect()
+    }
+
+    fn find(&mut self, net: usize) -> usize {
+        let mut root = net;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut net = net;
+        while self.parent[net] != root {
+            let next = self.parent[net];
+            self.parent[net] = root;
+            net = next;
+        }
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize) -> () {
+        let (a, b) = (self.find(a), self.find(b));
+        // 番号の小さい方(先に作られた外側のピン)を残す
+        if a < b {
+            self.parent[b] = a;
+        } else {
+            self.parent[a] = b;
+        }
+    }
+
+    // pinsには部品のすべての入出力ピンのnetが入っている
+    fn instantiate(
+        &mut self,
+        name: &str,
+        pins: HashMap<String, Vec<usize>>,
+        scope: &str,
+    ) -> Result<(), HdlError> {
+        let (def, file) = match self.library.resolve(name)? {
+            Resolved::BuiltIn(spec) => {
+                self.add_builtin(spec, &pins);
+                return Ok(());
+            }
+            Resolved::Hdl(loaded) => match &loaded.def.body {
+                ChipBody::BuiltIn { name, line, .. } => {
+                    let spec = builtin::find(name).ok_or_else(|| {
+                        HdlError::new(*line, HdlErrorKind::UnknownBuiltIn(name.clone()))
+                            .in_file(&loaded.file)
+                    })?;
+                    self.add_builtin(spec, &pins);
+                    return Ok(());
+                }
+                ChipBody::Parts(_) => (loaded.clone(), loaded.file.clone()),
+            },
+        };
+        let def = &def.def;
+        let parts = match &def.body {
+            ChipBody::Parts(parts) => parts,
+            ChipBody::BuiltIn { .. } => unreachable!(),
+        };
+        // 部品として書いた行のせいにするので、ファイルは付けずに返す
+        if self.stack.iter().any(|chip| chip == name) {
+            return Err(HdlError::new(
+                0,
+                HdlErrorKind::RecursiveChip(name.to_string()),
+            ));
+        }
+        self.stack.push(name.to_string());
+
+        // このチップの中で見えるピン。IN/OUTと内部のピン
+        let mut local = pins;
+        let error = |line: usize, kind: HdlErrorKind| HdlError::new(line, kind).in_file(&file);
+
+        // 1周目: 部品の出力をつないで内部のピンを作る
+        let mut part_pins = vec![];
+        for (index, part) in parts.iter().enumerate() {
+            let interface = self
+                .library
+                .interface(&part.chip)
+                .map_err(|e| match e.file {
+                    None => HdlError {
+                        line: part.line,
+                        ..e
+                    }
+                    .in_file(&file),
+                    Some(_) => e,
+                })?;
+            let part_scope = format!("{}/{}#{}", scope, part.chip, index);
+
+            let mut outputs: Vec<(String, Vec<Option<usize>>)> = interface
+                .outputs
+                .iter()
+                .map(|pin| (pin.name.clone(), vec![None; pin.width]))
+                .collect();
+            for connection in part.connections.iter() {
+                let inner = &connection.inner;
+                let pin = match interface.output(&inner.name) {
+                    Some(pin) => pin,
+                    None => continue,
+                };
+                let (start, end) = slice(inner, pin.width).map_err(|k| error(part.line, k))?;
+                let outer = match &connection.outer {
+                    Wire::Pin(outer) => outer,
+                    Wire::Const(_) => {
+                        return Err(error(
+                            part.line,
+                            HdlErrorKind::DrivesConstant(inner.name.clone()),
+                        ))
+                    }
+                };
+                if def.input(&outer.name).is_some() {
+                    return Err(error(
+                        part.line,
+                        HdlErrorKind::DrivesInput(outer.name.clone()),
+                    ));
+                }
+                let outer_nets = match local.get(&outer.name) {
+                    Some(nets) => {
+                        let (outer_start, outer_end) =
+                            slice(outer, nets.len()).map_err(|k| error(part.line, k))?;
+                        nets[outer_start..=outer_end].to_vec()
+                    }
+                    None if outer.range.is_some() => {
+                        return Err(error(part.line, HdlErrorKind::BadSlice(outer.name.clone())))
+                    }
+                    None => {
+                        let nets = self.new_nets(scope, &outer.name, end - start + 1);
+                        local.insert(outer.name.clone(), nets.clone());
+                        nets
+                    }
+                };
+                if outer_nets.len() != end - start + 1 {
+                    return Err(error(
+                        part.line,
+                        HdlErrorKind::WidthMismatch {
+                            pin: inner.name.clone(),
+                            inner: end - start + 1,
+                            outer: outer_nets.len(),
+                        },
+                    ));
+                }
+
+                let slots = &mut outputs
+                    .iter_mut()
+                    .find(|(name, _)| *name == pin.name)
+                    .unwrap()
+                    .1;
+                for (slot, outer_net) in slots[start..=end].iter_mut().zip(outer_nets) {
+                    match slot {
+                        Some(net) => {
+                            let net = *net;
+                            self.union(net, outer_net);
+                        }
+                        None => *slot = Some(outer_net),
+                    }
+                }
+            }
+
+            let mut nets = HashMap::new();
+            for (pin, slots) in outputs {
+                let unconnected = self.new_nets(&part_scope, &pin, slots.len());
+                let pin_nets = slots
+                    .iter()
+                    .zip(unconnected)
+                    .map(|(slot, net)| slot.unwrap_or(net))
+                    .collect();
+                nets.insert(pin, pin_nets);
+            }
+            part_pins.push((interface, part_scope, nets));
+        }
+
+        // 2周目: 部品の入力をつないで展開する
+        for (part, (interface, part_scope, mut nets)) in parts.iter().zip(part_pins) {
+            for pin in interface.inputs.iter() {
+                nets.insert(pin.name.clone(), vec![FALSE_NET; pin.width]);
+            }
+            for connection in part.connections.iter() {
+                let inner = &connection.inner;
+                let pin = match (interface.input(&inner.name), interface.output(&inner.name)) {
+                    (Some(pin), _) => pin,
+                    (None, Some(_)) => continue,
+                    (None, None) => {
+                        return Err(error(
+                            part.line,
+                            HdlErrorKind::UnknownPin {
+                                chip: part.chip.clone(),
+                                pin: inner.name.clone(),
+                            },
+                        ))
+                    }
+                };
+                let (start, end) = slice(inner, pin.width).map_err(|k| error(part.line, k))?;
+                let outer_nets = match &connection.outer {
+                    Wire::Const(value) => {
+                        vec![if *value { TRUE_NET } else { FALSE_NET }; end - start + 1]
+                    }
+                    Wire::Pin(outer) => {
+                        let nets = local.get(&outer.name).ok_or_else(|| {
+                            error(
+                                part.line,
+                                HdlErrorKind::UnknownPin {
+                                    chip: def.name.clone(),
+                                    pin: outer.name.clone(),
+                                },
+                            )
+                        })?;
+                        let (outer_start, outer_end) =
+                            slice(outer, nets.len()).map_err(|k| error(part.line, k))?;
+                        nets[outer_start..=outer_end].to_vec()
+                    }
+                };
+                if outer_nets.len() != end - start + 1 {
+                    return Err(error(
+                        part.line,
+                        HdlErrorKind::WidthMismatch {
+                            pin: inner.name.clone(),
+                            inner: end - start + 1,
+                            outer: outer_nets.len(),
+                        },
+                    ));
+                }
+                nets.get_mut(&pin.name).unwrap()[start..=end].copy_from_slice(&outer_nets);
+            }
+            self.parts.push((file.clone(), part.line));
+            self.instantiate(&part.chip, nets, &part_scope)
+                .map_err(|e| match e.line {
+                    // 部品のファイルが分からないエラーはこの行のせいにする
+                    0 if e.file.is_none() => HdlError {
+                        line: part.line,
+                        ..e
+                    }
+                    .in_file(&file),
+                    _ => e,
+                })?;
+            self.parts.pop();
+        }
+
+        self.stack.pop();
+        Ok(())
+    }
+
+    fn add_builtin(
+        &mut self,
+        spec: &'static BuiltInSpec,
+        pins: &HashMap<String, Vec<usize>>,
+    ) -> () {
+        let nets = |pins_spec: &[(&str, usize)]| -> Vec<Vec<usize>> {
+            pins_spec
+                .iter()
+                .map(|(name, _)| pins[*name].clone())
+                .collect()
+        };
+        let inputs = nets(spec.inputs);
+        let outputs = nets(spec.outputs);
+        let node = match spec.name {
+            "Nand" => NodeSpec::Nand {
+                a: inputs[0][0],
+                b: inputs[1][0],
+                out: outputs[0][0],
+            },
+            "DFF" => NodeSpec::Dff {
+                input: inputs[0][0],
+                out: outputs[0][0],
+            },
+            _ => NodeSpec::BuiltIn {
+                spec,
+                inputs,
+                outputs,
+            },
+        };
+        self.nodes.push(node);
+        self.node_parts.push(self.parts.clone());
+    }
+
+    // nodesの原因になった部品の行でエラーを作る。
+    // どのノードにも共通する部品の中で、最初に食い違う部品 (最後のノードのもの) の行にする
+    fn error_at(&self, nodes: &[usize], kind: HdlErrorKind) -> HdlError {
+        let paths: Vec<&Vec<PartLocation>> = nodes.iter().map(|i| &self.node_parts[*i]).collect();
+        let last = match paths.last() {
+            Some(last) if !last.is_empty() => last,
+            _ => return HdlError::new(0, kind),
+        };
+        let common = (0..last.len())
+            .take_while(|depth| {
+                paths
+                    .iter()
+                    .all(|path| path.get(*depth) == last.get(*depth))
+            })
+            .count();
+        let (file, line) = &last[common.min(last.len() - 1)];
+        HdlError::new(*line, kind).in_file(file)
+    }
+
+    fn finish(
+        mut self,
+        name: &str,
+        inputs: Vec<(String, Vec<usize>)>,
+        outputs: Vec<(String, Vec<usize>)>,
+    ) -> Result<Chip, HdlError> {
+        let roots: Vec<usize> = (0..self.parent.len()).map(|net| self.find(net)).collect();
+        let bits: Vec<SharedBit> = (0..self.parent.len())
+            .map(|net| Rc::new(Cell::new(if net == TRUE_NET { I } else { O })))
+            .collect();
+        let bit = |net: usize| bits[roots[net]].clone();
+
+        // 各netを出力しているノード
+        let mut drivers: Vec<Option<usize>> = vec![None; self.parent.len()];
+        for (index, node) in self.nodes.iter().enumerate() {
+            let outs = match node {
+                NodeSpec::Nand { out, .. } | NodeSpec::Dff { out, .. } => vec![*out],
+                NodeSpec::BuiltIn { outputs, .. } => outputs.concat(),
+            };
+            for net in outs {
+                let root = roots[net];
+                if let Some(first) = drivers[root] {
+                    return Err(self.error_at(
+                        &[first, index],
+                        HdlErrorKind::DrivenTwice(self.names[root].clone()),
+                    ));
+                }
+                drivers[root] = Some(index);
+            }
+        }
+
+        // 組み合わせ回路として依存しているノード同士で並べ替える
+        let mut dependents: Vec<Vec<usize>> = vec![vec![]; self.nodes.len()];
+        let mut dependencies: Vec<Vec<usize>> = vec![vec![]; self.nodes.len()];
+        let mut in_degree = vec![0; self.nodes.len()];
+        for (index, node) in self.nodes.iter().enumerate() {
+            let combinational = match node {
+                NodeSpec::Nand { a, b, .. } => vec![*a, *b],
+                NodeSpec::Dff { .. } => vec![],
+                NodeSpec::BuiltIn { spec, inputs, .. } => spec
+                    .inputs
+                    .iter()
+                    .zip(inputs)
+                    .filter(|((pin, _), _)| spec.combinational.contains(pin))
+                    .flat_map(|(_, nets)| nets.clone())
+                    .collect(),
+            };
+            for net in combinational {
+                if let Some(driver) = drivers[roots[net]] {
+                    dependents[driver].push(index);
+                    dependencies[index].push(driver);
+                    in_degree[index] += 1;
+                }
+            }
+        }
+        let mut queue: VecDeque<usize> = (0..self.nodes.len())
+            .filter(|i| in_degree[*i] == 0)
+            .collect();
+        let mut order = vec![];
+        while let Some(index) = queue.pop_front() {
+            order.push(index);
+            for dependent in dependents[index].iter() {
+                in_degree[*dependent] -= 1;
+                if in_degree[*dependent] == 0 {
+                    queue.push_back(*dependent);
+                }
+            }
+        }
+        if order.len() < self.nodes.len() {
+            let nets = (0..self.nodes.len())
+                .filter(|i| in_degree[*i] > 0)
+                .filter_map(|i| match &self.nodes[i] {
+                    NodeSpec::Nand { out, .. } => Some(self.names[roots[*out]].clone()),
+                    NodeSpec::BuiltIn { outputs, .. } => outputs
+                        .concat()
+                        .first()
+                        .map(|net| self.names[roots[*net]].clone()),
+                    NodeSpec::Dff { .. } => None,
+                })
+                .take(8)
+                .collect();
+            // 残ったノードから、残っている入力をさかのぼるといずれループに入る
+            let mut path = vec![];
+            let mut node = (0..self.nodes.len()).find(|i| in_degree[*i] > 0);
+            while let Some(index) = node {
+                if let Some(start) = path.iter().position(|i| *i == index) {
+                    path.drain(..start);
+                    break;
+                }
+                path.push(index);
+                node = dependencies[index]
+                    .iter()
+                    .copied()
+                    .find(|i| in_degree[*i] > 0);
+            }
+            return Err(self.error_at(&path, HdlErrorKind::CombinationalLoop(nets)));
+        }
+
+        let mut nodes = vec![];
+        let mut clocked = vec![];
+        for index in order {
+            let node = match &self.nodes[index] {
+                NodeSpec::Nand { a, b, out } => {
+                    let nand = Nand::<1>::new(
+                        Bus::new([bit(*a)]).to_shared_bus(),
+                        Bus::new([bit(*b)]).to_shared_bus(),
+                    );
+                    nand.out.0.borrow_mut().bits[0] = bit(*out);
+                    Node::Nand(nand)
+                }
+                NodeSpec::Dff { input, out } => {
+                    let dff = DFF::new(Bus::new([bit(*input)]).to_shared_bus());
+                    dff.out.0.borrow_mut().bits[0] = bit(*out);
+                    Node::Dff(dff)
+                }
+                NodeSpec::BuiltIn {
+                    spec,
+                    inputs,
+                    outputs,
+                } => {
+                    let input_bits: Vec<Vec<SharedBit>> = inputs
+                        .iter()
+                        .map(|nets| nets.iter().map(|net| bit(*net)).collect())
+                        .collect();
+                    let (gate, state, gate_outputs) =
+                        builtin::build(spec.name, &input_bits).unwrap();
+                    let outputs = gate_outputs
+                        .concat()
+                        .into_iter()
+                        .zip(outputs.concat().into_iter().map(bit))
+                        .collect();
+                    if spec.is_clocked() {
+                        clocked.push(nodes.len());
+                    }
+                    Node::BuiltIn(BuiltInNode {
+                        name: spec.name,
+                        gate,
+                        state,
+                        outputs,
+                    })
+                }
+            };
+            nodes.push(node);
+        }
+
+        let pins = |pins: Vec<(String, Vec<usize>)>| {
+            pins.into_iter()
+                .map(|(name, nets)| (name, nets.into_iter().map(bit).collect()))
+                .collect()
+        };
+        let chip = Chip {
+            name: name.to_string(),
+            inputs: pins(inputs),
+            outputs: pins(outputs),
+            nodes,
+            clocked,
+        };
+        // 組み合わせ回路のループは上で除いているので、ここで落ち着かなければ組み込みチップの中のループ
+        chip.settle().map_err(|e| {
+            // 変わり続けているnetを出力している組み込みチップの行にする
+            let driver = (0..self.parent.len())
+                .find(|net| e.bits.iter().any(|b| Rc::ptr_eq(b, &bit(*net))))
+                .and_then(|net| drivers[roots[net]]);
+            let kind = HdlErrorKind::CombinationalLoop(e.nets);
+            match driver {
+                Some(node) => self.error_at(&[node], kind),
+                None => HdlError::new(0, kind),
+            }
+        })?;
+        Ok(chip)
+    }
+}
+
+struct BuiltInNode {
+    name: &'static str,
+    gate: Rc<dyn Gate>,
+    // gateと同じもの。中のレジスタやRAMを見るときにdowncastする
+    state: Rc<dyn Any>,
+    // (組み込みチップの出力bit, つながっているnet)
+    outputs: Vec<(SharedBit, SharedBit)>,
+}
+
+impl fmt::Debug for BuiltInNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BuiltIn({})", self.name)
+    }
+}
+
+impl Gate for BuiltInNode {
+    // 組み込みチップの中で値が戻ってきても、Chipのsettleで落ち着くまで繰り返される
+    fn re_compute(&self) -> () {
+        self.gate.re_compute();
+        for (from, to) in self.outputs.iter() {
+            drive(to, from.get());
+        }
+    }
+
+    fn clock_up(&self) -> () {
+        self.gate.clock_up();
+    }
+
+    fn clock_down(&self) -> () {
+        self.gate.clock_down();
+    }
+
+    // Rustで書いたチップもNandとDFFでできていれば展開できる。RAM16KやScreenなどはエラー
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder
+            .named_part(self.name, self.name, self.gate.as_ref())
+            .map_err(|_| NetlistError::Unsupported(self.name.to_string()))?;
+        for (from, to) in self.outputs.iter() {
+            builder.connect(from, to);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Nand(Nand<1>),
+    Dff(DFF),
+    BuiltIn(BuiltInNode),
+}
+
+impl Gate for Node {
+    fn re_compute(&self) -> () {
+        match self {
+            Node::Nand(nand) => nand.re_compute(),
+            Node::Dff(dff) => dff.re_compute(),
+            Node::BuiltIn(builtin) => builtin.re_compute(),
+        }
+    }
+
+    fn clock_up(&self) -> () {
+        match self {
+            Node::Nand(nand) => nand.clock_up(),
+            Node::Dff(dff) => dff.clock_up(),
+            Node::BuiltIn(builtin) => builtin.clock_up(),
+        }
+    }
+
+    fn clock_down(&self) -> () {
+        match self {
+            Node::Nand(nand) => nand.clock_down(),
+            Node::Dff(dff) => dff.clock_down(),
+            Node::BuiltIn(builtin) => builtin.clock_down(),
+        }
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        match self {
+            Node::Nand(nand) => nand.flatten(builder),
+            Node::Dff(dff) => dff.flatten(builder),
+            Node::BuiltIn(builtin) => builtin.flatten(builder),
+        }
+    }
+}
+
+/// HDLから組み立てた回路。ノードは組み合わせ回路の依存順に並んでいる
+#[derive(Debug)]
+pub struct Chip {
+    name: String,
+    inputs: Vec<(String, Vec<SharedBit>)>,
+    outputs: Vec<(String, Vec<SharedBit>)>,
+    nodes: Vec<Node>,
+    // clockでしか効かない入力を持つ組み込みチップ。入力が決まった後にもう一度re_computeする
+    clocked: Vec<usize>,
+}
+
+impl Chip {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// (ピン名, 幅)
+    pub fn input_pins(&self) -> Vec<(&str, usize)> {
+        self.inputs
+            .iter()
+            .map(|(name, bits)| (name.as_str(), bits.len()))
+            .collect()
+    }
+
+    pub fn output_pins(&self) -> Vec<(&str, usize)> {
+        self.outputs
+            .iter()
+            .map(|(name, bits)| (name.as_str(), bits.len()))
+            .collect()
+    }
+
+    /// 入力ピンに値を入れる。そのような入力ピンがなければfalse
+    /// 出力に反映するにはre_compute()を呼ぶ
+    pub fn set(&self, pin: &str, value: u16) -> bool {
+        match self.inputs.iter().find(|(name, _)| name == pin) {
+            Some((_, bits)) => {
+                for (i, bit) in bits.iter().enumerate() {
+                    bit.set(if (value >> i) & 1 == 1 { I } else { O });
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 組み込みチップの (名前, 中身) を回路の並び順に返す
+    /// 中身はcomputer::CPUやsequential::Registerなどにdowncastできる
+    pub fn builtins(&self) -> impl Iterator<Item = (&'static str, &dyn Any)> {
+        self.nodes.iter().filter_map(|node| match node {
+            Node::BuiltIn(builtin) => Some((builtin.name, builtin.state.as_ref())),
+            _ => None,
+        })
+    }
+
+    /// NandとDFFだけのNetlistにする。組み込みチップを使っているとエラー
+    pub fn to_netlist(&self) -> Result<Netlist, NetlistError> {
+        Netlist::from_gate(self, &pin_bits(&self.inputs), &pin_bits(&self.outputs))
+    }
+
+    /// Vcdで記録する入出力ピンの (名前, bit)
+    pub fn probes(&self) -> Vec<(&str, Vec<SharedBit>)> {
+        let mut probes = pin_bits(&self.inputs);
+        probes.extend(pin_bits(&self.outputs));
+        probes
+    }
+
+    /// 入出力ピンの値
+    pub fn get(&self, pin: &str) -> Option<u16> {
+        self.inputs
+            .iter()
+            .chain(self.outputs.iter())
+            .find(|(name, _)| name == pin)
+            .map(|(_, bits)| {
+                bits.iter()
+                    .enumerate()
+                    .map(|(i, bit)| ((bit.get() == I) as u16) << i)
+                    .sum()
+            })
+    }
+}
+
+impl Gate for Chip {
+    fn re_compute(&self) -> () {
+        for node in self.nodes.iter() {
+            node.re_compute();
+        }
+        for index in self.clocked.iter() {
+            self.nodes[*index].re_compute();
+        }
+    }
+
+    fn clock_up(&self) -> () {
+        for node in self.nodes.iter() {
+            node.clock_up();
+        }
+    }
+
+    fn clock_down(&self) -> () {
+        for node in self.nodes.iter() {
+            node.clock_down();
+        }
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        for node in self.nodes.iter() {
+            node.flatten(builder)?;
+        }
+        Ok(())
+    }
+}
+
+fn pin_bits(pins: &[(String, Vec<SharedBit>)]) -> Vec<(&str, Vec<SharedBit>)> {
+    pins.iter()
+        .map(|(name, bits)| (name.as_str(), bits.clone()))
+        .collect()
+}
+
+// 参照しているbitの範囲。幅を超えていたらエラー
+fn slice(pin: &PinRef, width: usize) -> Result<(usize, usize), HdlErrorKind> {
+    match pin.range {
+        None => Ok((0, width - 1)),
+        Some((start, end)) if start <= end && end < width => Ok((start, end)),
+        Some(_) => Err(HdlErrorKind::BadSlice(pin.name.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XOR: &str = "
+        CHIP Xor {
+            IN a, b;
+            OUT out;
+            PARTS:
+            Nand(a=a, b=b, out=nandab);
+            Nand(a=a, b=nandab, out=x);
+            Nand(a=nandab, b=b, out=y);
+            Nand(a=x, b=y, out=out);
+        }
+    ";
+
+    // 順番を入れ替えても、内部のピンを先に使っても組み立てられる
+    const HALF_ADDER: &str = "
+        CHIP HalfAdder {
+            IN a, b;
+            OUT sum, carry;
+            PARTS:
+            Not(in=nab, out=carry);
+            Xor(a=a, b=b, out=sum);
+            Nand(a=a, b=b, out=nab);
+        }
+    ";
+
+    const NOT: &str = "
+        CHIP Not {
+            IN in;
+            OUT out;
+            PARTS:
+            Nand(a=in, b=true, out=out);
+        }
+    ";
+
+    fn library_of(sources: &[&str]) -> Library {
+        let mut library = Library::new::<&str>(&[]);
+        for source in sources {
+            let def = parse(source).unwrap();
+            let loaded = Rc::new(Loaded { def, file: None });
+            library.loaded.insert(loaded.def.name.clone(), loaded);
+        }
+        library
+    }
+
+    #[test]
+    fn combinational() {
+        let mut library = library_of(&[XOR, HALF_ADDER, NOT]);
+        let chip = library.build("HalfAdder").unwrap();
+        assert_eq!(chip.input_pins(), vec![("a", 1), ("b", 1)]);
+        assert_eq!(chip.output_pins(), vec![("sum", 1), ("carry", 1)]);
+        for (a, b, sum, carry) in [(0, 0, 0, 0), (0, 1, 1, 0), (1, 0, 1, 0), (1, 1, 0, 1)] {
+            assert!(chip.set("a", a));
+            assert!(chip.set("b", b));
+            chip.re_compute();
+            assert_eq!(chip.get("sum"), Some(sum));
+            assert_eq!(chip.get("carry"), Some(carry));
+        }
+        assert!(!chip.set("sum", 1));
+        assert_eq!(chip.get("nab"), None);
+        // Xor / Not はHDLの方、Nandまで展開される
+        assert!(chip.nodes.iter().all(|node| matches!(node, Node::Nand(_))));
+        assert_eq!(chip.nodes.len(), 6);
+    }
+
+    #[test]
+    fn buses_and_builtins() {
+        // 上位と下位のバイトを入れ替えて、1を足す
+        let mut library = library_of(&["
+            CHIP SwapInc {
+                IN in[16];
+                OUT out[16], low[8], neg, zero;
+                PARTS:
+                Inc16(in[0..7]=in[8..15], in[8..15]=in[0..7], out=out, out[0..7]=low, out[15]=neg);
+                Or16(a=false, b[3]=true, out[3]=zero);
+            }
+        "]);
+        let chip = library.build("SwapInc").unwrap();
+        chip.set("in", 0x12ff);
+        chip.re_compute();
+        assert_eq!(chip.get("out"), Some(0xff13));
+        assert_eq!(chip.get("low"), Some(0x13));
+        assert_eq!(chip.get("neg"), Some(1));
+        assert_eq!(chip.get("zero"), Some(1));
+    }
+
+    #[test]
+    fn netlist() {
+        // 組み込みのMuxとInc16も展開される
+        let mut library = library_of(&["
+            CHIP Counter {
+                IN in[16], load;
+                OUT out[16], low;
+                PARTS:
+                Inc16(in=prev, out=inc);
+                Mux16(a=inc, b=in, sel=load, out=next);
+                Register(in=next, load=true, out=prev, out=out, out[0]=low);
+            }
+        "]);
+        let chip = library.build("Counter").unwrap();
+        let netlist = chip.to_netlist().unwrap();
+        assert_eq!(netlist.dffs().len(), 16);
+        for (input, load) in [(5, 0), (100, 1), (0, 0), (0, 0), (0xffff, 1), (3, 0)] {
+            chip.set("in", input);
+            chip.set("load", load);
+            netlist.set("in", input);
+            netlist.set("load", load);
+            for gate in [&chip as &dyn Gate, &netlist] {
+                gate.re_compute();
+                gate.clock_up();
+                gate.clock_down();
+                gate.re_compute();
+            }
+            assert_eq!(netlist.get("out"), chip.get("out"));
+            assert_eq!(netlist.get("low"), chip.get("low"));
+        }
+
+        let mut library = library_of(&["
+            CHIP Memory2 {
+                IN address[14];
+                OUT out[16];
+                PARTS:
+                RAM16K(in=false, load=false, address=address, out=out);
+            }
+        "]);
+        let chip = library.build("Memory2").unwrap();
+        assert_eq!(
+            chip.to_netlist().unwrap_err(),
+            NetlistError::Unsupported("RAM16K".to_string())
+        );
+    }
+
+    #[test]
+    fn sequential() {
+        // DFFと組み込みのMuxで作ったBit
+        let mut library = library_of(&["
+            CHIP Bit {
+                IN in, load;
+                OUT out;
+                PARTS:
+                Mux(a=dffout, b=in, sel=load, out=muxout);
+                DFF(in=muxout, out=dffout, out=out);
+            }
+        "]);
+        let chip = library.build("Bit").unwrap();
+        let cycle = |input: u16, load: u16| {
+            chip.set("in", input);
+            chip.set("load", load);
+            chip.re_compute();
+            chip.clock_up();
+            chip.clock_down();
+            chip.re_compute();
+            chip.get("out").unwrap()
+        };
+        assert_eq!(cycle(1, 0), 0);
+        assert_eq!(cycle(1, 1), 1);
+        assert_eq!(cycle(0, 0), 1);
+        assert_eq!(cycle(0, 1), 0);
+
+        // 組み込みのRegisterとRAM8
+        let mut library = library_of(&["
+            CHIP Counter {
+                IN load, address[3];
+                OUT out[16], ram[16];
+                PARTS:
+                Inc16(in=regout, out=next);
+                Register(in=next, load=load, out=regout, out=out);
+                RAM8(in=regout, load=true, address=address, out=ram);
+            }
+        "]);
+        let chip = library.build("Counter").unwrap();
+        chip.set("load", 1);
+        for i in 0..5 {
+            chip.set("address", i);
+            chip.re_compute();
+            chip.clock_up();
+            chip.clock_down();
+            chip.re_compute();
+        }
+        assert_eq!(chip.get("out"), Some(5));
+        chip.set("load", 0);
+        chip.set("address", 2);
+        chip.re_compute();
+        assert_eq!(chip.get("ram"), Some(2));
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            (
+                "CHIP A { IN a; OUT out; PARTS: Foo(a=a, out=out); }",
+                1,
+                HdlErrorKind::UnknownChip("Foo".to_string()),
+            ),
+            (
+                "CHIP A { IN a; OUT out; PARTS:\n Not(x=a, out=out); }",
+                2,
+                HdlErrorKind::UnknownPin {
+                    chip: "Not".to_string(),
+                    pin: "x".to_string(),
+                },
+            ),
+            (
+                "CHIP A { IN a; OUT out; PARTS: Not(in=b, out=out); }",
+                1,
+                HdlErrorKind::UnknownPin {
+                    chip: "A".to_string(),
+                    pin: "b".to_string(),
+                },
+            ),
+            (
+                "CHIP A { IN a[2]; OUT out; PARTS: Not(in=a, out=out); }",
+                1,
+                HdlErrorKind::WidthMismatch {
+                    pin: "in".to_string(),
+                    inner: 1,
+                    outer: 2,
+                },
+            ),
+            (
+                "CHIP A { IN a[2]; OUT out; PARTS: Not(in=a[2], out=out); }",
+                1,
+                HdlErrorKind::BadSlice("a".to_string()),
+            ),
+            (
+                "CHIP A { IN a; OUT out; PARTS: Not(in=a, out=a); }",
+                1,
+                HdlErrorKind::DrivesInput("a".to_string()),
+            ),
+            (
+                "CHIP A { IN a; OUT out; PARTS: Not(in=a, out=true); }",
+                1,
+                HdlErrorKind::DrivesConstant("out".to_string()),
+            ),
+            (
+                "CHIP A { IN a; OUT out; PARTS: Not(in=a, out=x[0]); }",
+                1,
+                HdlErrorKind::BadSlice("x".to_string()),
+            ),
+            (
+                "CHIP A { IN a; OUT out; PARTS: Not(in=a, out=out); Not(in=a, out=out); }",
+                1,
+                HdlErrorKind::DrivenTwice("A.out".to_string()),
+            ),
+            (
+                "CHIP A { IN a; OUT out; PARTS: Not(in=x, out=y); Not(in=y, out=x); }",
+                1,
+                HdlErrorKind::CombinationalLoop(vec!["A.y".to_string(), "A.x".to_string()]),
+            ),
+            (
+                "CHIP A { IN a; OUT out; PARTS: A(a=a, out=out); }",
+                1,
+                HdlErrorKind::RecursiveChip("A".to_string()),
+            ),
+            (
+                "CHIP A { IN a; OUT out; BUILTIN Foo; }",
+                1,
+                HdlErrorKind::UnknownBuiltIn("Foo".to_string()),
+            ),
+        ];
+        for (source, line, kind) in cases {
+            let mut library = library_of(&[source]);
+            let error = library.build("A").unwrap_err();
+            assert_eq!((error.line, error.kind), (line, kind), "{}", source);
+        }
+
+        // DFFを通るループは組み合わせ回路のループではない
+        let mut library = library_of(&[
+            "CHIP A { OUT out; PARTS: Not(in=x, out=y, out=out); DFF(in=y, out=x); }",
+        ]);
+        let chip = library.build("A").unwrap();
+        assert_eq!(chip.get("out"), Some(1));
+        chip.clock_up();
+        chip.clock_down();
+        chip.re_compute();
+        assert_eq!(chip.get("out"), Some(0));
+    }
+
+    #[test]
+    fn from_files() {
+        let dir = std::env::temp_dir().join("nand2tetris_my_hs_circuit");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Xor.hdl"), XOR).unwrap();
+        fs::write(dir.join("HalfAdder.hdl"), HALF_ADDER).unwrap();
+        fs::write(
+            dir.join("Broken.hdl"),
+            "CHIP Broken {\n IN a;\n OUT out;\n PARTS:\n Xor(a=a, c=a, out=out);\n}",
+        )
+        .unwrap();
+
+        let chip = load_chip(dir.join("HalfAdder.hdl")).unwrap();
+        assert_eq!(chip.name(), "HalfAdder");
+        chip.set("a", 1);
+        chip.set("b", 1);
+        chip.re_compute();
+        assert_eq!(chip.get("carry"), Some(1));
+
+        let error = load_chip(dir.join("Broken.hdl")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{}:5: chip `Xor` has no pin `c`",
+                dir.join("Broken.hdl").display()
+            )
+        );
+        assert!(load_chip(dir.join("Missing.hdl")).is_err());
+
+        // 部品の中で2回出力しているnetやループは、その部品の中の行を指す
+        fs::write(
+            dir.join("Twice.hdl"),
+            "CHIP Twice {\n IN a;\n OUT out;\n PARTS:\n Not(in=a, out=out);\n Not(in=a, out=out);\n}",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("Loop.hdl"),
+            "CHIP Loop {\n IN a;\n OUT out;\n PARTS:\n Not(in=a, out=b);\n And(a=b, b=y, out=x);\n Not(in=x, out=y, out=out);\n}",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("Outer.hdl"),
+            "CHIP Outer {\n IN a;\n OUT out;\n PARTS:\n Not(in=a, out=b);\n Twice(a=b, out=out);\n}",
+        )
+        .unwrap();
+        let error = load_chip(dir.join("Outer.hdl")).unwrap_err();
+        assert_eq!((error.file, error.line), (Some(dir.join("Twice.hdl")), 6));
+        let error = load_chip(dir.join("Loop.hdl")).unwrap_err();
+        assert!(matches!(error.kind, HdlErrorKind::CombinationalLoop(_)));
+        assert_eq!(error.file, Some(dir.join("Loop.hdl")));
+        assert_eq!(error.line, 7, "{}", error);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
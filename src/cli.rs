@@ -0,0 +1,1413 @@
+use std::{
+    cell::Cell,
+    fmt, fs,
+    io::{self, BufRead, Write},
+    ops::Range,
+    path::Path,
+    rc::Rc,
+};
+
+use crate::{
+    assembler::{assemble_to_rom, AssembleError},
+    builtin,
+    circuit::load_chip,
+    computer::{Computer, HackLoadError, ROM32KBuiltIn},
+    cosim::Lockstep,
+    debugger::Debugger,
+    disassembler::{disassemble, disassemble_program},
+    display::{Renderer, Style},
+    emulator::{Engine, NativeComputer},
+    gate::*,
+    jack::{compile_path, JackError, JackErrorKind},
+    netlist::{GateCount, Netlist},
+    terminal::TerminalKeyboard,
+    tst::run_script,
+    vcd::Vcd,
+    vm::{read_sources, translate_path, translate_sources, VmError},
+};
+
+pub const USAGE: &str = "usage:
+  nand2tetris-my-hs run   <file.hack|file.asm> [--cycles N] [--dump START..END]... [--poke TARGET=VALUE]...
+                                                     [--until-halt] [--native] [--netlist] [--screen] [--keyboard]
+                                                     [--screenshot-at-cycle N FILE]... [--vcd FILE]
+  nand2tetris-my-hs trace <file.hack|file.asm> [--cycles N] [--dump START..END]... [--native] [--netlist] [--vcd FILE]
+  nand2tetris-my-hs step  <file.hack|file.asm> [--cycles N] [--dump START..END]... [--native] [--netlist] [--vcd FILE]
+  nand2tetris-my-hs disasm <file.hack|file.asm>
+  nand2tetris-my-hs cosim <file.hack|file.asm> [--cycles N] [--until-halt] [--poke TARGET=VALUE]... [--dump START..END]...
+                                                     [--netlist]
+  nand2tetris-my-hs hdl   <file.hdl> [PIN=VALUE]... [--netlist]
+  nand2tetris-my-hs test  <file.tst> [--hdl] [--netlist] [--vcd FILE]
+  nand2tetris-my-hs gates <CHIP|file.hdl> [--depth N]
+  nand2tetris-my-hs critical-path <CHIP|file.hdl> [--registers]
+  nand2tetris-my-hs translate <file.vm|dir>
+  nand2tetris-my-hs compile <file.jack|dir>
+  nand2tetris-my-hs debug <file.hack|file.asm> [--poke TARGET=VALUE]... [--dump START..END]... [--native]
+                                                     [--netlist]
+
+  run    N サイクル実行して最後の状態を表示する
+  trace  1サイクルごとに A/D/PC/RAM[0] と次の命令を表示する
+  step   Enter を押すごとに1サイクル進める (r でリセット、q で終了)
+  disasm ROMの中身をアセンブリに戻して表示する
+  cosim  ゲートの回路と --native のエミュレータを1サイクルずつ並べて動かし、
+         A/D/PC/writeM/addressM/outM/RAM が最初に食い違ったサイクルで両方の状態と命令を表示する
+  hdl    .hdlから回路を組み立て、入力ピンに値を入れて出力ピンを表示する
+         同じディレクトリの.hdlを部品に使い、見つからないチップは組み込みのものを使う
+         --netlist なら NandとDFFだけのnetlistに展開して計算し、Nand/DFF/netの数も表示する
+  test   テストスクリプト(.tst)を実行し、compare-to の.cmpと最初に食い違った行を表示する
+         load したチップは Rust の実装を使う。--hdl なら同じディレクトリの.hdlから組み立てる
+         --netlist なら NandとDFFだけのnetlistに展開して動かす (ARegister[] などの中身は読めない)
+         入力が変わったNandだけを計算し、最後に計算したNandの数を表示する
+         (Computer も展開できるが、ARegister[] や RAM16K[] は読み書きできない)
+         --vcd なら tick/tock ごとの入出力ピンの値を FILE に書き出す (--netlist とは一緒に使えない)
+  gates  組み込みのチップ (ALU、CPU、RAM4K、Computer など) か.hdlの回路が使っているNandとDFFの数を、
+         部品ごとの木にして表示する。NandとDFFに展開できない組み込みのチップも一覧にする
+         --depth N なら N 段目の部品まで表示する
+  critical-path  入力ピンかレジスタの出力から、出力ピンかレジスタの入力までで、Nandを一番多く通る経路の
+         段数と、通るnetを alu.add1.full_adder3.or.nand3 のような部品の名前で表示する
+         --registers なら始まりをレジスタの出力、終わりをレジスタの入力に限る (レジスタ間の段数)
+  debug  ブレークポイントやウォッチポイントを使える対話的なデバッガ (h でヘルプ)
+  translate  VMコードをHackアセンブリに変換して表示する
+         ディレクトリなら中の.vmをまとめて変換し、Sys.vmがあればbootstrapを付ける
+  compile  Jackをコンパイルして、.jackと同じ場所に.vmを書き出す
+
+  プログラムには .hack/.asm のほか .vm や .jack/.vm の入ったディレクトリも指定できる
+
+  --cycles N          実行するサイクル数 (デフォルト 1000)
+  --until-halt        (END) @END 0;JMP のループに入るまで実行する。--cycles は無視 (run/cosim)
+  --native            ゲートを通さずに命令を直接実行する (run/trace/step/debug)
+  --netlist           Computerをnetlistに展開し、入力が変わったNandだけを計算する。ROM/RAMは組み込みのまま
+                      最後に計算したNandの数を表示する (run/trace/step/debug/cosim、--native とは一緒に使えない)
+  --dump START..END   終了時に RAM[START..END] を表示する。複数指定可
+  --dump ADDR         終了時に RAM[ADDR] を表示する
+  --poke ADDR=VALUE   実行前に RAM[ADDR] (Screen/Keyboardも可) に VALUE を書き込む。複数指定可
+  --poke A=VALUE      実行前に A/D/PC レジスタに VALUE を設定する
+  --screen            実行中にScreenを端末に描画する (run のみ)
+  --scale N           Screenを 1/N に縮小して描画する (デフォルト 2)
+  --half-block        点字ではなく ▀▄ で描画する
+  --refresh N         N サイクルごとに描画し直す (デフォルト 500)
+  --keyboard          端末のキー入力をKeyboardに送る (run のみ、Ctrl-C で終了)
+  --screenshot-at-cycle N FILE
+                      N サイクル実行した時点のScreenを FILE (.png/.pbm) に保存する (run のみ)
+                      N サイクルまで実行しなかったときはエラーになる
+  --vcd FILE          CPUのA/D/PC/writeM/addressM/outMやALUのzr/ngを半サイクルごとに
+                      FILE (VCD) に書き出す。GTKWaveで見られる (run/trace/step、--native とは一緒に使えない)";
+
+const DEFAULT_CYCLES: usize = 1000;
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub path: String,
+    pub cycles: usize,
+    pub until_halt: bool,
+    pub dumps: Vec<Range<u16>>,
+    pub pokes: Vec<(PokeTarget, u16)>,
+    pub screen: Option<ScreenOptions>,
+    pub keyboard: bool,
+    pub screenshots: Vec<(usize, String)>,
+    pub engine: Engine,
+    pub netlist: bool,
+    pub vcd: Option<String>,
+}
+
+/// --poke の書き込み先
+#[derive(Debug, PartialEq)]
+pub enum PokeTarget {
+    Memory(u16),
+    A,
+    D,
+    PC,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ScreenOptions {
+    pub style: Style,
+    pub scale: usize,
+    pub refresh: usize,
+}
+
+impl Default for ScreenOptions {
+    fn default() -> Self {
+        ScreenOptions {
+            style: Style::Braille,
+            scale: 2,
+            refresh: 500,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Options),
+    Trace(Options),
+    Step(Options),
+    Debug(Options),
+    Disasm(Options),
+    Cosim(Options),
+    Hdl {
+        path: String,
+        inputs: Vec<(String, u16)>,
+        netlist: bool,
+    },
+    Test {
+        path: String,
+        use_hdl: bool,
+        netlist: bool,
+        vcd: Option<String>,
+    },
+    Gates {
+        target: String,
+        depth: usize,
+    },
+    CriticalPath {
+        target: String,
+        registers: bool,
+    },
+    Translate(String),
+    Compile(String),
+    Help,
+}
+
+/// コマンドライン引数(プログラム名は除く)を解釈する
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let subcommand = match args.first() {
+        Some(subcommand) => subcommand.as_str(),
+        None => return Ok(Command::Help),
+    };
+    if subcommand == "help" || subcommand == "-h" || subcommand == "--help" {
+        return Ok(Command::Help);
+    }
+    if subcommand == "hdl" {
+        return parse_hdl_args(&args[1..]);
+    }
+    if subcommand == "test" {
+        return parse_test_args(&args[1..]);
+    }
+    if subcommand == "gates" {
+        return parse_gates_args(&args[1..]);
+    }
+    if subcommand == "critical-path" {
+        return parse_critical_path_args(&args[1..]);
+    }
+    if subcommand == "translate" || subcommand == "compile" {
+        let path = match &args[1..] {
+            [path] => path.clone(),
+            [] => return Err("missing source file".to_string()),
+            [_, arg, ..] => return Err(format!("unexpected argument `{}`", arg)),
+        };
+        return match subcommand {
+            "translate" => Ok(Command::Translate(path)),
+            _ => Ok(Command::Compile(path)),
+        };
+    }
+
+    let mut path = None;
+    let mut cycles = DEFAULT_CYCLES;
+    let mut until_halt = false;
+    let mut dumps = vec![];
+    let mut pokes = vec![];
+    let mut screen: Option<ScreenOptions> = None;
+    let mut keyboard = false;
+    let mut screenshots = vec![];
+    let mut engine = Engine::Gate;
+    let mut netlist = false;
+    let mut vcd = None;
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--cycles" => {
+                let value = rest.next().ok_or("--cycles requires a value")?;
+                cycles = value
+                    .parse()
+                    .map_err(|_| format!("invalid cycle count `{}`", value))?;
+            }
+            "--until-halt" => until_halt = true,
+            "--native" => engine = Engine::Native,
+            "--netlist" => netlist = true,
+            "--dump" => {
+                let value = rest.next().ok_or("--dump requires a range")?;
+                dumps.push(parse_range(value)?);
+            }
+            "--poke" => {
+                let value = rest.next().ok_or("--poke requires TARGET=VALUE")?;
+                pokes.push(parse_poke(value)?);
+            }
+            "--screen" => {
+                screen.get_or_insert_with(Default::default);
+            }
+            "--keyboard" => keyboard = true,
+            "--screenshot-at-cycle" => {
+                let cycle = rest
+                    .next()
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .ok_or("--screenshot-at-cycle requires a cycle count")?;
+                let file = rest
+                    .next()
+                    .ok_or("--screenshot-at-cycle requires an output file")?;
+                screenshots.push((cycle, file.clone()));
+            }
+            "--vcd" => {
+                let file = rest.next().ok_or("--vcd requires an output file")?;
+                vcd = Some(file.clone());
+            }
+            "--half-block" => screen.get_or_insert_with(Default::default).style = Style::HalfBlock,
+            "--scale" | "--refresh" => {
+                let value = rest
+                    .next()
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .filter(|v| *v > 0)
+                    .ok_or(format!("{} requires a positive number", arg))?;
+                let screen = screen.get_or_insert_with(Default::default);
+                if arg == "--scale" {
+                    screen.scale = value;
+                } else {
+                    screen.refresh = value;
+                }
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option `{}`", arg)),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+
+    let path = path.ok_or("missing program file")?;
+    if vcd.is_some() && engine == Engine::Native {
+        return Err("--vcd cannot be used with --native".to_string());
+    }
+    if netlist && engine == Engine::Native {
+        return Err("--netlist cannot be used with --native".to_string());
+    }
+    if !screenshots.is_empty() && subcommand != "run" {
+        return Err(format!(
+            "--screenshot-at-cycle cannot be used with {}",
+            subcommand
+        ));
+    }
+    if let Some((at, _)) = screenshots
+        .iter()
+        .find(|(at, _)| !until_halt && *at > cycles)
+    {
+        return Err(format!(
+            "--screenshot-at-cycle {} is past --cycles {}",
+            at, cycles
+        ));
+    }
+    // 半サイクルずつ記録できるのは run/trace/step だけ
+    if vcd.is_some() && matches!(subcommand, "debug" | "cosim" | "disasm") {
+        return Err(format!("--vcd cannot be used with {}", subcommand));
+    }
+    let options = Options {
+        path,
+        cycles,
+        until_halt,
+        dumps,
+        pokes,
+        screen,
+        keyboard,
+        screenshots,
+        engine,
+        netlist,
+        vcd,
+    };
+    match subcommand {
+        "run" => Ok(Command::Run(options)),
+        "trace" => Ok(Command::Trace(options)),
+        "step" => Ok(Command::Step(options)),
+        "debug" => Ok(Command::Debug(options)),
+        "disasm" => Ok(Command::Disasm(options)),
+        "cosim" => Ok(Command::Cosim(options)),
+        _ => Err(format!("unknown command `{}`", subcommand)),
+    }
+}
+
+// hdl <file.hdl> [PIN=VALUE]... [--netlist]
+fn parse_hdl_args(args: &[String]) -> Result<Command, String> {
+    let (path, rest) = args.split_first().ok_or("missing HDL file")?;
+    let netlist = rest.iter().any(|arg| arg == "--netlist");
+    let inputs = rest
+        .iter()
+        .filter(|arg| *arg != "--netlist")
+        .map(|arg| {
+            let error = || format!("invalid pin value `{}`", arg);
+            let (pin, value) = arg.split_once('=').ok_or_else(error)?;
+            let value = match value.parse::<u16>() {
+                Ok(value) => value,
+                Err(_) => value.parse::<i16>().map_err(|_| error())? as u16,
+            };
+            Ok((pin.to_string(), value))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(Command::Hdl {
+        path: path.clone(),
+        inputs,
+        netlist,
+    })
+}
+
+// test <file.tst> [--hdl] [--netlist] [--vcd FILE]
+fn parse_test_args(args: &[String]) -> Result<Command, String> {
+    let mut path = None;
+    let mut use_hdl = false;
+    let mut netlist = false;
+    let mut vcd = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--hdl" => use_hdl = true,
+            "--netlist" => netlist = true,
+            "--vcd" => {
+                let file = rest.next().ok_or("--vcd requires an output file")?;
+                vcd = Some(file.clone());
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option `{}`", arg)),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+    let path = path.ok_or("missing test script")?;
+    if vcd.is_some() && netlist {
+        return Err("--vcd cannot be used with --netlist".to_string());
+    }
+    Ok(Command::Test {
+        path,
+        use_hdl,
+        netlist,
+        vcd,
+    })
+}
+
+// gates <CHIP|file.hdl> [--depth N]
+fn parse_gates_args(args: &[String]) -> Result<Command, String> {
+    let mut target = None;
+    let mut depth = usize::MAX;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--depth" => {
+                depth = rest
+                    .next()
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .ok_or("--depth requires a number")?;
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option `{}`", arg)),
+            _ if target.is_none() => target = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+    let target = target.ok_or("missing chip name or HDL file")?;
+    Ok(Command::Gates { target, depth })
+}
+
+// critical-path <CHIP|file.hdl> [--registers]
+fn parse_critical_path_args(args: &[String]) -> Result<Command, String> {
+    let mut target = None;
+    let mut registers = false;
+    for arg in args.iter() {
+        match arg.as_str() {
+            "--registers" => registers = true,
+            _ if arg.starts_with("--") => return Err(format!("unknown option `{}`", arg)),
+            _ if target.is_none() => target = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+    let target = target.ok_or("missing chip name or HDL file")?;
+    Ok(Command::CriticalPath { target, registers })
+}
+
+// "0..16" か "256"
+pub(crate) fn parse_range(s: &str) -> Result<Range<u16>, String> {
+    let error = || format!("invalid range `{}`", s);
+    match s.split_once("..") {
+        Some((start, end)) => {
+            let start = start.parse::<u16>().map_err(|_| error())?;
+            let end = end.parse::<u16>().map_err(|_| error())?;
+            if start > end {
+                return Err(error());
+            }
+            Ok(start..end)
+        }
+        None => {
+            let address = s.parse::<u16>().map_err(|_| error())?;
+            Ok(address..address.saturating_add(1))
+        }
+    }
+}
+
+// "256=-1" や "A=100"。値は負の数も受け付ける
+fn parse_poke(s: &str) -> Result<(PokeTarget, u16), String> {
+    let error = || format!("invalid poke `{}`", s);
+    let (target, value) = s.split_once('=').ok_or_else(error)?;
+    let target = match target {
+        "A" => PokeTarget::A,
+        "D" => PokeTarget::D,
+        "PC" => PokeTarget::PC,
+        _ => {
+            let address = target.parse::<u16>().map_err(|_| error())?;
+            if address > 24576 {
+                return Err(format!("address out of range `{}`", target));
+            }
+            PokeTarget::Memory(address)
+        }
+    };
+    let value = match value.parse::<u16>() {
+        Ok(value) => value,
+        Err(_) => value.parse::<i16>().map_err(|_| error())? as u16,
+    };
+    Ok((target, value))
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Hack(HackLoadError),
+    Asm(AssembleError),
+    Vm(VmError),
+    Jack(JackError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::Hack(e) => write!(f, "{}", e),
+            LoadError::Asm(e) => write!(f, "{}", e),
+            LoadError::Vm(e) => write!(f, "{}", e),
+            LoadError::Jack(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// 拡張子が.asmならアセンブルし、.vmならVMコードを変換してからアセンブルする
+/// ディレクトリなら中の.jackをコンパイルして.vmと合わせる。それ以外は.hackとして読み込む
+pub fn load_rom(path: &str, address: SharedBus<15>) -> Result<ROM32KBuiltIn, LoadError> {
+    let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+    if Path::new(path).is_dir() {
+        let source = translate_directory(path)?;
+        assemble_to_rom(&source, address).map_err(LoadError::Asm)
+    } else if extension == Some("vm") {
+        let source = translate_path(path).map_err(LoadError::Vm)?;
+        assemble_to_rom(&source, address).map_err(LoadError::Asm)
+    } else if extension == Some("asm") {
+        let source = fs::read_to_string(path).map_err(LoadError::Io)?;
+        assemble_to_rom(&source, address).map_err(LoadError::Asm)
+    } else {
+        ROM32KBuiltIn::from_hack_file(path, address).map_err(LoadError::Hack)
+    }
+}
+
+// 同じ名前の.jackと.vmがあれば.jackをコンパイルしたほうを使う
+fn translate_directory(path: &str) -> Result<String, LoadError> {
+    let mut files = match compile_path(path) {
+        Ok(files) => files,
+        Err(JackError {
+            kind: JackErrorKind::NoJackFiles,
+            ..
+        }) => vec![],
+        Err(e) => return Err(LoadError::Jack(e)),
+    };
+    for (name, source) in read_sources(Path::new(path), "vm").map_err(LoadError::Io)? {
+        if !files.iter().any(|(compiled, _)| *compiled == name) {
+            files.push((name, source));
+        }
+    }
+    files.sort();
+    translate_sources(&files, true).map_err(LoadError::Vm)
+}
+
+pub fn execute(command: Command) -> Result<(), String> {
+    let options = match &command {
+        Command::Help => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Command::Run(options)
+        | Command::Trace(options)
+        | Command::Step(options)
+        | Command::Debug(options)
+        | Command::Disasm(options)
+        | Command::Cosim(options) => options,
+        Command::Hdl {
+            path,
+            inputs,
+            netlist,
+        } => return evaluate_hdl(path, inputs, *netlist),
+        Command::Test {
+            path,
+            use_hdl,
+            netlist,
+            vcd,
+        } => return run_test(path, *use_hdl, *netlist, vcd.as_deref()),
+        Command::Translate(path) => {
+            print!("{}", translate_path(path).map_err(|e| e.to_string())?);
+            return Ok(());
+        }
+        Command::Gates { target, depth } => return count_gates(target, *depth),
+        Command::CriticalPath { target, registers } => {
+            return print_critical_path(target, *registers)
+        }
+        Command::Compile(path) => return compile_jack(path),
+    };
+
+    let address = Bus::<15>::all0().to_shared_bus();
+    let rom = load_rom(&options.path, address).map_err(|e| format!("{}: {}", options.path, e))?;
+    if let Command::Disasm(_) = command {
+        print!("{}", disassemble_program(&rom.program()));
+        return Ok(());
+    }
+    let reset = Bus::<1>::all0().to_shared_bus();
+    let mut computer = Computer::new(reset.clone(), rom);
+    apply_pokes(&computer, &options.pokes)?;
+    if options.netlist {
+        computer.use_netlist().map_err(|e| e.to_string())?;
+    }
+    let mut vcd = match &options.vcd {
+        Some(path) => Some(start_vcd(&computer, path)?),
+        None => None,
+    };
+
+    if let Command::Cosim(_) = command {
+        return cosimulate(computer, options);
+    }
+
+    if let Command::Debug(_) = command {
+        let mut debugger = Debugger::new(computer);
+        debugger.set_engine(options.engine);
+        let stdin = io::stdin();
+        debugger
+            .run_repl(stdin.lock(), &mut io::stdout())
+            .map_err(|e| e.to_string())?;
+        println!();
+        print_computer_status(debugger.computer(), debugger.cycle());
+        print_dumps(debugger.computer(), &options.dumps);
+        print_netlist_stats(debugger.computer());
+        return Ok(());
+    }
+
+    match &command {
+        Command::Run(_) => {
+            let renderer = options
+                .screen
+                .as_ref()
+                .map(|screen| (Renderer::new(screen.style, screen.scale), screen.refresh));
+            let draw = |renderer: &Renderer| {
+                let frame = computer.screen_buffer();
+                renderer
+                    .draw(&mut io::stdout(), &frame[..])
+                    .map_err(|e| e.to_string())
+            };
+            let keyboard = match options.keyboard {
+                true => Some(TerminalKeyboard::start().map_err(|e| e.to_string())?),
+                false => None,
+            };
+
+            if renderer.is_some() {
+                print!("\x1b[2J");
+            }
+            let screenshot = |cycle: usize| {
+                for (_, path) in options.screenshots.iter().filter(|(at, _)| *at == cycle) {
+                    computer
+                        .save_screenshot(path)
+                        .map_err(|e| format!("{}: {}", path, e))?;
+                }
+                Ok::<(), String>(())
+            };
+
+            let mut cycle = 0;
+            screenshot(cycle)?;
+            // 1サイクルごとにすることがなければまとめて実行する
+            let per_cycle = renderer.is_some()
+                || keyboard.is_some()
+                || !options.screenshots.is_empty()
+                || vcd.is_some();
+            if !per_cycle {
+                if options.until_halt {
+                    cycle = options
+                        .engine
+                        .run_until_halt(&computer)
+                        .map_err(|e| e.to_string())?;
+                } else {
+                    options
+                        .engine
+                        .run(&computer, options.cycles)
+                        .map_err(|e| e.to_string())?;
+                    cycle = options.cycles;
+                }
+            }
+            loop {
+                let done = match options.until_halt {
+                    true => computer.is_halted(),
+                    false => cycle >= options.cycles,
+                };
+                if done {
+                    break;
+                }
+                if let Some(keyboard) = &keyboard {
+                    if keyboard.quit_requested() {
+                        break;
+                    }
+                    match keyboard.current_key() {
+                        0 => computer.keyboard().release(),
+                        key if key != computer.get_keyboard_value() => {
+                            computer.keyboard().press(key)
+                        }
+                        _ => {}
+                    }
+                }
+                step_cycle(&computer, options.engine, &mut vcd)?;
+                cycle += 1;
+                screenshot(cycle)?;
+                if let Some((renderer, refresh)) = &renderer {
+                    if cycle % refresh == 0 {
+                        draw(renderer)?;
+                    }
+                }
+            }
+            if let Some((renderer, _)) = &renderer {
+                draw(renderer)?;
+            }
+            // 端末の設定を戻してから結果を表示する
+            drop(keyboard);
+            print_computer_status(&computer, cycle);
+            // 停止したか q で抜けて、撮れなかったものがあればエラーにする
+            if let Some((at, path)) = options.screenshots.iter().find(|(at, _)| *at > cycle) {
+                return Err(format!(
+                    "{}: cycle {} was not reached (stopped at cycle {})",
+                    path, at, cycle
+                ));
+            }
+        }
+        Command::Trace(_) => {
+            print_computer_status(&computer, 0);
+            for cycle in 1..=options.cycles {
+                step_cycle(&computer, options.engine, &mut vcd)?;
+                print_computer_status(&computer, cycle);
+            }
+        }
+        Command::Step(_) => {
+            let stdin = io::stdin();
+            let mut lines = stdin.lock().lines();
+            print_computer_status(&computer, 0);
+            for cycle in 1..=options.cycles {
+                print!("> ");
+                io::stdout().flush().map_err(|e| e.to_string())?;
+                match lines.next() {
+                    Some(Ok(line)) if line.trim() == "q" => break,
+                    Some(Ok(line)) if line.trim() == "r" => {
+                        reset_cycle(&computer, &reset, options.engine, &mut vcd)?;
+                        print_computer_status(&computer, cycle);
+                        continue;
+                    }
+                    Some(Ok(_)) => {}
+                    _ => break,
+                }
+                step_cycle(&computer, options.engine, &mut vcd)?;
+                print_computer_status(&computer, cycle);
+            }
+        }
+        Command::Debug(_)
+        | Command::Disasm(_)
+        | Command::Cosim(_)
+        | Command::Hdl { .. }
+        | Command::Test { .. }
+        | Command::Translate(_)
+        | Command::Gates { .. }
+        | Command::CriticalPath { .. }
+        | Command::Compile(_)
+        | Command::Help => {}
+    }
+
+    if let (Some(vcd), Some(path)) = (vcd, &options.vcd) {
+        vcd.finish().map_err(|e| format!("{}: {}", path, e))?;
+    }
+    print_dumps(&computer, &options.dumps);
+    print_netlist_stats(&computer);
+    Ok(())
+}
+
+// CPUの信号をpathに書き出すVcdを作って、今の値を記録しておく
+fn start_vcd(computer: &Computer, path: &str) -> Result<Vcd, String> {
+    computer.settle().map_err(|e| e.to_string())?;
+    let mut vcd = Vcd::create("Computer", path).map_err(|e| format!("{}: {}", path, e))?;
+    for (name, bits) in computer.probes() {
+        vcd.probe(&name, bits);
+    }
+    vcd.sample(O);
+    Ok(vcd)
+}
+
+// --vcd のときはゲートで半サイクルずつ進めて記録する
+fn step_cycle(computer: &Computer, engine: Engine, vcd: &mut Option<Vcd>) -> Result<(), String> {
+    let result = match vcd {
+        Some(vcd) => computer.tick().and_then(|_| {
+            vcd.sample(I);
+            computer.tock()?;
+            vcd.sample(O);
+            Ok(())
+        }),
+        None => engine.step(computer),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+// resetを立てて1サイクル進める。--vcd のときはそのサイクルも記録する
+fn reset_cycle(
+    computer: &Computer,
+    reset: &SharedBus<1>,
+    engine: Engine,
+    vcd: &mut Option<Vcd>,
+) -> Result<(), String> {
+    if vcd.is_none() {
+        return computer.reset().map_err(|e| e.to_string());
+    }
+    reset.get_shared_bit(0).set(I);
+    step_cycle(computer, engine, vcd)?;
+    reset.get_shared_bit(0).set(O);
+    computer.settle().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn apply_pokes(computer: &Computer, pokes: &[(PokeTarget, u16)]) -> Result<(), String> {
+    for (target, value) in pokes.iter() {
+        let result = match target {
+            PokeTarget::Memory(address) => computer.poke(*address, *value),
+            PokeTarget::A => computer.set_a_register_value(*value),
+            PokeTarget::D => computer.set_d_register_value(*value),
+            PokeTarget::PC => computer.set_pc(*value),
+        };
+        result.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// 同じプログラムをゲートとEmulatorで並べて動かし、食い違ったらそこで止める
+fn cosimulate(computer: Computer, options: &Options) -> Result<(), String> {
+    let native = NativeComputer::new(computer.rom().program());
+    for (target, value) in options.pokes.iter() {
+        match target {
+            PokeTarget::Memory(address) => native.poke(*address, *value),
+            PokeTarget::A => native.set_a_register_value(*value),
+            PokeTarget::D => native.set_d_register_value(*value),
+            PokeTarget::PC => native.set_pc(*value),
+        }
+    }
+
+    let mut lockstep = Lockstep::new(computer, native);
+    let result = match options.until_halt {
+        true => lockstep.run_until_halt().map(|_| ()),
+        false => lockstep.run(options.cycles),
+    };
+    print_computer_status(lockstep.gate(), lockstep.cycle());
+    if let Err(divergence) = result {
+        print_native_status(lockstep.native(), lockstep.cycle());
+        return Err(divergence.to_string());
+    }
+    println!("no divergence in {} cycles", lockstep.cycle());
+    print_dumps(lockstep.gate(), &options.dumps);
+    print_netlist_stats(lockstep.gate());
+    Ok(())
+}
+
+fn evaluate_hdl(path: &str, inputs: &[(String, u16)], use_netlist: bool) -> Result<(), String> {
+    let chip = load_chip(path).map_err(|e| e.to_string())?;
+    let no_pin = |pin: &str| format!("chip `{}` has no input pin `{}`", chip.name(), pin);
+    let print_pin = |pin: &str, width: usize, value: u16| {
+        println!(
+            "{}: {:0width$b} ({})",
+            pin,
+            value,
+            value as i16,
+            width = width
+        );
+    };
+
+    if use_netlist {
+        let netlist = chip.to_netlist().map_err(|e| format!("{}: {}", path, e))?;
+        for (pin, value) in inputs {
+            if !netlist.set(pin, *value) {
+                return Err(no_pin(pin));
+            }
+        }
+        netlist.settle().map_err(|e| e.to_string())?;
+        for (pin, nets) in netlist.input_pins().iter().chain(netlist.output_pins()) {
+            print_pin(pin, nets.len(), netlist.get(pin).unwrap());
+        }
+        println!(
+            "nand: {}, dff: {}, net: {}",
+            netlist.nands().len(),
+            netlist.dffs().len(),
+            netlist.net_count()
+        );
+        return Ok(());
+    }
+
+    for (pin, value) in inputs {
+        if !chip.set(pin, *value) {
+            return Err(no_pin(pin));
+        }
+    }
+    chip.settle().map_err(|e| e.to_string())?;
+    for (pin, width) in chip.input_pins().into_iter().chain(chip.output_pins()) {
+        print_pin(pin, width, chip.get(pin).unwrap());
+    }
+    Ok(())
+}
+
+// .hdlなら組み立てた回路を、それ以外は組み込みのチップを数える
+fn count_gates(target: &str, depth: usize) -> Result<(), String> {
+    let count = match target.ends_with(".hdl") {
+        true => {
+            let chip = load_chip(target).map_err(|e| e.to_string())?;
+            GateCount::of(chip.name(), &chip)
+        }
+        false => {
+            let (gate, _, _) = build_builtin(target)?;
+            GateCount::of(target, gate.as_ref())
+        }
+    }
+    .map_err(|e| format!("{}: {}", target, e))?;
+    println!("{}", count.tree(depth));
+    let builtins = count.builtins();
+    match builtins.is_empty() {
+        true => println!("built-in: none (Nand and DFF only)"),
+        false => println!("built-in: {}", builtins.join(", ")),
+    }
+    Ok(())
+}
+
+// 入力をすべて0にして組み込みのチップを作る。(チップ, 入力ピン, 出力ピン)
+type Pins = Vec<(&'static str, Vec<SharedBit>)>;
+
+fn build_builtin(name: &str) -> Result<(Rc<dyn Gate>, Pins, Pins), String> {
+    let spec = builtin::find(name).ok_or(format!("unknown chip `{}`", name))?;
+    let inputs: Vec<Vec<SharedBit>> = spec
+        .inputs
+        .iter()
+        .map(|(_, width)| (0..*width).map(|_| Rc::new(Cell::new(O))).collect())
+        .collect();
+    let (gate, _, outputs) = builtin::build(name, &inputs).unwrap();
+    let pins = |specs: &[(&'static str, usize)], bits: Vec<Vec<SharedBit>>| -> Pins {
+        specs.iter().map(|(pin, _)| *pin).zip(bits).collect()
+    };
+    Ok((gate, pins(spec.inputs, inputs), pins(spec.outputs, outputs)))
+}
+
+fn print_critical_path(target: &str, registers: bool) -> Result<(), String> {
+    let netlist = match target.ends_with(".hdl") {
+        true => load_chip(target).map_err(|e| e.to_string())?.to_netlist(),
+        false => {
+            let (gate, inputs, outputs) = build_builtin(target)?;
+            Netlist::from_gate(gate.as_ref(), &inputs, &outputs)
+        }
+    }
+    .map_err(|e| format!("{}: {}", target, e))?;
+    let path = match registers {
+        true => netlist.register_path(),
+        false => netlist.critical_path(),
+    };
+    print!("{}", path);
+    Ok(())
+}
+
+fn compile_jack(path: &str) -> Result<(), String> {
+    let files = compile_path(path).map_err(|e| e.to_string())?;
+    let dir = match Path::new(path).is_dir() {
+        true => Path::new(path),
+        false => Path::new(path).parent().unwrap_or(Path::new(".")),
+    };
+    for (name, vm) in files.iter() {
+        let out = dir.join(format!("{}.vm", name));
+        fs::write(&out, vm).map_err(|e| format!("{}: {}", out.display(), e))?;
+        println!("{}", out.display());
+    }
+    Ok(())
+}
+
+fn run_test(
+    path: &str,
+    use_hdl: bool,
+    use_netlist: bool,
+    vcd_path: Option<&str>,
+) -> Result<(), String> {
+    let outcome = run_script(path, use_hdl, use_netlist, vcd_path.map(Path::new))
+        .map_err(|e| e.to_string())?;
+    if let Some(mismatch) = outcome.mismatch {
+        return Err(format!(
+            "Comparison failure at line {}\nexpected: {}\nactual:   {}",
+            mismatch.line, mismatch.expected, mismatch.actual
+        ));
+    }
+    for line in outcome.lines.iter() {
+        println!("{}", line);
+    }
+    if outcome.compared {
+        println!("End of script - Comparison ended successfully");
+    } else {
+        println!("End of script");
+    }
+    if let Some(stats) = outcome.stats {
+        println!("{}", stats);
+    }
+    Ok(())
+}
+
+fn print_dumps(computer: &Computer, dumps: &[Range<u16>]) -> () {
+    for range in dumps.iter() {
+        for address in range.clone() {
+            println!("RAM[{}]: {}", address, computer.peek(address) as i16);
+        }
+    }
+}
+
+// --netlist のときだけ、計算したNandの数を表示する
+fn print_netlist_stats(computer: &Computer) -> () {
+    if let Some(stats) = computer.netlist_stats() {
+        println!("{}", stats);
+    }
+}
+
+fn print_computer_status(computer: &Computer, cycle: usize) -> () {
+    println!(
+        "cycle: {}, r0: {}, A: {}, D: {}, PC: {} | {}",
+        cycle,
+        computer.get_r0(),
+        computer.get_a_register_value(),
+        computer.get_d_register_value(),
+        computer.get_pc(),
+        disassemble(computer.get_instruction(computer.get_pc())),
+    );
+}
+
+fn print_native_status(native: &NativeComputer, cycle: usize) -> () {
+    println!(
+        "cycle: {}, r0: {}, A: {}, D: {}, PC: {} | {}",
+        cycle,
+        native.peek(0),
+        native.get_a_register_value(),
+        native.get_d_register_value(),
+        native.get_pc(),
+        disassemble(native.get_instruction(native.get_pc())),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_args(&args("")), Ok(Command::Help));
+        assert_eq!(parse_args(&args("--help")), Ok(Command::Help));
+        assert_eq!(
+            parse_args(&args("run Add.hack")),
+            Ok(Command::Run(Options {
+                path: "Add.hack".to_string(),
+                cycles: DEFAULT_CYCLES,
+                until_halt: false,
+                dumps: vec![],
+                pokes: vec![],
+                screen: None,
+                keyboard: false,
+                screenshots: vec![],
+                engine: Engine::Gate,
+                netlist: false,
+                vcd: None,
+            }))
+        );
+        assert_eq!(
+            parse_args(&args("trace Max.asm --cycles 20 --dump 0..3 --dump 256")),
+            Ok(Command::Trace(Options {
+                path: "Max.asm".to_string(),
+                cycles: 20,
+                until_halt: false,
+                dumps: vec![0..3, 256..257],
+                pokes: vec![],
+                screen: None,
+                keyboard: false,
+                screenshots: vec![],
+                engine: Engine::Gate,
+                netlist: false,
+                vcd: None,
+            }))
+        );
+        assert_eq!(
+            parse_args(&args("run Max.asm --until-halt")),
+            Ok(Command::Run(Options {
+                path: "Max.asm".to_string(),
+                cycles: DEFAULT_CYCLES,
+                until_halt: true,
+                dumps: vec![],
+                pokes: vec![],
+                screen: None,
+                keyboard: false,
+                screenshots: vec![],
+                engine: Engine::Gate,
+                netlist: false,
+                vcd: None,
+            }))
+        );
+        assert_eq!(
+            parse_args(&args("run Max.asm --until-halt --native")),
+            Ok(Command::Run(Options {
+                path: "Max.asm".to_string(),
+                cycles: DEFAULT_CYCLES,
+                until_halt: true,
+                dumps: vec![],
+                pokes: vec![],
+                screen: None,
+                keyboard: false,
+                screenshots: vec![],
+                engine: Engine::Native,
+                netlist: false,
+                vcd: None,
+            }))
+        );
+        assert_eq!(
+            parse_args(&args("step --cycles 5 Add.hack")),
+            Ok(Command::Step(Options {
+                path: "Add.hack".to_string(),
+                cycles: 5,
+                until_halt: false,
+                dumps: vec![],
+                pokes: vec![],
+                screen: None,
+                keyboard: false,
+                screenshots: vec![],
+                engine: Engine::Gate,
+                netlist: false,
+                vcd: None,
+            }))
+        );
+        assert_eq!(
+            parse_args(&args("run Fill.asm --screen --keyboard")),
+            Ok(Command::Run(Options {
+                path: "Fill.asm".to_string(),
+                cycles: DEFAULT_CYCLES,
+                until_halt: false,
+                dumps: vec![],
+                pokes: vec![],
+                screen: Some(ScreenOptions::default()),
+                keyboard: true,
+                screenshots: vec![],
+                engine: Engine::Gate,
+                netlist: false,
+                vcd: None,
+            }))
+        );
+        assert_eq!(
+            parse_args(&args("run Fill.asm --half-block --scale 4 --refresh 100")),
+            Ok(Command::Run(Options {
+                path: "Fill.asm".to_string(),
+                cycles: DEFAULT_CYCLES,
+                until_halt: false,
+                dumps: vec![],
+                pokes: vec![],
+                screen: Some(ScreenOptions {
+                    style: Style::HalfBlock,
+                    scale: 4,
+                    refresh: 100,
+                }),
+                keyboard: false,
+                screenshots: vec![],
+                engine: Engine::Gate,
+                netlist: false,
+                vcd: None,
+            }))
+        );
+
+        assert!(matches!(
+            parse_args(&args("debug Max.asm")),
+            Ok(Command::Debug(_))
+        ));
+        assert!(matches!(
+            parse_args(&args("cosim Max.asm --until-halt")),
+            Ok(Command::Cosim(Options {
+                until_halt: true,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            parse_args(&args("disasm Max.hack")),
+            Ok(Command::Disasm(_))
+        ));
+        assert_eq!(
+            parse_args(&args("hdl And.hdl a=1 b=-1")),
+            Ok(Command::Hdl {
+                path: "And.hdl".to_string(),
+                inputs: vec![("a".to_string(), 1), ("b".to_string(), 0xffff)],
+                netlist: false,
+            })
+        );
+        assert_eq!(
+            parse_args(&args("hdl ALU.hdl --netlist x=3")),
+            Ok(Command::Hdl {
+                path: "ALU.hdl".to_string(),
+                inputs: vec![("x".to_string(), 3)],
+                netlist: true,
+            })
+        );
+        assert!(parse_args(&args("hdl")).is_err());
+        assert_eq!(
+            parse_args(&args("test Xor.tst --hdl")),
+            Ok(Command::Test {
+                path: "Xor.tst".to_string(),
+                use_hdl: true,
+                netlist: false,
+                vcd: None,
+            })
+        );
+        assert_eq!(
+            parse_args(&args("test ALU.tst --netlist --hdl")),
+            Ok(Command::Test {
+                path: "ALU.tst".to_string(),
+                use_hdl: true,
+                netlist: true,
+                vcd: None,
+            })
+        );
+        assert!(parse_args(&args("test")).is_err());
+        assert_eq!(
+            parse_args(&args("test CPU.tst --vcd cpu.vcd")),
+            Ok(Command::Test {
+                path: "CPU.tst".to_string(),
+                use_hdl: false,
+                netlist: false,
+                vcd: Some("cpu.vcd".to_string()),
+            })
+        );
+        assert!(parse_args(&args("test CPU.tst --vcd")).is_err());
+        assert!(parse_args(&args("test CPU.tst --netlist --vcd cpu.vcd")).is_err());
+        assert_eq!(
+            parse_args(&args("translate FunctionCalls/FibonacciElement")),
+            Ok(Command::Translate(
+                "FunctionCalls/FibonacciElement".to_string()
+            ))
+        );
+        assert!(parse_args(&args("translate a.vm b.vm")).is_err());
+        assert_eq!(
+            parse_args(&args("compile Square")),
+            Ok(Command::Compile("Square".to_string()))
+        );
+        assert!(parse_args(&args("compile")).is_err());
+        assert_eq!(
+            parse_args(&args("gates CPU --depth 2")),
+            Ok(Command::Gates {
+                target: "CPU".to_string(),
+                depth: 2,
+            })
+        );
+        assert!(parse_args(&args("gates")).is_err());
+        assert_eq!(
+            parse_args(&args("critical-path ALU")),
+            Ok(Command::CriticalPath {
+                target: "ALU".to_string(),
+                registers: false
+            })
+        );
+        assert_eq!(
+            parse_args(&args("critical-path CPU --registers")),
+            Ok(Command::CriticalPath {
+                target: "CPU".to_string(),
+                registers: true
+            })
+        );
+        assert!(parse_args(&args("critical-path CPU --register")).is_err());
+        assert!(parse_args(&args("critical-path ALU CPU")).is_err());
+        assert!(parse_args(&args("gates ALU --depth")).is_err());
+        assert!(parse_args(&args("hdl And.hdl a")).is_err());
+        assert!(parse_args(&args("jump Add.hack")).is_err());
+        assert!(parse_args(&args("run")).is_err());
+        assert!(parse_args(&args("run Add.hack Max.hack")).is_err());
+        assert!(parse_args(&args("run Add.hack --cycles")).is_err());
+        assert!(parse_args(&args("run Add.hack --cycles x")).is_err());
+        assert!(parse_args(&args("run Add.hack --dump 3..1")).is_err());
+        assert!(parse_args(&args("run Add.hack --fast")).is_err());
+        assert_eq!(
+            parse_args(&args(
+                "run Fill.hack --screenshot-at-cycle 0 a.png --screenshot-at-cycle 500 b.pbm"
+            )),
+            Ok(Command::Run(Options {
+                path: "Fill.hack".to_string(),
+                cycles: DEFAULT_CYCLES,
+                until_halt: false,
+                dumps: vec![],
+                pokes: vec![],
+                screen: None,
+                keyboard: false,
+                screenshots: vec![(0, "a.png".to_string()), (500, "b.pbm".to_string())],
+                engine: Engine::Gate,
+                netlist: false,
+                vcd: None,
+            }))
+        );
+
+        assert_eq!(
+            parse_args(&args(
+                "run Add.asm --poke 0=2 --poke 24576=-1 --poke A=7 --poke PC=3"
+            )),
+            Ok(Command::Run(Options {
+                path: "Add.asm".to_string(),
+                cycles: DEFAULT_CYCLES,
+                until_halt: false,
+                dumps: vec![],
+                pokes: vec![
+                    (PokeTarget::Memory(0), 2),
+                    (PokeTarget::Memory(24576), 0xffff),
+                    (PokeTarget::A, 7),
+                    (PokeTarget::PC, 3),
+                ],
+                screen: None,
+                keyboard: false,
+                screenshots: vec![],
+                engine: Engine::Gate,
+                netlist: false,
+                vcd: None,
+            }))
+        );
+        assert!(parse_args(&args("run Add.hack --poke 0")).is_err());
+        assert!(parse_args(&args("run Add.hack --poke 24577=1")).is_err());
+        assert!(parse_args(&args("run Add.hack --poke M=1")).is_err());
+        assert!(parse_args(&args("run Add.hack --poke 0=70000")).is_err());
+
+        assert!(parse_args(&args("run Add.hack --scale 0")).is_err());
+        assert!(matches!(
+            parse_args(&args("trace Max.asm --vcd max.vcd")),
+            Ok(Command::Trace(Options { vcd: Some(path), .. })) if path == "max.vcd"
+        ));
+        assert!(parse_args(&args("run Max.asm --vcd max.vcd --native")).is_err());
+        assert!(parse_args(&args("debug Max.asm --vcd max.vcd")).is_err());
+        assert!(parse_args(&args("cosim Max.asm --vcd max.vcd")).is_err());
+        assert!(matches!(
+            parse_args(&args("cosim Max.asm --netlist")),
+            Ok(Command::Cosim(Options { netlist: true, .. }))
+        ));
+        assert!(parse_args(&args("run Max.asm --netlist --native")).is_err());
+        assert!(parse_args(&args("run Add.hack --screenshot-at-cycle 10")).is_err());
+        assert!(parse_args(&args(
+            "run Add.hack --screenshot-at-cycle 10 a.png --cycles 5"
+        ))
+        .is_err());
+        assert!(parse_args(&args(
+            "run Add.hack --screenshot-at-cycle 10 a.png --cycles 10"
+        ))
+        .is_ok());
+        assert!(parse_args(&args(
+            "run Add.hack --screenshot-at-cycle 2000 a.png --until-halt"
+        ))
+        .is_ok());
+        assert!(parse_args(&args("trace Add.hack --screenshot-at-cycle 0 a.png")).is_err());
+        assert!(parse_args(&args("step Add.hack --screenshot-at-cycle 0 a.png")).is_err());
+    }
+
+    #[test]
+    fn screenshot_after_halt() {
+        let dir = std::env::temp_dir();
+        let asm = dir.join("nand2tetris_my_hs_cli_screenshot.asm");
+        let png = dir.join("nand2tetris_my_hs_cli_screenshot.png");
+        fs::write(&asm, "(END)\n@END\n0;JMP\n").unwrap();
+        let _ = fs::remove_file(&png);
+        let run = |at: usize| {
+            let command = format!(
+                "run {} --until-halt --screenshot-at-cycle {} {}",
+                asm.display(),
+                at,
+                png.display()
+            );
+            execute(parse_args(&args(&command)).unwrap())
+        };
+        // 停止したあとのサイクルは撮れない
+        let error = run(100).unwrap_err();
+        assert!(error.contains("cycle 100 was not reached"), "{}", error);
+        assert!(!png.exists());
+        run(0).unwrap();
+        assert!(png.exists());
+
+        fs::remove_file(&asm).unwrap();
+        fs::remove_file(&png).unwrap();
+    }
+
+    #[test]
+    fn reset_in_vcd() {
+        let path = std::env::temp_dir().join("nand2tetris_my_hs_cli_reset.vcd");
+        let words = crate::assembler::assemble("@7\nD=A\n@0\n0;JMP\n").unwrap();
+        let rom = ROM32KBuiltIn::from_words(&words, Bus::all0().to_shared_bus());
+        let reset = Bus::<1>::all0().to_shared_bus();
+        let computer = Computer::new(reset.clone(), rom);
+        let mut vcd = Some(start_vcd(&computer, path.to_str().unwrap()).unwrap());
+        step_cycle(&computer, Engine::Gate, &mut vcd).unwrap();
+        reset_cycle(&computer, &reset, Engine::Gate, &mut vcd).unwrap();
+        assert_eq!(computer.get_pc(), 0);
+        vcd.unwrap().finish().unwrap();
+
+        // resetを立てたサイクルも半サイクルずつ記録される
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.contains("#3\n1!\n1\"\n"), "{}", text);
+        assert!(text.contains("#4\n0!\n"), "{}", text);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_asm_and_hack() {
+        let dir = std::env::temp_dir();
+        let asm = dir.join("nand2tetris_my_hs_cli_load.asm");
+        let hack = dir.join("nand2tetris_my_hs_cli_load.hack");
+        fs::write(&asm, "@7\nD=A\n").unwrap();
+        fs::write(&hack, "0000000000000111\n1110110000010000\n").unwrap();
+
+        let address = Bus::<15>::all0().to_shared_bus();
+        let from_asm = load_rom(asm.to_str().unwrap(), address.clone()).unwrap();
+        let from_hack = load_rom(hack.to_str().unwrap(), address.clone()).unwrap();
+        assert_eq!(from_asm.rom, from_hack.rom);
+
+        fs::write(&asm, "@7\nD=X\n").unwrap();
+        let error = load_rom(asm.to_str().unwrap(), address);
+        assert!(matches!(error, Err(LoadError::Asm(_))));
+
+        fs::remove_file(&asm).unwrap();
+        fs::remove_file(&hack).unwrap();
+    }
+
+    #[test]
+    fn load_vm_directory() {
+        let dir = std::env::temp_dir().join("nand2tetris_my_hs_cli_vm");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let sys = "function Sys.init 0\npush constant 40\ncall Main.double 1\npop static 0
+label END\ngoto END\n";
+        fs::write(dir.join("Sys.vm"), sys).unwrap();
+        let main = "function Main.double 0\npush argument 0\npush argument 0\nadd\nreturn\n";
+        fs::write(dir.join("Main.vm"), main).unwrap();
+
+        let rom = load_rom(dir.to_str().unwrap(), Bus::<15>::all0().to_shared_bus()).unwrap();
+        let computer = Computer::new(Bus::<1>::all0().to_shared_bus(), rom);
+        computer.run_until_halt().unwrap();
+        // Sys.0 は最初のstatic変数
+        assert_eq!(computer.peek(16), 80);
+
+        let error = load_rom(
+            dir.join("Main.vm").to_str().unwrap(),
+            Bus::<15>::all0().to_shared_bus(),
+        );
+        assert!(error.is_ok());
+        fs::write(dir.join("Main.vm"), "push nothing 0\n").unwrap();
+        let error = load_rom(dir.to_str().unwrap(), Bus::<15>::all0().to_shared_bus());
+        assert!(matches!(error, Err(LoadError::Vm(_))));
+
+        // 同じ名前の.jackがあればそちらを使う
+        let main = "class Main { function int double(int x) { return x + x + 1; } }";
+        fs::write(dir.join("Main.jack"), main).unwrap();
+        let rom = load_rom(dir.to_str().unwrap(), Bus::<15>::all0().to_shared_bus()).unwrap();
+        let computer = Computer::new(Bus::<1>::all0().to_shared_bus(), rom);
+        computer.run_until_halt().unwrap();
+        assert_eq!(computer.peek(16), 81);
+
+        fs::write(dir.join("Main.jack"), "class Main {").unwrap();
+        let error = load_rom(dir.to_str().unwrap(), Bus::<15>::all0().to_shared_bus());
+        assert!(matches!(error, Err(LoadError::Jack(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
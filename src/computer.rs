@@ -1,22 +1,89 @@
-use std::cell::{Cell, RefCell};
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    rc::Rc,
+};
 
 use crate::{
     arithmetic::ALU,
     gate::*,
+    image,
+    netlist::{Netlist, NetlistBuilder, NetlistError, Stats},
     sequential::{RAM16KBuiltIn, Register, PC},
 };
 
+/// .hackファイルを読み込むときのエラー。lineは1始まり
+#[derive(Debug)]
+pub enum HackLoadError {
+    Io(io::Error),
+    InvalidChar { line: usize, found: char },
+    InvalidWidth { line: usize, width: usize },
+    TooManyInstructions { line: usize },
+}
+
+impl fmt::Display for HackLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HackLoadError::Io(e) => write!(f, "{}", e),
+            HackLoadError::InvalidChar { line, found } => {
+                write!(f, "line {}: invalid character `{}`", line, found)
+            }
+            HackLoadError::InvalidWidth { line, width } => {
+                write!(f, "line {}: expected 16 bits but got {}", line, width)
+            }
+            HackLoadError::TooManyInstructions { line } => {
+                write!(f, "line {}: program exceeds 32768 instructions", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HackLoadError {}
+
+impl From<io::Error> for HackLoadError {
+    fn from(e: io::Error) -> Self {
+        HackLoadError::Io(e)
+    }
+}
+
 #[derive(Debug)]
 pub struct ROM32KBuiltIn {
     pub out: SharedBus<16>,
-    pub rom: Box<[u16; 32768]>,
+    pub rom: RefCell<Box<[u16; 32768]>>,
+    // 読み込んだプログラムの命令数。newで丸ごと渡されたときは32768
+    len: Cell<usize>,
     address: SharedBus<15>,
 }
 
 impl ROM32KBuiltIn {
     pub fn new(rom: Box<[u16; 32768]>, address: SharedBus<15>) -> ROM32KBuiltIn {
         let out = Bus::all0().to_shared_bus();
-        ROM32KBuiltIn { out, rom, address }
+        let len = Cell::new(rom.len());
+        let rom = RefCell::new(rom);
+        ROM32KBuiltIn {
+            out,
+            rom,
+            len,
+            address,
+        }
+    }
+
+    // ROMの中身を入れ替える。入り切らない分は捨てられ、残りは0になる
+    pub fn load(&self, words: &[u16]) -> () {
+        let mut rom = self.rom.borrow_mut();
+        rom.fill(0);
+        for (index, word) in words.iter().take(rom.len()).enumerate() {
+            rom[index] = *word;
+        }
+        self.len.set(words.len().min(rom.len()));
+    }
+
+    // 読み込んだプログラム。末尾の@0も命令として含む
+    pub fn program(&self) -> Vec<u16> {
+        self.rom.borrow()[..self.len.get()].to_vec()
     }
 
     // rom_str example
@@ -24,9 +91,12 @@ impl ROM32KBuiltIn {
     //  1111110000010000
     //  0000000000010111
     //  1110001100000110"
+    #[allow(dead_code)]
     pub fn from_rom_str(rom_str: &str, address: SharedBus<15>) -> ROM32KBuiltIn {
         let mut rom: Box<[u16; 32768]> = Box::new([0; 32768]);
+        let mut len = 0;
         for (index, line) in rom_str.lines().enumerate() {
+            len = index + 1;
             // FIXME bus_to_u16とロジックがかぶってるので直す
             let bits = line.chars().rev().collect::<Vec<char>>();
             let mut u = 0;
@@ -39,7 +109,69 @@ impl ROM32KBuiltIn {
             rom[index] = u;
         }
 
-        ROM32KBuiltIn::new(rom, address)
+        let rom = ROM32KBuiltIn::new(rom, address);
+        rom.len.set(len);
+        rom
+    }
+
+    // 先頭から順番に命令を並べる。入り切らない分は捨てられる
+    pub fn from_words(words: &[u16], address: SharedBus<15>) -> ROM32KBuiltIn {
+        let mut rom: Box<[u16; 32768]> = Box::new([0; 32768]);
+        for (index, word) in words.iter().take(rom.len()).enumerate() {
+            rom[index] = *word;
+        }
+
+        let rom = ROM32KBuiltIn::new(rom, address);
+        rom.len.set(words.len().min(32768));
+        rom
+    }
+
+    pub fn from_hack_file<P: AsRef<Path>>(
+        path: P,
+        address: SharedBus<15>,
+    ) -> Result<ROM32KBuiltIn, HackLoadError> {
+        let file = File::open(path)?;
+        Self::from_hack_reader(BufReader::new(file), address)
+    }
+
+    // 空行と//以降のコメントは読み飛ばす
+    pub fn from_hack_reader<R: BufRead>(
+        reader: R,
+        address: SharedBus<15>,
+    ) -> Result<ROM32KBuiltIn, HackLoadError> {
+        let mut rom: Box<[u16; 32768]> = Box::new([0; 32768]);
+        let mut index = 0;
+        for (line_index, line) in reader.lines().enumerate() {
+            let line_number = line_index + 1;
+            let line = line?;
+            let code = line.split("//").next().unwrap_or("").trim();
+            if code.is_empty() {
+                continue;
+            }
+
+            if let Some(found) = code.chars().find(|c| *c != '0' && *c != '1') {
+                return Err(HackLoadError::InvalidChar {
+                    line: line_number,
+                    found,
+                });
+            }
+            if code.len() != 16 {
+                return Err(HackLoadError::InvalidWidth {
+                    line: line_number,
+                    width: code.len(),
+                });
+            }
+            if index >= rom.len() {
+                return Err(HackLoadError::TooManyInstructions { line: line_number });
+            }
+
+            rom[index] = u16::from_str_radix(code, 2).unwrap();
+            index += 1;
+        }
+
+        let rom = ROM32KBuiltIn::new(rom, address);
+        rom.len.set(index);
+        Ok(rom)
     }
 
     fn bus_to_u16<const N: usize>(bus: SharedBus<N>) -> u16 {
@@ -57,7 +189,7 @@ impl ROM32KBuiltIn {
 impl Gate for ROM32KBuiltIn {
     fn re_compute(&self) -> () {
         let address = Self::bus_to_u16::<15>(self.address.clone()) as usize;
-        let mut value = self.rom[address];
+        let mut value = self.rom.borrow()[address];
 
         let mut bits = vec![];
         loop {
@@ -65,16 +197,17 @@ impl Gate for ROM32KBuiltIn {
                 1 => I,
                 _ => O,
             });
-            value = value / 2;
+            value /= 2;
             if value < 1 {
                 break;
             }
         }
 
         for i in 0..16 {
-            self.out
-                .get_shared_bit(i)
-                .set(bits.get(i).map(|b| b.clone()).unwrap_or(O));
+            drive(
+                &self.out.get_shared_bit(i),
+                bits.get(i).copied().unwrap_or(O),
+            );
         }
     }
 }
@@ -104,6 +237,16 @@ impl ScreenBuiltIn {
         }
     }
 
+    // 現在のフレームバッファのコピー
+    pub fn snapshot(&self) -> Box<[u16; 8192]> {
+        self.ram.borrow().clone()
+    }
+
+    // .pbmか.pngで保存する
+    pub fn save_screenshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        image::save_screenshot(path, &self.ram.borrow()[..])
+    }
+
     fn bus_to_u16<const N: usize>(bus: SharedBus<N>) -> u16 {
         let mut u = 0;
         for i in 0..N {
@@ -142,34 +285,59 @@ impl Gate for ScreenBuiltIn {
                 1 => I,
                 _ => O,
             });
-            value = value / 2;
+            value /= 2;
             if value < 1 {
                 break;
             }
         }
 
         for i in 0..16 {
-            self.out
-                .get_shared_bit(i)
-                .set(bits.get(i).map(|b| b.clone()).unwrap_or(O));
+            drive(
+                &self.out.get_shared_bit(i),
+                bits.get(i).copied().unwrap_or(O),
+            );
         }
     }
 }
 
+/// 押されているキーのHackキーコードを出力する。何も押されていなければ0
 #[derive(Debug)]
 pub struct KeyboardBuiltIn {
     pub out: SharedBus<16>,
+    key: Cell<u16>,
 }
 
 impl KeyboardBuiltIn {
     pub fn new() -> KeyboardBuiltIn {
         let out = Bus::all0().to_shared_bus();
-        KeyboardBuiltIn { out }
+        let key = Cell::new(0);
+        KeyboardBuiltIn { out, key }
+    }
+
+    pub fn press(&self, key: u16) -> () {
+        self.key.set(key);
+    }
+
+    pub fn release(&self) -> () {
+        self.key.set(0);
+    }
+
+    pub fn key(&self) -> u16 {
+        self.key.get()
     }
 }
 
 impl Gate for KeyboardBuiltIn {
-    // TODO
+    fn re_compute(&self) -> () {
+        let key = self.key.get();
+        for i in 0..16 {
+            let bit = match (key >> i) & 1 {
+                1 => I,
+                _ => O,
+            };
+            drive(&self.out.get_shared_bit(i), bit);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -185,17 +353,14 @@ pub struct MemoryBuiltIn {
 
 impl MemoryBuiltIn {
     pub fn new(input: SharedBus<16>, load: SharedBus<1>, address: SharedBus<15>) -> MemoryBuiltIn {
-        let dmux4way = DMux4Way::new(load.clone(), address.reconnect([13, 14]));
+        let dmux4way = DMux4Way::new(load.clone(), address.slice_len::<13, 2>());
         let or = Or::new(dmux4way.out1.clone(), dmux4way.out2.clone());
-        let ram16k = RAM16KBuiltIn::new(
-            input.clone(),
-            or.out.clone(),
-            address.reconnect([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13]),
-        );
+        let ram16k =
+            RAM16KBuiltIn::new(input.clone(), or.out.clone(), address.slice_len::<0, 14>());
         let screen = ScreenBuiltIn::new(
             input.clone(),
             dmux4way.out3.clone(),
-            address.reconnect([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]),
+            address.slice_len::<0, 13>(),
         );
         let keyboard = KeyboardBuiltIn::new();
         let mux4way16 = Mux4Way16::new(
@@ -203,7 +368,7 @@ impl MemoryBuiltIn {
             ram16k.out.clone(),
             screen.out.clone(),
             keyboard.out.clone(),
-            address.reconnect([13, 14]),
+            address.slice_len::<13, 2>(),
         );
 
         MemoryBuiltIn {
@@ -216,6 +381,31 @@ impl MemoryBuiltIn {
             mux4way16,
         }
     }
+
+    // RAM(0..16384)、Screen(16384..24576)、Keyboard(24576)の値を読む
+    // それ以外のアドレスは0を返す
+    pub fn peek(&self, address: u16) -> u16 {
+        let address = address as usize;
+        match address {
+            0..=16383 => self.ram16k.ram.borrow()[address],
+            16384..=24575 => self.screen.ram.borrow()[address - 16384],
+            24576 => self.keyboard.key(),
+            _ => 0,
+        }
+    }
+
+    // peekと同じ範囲に書き込む。Keyboardに書くとそのキーが押された状態になる
+    // 範囲外なら何もせずfalseを返す
+    pub fn poke(&self, address: u16, value: u16) -> bool {
+        let address = address as usize;
+        match address {
+            0..=16383 => self.ram16k.ram.borrow_mut()[address] = value,
+            16384..=24575 => self.screen.ram.borrow_mut()[address - 16384] = value,
+            24576 => self.keyboard.press(value),
+            _ => return false,
+        }
+        true
+    }
 }
 
 impl Gate for MemoryBuiltIn {
@@ -245,9 +435,24 @@ impl Gate for MemoryBuiltIn {
         self.keyboard.re_compute();
         self.mux4way16.re_compute();
     }
+
+    // RAM16K、Screen、KeyboardはNandとDFFに展開できない。数えるときだけ部品をたどる
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        if !builder.is_counting() {
+            return Err(NetlistError::unsupported::<Self>());
+        }
+        builder.part("dmux4way", &self.dmux4way)?;
+        builder.part("or", &self.or)?;
+        builder.part("ram16k", &self.ram16k)?;
+        builder.part("screen", &self.screen)?;
+        builder.part("keyboard", &self.keyboard)?;
+        builder.part("mux4way16", &self.mux4way16)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)] // nand2tetrisのチップ名に合わせる
 pub struct CPU {
     pub out_m: SharedBus<16>,
     pub write_m: SharedBus<1>,
@@ -256,14 +461,13 @@ pub struct CPU {
     not1: Not<1>,
     not2: Not<1>,
     and1: And<1>,
-    alu_out: SharedBus<16>,
+    mux2: Mux<16>,
+    alu: ALU,
     mux1: Mux<16>,
     or1: Or<1>,
     a_register: Register,
-    mux2: Mux<16>,
     and2: And<1>,
     d_register: Register,
-    alu: ALU,
     or2: Or<16>,
     or3: Or<16>,
     and3: And<1>,
@@ -281,46 +485,44 @@ pub struct CPU {
 
 impl CPU {
     pub fn new(in_m: SharedBus<16>, instruction: SharedBus<16>, reset: SharedBus<1>) -> CPU {
-        let not1 = Not::new(instruction.reconnect([15]).clone());
+        let not1 = Not::new(instruction.bit::<15>());
         let not2 = Not::new(not1.out.clone());
 
-        let and1 = And::new(not2.out.clone(), instruction.reconnect([5]));
+        let and1 = And::new(not2.out.clone(), instruction.bit::<5>());
 
-        let alu_out = Bus::<16>::all0().to_shared_bus();
-        let mux1 = Mux::new(instruction.clone(), alu_out.clone(), and1.out.clone());
+        // A/Dレジスタの出力を先に作っておき、ALUの出力をレジスタの入力に戻す
+        let a_out = Bus::<16>::all0().to_shared_bus();
+        let d_out = Bus::<16>::all0().to_shared_bus();
 
-        let or1 = Or::new(not1.out.clone(), and1.out.clone());
-        let a_register = Register::new(mux1.out.clone(), or1.out.clone());
-
-        let mux2 = Mux::new(
-            a_register.out.clone(),
-            in_m.clone(),
-            instruction.reconnect([12]),
-        );
-
-        let and2 = And::new(not2.out.clone(), instruction.reconnect([4]));
-        let d_register = Register::new(alu_out.clone(), and2.out.clone());
+        let mux2 = Mux::new(a_out.clone(), in_m.clone(), instruction.bit::<12>());
 
         let alu = ALU::new(
-            d_register.out.clone(),
+            d_out.clone(),
             mux2.out.clone(),
-            instruction.reconnect([11]),
-            instruction.reconnect([10]),
-            instruction.reconnect([9]),
-            instruction.reconnect([8]),
-            instruction.reconnect([7]),
-            instruction.reconnect([6]),
+            instruction.bit::<11>(),
+            instruction.bit::<10>(),
+            instruction.bit::<9>(),
+            instruction.bit::<8>(),
+            instruction.bit::<7>(),
+            instruction.bit::<6>(),
         );
 
+        let mux1 = Mux::new(instruction.clone(), alu.out.clone(), and1.out.clone());
+        let or1 = Or::new(not1.out.clone(), and1.out.clone());
+        let a_register = Register::with_out(mux1.out.clone(), or1.out.clone(), a_out);
+
+        let and2 = And::new(not2.out.clone(), instruction.bit::<4>());
+        let d_register = Register::with_out(alu.out.clone(), and2.out.clone(), d_out);
+
         let or2 = Or::new(Bus::all0().to_shared_bus(), a_register.out.clone());
         let or3 = Or::new(Bus::all0().to_shared_bus(), alu.out.clone());
-        let and3 = And::new(not2.out.clone(), instruction.reconnect([3]));
+        let and3 = And::new(not2.out.clone(), instruction.bit::<3>());
 
-        let and4 = And::new(alu.zr.clone(), instruction.reconnect([1]));
-        let and5 = And::new(alu.ng.clone(), instruction.reconnect([2]));
+        let and4 = And::new(alu.zr.clone(), instruction.bit::<1>());
+        let and5 = And::new(alu.ng.clone(), instruction.bit::<2>());
         let or4 = Or::new(alu.zr.clone(), alu.ng.clone());
         let not3 = Not::new(or4.out.clone());
-        let and6 = And::new(not3.out.clone(), instruction.reconnect([0]));
+        let and6 = And::new(not3.out.clone(), instruction.bit::<0>());
         let or5 = Or::new(and4.out.clone(), and5.out.clone());
         let or6 = Or::new(or5.out.clone(), and6.out.clone());
         let and7 = And::new(not2.out.clone(), or6.out.clone());
@@ -332,26 +534,21 @@ impl CPU {
             reset.clone(),
         );
 
-        return CPU {
+        CPU {
             out_m: or3.out.clone(),
             write_m: and3.out.clone(),
-            address_m: or2
-                .out
-                .reconnect([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14]),
-            pc: pc_gate
-                .out
-                .reconnect([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14]),
+            address_m: or2.out.slice_len::<0, 15>(),
+            pc: pc_gate.out.slice_len::<0, 15>(),
             not1,
             not2,
             and1,
-            alu_out,
+            mux2,
+            alu,
             mux1,
             or1,
             a_register,
-            mux2,
             and2,
             d_register,
-            alu,
             or2,
             or3,
             and3,
@@ -365,7 +562,7 @@ impl CPU {
             and7,
             not4,
             pc_gate,
-        };
+        }
     }
 
     pub fn get_a_register_value(&self) -> u16 {
@@ -375,6 +572,33 @@ impl CPU {
     pub fn get_d_register_value(&self) -> u16 {
         self.d_register.out.to_u16()
     }
+
+    // set_*はレジスタの値を直接書き換えるだけなので、あとでre_compute()が必要
+    pub fn set_a_register_value(&self, value: u16) -> () {
+        self.a_register.set(value.into());
+    }
+
+    pub fn set_d_register_value(&self, value: u16) -> () {
+        self.d_register.set(value.into());
+    }
+
+    pub fn set_pc(&self, value: u16) -> () {
+        self.pc_gate.set(value & 0x7fff);
+    }
+
+    /// Vcdで記録したい信号の (名前, bit)
+    pub fn probes(&self) -> Vec<(&'static str, Vec<SharedBit>)> {
+        vec![
+            ("A", self.a_register.out.shared_bits()),
+            ("D", self.d_register.out.shared_bits()),
+            ("PC", self.pc.shared_bits()),
+            ("writeM", self.write_m.shared_bits()),
+            ("addressM", self.address_m.shared_bits()),
+            ("outM", self.out_m.shared_bits()),
+            ("alu.zr", self.alu.zr.shared_bits()),
+            ("alu.ng", self.alu.ng.shared_bits()),
+        ]
+    }
 }
 
 impl Gate for CPU {
@@ -382,13 +606,13 @@ impl Gate for CPU {
         self.not1.clock_up();
         self.not2.clock_up();
         self.and1.clock_up();
+        self.mux2.clock_up();
+        self.alu.clock_up();
         self.mux1.clock_up();
         self.or1.clock_up();
         self.a_register.clock_up();
-        self.mux2.clock_up();
         self.and2.clock_up();
         self.d_register.clock_up();
-        self.alu.clock_up();
         self.or2.clock_up();
         self.or3.clock_up();
         self.and3.clock_up();
@@ -408,13 +632,13 @@ impl Gate for CPU {
         self.not1.clock_down();
         self.not2.clock_down();
         self.and1.clock_down();
+        self.mux2.clock_down();
+        self.alu.clock_down();
         self.mux1.clock_down();
         self.or1.clock_down();
         self.a_register.clock_down();
-        self.mux2.clock_down();
         self.and2.clock_down();
         self.d_register.clock_down();
-        self.alu.clock_down();
         self.or2.clock_down();
         self.or3.clock_down();
         self.and3.clock_down();
@@ -434,14 +658,13 @@ impl Gate for CPU {
         self.not1.re_compute();
         self.not2.re_compute();
         self.and1.re_compute();
-        //self.mux1.re_compute();
-        self.or1.re_compute();
-        //self.a_register.re_compute();
         self.mux2.re_compute();
-        self.and2.re_compute();
-        //self.d_register.re_compute();
         self.alu.re_compute();
-        self.alu_out.overwrite(&self.alu.out.0.borrow());
+        self.mux1.re_compute();
+        self.or1.re_compute();
+        self.a_register.re_compute();
+        self.and2.re_compute();
+        self.d_register.re_compute();
         self.or2.re_compute();
         self.or3.re_compute();
         self.and3.re_compute();
@@ -455,75 +678,343 @@ impl Gate for CPU {
         self.and7.re_compute();
         self.not4.re_compute();
         self.pc_gate.re_compute();
+    }
 
-        // alu_outの出力をレジスタまで伝播させる
-        self.mux1.re_compute();
-        self.a_register.re_compute();
-        self.d_register.re_compute();
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("not1", &self.not1)?;
+        builder.part("not2", &self.not2)?;
+        builder.part("and1", &self.and1)?;
+        builder.part("mux2", &self.mux2)?;
+        builder.part("alu", &self.alu)?;
+        builder.part("mux1", &self.mux1)?;
+        builder.part("or1", &self.or1)?;
+        builder.part("a_register", &self.a_register)?;
+        builder.part("and2", &self.and2)?;
+        builder.part("d_register", &self.d_register)?;
+        builder.part("or2", &self.or2)?;
+        builder.part("or3", &self.or3)?;
+        builder.part("and3", &self.and3)?;
+        builder.part("and4", &self.and4)?;
+        builder.part("and5", &self.and5)?;
+        builder.part("or4", &self.or4)?;
+        builder.part("not3", &self.not3)?;
+        builder.part("and6", &self.and6)?;
+        builder.part("or5", &self.or5)?;
+        builder.part("or6", &self.or6)?;
+        builder.part("and7", &self.and7)?;
+        builder.part("not4", &self.not4)?;
+        builder.part("pc_gate", &self.pc_gate)?;
+        Ok(())
     }
 }
 
+// ROMのpc番地から、何もせず自分自身に飛び続けるループになっているか
+pub(crate) fn is_halt_loop(rom: &[u16], pc: u16, a: u16) -> bool {
+    let pc = pc as usize;
+    let word = |address: usize| rom.get(address).copied();
+    // dest無しで無条件にジャンプするC命令
+    let is_jump_only = |word: Option<u16>| match word {
+        Some(word) => word & 0xe000 == 0xe000 && word & 0b111_111 == 0b000_111,
+        None => false,
+    };
+
+    // @pc の次が 0;JMP
+    if word(pc) == Some(pc as u16) && is_jump_only(word(pc + 1)) {
+        return true;
+    }
+    if !is_jump_only(word(pc)) {
+        return false;
+    }
+    // 0;JMP の飛び先が自分自身か、直前の @(pc-1)
+    let a = a as usize;
+    a == pc || (a + 1 == pc && word(a) == Some(a as u16))
+}
+
 #[derive(Debug)]
 pub struct Computer {
-    rom: ROM32KBuiltIn,
+    // ROMは外から渡されるので、CPUのpcをROMのaddressに写す
+    pc_link: Link<15>,
+    // use_netlistのときはNetlistと共有する
+    rom: Rc<ROM32KBuiltIn>,
     pub cpu: CPU,
-    memory: MemoryBuiltIn,
+    memory: Rc<MemoryBuiltIn>,
+    // CPUはメモリより先に作るので、メモリの出力をCPUのinMに写す
+    memory_link: Link<16>,
     memory_out: SharedBus<16>,
+    reset: SharedBus<1>,
+    // use_netlistのあとは、re_computeやクロックをこちらで計算する
+    netlist: Option<Netlist>,
 }
 
 impl Computer {
     pub fn new(reset: SharedBus<1>, rom: ROM32KBuiltIn) -> Computer {
         let memory_out = Bus::all0().to_shared_bus();
         let cpu = CPU::new(memory_out.clone(), rom.out.clone(), reset.clone());
+        let pc_link = Link::new(cpu.pc.clone(), rom.address.clone());
         let memory = MemoryBuiltIn::new(
             cpu.out_m.clone(),
             cpu.write_m.clone(),
             cpu.address_m.clone(),
         );
-        return Computer {
-            memory_out,
-            rom,
+        let memory_link = Link::new(memory.out.clone(), memory_out.clone());
+        Computer {
+            pc_link,
+            rom: Rc::new(rom),
             cpu,
-            memory,
-        };
+            memory: Rc::new(memory),
+            memory_link,
+            memory_out,
+            reset,
+            netlist: None,
+        }
+    }
+
+    /// これより後はCPUをNandとDFFのnetlistに展開して、値が変わったところだけを計算する
+    /// ROMとメモリはそのままnetlistのノードにするので、peekやScreenもそのまま使える
+    /// netlistで計算した値はゲートのbitにも書き戻すので、A/D/PCやVcdもそのまま読める
+    pub fn use_netlist(&mut self) -> Result<(), NetlistError> {
+        if self.netlist.is_some() {
+            return Ok(());
+        }
+        let mut netlist = Netlist::from_gate(self, &[("reset", self.reset.shared_bits())], &[])?;
+        netlist.write_back();
+        self.netlist = Some(netlist);
+        Ok(())
+    }
+
+    /// use_netlistのあとに計算したNandの数
+    pub fn netlist_stats(&self) -> Option<Stats> {
+        self.netlist.as_ref().map(|netlist| netlist.stats())
+    }
+
+    // レジスタを直接書き換えたらnetlistにも読み直させる
+    fn reload(&self) -> () {
+        if let Some(netlist) = &self.netlist {
+            netlist.reload();
+        }
+    }
+
+    // resetを1サイクルだけ立ててPCを0に戻す。RAMやA/Dはそのまま
+    pub fn reset(&self) -> Result<(), Oscillation> {
+        self.reset.get_shared_bit(0).set(I);
+        self.tick()?;
+        self.tock()?;
+        self.reset.get_shared_bit(0).set(O);
+        self.settle()?;
+        Ok(())
+    }
+
+    // max_cycles サイクル実行する
+    pub fn run(&self, max_cycles: usize) -> Result<(), Oscillation> {
+        for _ in 0..max_cycles {
+            self.tick()?;
+            self.tock()?;
+        }
+        Ok(())
     }
 
-    pub fn tick(&self) -> () {
-        self.re_compute();
+    // 停止するまで実行して、実行したサイクル数を返す
+    // 停止しないプログラムだと返ってこない
+    pub fn run_until_halt(&self) -> Result<usize, Oscillation> {
+        let mut cycles = 0;
+        while !self.is_halted() {
+            self.tick()?;
+            self.tock()?;
+            cycles += 1;
+        }
+        Ok(cycles)
+    }
+
+    // (END) @END 0;JMP のような、何もせず自分自身に飛び続けるループに入っているか
+    pub fn is_halted(&self) -> bool {
+        let rom = self.rom.rom.borrow();
+        is_halt_loop(&rom[..], self.get_pc(), self.get_a_register_value())
+    }
+
+    pub fn tick(&self) -> Result<(), Oscillation> {
+        self.settle()?;
         self.clock_up();
+        Ok(())
     }
 
-    pub fn tock(&self) -> () {
+    pub fn tock(&self) -> Result<(), Oscillation> {
         self.clock_down();
-        self.re_compute();
+        self.settle()?;
+        Ok(())
     }
 
     pub fn get_r0(&self) -> u16 {
         let d = self.memory.ram16k.ram.borrow();
         d[0]
     }
+
+    pub fn rom(&self) -> &ROM32KBuiltIn {
+        &self.rom
+    }
+
+    pub fn memory(&self) -> &MemoryBuiltIn {
+        &self.memory
+    }
+
+    pub fn screen(&self) -> &ScreenBuiltIn {
+        &self.memory.screen
+    }
+
+    pub fn keyboard(&self) -> &KeyboardBuiltIn {
+        &self.memory.keyboard
+    }
+
+    pub fn save_screenshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.memory.screen.save_screenshot(path)
+    }
+
+    pub fn screen_buffer(&self) -> Box<[u16; 8192]> {
+        self.screen().snapshot()
+    }
+
+    // ROMのaddress番地の命令
+    pub fn get_instruction(&self, address: u16) -> u16 {
+        self.rom
+            .rom
+            .borrow()
+            .get(address as usize)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn get_keyboard_value(&self) -> u16 {
+        self.memory.keyboard.key()
+    }
+
+    // RAM(0..16384)、Screen(16384..24576)、Keyboard(24576)の値を読む
+    // それ以外のアドレスは0を返す
+    pub fn peek(&self, address: u16) -> u16 {
+        self.memory.peek(address)
+    }
+
+    // peekと同じ範囲に書き込む。Keyboardに書くとそのキーが押された状態になる
+    pub fn poke(&self, address: u16, value: u16) -> Result<(), Oscillation> {
+        if self.memory.poke(address, value) {
+            self.settle()?;
+        }
+        Ok(())
+    }
+
+    // ROMを入れ替える。PCやRAMはそのまま
+    pub fn load_program(&self, words: &[u16]) -> Result<(), Oscillation> {
+        self.rom.load(words);
+        self.settle()?;
+        Ok(())
+    }
+
+    pub fn get_a_register_value(&self) -> u16 {
+        self.cpu.get_a_register_value()
+    }
+
+    pub fn get_d_register_value(&self) -> u16 {
+        self.cpu.get_d_register_value()
+    }
+
+    pub fn get_pc(&self) -> u16 {
+        self.cpu.pc.to_u16()
+    }
+
+    pub fn set_a_register_value(&self, value: u16) -> Result<(), Oscillation> {
+        self.cpu.set_a_register_value(value);
+        self.reload();
+        self.settle()?;
+        Ok(())
+    }
+
+    pub fn set_d_register_value(&self, value: u16) -> Result<(), Oscillation> {
+        self.cpu.set_d_register_value(value);
+        self.reload();
+        self.settle()?;
+        Ok(())
+    }
+
+    pub fn set_pc(&self, value: u16) -> Result<(), Oscillation> {
+        self.cpu.set_pc(value);
+        self.reload();
+        self.settle()?;
+        Ok(())
+    }
+
+    /// Vcdで記録したい信号の (名前, bit)。CPUの中は "cpu." から始まる
+    pub fn probes(&self) -> Vec<(String, Vec<SharedBit>)> {
+        let mut probes = vec![
+            ("reset".to_string(), self.reset.shared_bits()),
+            ("instruction".to_string(), self.rom.out.shared_bits()),
+            ("inM".to_string(), self.memory_out.shared_bits()),
+        ];
+        for (name, bits) in self.cpu.probes() {
+            probes.push((format!("cpu.{}", name), bits));
+        }
+        probes
+    }
 }
 
 impl Gate for Computer {
     fn clock_up(&self) -> () {
+        if let Some(netlist) = &self.netlist {
+            return netlist.clock_up();
+        }
         self.rom.clock_up();
         self.cpu.clock_up();
         self.memory.clock_up();
     }
 
     fn clock_down(&self) -> () {
+        if let Some(netlist) = &self.netlist {
+            return netlist.clock_down();
+        }
         self.rom.clock_down();
         self.cpu.clock_down();
         self.memory.clock_down();
     }
 
+    // メモリの出力はCPUに戻ってくるので、一回では落ち着かない。使う側はsettleを呼ぶ
+    // netlistなら依存順に計算するので一回で決まる
     fn re_compute(&self) -> () {
-        self.rom.address.overwrite(&self.cpu.pc.0.borrow());
-        self.memory_out.overwrite(&self.memory.out.0.borrow());
-
+        if let Some(netlist) = &self.netlist {
+            return netlist.re_compute();
+        }
+        self.pc_link.re_compute();
         self.rom.re_compute();
         self.cpu.re_compute();
         self.memory.re_compute();
+        self.memory_link.re_compute();
+    }
+
+    // ROMとメモリは展開できないので、組み込みのノードにする。数えるときは部品をたどる
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        // Linkはただの配線なので部品として数えない
+        self.pc_link.flatten(builder)?;
+        if builder.is_counting() {
+            builder.part("rom", self.rom.as_ref())?;
+            builder.part("cpu", &self.cpu)?;
+            builder.part("memory", self.memory.as_ref())?;
+            return self.memory_link.flatten(builder);
+        }
+        builder.builtin(
+            "rom",
+            self.rom.clone(),
+            &self.rom.address.shared_bits(),
+            &[],
+            &self.rom.out.shared_bits(),
+        );
+        builder.part("cpu", &self.cpu)?;
+        // 読むのはaddressMの番地だけで、outMとwriteMはクロックのときに書き込むだけ
+        let mut clocked = self.cpu.out_m.shared_bits();
+        clocked.extend(self.cpu.write_m.shared_bits());
+        builder.builtin(
+            "memory",
+            self.memory.clone(),
+            &self.cpu.address_m.shared_bits(),
+            &clocked,
+            &self.memory.out.shared_bits(),
+        );
+        self.memory_link.flatten(builder)
     }
 }
 
@@ -532,6 +1023,7 @@ mod tests {
     use super::*;
     use core::cell::Cell;
     use std::rc::Rc;
+    use std::time::Instant;
 
     fn str_to_shared_bus<const N: usize>(s: &str) -> SharedBus<N> {
         let mut bits = [O; N];
@@ -578,6 +1070,64 @@ mod tests {
         assert_eq!(rom.out.to_u16(), 58118);
     }
 
+    #[test]
+    fn rom32k_from_hack_reader() {
+        let hack = "// 2 + 3
+                    0000000000000010
+
+                    1110110000010000 // D=A
+                    0000000000000011";
+        let address = str_to_shared_bus::<15>("10");
+        let rom = ROM32KBuiltIn::from_hack_reader(hack.as_bytes(), address).unwrap();
+        rom.re_compute();
+        assert_eq!(rom.out.to_u16(), 3);
+        assert_eq!(rom.rom.borrow()[1], 0b1110110000010000);
+        assert_eq!(rom.rom.borrow()[3], 0);
+
+        let address = Bus::<15>::all0().to_shared_bus();
+        let error = ROM32KBuiltIn::from_hack_reader(
+            "0000000000000010\n00000000x0000000".as_bytes(),
+            address.clone(),
+        );
+        assert!(matches!(
+            error,
+            Err(HackLoadError::InvalidChar {
+                line: 2,
+                found: 'x'
+            })
+        ));
+
+        let error =
+            ROM32KBuiltIn::from_hack_reader("\n\n000000000000001".as_bytes(), address.clone());
+        assert!(matches!(
+            error,
+            Err(HackLoadError::InvalidWidth { line: 3, width: 15 })
+        ));
+
+        let too_long = "0000000000000000\n".repeat(32769);
+        let error = ROM32KBuiltIn::from_hack_reader(too_long.as_bytes(), address.clone());
+        assert!(matches!(
+            error,
+            Err(HackLoadError::TooManyInstructions { line: 32769 })
+        ));
+    }
+
+    #[test]
+    fn rom32k_from_hack_file() {
+        let path = std::env::temp_dir().join("nand2tetris_my_hs_rom32k_from_hack_file.hack");
+        std::fs::write(&path, "1110110000010000\n0000000000000000\n").unwrap();
+        let address = Bus::<15>::all0().to_shared_bus();
+        let rom = ROM32KBuiltIn::from_hack_file(&path, address.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        rom.re_compute();
+        assert_eq!(rom.out.to_u16(), 0b1110110000010000);
+        // 末尾の@0もプログラムに含む
+        assert_eq!(rom.program(), vec![0b1110110000010000, 0]);
+
+        let error = ROM32KBuiltIn::from_hack_file(&path, address);
+        assert!(matches!(error, Err(HackLoadError::Io(_))));
+    }
+
     #[test]
     fn ram16kbuiltin() {
         // 順番に依存している
@@ -586,7 +1136,9 @@ mod tests {
             (12345, 1, 8192, 12345),
             (12345, 1, 16384, 0),
             (12345, 1, 16384, 12345),
-            // keyboardは未対応
+            // keyboardには書き込めない
+            (12345, 1, 24576, 0),
+            (12345, 1, 24576, 0),
         ];
 
         let input: SharedBus<16> = Bus::all0().to_shared_bus();
@@ -615,6 +1167,20 @@ mod tests {
             memory.clock_down();
             memory.re_compute();
         }
+
+        // keyboard
+        load.overwrite(&i16_to_bus1(0));
+        memory.keyboard.press(75);
+        memory.re_compute();
+        assert_eq!(memory.out.to_u16(), 75);
+
+        memory.keyboard.press(131);
+        memory.re_compute();
+        assert_eq!(memory.out.to_u16(), 131);
+
+        memory.keyboard.release();
+        memory.re_compute();
+        assert_eq!(memory.out.to_u16(), 0);
     }
 
     #[test]
@@ -663,6 +1229,239 @@ mod tests {
         assert_eq!(cpu.get_d_register_value(), 12345);
     }
 
+    #[test]
+    fn peek_poke() {
+        let address = Bus::<15>::all0().to_shared_bus();
+        let rom = ROM32KBuiltIn::from_words(&[], address);
+        let reset = Bus::<1>::all0().to_shared_bus();
+        let computer = Computer::new(reset, rom);
+
+        for (address, value) in [(0, 1), (16383, 2), (16384, 3), (24575, 4), (24576, 75)] {
+            assert_eq!(computer.peek(address), 0);
+            computer.poke(address, value).unwrap();
+            assert_eq!(computer.peek(address), value);
+        }
+        assert_eq!(computer.get_r0(), 1);
+        assert_eq!(computer.screen_buffer()[0], 3);
+        assert_eq!(computer.screen_buffer()[8191], 4);
+        assert_eq!(computer.get_keyboard_value(), 75);
+
+        // 範囲外は無視される
+        computer.poke(24577, 5).unwrap();
+        assert_eq!(computer.peek(24577), 0);
+    }
+
+    #[test]
+    fn set_registers() {
+        // 0: D=D+M, 1: AM=M+1, 2: D=A
+        let words = crate::assembler::assemble("D=D+M\nAM=M+1\nD=A\n@7\nD=A").unwrap();
+        let address = Bus::<15>::all0().to_shared_bus();
+        let rom = ROM32KBuiltIn::from_words(&words, address);
+        let reset = Bus::<1>::all0().to_shared_bus();
+        let computer = Computer::new(reset, rom);
+
+        computer.set_a_register_value(100).unwrap();
+        computer.set_d_register_value(5).unwrap();
+        computer.poke(100, 7).unwrap();
+        assert_eq!(computer.get_a_register_value(), 100);
+        assert_eq!(computer.get_d_register_value(), 5);
+
+        computer.tick().unwrap();
+        computer.tock().unwrap();
+        assert_eq!(computer.get_d_register_value(), 12);
+        assert_eq!(computer.get_pc(), 1);
+
+        computer.tick().unwrap();
+        computer.tock().unwrap();
+        assert_eq!(computer.peek(100), 8);
+        assert_eq!(computer.get_a_register_value(), 8);
+
+        // @7 に飛ぶ
+        computer.set_pc(3).unwrap();
+        assert_eq!(computer.get_pc(), 3);
+        computer.tick().unwrap();
+        computer.tock().unwrap();
+        computer.tick().unwrap();
+        computer.tock().unwrap();
+        assert_eq!(computer.get_d_register_value(), 7);
+        assert_eq!(computer.get_pc(), 5);
+    }
+
+    #[test]
+    fn reset_and_run_until_halt() {
+        // RAM[2] = RAM[0] + RAM[1]
+        let source = "@0\nD=M\n@1\nD=D+M\n@2\nM=D\n(END)\n@END\n0;JMP\n";
+        let words = crate::assembler::assemble(source).unwrap();
+        let address = Bus::<15>::all0().to_shared_bus();
+        let rom = ROM32KBuiltIn::from_words(&words, address);
+        let reset = Bus::<1>::all0().to_shared_bus();
+        let computer = Computer::new(reset, rom);
+
+        computer.poke(0, 2).unwrap();
+        computer.poke(1, 3).unwrap();
+        assert!(!computer.is_halted());
+        assert_eq!(computer.run_until_halt().unwrap(), 6);
+        assert_eq!(computer.peek(2), 5);
+        assert_eq!(computer.get_pc(), 6);
+
+        // 止まった後も同じところを回り続ける
+        computer.run(3).unwrap();
+        assert_eq!(computer.get_pc(), 7);
+        assert!(computer.is_halted());
+
+        computer.reset().unwrap();
+        assert_eq!(computer.get_pc(), 0);
+        assert_eq!(computer.peek(2), 5);
+
+        computer.poke(0, 10).unwrap();
+        computer.run(2).unwrap();
+        assert_eq!(computer.get_pc(), 2);
+        assert_eq!(computer.get_d_register_value(), 10);
+        assert_eq!(computer.run_until_halt().unwrap(), 4);
+        assert_eq!(computer.peek(2), 13);
+    }
+
+    #[test]
+    fn halt_on_self_jump() {
+        // 0: @1, 1: 0;JMP (1に飛んだ後、A=1のまま自分自身に飛び続ける)
+        let words = crate::assembler::assemble("@1\n0;JMP\n").unwrap();
+        let address = Bus::<15>::all0().to_shared_bus();
+        let rom = ROM32KBuiltIn::from_words(&words, address);
+        let reset = Bus::<1>::all0().to_shared_bus();
+        let computer = Computer::new(reset, rom);
+        assert_eq!(computer.run_until_halt().unwrap(), 1);
+
+        // ジャンプ先がループの外なら止まっていない
+        let words = crate::assembler::assemble("@0\nM=M+1\n@0\n0;JMP\n").unwrap();
+        let address = Bus::<15>::all0().to_shared_bus();
+        let rom = ROM32KBuiltIn::from_words(&words, address);
+        let reset = Bus::<1>::all0().to_shared_bus();
+        let computer = Computer::new(reset, rom);
+        computer.run(10).unwrap();
+        assert!(!computer.is_halted());
+    }
+
+    #[test]
+    fn use_netlist() {
+        // RAM[2] = RAM[0] * RAM[1] のあと、Keyboardの値をScreenに書く
+        let source = "@R2\nM=0\n(LOOP)\n@R0\nD=M\n@END\nD;JEQ\n@R1\nD=M\n@R2\nM=D+M\n@R0\nM=M-1
+@LOOP\n0;JMP\n(END)\n@KBD\nD=M\n@SCREEN\nM=D\n(HALT)\n@HALT\n0;JMP\n";
+        let words = crate::assembler::assemble(source).unwrap();
+        let computer = || {
+            let rom = ROM32KBuiltIn::from_words(&words, Bus::all0().to_shared_bus());
+            Computer::new(Bus::all0().to_shared_bus(), rom)
+        };
+        let gate = computer();
+        let mut netlist = computer();
+        netlist.use_netlist().unwrap();
+        assert!(gate.netlist_stats().is_none());
+        for c in [&gate, &netlist] {
+            c.poke(0, 6).unwrap();
+            c.poke(1, 7).unwrap();
+            c.keyboard().press(65);
+        }
+
+        let state = |c: &Computer| {
+            (
+                c.get_a_register_value(),
+                c.get_d_register_value(),
+                c.get_pc(),
+                c.cpu.out_m.to_u16(),
+                c.cpu.write_m.to_u16(),
+            )
+        };
+        let mut cycles = 0;
+        while !gate.is_halted() {
+            assert_eq!(state(&netlist), state(&gate), "cycle {}", cycles);
+            for c in [&gate, &netlist] {
+                c.tick().unwrap();
+                c.tock().unwrap();
+            }
+            cycles += 1;
+        }
+        assert!(netlist.is_halted());
+        assert_eq!(netlist.peek(2), 42);
+        assert_eq!(netlist.peek(16384), 65);
+        // 1サイクルで変わるのはCPUの一部だけ
+        let stats = netlist.netlist_stats().unwrap();
+        assert!(stats.passes >= 2 * cycles);
+        assert!(stats.evaluations * 4 < stats.full_evaluations, "{}", stats);
+
+        // レジスタやresetを直接書き換えてもnetlistに伝わる
+        for c in [&gate, &netlist] {
+            c.poke(0, 3).unwrap();
+            c.reset().unwrap();
+            c.set_d_register_value(1).unwrap();
+        }
+        assert_eq!(state(&netlist), state(&gate));
+        assert_eq!(netlist.get_pc(), 0);
+        assert_eq!(
+            netlist.run_until_halt().unwrap(),
+            gate.run_until_halt().unwrap()
+        );
+        assert_eq!(netlist.peek(2), 21);
+    }
+
+    #[test]
+    #[ignore]
+    fn settle_cost() {
+        // tick/tockごとのsettleで何回re_computeしているかと、かかる時間を比べる
+        // cargo test --release settle_cost -- --ignored --nocapture
+        let source = "(LOOP)\n@R0\nM=M+1\nD=M\n@R1\nM=D+M\n@LOOP\n0;JMP\n";
+        let words = crate::assembler::assemble(source).unwrap();
+        let computer = || {
+            let rom = ROM32KBuiltIn::from_words(&words, Bus::all0().to_shared_bus());
+            Computer::new(Bus::all0().to_shared_bus(), rom)
+        };
+        let cycles = 20000;
+        // 一回のre_computeで済ませたときの時間 (メモリの値が戻ってこないので結果は正しくない)
+        let once = computer();
+        let start = Instant::now();
+        for _ in 0..cycles {
+            once.re_compute();
+            once.clock_up();
+            once.clock_down();
+            once.re_compute();
+        }
+        let once_time = start.elapsed();
+
+        let gate = computer();
+        let mut passes = 0;
+        let start = Instant::now();
+        for _ in 0..cycles {
+            passes += gate.settle().unwrap();
+            gate.clock_up();
+            gate.clock_down();
+            passes += gate.settle().unwrap();
+        }
+        let gate_time = start.elapsed();
+
+        let mut netlist = computer();
+        netlist.use_netlist().unwrap();
+        let start = Instant::now();
+        netlist.run(cycles).unwrap();
+        let netlist_time = start.elapsed();
+
+        println!(
+            "{} cycles: re_compute once {:?}, settle {:?} ({:.2} passes/cycle), netlist {:?} ({})",
+            cycles,
+            once_time,
+            gate_time,
+            passes as f64 / cycles as f64,
+            netlist_time,
+            netlist.netlist_stats().unwrap()
+        );
+        assert_eq!(gate.peek(0), netlist.peek(0));
+        assert_eq!(gate.peek(1), netlist.peek(1));
+        // tickは前のtockで落ち着いているので確かめる1回、tockは計算、メモリの値を戻す、確かめるの3回
+        // 一回で済ませるより増えるのは半分ほど
+        assert!(passes <= 4 * cycles, "{} passes", passes);
+        assert!(gate_time < once_time * 2, "{:?}", gate_time);
+        // netlistは変わったNandだけを計算する
+        let stats = netlist.netlist_stats().unwrap();
+        assert!(stats.evaluations * 4 < stats.full_evaluations, "{}", stats);
+    }
+
     // TODO generic
     fn i16_to_bus1(x: i16) -> Bus<1> {
         let s = format!("{x:01b}");
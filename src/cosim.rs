@@ -0,0 +1,264 @@
+use std::fmt;
+
+use crate::{
+    computer::Computer,
+    disassembler::disassemble,
+    emulator::{Emulator, Machine, NativeComputer},
+    gate::{Gate, Oscillation},
+};
+
+/// 1サイクル分のCPUの様子
+/// write_m/address_m/out_m はサイクルの前、それ以外はサイクルの後の値
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CpuState {
+    pub a: u16,
+    pub d: u16,
+    pub pc: u16,
+    pub write_m: bool,
+    pub address_m: u16,
+    pub out_m: u16,
+}
+
+/// ゲートとEmulatorが最初に食い違ったサイクル
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    /// 何サイクル目で食い違ったか (1から数える)
+    pub cycle: usize,
+    /// 食い違った命令の番地と中身
+    pub pc: u16,
+    pub instruction: u16,
+    pub gate: CpuState,
+    pub native: CpuState,
+    /// 食い違ったRAMの (番地, ゲートの値, Emulatorの値)
+    pub ram: Vec<(u16, u16, u16)>,
+}
+
+impl Divergence {
+    /// 食い違った項目の名前
+    pub fn fields(&self) -> Vec<String> {
+        let (g, n) = (&self.gate, &self.native);
+        let mut fields = vec![];
+        for (name, diverged) in [
+            ("A", g.a != n.a),
+            ("D", g.d != n.d),
+            ("PC", g.pc != n.pc),
+            ("writeM", g.write_m != n.write_m),
+            ("addressM", g.address_m != n.address_m),
+            ("outM", (g.write_m || n.write_m) && g.out_m != n.out_m),
+        ] {
+            if diverged {
+                fields.push(name.to_string());
+            }
+        }
+        for (address, _, _) in self.ram.iter() {
+            fields.push(format!("RAM[{}]", address));
+        }
+        fields
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "diverged at cycle {}: PC={} {:016b} {}",
+            self.cycle,
+            self.pc,
+            self.instruction,
+            disassemble(self.instruction)
+        )?;
+        write!(f, "{:>10} {:>6} {:>6}", "", "gate", "native")?;
+        let (g, n) = (&self.gate, &self.native);
+        let rows = [
+            ("A".to_string(), g.a, n.a),
+            ("D".to_string(), g.d, n.d),
+            ("PC".to_string(), g.pc, n.pc),
+            ("writeM".to_string(), g.write_m as u16, n.write_m as u16),
+            ("addressM".to_string(), g.address_m, n.address_m),
+            ("outM".to_string(), g.out_m, n.out_m),
+        ];
+        let ram = self
+            .ram
+            .iter()
+            .map(|(address, g, n)| (format!("RAM[{}]", address), *g, *n));
+        let fields = self.fields();
+        for (name, g, n) in rows.into_iter().chain(ram) {
+            let mark = match fields.contains(&name) {
+                true => " <-",
+                false => "",
+            };
+            write!(f, "\n{:>10} {:>6} {:>6}{}", name, g, n, mark)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+/// Lockstepが止まった理由
+#[derive(Debug)]
+pub enum LockstepError {
+    Diverged(Box<Divergence>),
+    /// ゲートの組み合わせ回路が落ち着かなかった
+    Oscillation(Oscillation),
+}
+
+impl fmt::Display for LockstepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockstepError::Diverged(divergence) => write!(f, "{}", divergence),
+            LockstepError::Oscillation(oscillation) => write!(f, "{}", oscillation),
+        }
+    }
+}
+
+impl std::error::Error for LockstepError {}
+
+/// ゲートのComputerとEmulatorで動かすNativeComputerを1サイクルずつ並べて進める
+/// A/D/PC、writeM/addressM/outM、書き込んだRAMを毎サイクル比べる
+#[derive(Debug)]
+pub struct Lockstep {
+    gate: Computer,
+    native: NativeComputer,
+    cycle: usize,
+}
+
+impl Lockstep {
+    /// 同じプログラムと状態のComputerとNativeComputerを渡す
+    pub fn new(gate: Computer, native: NativeComputer) -> Lockstep {
+        Lockstep {
+            gate,
+            native,
+            cycle: 0,
+        }
+    }
+
+    pub fn gate(&self) -> &Computer {
+        &self.gate
+    }
+
+    pub fn native(&self) -> &NativeComputer {
+        &self.native
+    }
+
+    pub fn cycle(&self) -> usize {
+        self.cycle
+    }
+
+    pub fn step(&mut self) -> Result<(), LockstepError> {
+        let pc = self.gate.get_pc();
+        let instruction = self.gate.get_instruction(pc);
+
+        self.gate.settle().map_err(LockstepError::Oscillation)?;
+        let gate_outputs = (
+            self.gate.cpu.write_m.to_u16() != 0,
+            self.gate.cpu.address_m.to_u16(),
+            self.gate.cpu.out_m.to_u16(),
+        );
+        let emulator = Emulator::new(&self.native);
+        let native_outputs = emulator.outputs();
+        self.gate.tick().map_err(LockstepError::Oscillation)?;
+        self.gate.tock().map_err(LockstepError::Oscillation)?;
+        emulator.step();
+        self.cycle += 1;
+
+        let state = |(a, d, pc), (write_m, address_m, out_m)| CpuState {
+            a,
+            d,
+            pc,
+            write_m,
+            address_m,
+            out_m,
+        };
+        let gate = state(self.gate.registers(), gate_outputs);
+        let native = state(self.native.registers(), native_outputs);
+        // 書き込んだ番地だけ比べる
+        let mut ram = vec![];
+        for state in [&gate, &native] {
+            let address = state.address_m;
+            let (g, n) = (self.gate.peek(address), self.native.peek(address));
+            if state.write_m && g != n && !ram.iter().any(|(a, _, _)| *a == address) {
+                ram.push((address, g, n));
+            }
+        }
+
+        let divergence = Divergence {
+            cycle: self.cycle,
+            pc,
+            instruction,
+            gate,
+            native,
+            ram,
+        };
+        match divergence.fields().is_empty() {
+            true => Ok(()),
+            false => Err(LockstepError::Diverged(Box::new(divergence))),
+        }
+    }
+
+    pub fn run(&mut self, max_cycles: usize) -> Result<(), LockstepError> {
+        for _ in 0..max_cycles {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// ゲートのほうが停止ループに入るまで進めて、実行したサイクル数を返す
+    pub fn run_until_halt(&mut self) -> Result<usize, LockstepError> {
+        let start = self.cycle;
+        while !self.gate.is_halted() {
+            self.step()?;
+        }
+        Ok(self.cycle - start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, computer::ROM32KBuiltIn, gate::*};
+
+    fn lockstep(source: &str) -> Lockstep {
+        let words = assemble(source).unwrap();
+        let rom = ROM32KBuiltIn::from_words(&words, Bus::all0().to_shared_bus());
+        let gate = Computer::new(Bus::all0().to_shared_bus(), rom);
+        let native = NativeComputer::new(gate.rom().program());
+        Lockstep::new(gate, native)
+    }
+
+    #[test]
+    fn no_divergence() {
+        let source = "@R0\nD=M\n@R1\nD=D-M\n@OUTPUT_FIRST\nD;JGT\n@R1\nD=M\n@OUTPUT_D\n0;JMP
+(OUTPUT_FIRST)\n@R0\nD=M\n(OUTPUT_D)\n@R2\nM=D\n@SCREEN\nAM=D+1\nM=!M\n(END)\n@END\n0;JMP\n";
+        let mut lockstep = lockstep(source);
+        lockstep.gate().poke(0, 12).unwrap();
+        lockstep.native().poke(0, 12);
+        lockstep.gate().poke(1, 34).unwrap();
+        lockstep.native().poke(1, 34);
+        let cycles = lockstep.run_until_halt().unwrap();
+        assert_eq!(cycles, lockstep.cycle());
+        assert_eq!(lockstep.native().peek(2), 34);
+        assert_eq!(lockstep.native().peek(35), 0xffff);
+        assert!(lockstep.run(10).is_ok());
+    }
+
+    #[test]
+    fn report_first_divergence() {
+        let mut lockstep = lockstep("@7\nD=A\n@100\nD=D+M\n@101\nM=D\n");
+        // ゲート側のRAMだけ書き換えておく
+        lockstep.gate().poke(100, 5).unwrap();
+        let LockstepError::Diverged(divergence) = lockstep.run(10).unwrap_err() else {
+            panic!("no divergence");
+        };
+        assert_eq!(lockstep.cycle(), 4);
+        assert_eq!(divergence.cycle, 4);
+        assert_eq!(divergence.pc, 3);
+        assert_eq!(disassemble(divergence.instruction), "D=D+M");
+        assert_eq!(divergence.fields(), vec!["D"]);
+        assert_eq!((divergence.gate.d, divergence.native.d), (12, 7));
+        let message = divergence.to_string();
+        assert!(message.starts_with("diverged at cycle 4: PC=3 1111000010010000 D=D+M\n"));
+        assert!(message.contains("\n         D     12      7 <-\n"));
+        assert!(message.contains("\n         A    100    100\n"));
+    }
+}
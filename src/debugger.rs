@@ -0,0 +1,559 @@
+use std::{
+    collections::BTreeSet,
+    fmt,
+    io::{self, BufRead, Write},
+    ops::Range,
+};
+
+use crate::{
+    cli::parse_range, computer::Computer, disassembler::disassemble, emulator::Engine,
+    gate::Oscillation,
+};
+
+pub const HELP: &str = "commands:
+  s, step [N]          N サイクル進める (デフォルト 1)。空行も step
+  c, continue          ブレークポイント・ウォッチポイントか停止ループまで実行する
+  b, break ADDR        PC が ADDR になったら止める
+  d, delete ADDR       ブレークポイントを消す
+  w, watch TARGET      TARGET (RAM のアドレス, A, D) の値が変わったら止める
+  unwatch TARGET       ウォッチポイントを消す
+  i, info              ブレークポイントとウォッチポイントの一覧
+  r, regs              A/D/PC と M(RAM[A]) を表示する
+  x, mem START..END    RAM[START..END] を表示する (x ADDR で1ワード)
+  l, list [N]          PC の周りの命令を N 個表示する (デフォルト 8)
+  reset                PC を 0 に戻す
+  h, help              このヘルプ
+  q, quit              終了";
+
+const DEFAULT_LIST: u16 = 8;
+
+/// ウォッチポイントの対象
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watch {
+    Ram(u16),
+    A,
+    D,
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Watch::Ram(address) => write!(f, "RAM[{}]", address),
+            Watch::A => write!(f, "A"),
+            Watch::D => write!(f, "D"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DebugCommand {
+    Step(usize),
+    Continue,
+    Break(u16),
+    Delete(u16),
+    Watch(Watch),
+    Unwatch(Watch),
+    Info,
+    Registers,
+    Memory(Range<u16>),
+    List(u16),
+    Reset,
+    Help,
+    Quit,
+}
+
+/// 1行を解釈する。空行は1ステップ
+pub fn parse_command(line: &str) -> Result<DebugCommand, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return Ok(DebugCommand::Step(1)),
+    };
+
+    let arg = |i: usize| {
+        args.get(i)
+            .copied()
+            .ok_or(format!("`{}` requires an argument", name))
+    };
+    let number = |s: &str| {
+        s.parse::<usize>()
+            .map_err(|_| format!("invalid number `{}`", s))
+    };
+    let address = |s: &str| {
+        s.parse::<u16>()
+            .ok()
+            .filter(|address| *address <= 32767)
+            .ok_or(format!("invalid address `{}`", s))
+    };
+
+    let command = match name {
+        "s" | "step" => DebugCommand::Step(match args.first() {
+            Some(n) => number(n)?,
+            None => 1,
+        }),
+        "c" | "continue" => DebugCommand::Continue,
+        "b" | "break" => DebugCommand::Break(address(arg(0)?)?),
+        "d" | "delete" => DebugCommand::Delete(address(arg(0)?)?),
+        "w" | "watch" => DebugCommand::Watch(parse_watch(arg(0)?)?),
+        "unwatch" => DebugCommand::Unwatch(parse_watch(arg(0)?)?),
+        "i" | "info" => DebugCommand::Info,
+        "r" | "regs" => DebugCommand::Registers,
+        "x" | "mem" => DebugCommand::Memory(parse_range(arg(0)?)?),
+        // ROMより多く表示することはないので、u16に収まらない数はエラーにする
+        "l" | "list" => DebugCommand::List(match args.first() {
+            Some(n) => n
+                .parse::<u16>()
+                .map_err(|_| format!("invalid line count `{}`", n))?,
+            None => DEFAULT_LIST,
+        }),
+        "reset" => DebugCommand::Reset,
+        "h" | "help" => DebugCommand::Help,
+        "q" | "quit" => DebugCommand::Quit,
+        _ => return Err(format!("unknown command `{}` (h でヘルプ)", name)),
+    };
+    Ok(command)
+}
+
+// "A", "D", "RAM[16]", "16"
+fn parse_watch(s: &str) -> Result<Watch, String> {
+    match s {
+        "A" => return Ok(Watch::A),
+        "D" => return Ok(Watch::D),
+        _ => {}
+    }
+    let address = s
+        .strip_prefix("RAM[")
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(s);
+    address
+        .parse::<u16>()
+        .ok()
+        .filter(|address| *address <= 24576)
+        .map(Watch::Ram)
+        .ok_or(format!("invalid watch target `{}`", s))
+}
+
+/// 実行が止まった理由
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    /// 指定されたサイクル数を実行し終えた
+    Done,
+    Breakpoint(u16),
+    Watchpoint {
+        watch: Watch,
+        old: u16,
+        new: u16,
+    },
+    /// (END) @END 0;JMP のループに入った
+    Halted,
+    /// 組み合わせ回路が落ち着かなかった
+    Oscillation(String),
+}
+
+#[derive(Debug)]
+pub struct Debugger {
+    computer: Computer,
+    cycle: usize,
+    breakpoints: BTreeSet<u16>,
+    // 対象と最後に見た値
+    watchpoints: Vec<(Watch, u16)>,
+    engine: Engine,
+}
+
+impl Debugger {
+    pub fn new(computer: Computer) -> Debugger {
+        Debugger {
+            computer,
+            cycle: 0,
+            breakpoints: BTreeSet::new(),
+            watchpoints: vec![],
+            engine: Engine::Gate,
+        }
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) -> () {
+        self.engine = engine;
+    }
+
+    pub fn computer(&self) -> &Computer {
+        &self.computer
+    }
+
+    pub fn cycle(&self) -> usize {
+        self.cycle
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) -> () {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn add_watchpoint(&mut self, watch: Watch) -> () {
+        if self.watchpoints.iter().all(|(w, _)| *w != watch) {
+            let value = self.read(watch);
+            self.watchpoints.push((watch, value));
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watch: Watch) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|(w, _)| *w != watch);
+        self.watchpoints.len() != len
+    }
+
+    /// n サイクル進める。途中でブレークポイントかウォッチポイントに当たったらそこで止める
+    pub fn step(&mut self, n: usize) -> Stop {
+        for _ in 0..n {
+            if let Some(stop) = self.cycle_once() {
+                return stop;
+            }
+        }
+        Stop::Done
+    }
+
+    /// ブレークポイントかウォッチポイントに当たるか、停止ループに入るまで実行する
+    pub fn resume(&mut self) -> Stop {
+        loop {
+            if let Some(stop) = self.cycle_once() {
+                return stop;
+            }
+            if self.computer.is_halted() {
+                return Stop::Halted;
+            }
+        }
+    }
+
+    pub fn reset(&mut self) -> Result<(), Oscillation> {
+        self.computer.reset()?;
+        self.cycle += 1;
+        self.refresh_watchpoints();
+        Ok(())
+    }
+
+    fn cycle_once(&mut self) -> Option<Stop> {
+        if let Err(oscillation) = self.engine.step(&self.computer) {
+            return Some(Stop::Oscillation(oscillation.to_string()));
+        }
+        self.cycle += 1;
+
+        let changed = self
+            .watchpoints
+            .iter()
+            .map(|(watch, old)| (*watch, *old, self.read(*watch)))
+            .find(|(_, old, new)| old != new);
+        if let Some((watch, old, new)) = changed {
+            self.refresh_watchpoints();
+            return Some(Stop::Watchpoint { watch, old, new });
+        }
+
+        let pc = self.computer.get_pc();
+        if self.breakpoints.contains(&pc) {
+            return Some(Stop::Breakpoint(pc));
+        }
+        None
+    }
+
+    fn refresh_watchpoints(&mut self) -> () {
+        for i in 0..self.watchpoints.len() {
+            self.watchpoints[i].1 = self.read(self.watchpoints[i].0);
+        }
+    }
+
+    fn read(&self, watch: Watch) -> u16 {
+        match watch {
+            Watch::Ram(address) => self.computer.peek(address),
+            Watch::A => self.computer.get_a_register_value(),
+            Watch::D => self.computer.get_d_register_value(),
+        }
+    }
+
+    /// コマンドを1つ実行する。quitならfalseを返す
+    pub fn execute<W: Write>(&mut self, command: DebugCommand, out: &mut W) -> io::Result<bool> {
+        match command {
+            DebugCommand::Step(n) => {
+                let stop = self.step(n);
+                self.print_stop(&stop, out)?;
+            }
+            DebugCommand::Continue => {
+                let stop = self.resume();
+                self.print_stop(&stop, out)?;
+            }
+            DebugCommand::Break(address) => {
+                self.add_breakpoint(address);
+                writeln!(
+                    out,
+                    "breakpoint at {}: {}",
+                    address,
+                    self.disassemble_at(address)
+                )?;
+            }
+            DebugCommand::Delete(address) => {
+                if !self.remove_breakpoint(address) {
+                    writeln!(out, "no breakpoint at {}", address)?;
+                }
+            }
+            DebugCommand::Watch(watch) => {
+                self.add_watchpoint(watch);
+                writeln!(out, "watching {} = {}", watch, self.read(watch) as i16)?;
+            }
+            DebugCommand::Unwatch(watch) => {
+                if !self.remove_watchpoint(watch) {
+                    writeln!(out, "not watching {}", watch)?;
+                }
+            }
+            DebugCommand::Info => {
+                for address in self.breakpoints.iter() {
+                    writeln!(out, "break {}: {}", address, self.disassemble_at(*address))?;
+                }
+                for (watch, value) in self.watchpoints.iter() {
+                    writeln!(out, "watch {} = {}", watch, *value as i16)?;
+                }
+            }
+            DebugCommand::Registers => {
+                let a = self.computer.get_a_register_value();
+                writeln!(
+                    out,
+                    "A: {}, D: {}, PC: {}, M: {}",
+                    a as i16,
+                    self.computer.get_d_register_value() as i16,
+                    self.computer.get_pc(),
+                    self.computer.peek(a) as i16,
+                )?;
+            }
+            DebugCommand::Memory(range) => {
+                for address in range {
+                    writeln!(
+                        out,
+                        "RAM[{}]: {}",
+                        address,
+                        self.computer.peek(address) as i16
+                    )?;
+                }
+            }
+            DebugCommand::List(n) => {
+                let pc = self.computer.get_pc();
+                let start = pc.saturating_sub(2);
+                for address in start..start.saturating_add(n).min(32768) {
+                    let marker = if address == pc { "=>" } else { "  " };
+                    let breakpoint = if self.breakpoints.contains(&address) {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    writeln!(
+                        out,
+                        "{}{} {:5}: {}",
+                        marker,
+                        breakpoint,
+                        address,
+                        self.disassemble_at(address)
+                    )?;
+                }
+            }
+            DebugCommand::Reset => {
+                if let Err(oscillation) = self.reset() {
+                    writeln!(out, "{}", oscillation)?;
+                }
+                self.print_status(out)?;
+            }
+            DebugCommand::Help => writeln!(out, "{}", HELP)?,
+            DebugCommand::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// 入力から1行ずつコマンドを読んで実行する
+    pub fn run_repl<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> io::Result<()> {
+        self.print_status(out)?;
+        let mut lines = input.lines();
+        loop {
+            write!(out, "(debug) ")?;
+            out.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+            match parse_command(&line) {
+                Ok(command) => {
+                    if !self.execute(command, out)? {
+                        break;
+                    }
+                }
+                Err(e) => writeln!(out, "{}", e)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn print_stop<W: Write>(&self, stop: &Stop, out: &mut W) -> io::Result<()> {
+        match stop {
+            Stop::Done => {}
+            Stop::Breakpoint(address) => writeln!(out, "breakpoint at {}", address)?,
+            Stop::Watchpoint { watch, old, new } => writeln!(
+                out,
+                "watchpoint {} changed: {} -> {}",
+                watch, *old as i16, *new as i16
+            )?,
+            Stop::Halted => writeln!(out, "halted")?,
+            Stop::Oscillation(message) => writeln!(out, "{}", message)?,
+        }
+        self.print_status(out)
+    }
+
+    // 状態と、次に実行する命令
+    fn print_status<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let pc = self.computer.get_pc();
+        writeln!(
+            out,
+            "cycle: {}, A: {}, D: {}, PC: {} | {}",
+            self.cycle,
+            self.computer.get_a_register_value() as i16,
+            self.computer.get_d_register_value() as i16,
+            pc,
+            self.disassemble_at(pc)
+        )
+    }
+
+    fn disassemble_at(&self, address: u16) -> String {
+        disassemble(self.computer.get_instruction(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble_to_rom, gate::*};
+
+    // RAM[1] = RAM[0] * 3 を足し算の繰り返しで計算する
+    const PROGRAM: &str = "
+        @1
+        M=0
+        @3
+        D=A
+        @2
+        M=D
+    (LOOP)
+        @0
+        D=M
+        @1
+        M=D+M
+        @2
+        MD=M-1
+        @LOOP
+        D;JGT
+    (END)
+        @END
+        0;JMP
+    ";
+
+    fn debugger() -> Debugger {
+        let address = Bus::<15>::all0().to_shared_bus();
+        let rom = assemble_to_rom(PROGRAM, address).unwrap();
+        let reset = Bus::<1>::all0().to_shared_bus();
+        let computer = Computer::new(reset, rom);
+        computer.poke(0, 7).unwrap();
+        Debugger::new(computer)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_command(""), Ok(DebugCommand::Step(1)));
+        assert_eq!(parse_command("s 10"), Ok(DebugCommand::Step(10)));
+        assert_eq!(parse_command("continue"), Ok(DebugCommand::Continue));
+        assert_eq!(parse_command("b 6"), Ok(DebugCommand::Break(6)));
+        assert_eq!(parse_command("delete 6"), Ok(DebugCommand::Delete(6)));
+        assert_eq!(
+            parse_command("watch RAM[16]"),
+            Ok(DebugCommand::Watch(Watch::Ram(16)))
+        );
+        assert_eq!(
+            parse_command("w 24576"),
+            Ok(DebugCommand::Watch(Watch::Ram(24576)))
+        );
+        assert_eq!(
+            parse_command("unwatch D"),
+            Ok(DebugCommand::Unwatch(Watch::D))
+        );
+        assert_eq!(parse_command("x 0..4"), Ok(DebugCommand::Memory(0..4)));
+        assert_eq!(parse_command("x 5"), Ok(DebugCommand::Memory(5..6)));
+        assert_eq!(parse_command("l"), Ok(DebugCommand::List(DEFAULT_LIST)));
+        assert_eq!(parse_command("q"), Ok(DebugCommand::Quit));
+
+        assert!(parse_command("b").is_err());
+        assert!(parse_command("b 32768").is_err());
+        assert!(parse_command("w M").is_err());
+        assert!(parse_command("s x").is_err());
+        assert_eq!(parse_command("l 65535"), Ok(DebugCommand::List(65535)));
+        assert!(parse_command("l 70000").is_err());
+        assert!(parse_command("jump 3").is_err());
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let mut debugger = debugger();
+
+        // LOOPの先頭は6
+        debugger.add_breakpoint(6);
+        assert_eq!(debugger.resume(), Stop::Breakpoint(6));
+        assert_eq!(debugger.cycle(), 6);
+        assert_eq!(debugger.resume(), Stop::Breakpoint(6));
+        assert_eq!(debugger.computer().peek(1), 7);
+
+        assert!(debugger.remove_breakpoint(6));
+        assert!(!debugger.remove_breakpoint(6));
+        debugger.add_watchpoint(Watch::Ram(1));
+        assert_eq!(
+            debugger.resume(),
+            Stop::Watchpoint {
+                watch: Watch::Ram(1),
+                old: 7,
+                new: 14,
+            }
+        );
+        assert!(debugger.remove_watchpoint(Watch::Ram(1)));
+
+        assert_eq!(debugger.step(3), Stop::Done);
+        assert_eq!(debugger.resume(), Stop::Halted);
+        assert_eq!(debugger.computer().peek(1), 21);
+
+        debugger.reset().unwrap();
+        assert_eq!(debugger.computer().get_pc(), 0);
+        debugger.add_watchpoint(Watch::D);
+        assert_eq!(
+            debugger.step(10),
+            Stop::Watchpoint {
+                watch: Watch::D,
+                old: 0,
+                new: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn repl() {
+        let mut debugger = debugger();
+        let input = "b 6\nc\nr\nl 3\nx 0..2\ni\nwat\nq\ns\n";
+        let mut out = vec![];
+        debugger.run_repl(input.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let expected = [
+            "cycle: 0, A: 0, D: 0, PC: 0 | @1",
+            "(debug) breakpoint at 6: @0",
+            "(debug) breakpoint at 6",
+            "cycle: 6, A: 2, D: 3, PC: 6 | @0",
+            "(debug) A: 2, D: 3, PC: 6, M: 3",
+            "(debug)         4: @2",
+            "        5: M=D",
+            "=>*     6: @0",
+            "(debug) RAM[0]: 7",
+            "RAM[1]: 0",
+            "(debug) break 6: @0",
+            "(debug) unknown command `wat` (h でヘルプ)",
+            "(debug) ",
+        ];
+        assert_eq!(out, expected.join("\n"));
+    }
+}
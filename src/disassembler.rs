@@ -0,0 +1,128 @@
+use std::fmt;
+
+use crate::assembler::{COMP_TABLE, JUMP_TABLE};
+
+const DEST_NAMES: [&str; 8] = ["", "M", "D", "MD", "A", "AM", "AD", "AMD"];
+
+/// アセンブリに戻せない命令
+#[derive(Debug, PartialEq)]
+pub enum DisassembleError {
+    /// C命令のcomp(a c1..c6の7bit)がどの計算にも当たらない
+    InvalidComp(u16),
+}
+
+impl fmt::Display for DisassembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisassembleError::InvalidComp(bits) => write!(f, "invalid comp {:07b}", bits),
+        }
+    }
+}
+
+impl std::error::Error for DisassembleError {}
+
+/// 1命令をアセンブリの表記に戻す
+pub fn try_disassemble(word: u16) -> Result<String, DisassembleError> {
+    if word & 0x8000 == 0 {
+        return Ok(format!("@{}", word));
+    }
+
+    let comp_bits = (word >> 6) & 0b111_1111;
+    let dest = DEST_NAMES[((word >> 3) & 0b111) as usize];
+    let jump = JUMP_TABLE[(word & 0b111) as usize].0;
+    let comp = COMP_TABLE
+        .iter()
+        .find(|(_, bits)| *bits == comp_bits)
+        .map(|(name, _)| *name)
+        .ok_or(DisassembleError::InvalidComp(comp_bits))?;
+    Ok(format_c_instruction(dest, comp, jump))
+}
+
+/// try_disassembleと同じだが、不正なcompは ??? にしてコメントで理由を付ける
+pub fn disassemble(word: u16) -> String {
+    match try_disassemble(word) {
+        Ok(s) => s,
+        Err(e) => {
+            let dest = DEST_NAMES[((word >> 3) & 0b111) as usize];
+            let jump = JUMP_TABLE[(word & 0b111) as usize].0;
+            format!("{} // {}", format_c_instruction(dest, "???", jump), e)
+        }
+    }
+}
+
+/// アドレスと機械語を付けた一覧。
+/// プログラムの長さが分からずROMを丸ごと渡したときだけ、末尾に続く0(@0)は空きとみなして省く
+pub fn disassemble_program(words: &[u16]) -> String {
+    let len = match words.len() {
+        32768 => words.iter().rposition(|w| *w != 0).map_or(0, |i| i + 1),
+        len => len,
+    };
+    words[..len]
+        .iter()
+        .enumerate()
+        .map(|(address, word)| format!("{:5}: {:016b}  {}\n", address, word, disassemble(*word)))
+        .collect()
+}
+
+fn format_c_instruction(dest: &str, comp: &str, jump: &str) -> String {
+    let mut s = String::new();
+    if !dest.is_empty() {
+        s.push_str(dest);
+        s.push('=');
+    }
+    s.push_str(comp);
+    if !jump.is_empty() {
+        s.push(';');
+        s.push_str(jump);
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn round_trip() {
+        let source = "@0\n@32767\nD=M\nAM=M+1\nD;JGT\nAMD=D|A;JMP\n0;JMP\nM=-1\n";
+        let words = assemble(source).unwrap();
+        let lines: Vec<String> = words.iter().map(|w| disassemble(*w)).collect();
+        assert_eq!(lines, source.lines().collect::<Vec<_>>());
+
+        // COMP_TABLEの全部
+        for (comp, _) in COMP_TABLE.iter() {
+            let word = assemble(&format!("D={};JLE", comp)).unwrap()[0];
+            assert_eq!(try_disassemble(word), Ok(format!("D={};JLE", comp)));
+        }
+    }
+
+    #[test]
+    fn invalid_comp() {
+        // comp = 0101011
+        let word = 0b1110_1010_1101_0001;
+        assert_eq!(
+            try_disassemble(word),
+            Err(DisassembleError::InvalidComp(0b0101011))
+        );
+        assert_eq!(disassemble(word), "D=???;JGT // invalid comp 0101011");
+        // a=1の"1"はない
+        assert!(try_disassemble(0b1111_1111_1100_0000).is_err());
+    }
+
+    #[test]
+    fn program() {
+        let words = assemble("@2\nD=A\n@0\n").unwrap();
+        let listing = "    0: 0000000000000010  @2\n    1: 1110110000010000  D=A\n";
+        // 末尾の@0も命令なので省かない
+        assert_eq!(
+            disassemble_program(&words),
+            format!("{}    2: 0000000000000000  @0\n", listing)
+        );
+        // ROMを丸ごと渡したときは末尾の0を省く
+        let mut rom = vec![0; 32768];
+        rom[..3].copy_from_slice(&words);
+        assert_eq!(disassemble_program(&rom), listing);
+        assert_eq!(disassemble_program(&[0; 32768]), "");
+    }
+}
@@ -0,0 +1,152 @@
+use std::io::{self, Write};
+
+pub const SCREEN_WIDTH: usize = 512;
+pub const SCREEN_HEIGHT: usize = 256;
+/// 1行あたりのワード数
+pub const WORDS_PER_ROW: usize = SCREEN_WIDTH / 16;
+
+/// (x, y) のピクセルが黒か。1行は32ワードで、各ワードの最下位bitが左端
+pub fn pixel(frame: &[u16], x: usize, y: usize) -> bool {
+    let word = frame[y * WORDS_PER_ROW + x / 16];
+    (word >> (x % 16)) & 1 == 1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    /// 点字で1文字に2x4ドット
+    Braille,
+    /// ▀▄█で1文字に1x2ドット
+    HalfBlock,
+}
+
+/// Screenのフレームバッファを端末に描画する
+#[derive(Debug)]
+pub struct Renderer {
+    style: Style,
+    // 1ドットで scale x scale ピクセルを表す。どれかが黒ならドットも黒
+    scale: usize,
+}
+
+impl Renderer {
+    pub fn new(style: Style, scale: usize) -> Renderer {
+        Renderer {
+            style,
+            scale: scale.max(1),
+        }
+    }
+
+    pub fn render(&self, frame: &[u16]) -> String {
+        let (cell_width, cell_height) = match self.style {
+            Style::Braille => (2, 4),
+            Style::HalfBlock => (1, 2),
+        };
+        let columns = SCREEN_WIDTH.div_ceil(self.scale * cell_width);
+        let rows = SCREEN_HEIGHT.div_ceil(self.scale * cell_height);
+
+        let mut s = String::with_capacity((columns * 3 + 1) * rows);
+        for row in 0..rows {
+            for column in 0..columns {
+                let dot = |dx: usize, dy: usize| {
+                    self.dot(column * cell_width + dx, row * cell_height + dy, frame)
+                };
+                let c = match self.style {
+                    Style::Braille => {
+                        // 点字のドット番号は左列が上から1,2,3,7、右列が4,5,6,8
+                        const BITS: [[u32; 4]; 2] =
+                            [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+                        let mut code = 0;
+                        for (dx, column_bits) in BITS.iter().enumerate() {
+                            for (dy, bit) in column_bits.iter().enumerate() {
+                                if dot(dx, dy) {
+                                    code |= bit;
+                                }
+                            }
+                        }
+                        char::from_u32(0x2800 + code).unwrap()
+                    }
+                    Style::HalfBlock => match (dot(0, 0), dot(0, 1)) {
+                        (false, false) => ' ',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (true, true) => '█',
+                    },
+                };
+                s.push(c);
+            }
+            s.push('\n');
+        }
+        s
+    }
+
+    /// カーソルを左上に戻してから上書きで描画する
+    pub fn draw<W: Write>(&self, out: &mut W, frame: &[u16]) -> io::Result<()> {
+        write!(out, "\x1b[H{}", self.render(frame))?;
+        out.flush()
+    }
+
+    fn dot(&self, dot_x: usize, dot_y: usize, frame: &[u16]) -> bool {
+        let x0 = dot_x * self.scale;
+        let y0 = dot_y * self.scale;
+        (y0..(y0 + self.scale).min(SCREEN_HEIGHT))
+            .any(|y| (x0..(x0 + self.scale).min(SCREEN_WIDTH)).any(|x| pixel(frame, x, y)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_with(pixels: &[(usize, usize)]) -> Vec<u16> {
+        let mut frame = vec![0; 8192];
+        for (x, y) in pixels {
+            frame[y * WORDS_PER_ROW + x / 16] |= 1 << (x % 16);
+        }
+        frame
+    }
+
+    #[test]
+    fn pixel_address() {
+        let mut frame = vec![0; 8192];
+        // 左上
+        frame[0] = 0b1;
+        // 1行目の右端
+        frame[31] = 0b1000_0000_0000_0000;
+        // 2行目の17ピクセル目
+        frame[33] = 0b10;
+        // 右下
+        frame[8191] = 0b1000_0000_0000_0000;
+
+        assert!(pixel(&frame, 0, 0));
+        assert!(!pixel(&frame, 1, 0));
+        assert!(pixel(&frame, 511, 0));
+        assert!(pixel(&frame, 17, 1));
+        assert!(!pixel(&frame, 16, 1));
+        assert!(pixel(&frame, 511, 255));
+    }
+
+    #[test]
+    fn render_braille() {
+        let renderer = Renderer::new(Style::Braille, 1);
+        let blank = renderer.render(&vec![0; 8192]);
+        let lines: Vec<&str> = blank.lines().collect();
+        assert_eq!(lines.len(), 64);
+        assert!(lines.iter().all(|l| l.chars().count() == 256));
+        assert!(lines.iter().all(|l| l.chars().all(|c| c == '\u{2800}')));
+
+        // 1文字目に左上と右下のドット
+        let frame = frame_with(&[(0, 0), (1, 3)]);
+        let rendered = renderer.render(&frame);
+        assert!(rendered.starts_with('\u{2881}'));
+    }
+
+    #[test]
+    fn render_half_block() {
+        let renderer = Renderer::new(Style::HalfBlock, 4);
+        let frame = frame_with(&[(0, 0), (4, 7), (8, 3), (8, 4)]);
+        let rendered = renderer.render(&frame);
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 32);
+        assert_eq!(lines[0].chars().count(), 128);
+        assert!(lines[0].starts_with("▀▄█ "));
+    }
+}
@@ -0,0 +1,395 @@
+use std::cell::{Cell, RefCell};
+
+use crate::{
+    computer::{is_halt_loop, Computer},
+    gate::Oscillation,
+};
+
+/// Keyboardの番地。これより上はどこを読んでもKeyboardになり、書き込みは無視される
+const KEYBOARD: u16 = 0x6000;
+
+/// Emulatorが読み書きするROM/RAMとA/D/PC
+pub trait Machine {
+    fn with_rom<R>(&self, f: impl FnOnce(&[u16]) -> R) -> R;
+    /// RAM/Screen/Keyboardを読む
+    fn read(&self, address: u16) -> u16;
+    /// Keyboardより下だけ呼ばれる
+    fn write(&self, address: u16, value: u16) -> ();
+    /// (A, D, PC)
+    fn registers(&self) -> (u16, u16, u16);
+    fn set_registers(&self, a: u16, d: u16, pc: u16) -> ();
+
+    fn instruction(&self, address: u16) -> u16 {
+        self.with_rom(|rom| rom.get(address as usize).copied().unwrap_or(0))
+    }
+}
+
+/// ROM/RAM/Screen/KeyboardとA/D/PCはComputerのものをそのまま読み書きする
+impl Machine for Computer {
+    fn with_rom<R>(&self, f: impl FnOnce(&[u16]) -> R) -> R {
+        f(&self.rom().rom.borrow()[..])
+    }
+
+    fn read(&self, address: u16) -> u16 {
+        self.memory().peek(address)
+    }
+
+    fn write(&self, address: u16, value: u16) -> () {
+        self.memory().poke(address, value);
+    }
+
+    fn registers(&self) -> (u16, u16, u16) {
+        (
+            self.get_a_register_value(),
+            self.get_d_register_value(),
+            self.get_pc(),
+        )
+    }
+
+    fn set_registers(&self, a: u16, d: u16, pc: u16) -> () {
+        self.cpu.set_a_register_value(a);
+        self.cpu.set_d_register_value(d);
+        self.cpu.set_pc(pc);
+    }
+}
+
+/// ゲートを持たず、プログラムとRAM/Screen/Keyboard、A/D/PCだけを持つComputer
+/// Emulatorでしか動かせない
+#[derive(Debug)]
+pub struct NativeComputer {
+    program: Vec<u16>,
+    // RAM(0..16384)、Screen(16384..24576)、Keyboard(24576)
+    ram: RefCell<Vec<u16>>,
+    registers: Cell<(u16, u16, u16)>,
+}
+
+impl NativeComputer {
+    pub fn new(program: Vec<u16>) -> NativeComputer {
+        NativeComputer {
+            program,
+            ram: RefCell::new(vec![0; KEYBOARD as usize + 1]),
+            registers: Cell::new((0, 0, 0)),
+        }
+    }
+
+    pub fn get_instruction(&self, address: u16) -> u16 {
+        self.instruction(address)
+    }
+
+    pub fn get_a_register_value(&self) -> u16 {
+        self.registers.get().0
+    }
+
+    pub fn get_d_register_value(&self) -> u16 {
+        self.registers.get().1
+    }
+
+    pub fn get_pc(&self) -> u16 {
+        self.registers.get().2
+    }
+
+    pub fn set_a_register_value(&self, value: u16) -> () {
+        let (_, d, pc) = self.registers.get();
+        self.registers.set((value, d, pc));
+    }
+
+    pub fn set_d_register_value(&self, value: u16) -> () {
+        let (a, _, pc) = self.registers.get();
+        self.registers.set((a, value, pc));
+    }
+
+    pub fn set_pc(&self, value: u16) -> () {
+        let (a, d, _) = self.registers.get();
+        self.registers.set((a, d, value & 0x7fff));
+    }
+
+    // Computer::peekと同じく、範囲の外は0を返す
+    pub fn peek(&self, address: u16) -> u16 {
+        self.ram
+            .borrow()
+            .get(address as usize)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // Computer::pokeと同じく、Keyboardに書くとそのキーが押された状態になる
+    pub fn poke(&self, address: u16, value: u16) -> () {
+        if let Some(word) = self.ram.borrow_mut().get_mut(address as usize) {
+            *word = value;
+        }
+    }
+}
+
+impl Machine for NativeComputer {
+    fn with_rom<R>(&self, f: impl FnOnce(&[u16]) -> R) -> R {
+        f(&self.program)
+    }
+
+    fn read(&self, address: u16) -> u16 {
+        self.peek(address)
+    }
+
+    fn write(&self, address: u16, value: u16) -> () {
+        self.poke(address, value);
+    }
+
+    fn registers(&self) -> (u16, u16, u16) {
+        self.registers.get()
+    }
+
+    fn set_registers(&self, a: u16, d: u16, pc: u16) -> () {
+        self.registers.set((a, d, pc));
+    }
+}
+
+/// ゲートを通さずに命令を直接実行するHack CPU
+/// ROM/RAMとA/D/PCはMachineのものを読み書きする
+/// resetピンは見ないので、リセットはComputer::resetで行う
+#[derive(Debug)]
+pub struct Emulator<'a, M: Machine = Computer> {
+    machine: &'a M,
+}
+
+// zx nx zy ny f no の6bitでALUと同じ計算をする。compが表にない組み合わせでもゲートと同じ結果になる
+pub(crate) fn alu(x: u16, y: u16, control: u16) -> u16 {
+    let bit = |i: u16| control & (1 << i) != 0;
+    let x = if bit(5) { 0 } else { x };
+    let x = if bit(4) { !x } else { x };
+    let y = if bit(3) { 0 } else { y };
+    let y = if bit(2) { !y } else { y };
+    let out = if bit(1) { x.wrapping_add(y) } else { x & y };
+    if bit(0) {
+        !out
+    } else {
+        out
+    }
+}
+
+impl<'a, M: Machine> Emulator<'a, M> {
+    pub fn new(machine: &'a M) -> Emulator<'a, M> {
+        Emulator { machine }
+    }
+
+    /// 1命令(1サイクル)実行する
+    pub fn step(&self) -> () {
+        self.execute(1, false);
+    }
+
+    /// max_cycles 命令実行する
+    pub fn run(&self, max_cycles: usize) -> () {
+        self.execute(max_cycles, false);
+    }
+
+    /// 停止ループに入るまで実行して、実行した命令数を返す
+    pub fn run_until_halt(&self) -> usize {
+        self.execute(usize::MAX, true)
+    }
+
+    /// 今のPCの命令でCPUが出す (writeM, addressM, outM)。状態は変えない
+    pub fn outputs(&self) -> (bool, u16, u16) {
+        let machine = self.machine;
+        let (a, d, pc) = machine.registers();
+        let instruction = machine.instruction(pc & 0x7fff);
+        let address = a & 0x7fff;
+        if instruction & 0x8000 == 0 {
+            return (false, address, 0);
+        }
+        let y = match instruction & 0x1000 != 0 {
+            true => machine.read(address.min(KEYBOARD)),
+            false => a,
+        };
+        let out = alu(d, y, (instruction >> 6) & 0b11_1111);
+        (instruction & 0b1000 != 0, address, out)
+    }
+
+    fn execute(&self, max_cycles: usize, until_halt: bool) -> usize {
+        let machine = self.machine;
+        let (mut a, mut d, mut pc) = machine.registers();
+
+        let cycles = machine.with_rom(|rom| {
+            let mut cycles = 0;
+            while cycles < max_cycles {
+                if until_halt && is_halt_loop(rom, pc, a) {
+                    break;
+                }
+                let instruction = rom.get((pc & 0x7fff) as usize).copied().unwrap_or(0);
+                cycles += 1;
+                if instruction & 0x8000 == 0 {
+                    a = instruction;
+                    pc = pc.wrapping_add(1) & 0x7fff;
+                    continue;
+                }
+
+                let address = a & 0x7fff;
+                let y = match instruction & 0x1000 != 0 {
+                    true => machine.read(address.min(KEYBOARD)),
+                    false => a,
+                };
+                let out = alu(d, y, (instruction >> 6) & 0b11_1111);
+                if instruction & 0b1000 != 0 && address < KEYBOARD {
+                    machine.write(address, out);
+                }
+
+                // j1: out < 0, j2: out == 0, j3: out > 0
+                let negative = out & 0x8000 != 0;
+                let jump = (instruction & 0b100 != 0 && negative)
+                    || (instruction & 0b010 != 0 && out == 0)
+                    || (instruction & 0b001 != 0 && !negative && out != 0);
+                // PCに入るのは書き換える前のA
+                pc = match jump {
+                    true => a & 0x7fff,
+                    false => pc.wrapping_add(1) & 0x7fff,
+                };
+                if instruction & 0b10_0000 != 0 {
+                    a = out;
+                }
+                if instruction & 0b01_0000 != 0 {
+                    d = out;
+                }
+            }
+            cycles
+        });
+
+        machine.set_registers(a, d, pc);
+        cycles
+    }
+}
+
+/// Computerのサイクルをどう進めるか
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+    /// NandとDFFの回路で1サイクルずつ計算する
+    Gate,
+    /// Emulatorで命令を直接実行する
+    Native,
+}
+
+impl Engine {
+    pub fn step(&self, computer: &Computer) -> Result<(), Oscillation> {
+        match self {
+            Engine::Gate => {
+                computer.tick()?;
+                computer.tock()
+            }
+            Engine::Native => {
+                Emulator::new(computer).step();
+                Ok(())
+            }
+        }
+    }
+
+    pub fn run(&self, computer: &Computer, max_cycles: usize) -> Result<(), Oscillation> {
+        match self {
+            Engine::Gate => computer.run(max_cycles),
+            Engine::Native => {
+                Emulator::new(computer).run(max_cycles);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn run_until_halt(&self, computer: &Computer) -> Result<usize, Oscillation> {
+        match self {
+            Engine::Gate => computer.run_until_halt(),
+            Engine::Native => Ok(Emulator::new(computer).run_until_halt()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, computer::ROM32KBuiltIn, gate::*};
+
+    fn computer(source: &str) -> Computer {
+        let words = assemble(source).unwrap();
+        let rom = ROM32KBuiltIn::from_words(&words, Bus::all0().to_shared_bus());
+        Computer::new(Bus::all0().to_shared_bus(), rom)
+    }
+
+    const MULT: &str = "@R2\nM=0\n@R0\nD=M\n@I\nM=D\n(LOOP)\n@I\nD=M\n@END\nD;JLE
+@R1\nD=M\n@R2\nM=D+M\n@I\nM=M-1\n@LOOP\n0;JMP\n(END)\n@END\n0;JMP\n";
+
+    #[test]
+    fn same_as_gates() {
+        let gate = computer(MULT);
+        let native = computer(MULT);
+        for c in [&gate, &native] {
+            c.poke(0, 6).unwrap();
+            c.poke(1, 7).unwrap();
+        }
+        let cycles = gate.run_until_halt().unwrap();
+        assert_eq!(Emulator::new(&native).run_until_halt(), cycles);
+        assert_eq!(native.peek(2), 42);
+        for (g, n) in [
+            (gate.get_a_register_value(), native.get_a_register_value()),
+            (gate.get_d_register_value(), native.get_d_register_value()),
+            (gate.get_pc(), native.get_pc()),
+        ] {
+            assert_eq!(g, n);
+        }
+        assert!(native.is_halted());
+
+        // Emulatorで進めたあとゲートで続けても同じ
+        let source = "@5\nD=A\n@SCREEN\nM=D\nD=D-1\n@2\nD;JGT\n@KBD\nD=M\n@24577\nM=D\nAD=M+1\n";
+        let gate = computer(source);
+        let mixed = computer(source);
+        gate.keyboard().press(65);
+        mixed.keyboard().press(65);
+        gate.run(40).unwrap();
+        Emulator::new(&mixed).run(10);
+        mixed.run(5).unwrap();
+        Engine::Native.run(&mixed, 25).unwrap();
+        assert_eq!(mixed.peek(16384), gate.peek(16384));
+        assert_eq!(mixed.get_d_register_value(), gate.get_d_register_value());
+        assert_eq!(mixed.get_a_register_value(), gate.get_a_register_value());
+        assert_eq!(mixed.get_pc(), gate.get_pc());
+        // Keyboardの上は読めるが書き込めない
+        assert_eq!(gate.get_d_register_value(), 66);
+        assert_eq!(mixed.get_keyboard_value(), 65);
+    }
+
+    #[test]
+    fn native_computer() {
+        let gate = computer(MULT);
+        let native = NativeComputer::new(gate.rom().program());
+        gate.poke(0, 6).unwrap();
+        gate.poke(1, 7).unwrap();
+        native.poke(0, 6);
+        native.poke(1, 7);
+        let cycles = gate.run_until_halt().unwrap();
+        assert_eq!(Emulator::new(&native).run_until_halt(), cycles);
+        assert_eq!(native.peek(2), 42);
+        assert_eq!(native.registers(), gate.registers());
+        // プログラムの後ろは0として読む
+        assert_eq!(native.get_instruction(1000), 0);
+        assert_eq!(native.peek(24577), 0);
+    }
+
+    #[test]
+    fn alu_table() {
+        // (comp, x=D, y=A) の結果をゲートのALUと比べる
+        let (x, y) = (0b0101_1100_0011_1010u16, 0b1110_0001_0110_0101u16);
+        for control in 0..64 {
+            let gate = computer("");
+            let native = computer("");
+            // dest=D、a=0、compを直接組み立てる
+            let word = 0xe000 | (control << 6) | 0b010_000;
+            for c in [&gate, &native] {
+                c.set_a_register_value(y).unwrap();
+                c.set_d_register_value(x).unwrap();
+                c.rom().load(&[word]);
+                c.set_pc(0).unwrap();
+            }
+            Engine::Gate.step(&gate).unwrap();
+            Engine::Native.step(&native).unwrap();
+            assert_eq!(
+                native.get_d_register_value(),
+                gate.get_d_register_value(),
+                "{:06b}",
+                control
+            );
+            assert_eq!(native.get_d_register_value(), alu(x, y, control));
+        }
+    }
+}
@@ -2,6 +2,8 @@ use core::str::FromStr;
 use std::cell::RefCell;
 use std::{cell::Cell, rc::Rc};
 
+use crate::netlist::{bit_names, NetlistBuilder, NetlistError};
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Bit {
     O, // 0
@@ -69,6 +71,8 @@ impl<const N: usize> Bus<N> {
         }
     }
 
+    // Busはbitを共有しているだけなので、持ち主ごと渡す
+    #[allow(clippy::wrong_self_convention)]
     pub fn to_shared_bus(self) -> SharedBus<N> {
         SharedBus(Rc::new(RefCell::new(self)))
     }
@@ -101,13 +105,60 @@ impl<const N: usize> SharedBus<N> {
 
     // もとのSharedBusの指定bitをつなぎ直した新しいSharedBusを生成する
     // FIXME indexがN以上の値だとpanicになってしまう。できればコンパイル時にエラーにしたい。
+    // indexが決まっているときはbit/slice/slice_len/concat/reverseを使えばコンパイル時に調べられる
     pub fn reconnect<const M: usize>(&self, bits: [usize; M]) -> SharedBus<M> {
         Bus::new(bits.map(|index| self.get_shared_bit(index).clone())).to_shared_bus()
     }
 
+    /// I bit目だけをつなぎ直す。IがN以上だとコンパイル時にエラーになる
+    pub fn bit<const I: usize>(&self) -> SharedBus<1> {
+        const { assert!(I < N, "bit index out of range") };
+        self.reconnect([I])
+    }
+
+    /// HDLの bus[START..END] と同じで、START bit目からEND bit目までをつなぎ直す。
+    /// 幅のMは END - START + 1 で、渡す先の型が決まっていれば bus.slice::<13, 14, _>() と推論させられる。
+    /// START <= END < N でないか、Mが合わないとコンパイル時にエラーになる
+    pub fn slice<const START: usize, const END: usize, const M: usize>(&self) -> SharedBus<M> {
+        const {
+            assert!(START <= END && END < N, "slice out of range");
+            assert!(END - START + 1 == M, "slice width mismatch");
+        };
+        self.reconnect(std::array::from_fn(|i| START + i))
+    }
+
+    /// START bit目からLEN bitをつなぎ直す。2つ目は終わりではなく長さで、
+    /// bus.slice_len::<13, 2>() は13と14bit目。はみ出すとコンパイル時にエラーになる
+    pub fn slice_len<const START: usize, const LEN: usize>(&self) -> SharedBus<LEN> {
+        const { assert!(START + LEN <= N, "slice out of range") };
+        self.reconnect(std::array::from_fn(|i| START + i))
+    }
+
+    /// selfを下位、upperを上位にしてつなげる。幅の合計がKでなければコンパイル時にエラーになる
+    pub fn concat<const M: usize, const K: usize>(&self, upper: &SharedBus<M>) -> SharedBus<K> {
+        const { assert!(N + M == K, "concat width mismatch") };
+        let bits: Vec<SharedBit> = self
+            .shared_bits()
+            .into_iter()
+            .chain(upper.shared_bits())
+            .collect();
+        Bus::new(std::array::from_fn(|i| bits[i].clone())).to_shared_bus()
+    }
+
+    /// bitの並びを逆にする。0bit目がN-1bit目になる。幅0のbusはコンパイル時にエラーになる
+    #[allow(dead_code)]
+    pub fn reverse(&self) -> SharedBus<N> {
+        const { assert!(N > 0, "reverse of an empty bus") };
+        self.reconnect(std::array::from_fn(|i| N - 1 - i))
+    }
+
+    pub fn shared_bits(&self) -> Vec<SharedBit> {
+        self.0.borrow().bits.to_vec()
+    }
+
     pub fn overwrite(&self, bus: &Bus<N>) -> () {
         for i in 0..N {
-            self.get_shared_bit(i).set(bus.get_shared_bit(i).get());
+            drive(&self.get_shared_bit(i), bus.get_shared_bit(i).get());
         }
     }
 
@@ -141,25 +192,160 @@ impl<const N: usize> FromStr for Bus<N> {
         }
         let mut bits = bits.unwrap();
         let bit_array: [SharedBit; N] = [(); N].map(|_| Rc::new(Cell::new(O)));
-        for i in 0..N {
-            bit_array[i].set(bits.pop().unwrap_or(O))
+        for bit in bit_array.iter() {
+            bit.set(bits.pop().unwrap_or(O))
         }
 
         Ok(Bus { bits: bit_array })
     }
 }
 
+/// 部品を持つチップのre_computeは部品を作った順に一回ずつ計算すればよい。
+/// チップを使う側はre_computeではなくsettleを呼ぶ。部品の間で値が戻ってきても落ち着くまで繰り返す
 pub trait Gate {
     fn re_compute(&self) -> () {}
     fn clock_up(&self) -> () {}
     fn clock_down(&self) -> () {}
+    // NandとDFFまで展開してbuilderに加える。部品を持つチップはbuilder.partで部品を順にflattenする
+    // re_computeで値を写しているだけのところはbuilder.connectでつなぐ
+    fn flatten(&self, _builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        Err(NetlistError::unsupported::<Self>())
+    }
+    // 値が変わらなくなるまでre_computeを繰り返す。発振したbitにはflattenでたどった部品の名前を付ける
+    fn settle(&self) -> Result<usize, Oscillation> {
+        settle(|| self.re_compute()).map_err(|oscillation| {
+            let nets = bit_names(self, &oscillation.bits);
+            Oscillation {
+                nets,
+                ..oscillation
+            }
+        })
+    }
+}
+
+// これだけ繰り返しても落ち着かなければ発振しているとみなす
+const MAX_SETTLE_PASSES: usize = 64;
+
+thread_local! {
+    // driveで値が変わったbitの数
+    static CHANGES: Cell<usize> = const { Cell::new(0) };
+    // 発振を調べているあいだだけ、値が変わったbitを集める
+    static CHANGED: RefCell<Option<Vec<SharedBit>>> = const { RefCell::new(None) };
+}
+
+/// re_computeで出力bitを書き換えるときはこれを使う。値が変わったかどうかをsettleが見ている
+pub fn drive(bit: &SharedBit, value: Bit) -> () {
+    if bit.get() == value {
+        return;
+    }
+    bit.set(value);
+    CHANGES.with(|changes| changes.set(changes.get() + 1));
+    CHANGED.with(|changed| {
+        if let Some(bits) = changed.borrow_mut().as_mut() {
+            if !bits.iter().any(|b| Rc::ptr_eq(b, bit)) {
+                bits.push(bit.clone());
+            }
+        }
+    });
+}
+
+/// どのbitも変わらなくなるまでre_computeを繰り返して、繰り返した回数を返す。
+/// 部品をどの順に計算しても、フィードバックや写しているだけの配線まで伝わる
+pub fn settle(mut re_compute: impl FnMut() -> ()) -> Result<usize, Oscillation> {
+    let changes = || CHANGES.with(|changes| changes.get());
+    for pass in 1..=MAX_SETTLE_PASSES {
+        let before = changes();
+        re_compute();
+        if changes() == before {
+            return Ok(pass);
+        }
+    }
+
+    // 何回か余分に回して、変わり続けているbitを集める
+    CHANGED.with(|changed| *changed.borrow_mut() = Some(vec![]));
+    for _ in 0..4 {
+        re_compute();
+    }
+    let bits = CHANGED.with(|changed| changed.borrow_mut().take().unwrap_or_default());
+    Err(Oscillation {
+        passes: MAX_SETTLE_PASSES,
+        nets: vec![],
+        bits,
+    })
+}
+
+/// 組み合わせ回路がループしていて値が落ち着かない
+#[derive(Debug)]
+pub struct Oscillation {
+    pub passes: usize,
+    /// 変わり続けているbit
+    pub bits: Vec<SharedBit>,
+    /// bitsの名前 (Netlist::net_nameと同じ形)。Gate::settleで付ける
+    pub nets: Vec<String>,
+}
+
+impl std::fmt::Display for Oscillation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 同じnetにつながっているbitは一つにまとめる。名前がなければbitの数を数える
+        let mut nets: Vec<&str> = vec![];
+        for net in self.nets.iter() {
+            if !nets.contains(&net.as_str()) {
+                nets.push(net);
+            }
+        }
+        let (count, unit) = match nets.len() {
+            0 => (self.bits.len(), "bit"),
+            n => (n, "net"),
+        };
+        write!(
+            f,
+            "combinational loop did not settle after {} passes: {} {}{} keep{} changing",
+            self.passes,
+            count,
+            unit,
+            if count == 1 { "" } else { "s" },
+            if count == 1 { "s" } else { "" },
+        )?;
+        if !nets.is_empty() {
+            write!(f, " ({})", nets.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Oscillation {}
+
+/// fromの値をtoに写すだけの配線。あとから作る部品の出力を、先に作ったbusにつなぐときに使う
+#[derive(Debug)]
+pub struct Link<const N: usize> {
+    from: SharedBus<N>,
+    to: SharedBus<N>,
+}
+
+impl<const N: usize> Link<N> {
+    pub fn new(from: SharedBus<N>, to: SharedBus<N>) -> Link<N> {
+        Link { from, to }
+    }
+}
+
+impl<const N: usize> Gate for Link<N> {
+    fn re_compute(&self) -> () {
+        self.to.overwrite(&self.from.0.borrow());
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        for i in 0..N {
+            builder.connect(&self.from.get_shared_bit(i), &self.to.get_shared_bit(i));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct Nand<const N: usize> {
     a: SharedBus<N>,
     b: SharedBus<N>,
-    out: SharedBus<N>,
+    pub out: SharedBus<N>,
 }
 
 impl<const N: usize> Nand<N> {
@@ -184,8 +370,19 @@ impl<const N: usize> Gate for Nand<N> {
                 (I, O) => I,
                 (I, I) => O,
             };
-            out.bits[i].set(bit);
+            drive(&out.bits[i], bit);
+        }
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        for i in 0..N {
+            builder.nand(
+                &self.a.get_shared_bit(i),
+                &self.b.get_shared_bit(i),
+                &self.out.get_shared_bit(i),
+            );
         }
+        Ok(())
     }
 }
 
@@ -209,6 +406,11 @@ impl<const N: usize> Gate for Not<N> {
     fn re_compute(&self) -> () {
         self.nand.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("nand", &self.nand)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -235,6 +437,12 @@ impl<const N: usize> Gate for And<N> {
         self.nand.re_compute();
         self.not.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("nand", &self.nand)?;
+        builder.part("not", &self.not)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -265,6 +473,13 @@ impl<const N: usize> Gate for Or<N> {
         self.nand2.re_compute();
         self.nand3.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("nand1", &self.nand1)?;
+        builder.part("nand2", &self.nand2)?;
+        builder.part("nand3", &self.nand3)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -300,6 +515,14 @@ impl<const N: usize> Gate for Xor<N> {
         self.nand3.re_compute();
         self.nand4.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("nand1", &self.nand1)?;
+        builder.part("nand2", &self.nand2)?;
+        builder.part("nand3", &self.nand3)?;
+        builder.part("nand4", &self.nand4)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -336,6 +559,14 @@ impl<const N: usize> Gate for Mux<N> {
         self.and2.re_compute();
         self.or.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("not", &self.not)?;
+        builder.part("and1", &self.and1)?;
+        builder.part("and2", &self.and2)?;
+        builder.part("or", &self.or)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -368,6 +599,13 @@ impl Gate for DMux {
         self.and1.re_compute();
         self.and2.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("not", &self.not)?;
+        builder.part("and1", &self.and1)?;
+        builder.part("and2", &self.and2)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -384,14 +622,14 @@ pub struct Or8Way {
 
 impl Or8Way {
     pub fn new(input: SharedBus<8>) -> Or8Way {
-        let i0 = Bus::new([input.get_shared_bit(0)]).to_shared_bus();
-        let i1 = Bus::new([input.get_shared_bit(1)]).to_shared_bus();
-        let i2 = Bus::new([input.get_shared_bit(2)]).to_shared_bus();
-        let i3 = Bus::new([input.get_shared_bit(3)]).to_shared_bus();
-        let i4 = Bus::new([input.get_shared_bit(4)]).to_shared_bus();
-        let i5 = Bus::new([input.get_shared_bit(5)]).to_shared_bus();
-        let i6 = Bus::new([input.get_shared_bit(6)]).to_shared_bus();
-        let i7 = Bus::new([input.get_shared_bit(7)]).to_shared_bus();
+        let i0 = input.bit::<0>();
+        let i1 = input.bit::<1>();
+        let i2 = input.bit::<2>();
+        let i3 = input.bit::<3>();
+        let i4 = input.bit::<4>();
+        let i5 = input.bit::<5>();
+        let i6 = input.bit::<6>();
+        let i7 = input.bit::<7>();
 
         let or1 = Or::new(i0, i1);
         let or2 = Or::new(i2, i3);
@@ -423,6 +661,17 @@ impl Gate for Or8Way {
         self.or6.re_compute();
         self.or7.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("or1", &self.or1)?;
+        builder.part("or2", &self.or2)?;
+        builder.part("or3", &self.or3)?;
+        builder.part("or4", &self.or4)?;
+        builder.part("or5", &self.or5)?;
+        builder.part("or6", &self.or6)?;
+        builder.part("or7", &self.or7)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -441,8 +690,8 @@ impl Mux4Way16 {
         d: SharedBus<16>,
         sel: SharedBus<2>,
     ) -> Mux4Way16 {
-        let sel0 = sel.reconnect([0]);
-        let sel1 = sel.reconnect([1]);
+        let sel0 = sel.bit::<0>();
+        let sel1 = sel.bit::<1>();
 
         let mux1 = Mux::new(a.clone(), b.clone(), sel0.clone());
         let mux2 = Mux::new(c.clone(), d.clone(), sel0.clone());
@@ -463,6 +712,13 @@ impl Gate for Mux4Way16 {
         self.mux2.re_compute();
         self.mux3.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("mux1", &self.mux1)?;
+        builder.part("mux2", &self.mux2)?;
+        builder.part("mux3", &self.mux3)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -475,6 +731,8 @@ pub struct Mux8Way16 {
 
 #[allow(dead_code)]
 impl Mux8Way16 {
+    // 引数はMux8Way16.hdlのピンと同じ並び
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         a: SharedBus<16>,
         b: SharedBus<16>,
@@ -486,8 +744,8 @@ impl Mux8Way16 {
         h: SharedBus<16>,
         sel: SharedBus<3>,
     ) -> Mux8Way16 {
-        let sel01 = sel.reconnect([0, 1]);
-        let sel2 = sel.reconnect([2]);
+        let sel01 = sel.slice::<0, 1, _>();
+        let sel2 = sel.bit::<2>();
 
         let mux1 = Mux4Way16::new(a.clone(), b.clone(), c.clone(), d.clone(), sel01.clone());
         let mux2 = Mux4Way16::new(e.clone(), f.clone(), g.clone(), h.clone(), sel01.clone());
@@ -508,6 +766,13 @@ impl Gate for Mux8Way16 {
         self.mux2.re_compute();
         self.mux3.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("mux1", &self.mux1)?;
+        builder.part("mux2", &self.mux2)?;
+        builder.part("mux3", &self.mux3)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -523,8 +788,8 @@ pub struct DMux4Way {
 
 impl DMux4Way {
     pub fn new(input: SharedBus<1>, sel: SharedBus<2>) -> DMux4Way {
-        let sel0 = sel.reconnect([0]);
-        let sel1 = sel.reconnect([1]);
+        let sel0 = sel.bit::<0>();
+        let sel1 = sel.bit::<1>();
 
         let dmux1 = DMux::new(input.clone(), sel1.clone());
         let dmux2 = DMux::new(dmux1.out1.clone(), sel0.clone());
@@ -548,6 +813,13 @@ impl Gate for DMux4Way {
         self.dmux2.re_compute();
         self.dmux3.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("dmux1", &self.dmux1)?;
+        builder.part("dmux2", &self.dmux2)?;
+        builder.part("dmux3", &self.dmux3)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -572,9 +844,9 @@ pub struct DMux8Way {
 #[allow(dead_code)]
 impl DMux8Way {
     pub fn new(input: SharedBus<1>, sel: SharedBus<3>) -> DMux8Way {
-        let sel0 = sel.reconnect([0]);
-        let sel1 = sel.reconnect([1]);
-        let sel2 = sel.reconnect([2]);
+        let sel0 = sel.bit::<0>();
+        let sel1 = sel.bit::<1>();
+        let sel2 = sel.bit::<2>();
 
         let dmux1 = DMux::new(input.clone(), sel2.clone());
 
@@ -616,11 +888,23 @@ impl Gate for DMux8Way {
         self.dmux6.re_compute();
         self.dmux7.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("dmux1", &self.dmux1)?;
+        builder.part("dmux2", &self.dmux2)?;
+        builder.part("dmux3", &self.dmux3)?;
+        builder.part("dmux4", &self.dmux4)?;
+        builder.part("dmux5", &self.dmux5)?;
+        builder.part("dmux6", &self.dmux6)?;
+        builder.part("dmux7", &self.dmux7)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::netlist::Twin;
 
     #[test]
     fn widen() {
@@ -695,6 +979,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn slice_and_concat() {
+        let bus = "1100101000111010"
+            .parse::<Bus<16>>()
+            .unwrap()
+            .to_shared_bus();
+        assert_eq!(bus.slice_len::<0, 15>().to_u16(), 0b100101000111010);
+        assert_eq!(bus.slice_len::<4, 8>().to_u16(), 0b10100011);
+        assert_eq!(bus.bit::<15>().to_u16(), 1);
+        // sliceはHDLと同じく終わりのbitを含む
+        let low15: SharedBus<15> = bus.slice::<0, 14, _>();
+        assert_eq!(low15.to_u16(), 0b100101000111010);
+        assert_eq!(bus.slice::<4, 11, 8>().to_u16(), 0b10100011);
+        assert_eq!(bus.slice::<15, 15, 1>().to_u16(), 1);
+        assert_eq!(bus.reverse().to_u16(), 0b0101110001010011);
+        assert_eq!(bus.reverse().reverse().to_u16(), bus.to_u16());
+
+        // 同じbitにつながっているので、もとのbusを書き換えると変わる
+        let low = bus.slice_len::<0, 8>();
+        let high = bus.slice_len::<8, 8>();
+        let swapped: SharedBus<16> = high.concat(&low);
+        assert_eq!(swapped.to_u16(), 0b0011101011001010);
+        bus.overwrite(&"0000000011111111".parse().unwrap());
+        assert_eq!(swapped.to_u16(), 0b1111111100000000);
+        assert_eq!(low.concat::<8, 16>(&high).to_u16(), 0b0000000011111111);
+    }
+
     #[test]
     fn nand_re_compute() {
         let cases = vec![
@@ -708,8 +1019,13 @@ mod tests {
             let a = case[0].parse::<Bus<1>>().unwrap().to_shared_bus();
             let b = case[1].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out = case[2].parse::<Bus<1>>().unwrap().to_shared_bus();
-            let nand = Nand::new(a, b);
-            nand.re_compute();
+            let nand = Nand::new(a.clone(), b.clone());
+            Twin::new(
+                &nand,
+                &[a.shared_bits(), b.shared_bits()],
+                &[nand.out.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(nand.out, out);
         }
     }
@@ -724,8 +1040,8 @@ mod tests {
         for case in cases {
             let input = case[0].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out = case[1].parse::<Bus<1>>().unwrap().to_shared_bus();
-            let not = Not::new(input);
-            not.re_compute();
+            let not = Not::new(input.clone());
+            Twin::new(&not, &[input.shared_bits()], &[not.out.shared_bits()]).re_compute();
             assert_eq!(not.out, out);
         }
     }
@@ -743,8 +1059,8 @@ mod tests {
         for case in cases {
             let input = case[0].parse::<Bus<16>>().unwrap().to_shared_bus();
             let out = case[1].parse::<Bus<16>>().unwrap().to_shared_bus();
-            let not = Not::new(input);
-            not.re_compute();
+            let not = Not::new(input.clone());
+            Twin::new(&not, &[input.shared_bits()], &[not.out.shared_bits()]).re_compute();
             assert_eq!(not.out, out);
         }
     }
@@ -762,8 +1078,13 @@ mod tests {
             let a = case[0].parse::<Bus<1>>().unwrap().to_shared_bus();
             let b = case[1].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out = case[2].parse::<Bus<1>>().unwrap().to_shared_bus();
-            let and = And::new(a, b);
-            and.re_compute();
+            let and = And::new(a.clone(), b.clone());
+            Twin::new(
+                &and,
+                &[a.shared_bits(), b.shared_bits()],
+                &[and.out.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(and.out, out);
         }
     }
@@ -783,8 +1104,13 @@ mod tests {
             let a = case[0].parse::<Bus<16>>().unwrap().to_shared_bus();
             let b = case[1].parse::<Bus<16>>().unwrap().to_shared_bus();
             let out = case[2].parse::<Bus<16>>().unwrap().to_shared_bus();
-            let and = And::new(a, b);
-            and.re_compute();
+            let and = And::new(a.clone(), b.clone());
+            Twin::new(
+                &and,
+                &[a.shared_bits(), b.shared_bits()],
+                &[and.out.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(and.out, out);
         }
     }
@@ -802,8 +1128,13 @@ mod tests {
             let a = case[0].parse::<Bus<1>>().unwrap().to_shared_bus();
             let b = case[1].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out = case[2].parse::<Bus<1>>().unwrap().to_shared_bus();
-            let or = Or::new(a, b);
-            or.re_compute();
+            let or = Or::new(a.clone(), b.clone());
+            Twin::new(
+                &or,
+                &[a.shared_bits(), b.shared_bits()],
+                &[or.out.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(or.out, out);
         }
     }
@@ -823,8 +1154,13 @@ mod tests {
             let a = case[0].parse::<Bus<16>>().unwrap().to_shared_bus();
             let b = case[1].parse::<Bus<16>>().unwrap().to_shared_bus();
             let out = case[2].parse::<Bus<16>>().unwrap().to_shared_bus();
-            let or = Or::new(a, b);
-            or.re_compute();
+            let or = Or::new(a.clone(), b.clone());
+            Twin::new(
+                &or,
+                &[a.shared_bits(), b.shared_bits()],
+                &[or.out.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(or.out, out);
         }
     }
@@ -842,8 +1178,13 @@ mod tests {
             let a = case[0].parse::<Bus<1>>().unwrap().to_shared_bus();
             let b = case[1].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out = case[2].parse::<Bus<1>>().unwrap().to_shared_bus();
-            let xor = Xor::new(a, b);
-            xor.re_compute();
+            let xor = Xor::new(a.clone(), b.clone());
+            Twin::new(
+                &xor,
+                &[a.shared_bits(), b.shared_bits()],
+                &[xor.out.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(xor.out, out);
         }
     }
@@ -866,8 +1207,13 @@ mod tests {
             let b = case[1].parse::<Bus<1>>().unwrap().to_shared_bus();
             let sel = case[2].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out = case[3].parse::<Bus<1>>().unwrap().to_shared_bus();
-            let mux = Mux::new(a, b, sel);
-            mux.re_compute();
+            let mux = Mux::new(a.clone(), b.clone(), sel.clone());
+            Twin::new(
+                &mux,
+                &[a.shared_bits(), b.shared_bits(), sel.shared_bits()],
+                &[mux.out.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(mux.out, out);
         }
     }
@@ -930,8 +1276,13 @@ mod tests {
             let b = case[1].parse::<Bus<16>>().unwrap().to_shared_bus();
             let sel = case[2].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out = case[3].parse::<Bus<16>>().unwrap().to_shared_bus();
-            let mux = Mux::new(a, b, sel);
-            mux.re_compute();
+            let mux = Mux::new(a.clone(), b.clone(), sel.clone());
+            Twin::new(
+                &mux,
+                &[a.shared_bits(), b.shared_bits(), sel.shared_bits()],
+                &[mux.out.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(mux.out, out);
         }
     }
@@ -950,8 +1301,13 @@ mod tests {
             let sel = case[1].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out1 = case[2].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out2 = case[3].parse::<Bus<1>>().unwrap().to_shared_bus();
-            let dmux = DMux::new(input, sel);
-            dmux.re_compute();
+            let dmux = DMux::new(input.clone(), sel.clone());
+            Twin::new(
+                &dmux,
+                &[input.shared_bits(), sel.shared_bits()],
+                &[dmux.out1.shared_bits(), dmux.out2.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(dmux.out1, out1);
             assert_eq!(dmux.out2, out2);
         }
@@ -970,8 +1326,8 @@ mod tests {
         for case in cases {
             let input = case[0].parse::<Bus<8>>().unwrap().to_shared_bus();
             let out = case[1].parse::<Bus<1>>().unwrap().to_shared_bus();
-            let or8way = Or8Way::new(input);
-            or8way.re_compute();
+            let or8way = Or8Way::new(input.clone());
+            Twin::new(&or8way, &[input.shared_bits()], &[or8way.out.shared_bits()]).re_compute();
             assert_eq!(or8way.out, out);
         }
     }
@@ -1052,8 +1408,19 @@ mod tests {
             let d = case[3].parse::<Bus<16>>().unwrap().to_shared_bus();
             let sel = case[4].parse::<Bus<2>>().unwrap().to_shared_bus();
             let out = case[5].parse::<Bus<16>>().unwrap().to_shared_bus();
-            let mux4way16 = Mux4Way16::new(a, b, c, d, sel);
-            mux4way16.re_compute();
+            let mux4way16 = Mux4Way16::new(a.clone(), b.clone(), c.clone(), d.clone(), sel.clone());
+            Twin::new(
+                &mux4way16,
+                &[
+                    a.shared_bits(),
+                    b.shared_bits(),
+                    c.shared_bits(),
+                    d.shared_bits(),
+                    sel.shared_bits(),
+                ],
+                &[mux4way16.out.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(mux4way16.out, out);
         }
     }
@@ -1266,8 +1633,33 @@ mod tests {
             let h = case[7].parse::<Bus<16>>().unwrap().to_shared_bus();
             let sel = case[8].parse::<Bus<3>>().unwrap().to_shared_bus();
             let out = case[9].parse::<Bus<16>>().unwrap().to_shared_bus();
-            let mux8way16 = Mux8Way16::new(a, b, c, d, e, f, g, h, sel);
-            mux8way16.re_compute();
+            let mux8way16 = Mux8Way16::new(
+                a.clone(),
+                b.clone(),
+                c.clone(),
+                d.clone(),
+                e.clone(),
+                f.clone(),
+                g.clone(),
+                h.clone(),
+                sel.clone(),
+            );
+            Twin::new(
+                &mux8way16,
+                &[
+                    a.shared_bits(),
+                    b.shared_bits(),
+                    c.shared_bits(),
+                    d.shared_bits(),
+                    e.shared_bits(),
+                    f.shared_bits(),
+                    g.shared_bits(),
+                    h.shared_bits(),
+                    sel.shared_bits(),
+                ],
+                &[mux8way16.out.shared_bits()],
+            )
+            .re_compute();
             assert_eq!(mux8way16.out, out);
         }
     }
@@ -1292,8 +1684,18 @@ mod tests {
             let out2 = case[3].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out3 = case[4].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out4 = case[5].parse::<Bus<1>>().unwrap().to_shared_bus();
-            let dmux4way = DMux4Way::new(input, sel);
-            dmux4way.re_compute();
+            let dmux4way = DMux4Way::new(input.clone(), sel.clone());
+            Twin::new(
+                &dmux4way,
+                &[input.shared_bits(), sel.shared_bits()],
+                &[
+                    dmux4way.out1.shared_bits(),
+                    dmux4way.out2.shared_bits(),
+                    dmux4way.out3.shared_bits(),
+                    dmux4way.out4.shared_bits(),
+                ],
+            )
+            .re_compute();
             assert_eq!(dmux4way.out1, out1);
             assert_eq!(dmux4way.out2, out2);
             assert_eq!(dmux4way.out3, out3);
@@ -1333,8 +1735,22 @@ mod tests {
             let out6 = case[7].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out7 = case[8].parse::<Bus<1>>().unwrap().to_shared_bus();
             let out8 = case[9].parse::<Bus<1>>().unwrap().to_shared_bus();
-            let dmux8way = DMux8Way::new(input, sel);
-            dmux8way.re_compute();
+            let dmux8way = DMux8Way::new(input.clone(), sel.clone());
+            Twin::new(
+                &dmux8way,
+                &[input.shared_bits(), sel.shared_bits()],
+                &[
+                    dmux8way.out1.shared_bits(),
+                    dmux8way.out2.shared_bits(),
+                    dmux8way.out3.shared_bits(),
+                    dmux8way.out4.shared_bits(),
+                    dmux8way.out5.shared_bits(),
+                    dmux8way.out6.shared_bits(),
+                    dmux8way.out7.shared_bits(),
+                    dmux8way.out8.shared_bits(),
+                ],
+            )
+            .re_compute();
             assert_eq!(dmux8way.out1, out1);
             assert_eq!(dmux8way.out2, out2);
             assert_eq!(dmux8way.out3, out3);
@@ -1345,4 +1761,87 @@ mod tests {
             assert_eq!(dmux8way.out8, out8);
         }
     }
+
+    #[test]
+    fn settle() {
+        // 後ろの部品から計算しても、落ち着くまで繰り返せば同じになる
+        let input = Bus::<1>::all0().to_shared_bus();
+        let not1 = Not::new(input.clone());
+        let wire = Bus::<1>::all0().to_shared_bus();
+        let not2 = Not::<1>::new(wire.clone());
+        let passes = super::settle(|| {
+            not2.re_compute();
+            wire.overwrite(&not1.out.0.borrow());
+            not1.re_compute();
+        })
+        .unwrap();
+        assert_eq!(passes, 4);
+        assert_eq!(not2.out.to_u16(), 0);
+        assert_eq!(not1.settle().unwrap(), 1);
+
+        // Nandをたすき掛けにしたラッチはループしていても落ち着く
+        let (s, r) = (
+            "0".parse::<Bus<1>>().unwrap(),
+            "1".parse::<Bus<1>>().unwrap(),
+        );
+        let (s, r) = (s.to_shared_bus(), r.to_shared_bus());
+        let (q_in, nq_in) = (Bus::all0().to_shared_bus(), Bus::all0().to_shared_bus());
+        let q = Nand::<1>::new(s.clone(), nq_in.clone());
+        let nq = Nand::<1>::new(r.clone(), q_in.clone());
+        let latch = || {
+            q.re_compute();
+            nq.re_compute();
+            q_in.overwrite(&q.out.0.borrow());
+            nq_in.overwrite(&nq.out.0.borrow());
+        };
+        super::settle(latch).unwrap();
+        assert_eq!((q.out.to_u16(), nq.out.to_u16()), (1, 0));
+        s.overwrite(&"1".parse().unwrap());
+        super::settle(latch).unwrap();
+        assert_eq!((q.out.to_u16(), nq.out.to_u16()), (1, 0));
+
+        // 自分の出力を反転して入力に戻すと発振する
+        let feedback = Bus::<1>::all0().to_shared_bus();
+        let not = Not::<1>::new(feedback.clone());
+        let oscillation = super::settle(|| {
+            not.re_compute();
+            feedback.overwrite(&not.out.0.borrow());
+        })
+        .unwrap_err();
+        assert_eq!(oscillation.bits.len(), 2);
+        for bus in [&feedback, &not.out] {
+            let bit = bus.get_shared_bit(0);
+            assert!(oscillation.bits.iter().any(|b| Rc::ptr_eq(b, &bit)));
+        }
+        assert_eq!(
+            oscillation.to_string(),
+            "combinational loop did not settle after 64 passes: 2 bits keep changing"
+        );
+
+        // Gate::settleはflattenでたどった部品の名前を付ける
+        struct Ring {
+            not: Not<1>,
+            link: Link<1>,
+        }
+        impl Gate for Ring {
+            fn re_compute(&self) -> () {
+                self.not.re_compute();
+                self.link.re_compute();
+            }
+            fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+                builder.part("not", &self.not)?;
+                self.link.flatten(builder)
+            }
+        }
+        let feedback = Bus::<1>::all0().to_shared_bus();
+        let not = Not::<1>::new(feedback.clone());
+        let link = Link::new(not.out.clone(), feedback);
+        let oscillation = Ring { not, link }.settle().unwrap_err();
+        // Linkでつながった2つのbitは同じnetなので名前は一つになる
+        assert_eq!(oscillation.nets.len(), 2);
+        assert_eq!(
+            oscillation.to_string(),
+            "combinational loop did not settle after 64 passes: 1 net keeps changing (not.nand)"
+        );
+    }
 }
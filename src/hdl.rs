@@ -0,0 +1,639 @@
+use std::{fmt, path::PathBuf};
+
+/// `a[16]` のようなピンの宣言
+#[derive(Debug, Clone, PartialEq)]
+pub struct PinDecl {
+    pub name: String,
+    pub width: usize,
+}
+
+/// `a`, `a[3]`, `a[0..7]` のようなピンの参照。rangeは両端を含む
+#[derive(Debug, Clone, PartialEq)]
+pub struct PinRef {
+    pub name: String,
+    pub range: Option<(usize, usize)>,
+}
+
+/// 接続の外側。ピンか true/false
+#[derive(Debug, Clone, PartialEq)]
+pub enum Wire {
+    Pin(PinRef),
+    Const(bool),
+}
+
+/// `inner=outer`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connection {
+    pub inner: PinRef,
+    pub outer: Wire,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartDef {
+    pub chip: String,
+    pub connections: Vec<Connection>,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChipBody {
+    Parts(Vec<PartDef>),
+    /// `BUILTIN Name;` で組み込みの実装を使う
+    BuiltIn {
+        name: String,
+        clocked: Vec<String>,
+        /// BUILTINを書いた行
+        line: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChipDef {
+    pub name: String,
+    pub inputs: Vec<PinDecl>,
+    pub outputs: Vec<PinDecl>,
+    pub body: ChipBody,
+}
+
+impl ChipDef {
+    pub fn input(&self, name: &str) -> Option<&PinDecl> {
+        self.inputs.iter().find(|pin| pin.name == name)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HdlErrorKind {
+    Io(String),
+    Syntax(String),
+    UnknownChip(String),
+    UnknownBuiltIn(String),
+    UnknownPin {
+        chip: String,
+        pin: String,
+    },
+    BadSlice(String),
+    WidthMismatch {
+        pin: String,
+        inner: usize,
+        outer: usize,
+    },
+    DrivenTwice(String),
+    DrivesInput(String),
+    DrivesConstant(String),
+    RecursiveChip(String),
+    CombinationalLoop(Vec<String>),
+}
+
+/// fileは分かる場合だけ。lineは1始まりで、行に関係ないエラーは0
+#[derive(Debug, PartialEq)]
+pub struct HdlError {
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub kind: HdlErrorKind,
+}
+
+impl HdlError {
+    pub fn new(line: usize, kind: HdlErrorKind) -> HdlError {
+        HdlError {
+            file: None,
+            line,
+            kind,
+        }
+    }
+
+    /// まだファイルが付いていなければ付ける
+    pub fn in_file(mut self, file: &Option<PathBuf>) -> HdlError {
+        if self.file.is_none() {
+            self.file = file.clone();
+        }
+        self
+    }
+}
+
+impl fmt::Display for HdlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        if self.line > 0 {
+            write!(f, "{}:", self.line)?;
+        }
+        if self.file.is_some() || self.line > 0 {
+            write!(f, " ")?;
+        }
+        match &self.kind {
+            HdlErrorKind::Io(e) => write!(f, "{}", e),
+            HdlErrorKind::Syntax(message) => write!(f, "{}", message),
+            HdlErrorKind::UnknownChip(chip) => write!(f, "unknown chip `{}`", chip),
+            HdlErrorKind::UnknownBuiltIn(chip) => write!(f, "no built-in chip `{}`", chip),
+            HdlErrorKind::UnknownPin { chip, pin } => {
+                write!(f, "chip `{}` has no pin `{}`", chip, pin)
+            }
+            HdlErrorKind::BadSlice(pin) => write!(f, "invalid sub-bus `{}`", pin),
+            HdlErrorKind::WidthMismatch { pin, inner, outer } => write!(
+                f,
+                "width mismatch on `{}`: {} bits connected to {} bits",
+                pin, inner, outer
+            ),
+            HdlErrorKind::DrivenTwice(net) => {
+                write!(f, "`{}` is driven by more than one part", net)
+            }
+            HdlErrorKind::DrivesInput(pin) => write!(f, "a part output drives input pin `{}`", pin),
+            HdlErrorKind::DrivesConstant(pin) => {
+                write!(f, "a part output `{}` is connected to true/false", pin)
+            }
+            HdlErrorKind::RecursiveChip(chip) => write!(f, "chip `{}` contains itself", chip),
+            HdlErrorKind::CombinationalLoop(nets) => {
+                write!(f, "combinational loop through {}", nets.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for HdlError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(usize),
+    Symbol(char),
+    // ..
+    Range,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(s) => write!(f, "`{}`", s),
+            Token::Number(n) => write!(f, "`{}`", n),
+            Token::Symbol(c) => write!(f, "`{}`", c),
+            Token::Range => write!(f, "`..`"),
+        }
+    }
+}
+
+// (token, line)
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, HdlError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\n' => {
+                line += 1;
+                i += 1;
+            }
+            _ if c.is_whitespace() => i += 1,
+            '/' if chars.get(i + 1) == Some(&'/') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                let start_line = line;
+                i += 2;
+                loop {
+                    match chars.get(i) {
+                        None => {
+                            return Err(HdlError::new(
+                                start_line,
+                                HdlErrorKind::Syntax("unterminated comment".to_string()),
+                            ))
+                        }
+                        Some('*') if chars.get(i + 1) == Some(&'/') => {
+                            i += 2;
+                            break;
+                        }
+                        Some('\n') => line += 1,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+            }
+            '.' if chars.get(i + 1) == Some(&'.') => {
+                tokens.push((Token::Range, line));
+                i += 2;
+            }
+            '{' | '}' | '(' | ')' | '[' | ']' | ';' | ',' | '=' | ':' => {
+                tokens.push((Token::Symbol(c), line));
+                i += 1;
+            }
+            _ if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let s: String = chars[start..i].iter().collect();
+                let n = s.parse().map_err(|_| {
+                    HdlError::new(
+                        line,
+                        HdlErrorKind::Syntax(format!("invalid number `{}`", s)),
+                    )
+                })?;
+                tokens.push((Token::Number(n), line));
+            }
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push((Token::Ident(chars[start..i].iter().collect()), line));
+            }
+            _ => {
+                return Err(HdlError::new(
+                    line,
+                    HdlErrorKind::Syntax(format!("unexpected character `{}`", c)),
+                ))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        match self.tokens.get(self.position) {
+            Some((_, line)) => *line,
+            None => self.tokens.last().map(|(_, line)| *line).unwrap_or(1),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn error(&self, expected: &str) -> HdlError {
+        let message = match self.peek() {
+            Some(token) => format!("expected {}, found {}", expected, token),
+            None => format!("expected {}, found end of file", expected),
+        };
+        HdlError::new(self.line(), HdlErrorKind::Syntax(message))
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat_symbol(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(c)) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_symbol(&mut self, c: char) -> Result<(), HdlError> {
+        match self.eat_symbol(c) {
+            true => Ok(()),
+            false => Err(self.error(&format!("`{}`", c))),
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if let Some(Token::Ident(s)) = self.peek() {
+            if s == keyword {
+                self.position += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), HdlError> {
+        match self.eat_keyword(keyword) {
+            true => Ok(()),
+            false => Err(self.error(&format!("`{}`", keyword))),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, HdlError> {
+        match self.peek() {
+            Some(Token::Ident(s)) => {
+                let s = s.clone();
+                self.position += 1;
+                Ok(s)
+            }
+            _ => Err(self.error("a name")),
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, HdlError> {
+        match self.peek() {
+            Some(Token::Number(n)) => {
+                let n = *n;
+                self.position += 1;
+                Ok(n)
+            }
+            _ => Err(self.error("a number")),
+        }
+    }
+
+    fn chip(&mut self) -> Result<ChipDef, HdlError> {
+        self.expect_keyword("CHIP")?;
+        let name = self.ident()?;
+        self.expect_symbol('{')?;
+
+        let mut inputs = vec![];
+        let mut outputs = vec![];
+        if self.eat_keyword("IN") {
+            inputs = self.pin_decls()?;
+        }
+        if self.eat_keyword("OUT") {
+            outputs = self.pin_decls()?;
+        }
+
+        let line = self.line();
+        let body = if self.eat_keyword("BUILTIN") {
+            let name = self.ident()?;
+            self.expect_symbol(';')?;
+            let mut clocked = vec![];
+            if self.eat_keyword("CLOCKED") {
+                loop {
+                    clocked.push(self.ident()?);
+                    if !self.eat_symbol(',') {
+                        break;
+                    }
+                }
+                self.expect_symbol(';')?;
+            }
+            ChipBody::BuiltIn {
+                name,
+                clocked,
+                line,
+            }
+        } else {
+            self.expect_keyword("PARTS")?;
+            self.expect_symbol(':')?;
+            let mut parts = vec![];
+            while self.peek() != Some(&Token::Symbol('}')) && self.peek().is_some() {
+                parts.push(self.part()?);
+            }
+            ChipBody::Parts(parts)
+        };
+
+        self.expect_symbol('}')?;
+        if self.peek().is_some() {
+            return Err(self.error("end of file"));
+        }
+        Ok(ChipDef {
+            name,
+            inputs,
+            outputs,
+            body,
+        })
+    }
+
+    // a, b[16], c;
+    fn pin_decls(&mut self) -> Result<Vec<PinDecl>, HdlError> {
+        let mut pins = vec![];
+        loop {
+            let name = self.ident()?;
+            let mut width = 1;
+            if self.eat_symbol('[') {
+                width = self.number()?;
+                if width == 0 || width > 16 {
+                    return Err(HdlError::new(
+                        self.line(),
+                        HdlErrorKind::Syntax(format!("invalid width {} for `{}`", width, name)),
+                    ));
+                }
+                self.expect_symbol(']')?;
+            }
+            pins.push(PinDecl { name, width });
+            if !self.eat_symbol(',') {
+                break;
+            }
+        }
+        self.expect_symbol(';')?;
+        Ok(pins)
+    }
+
+    // Chip(a=x, b[0..7]=y[8..15], c=true);
+    fn part(&mut self) -> Result<PartDef, HdlError> {
+        let line = self.line();
+        let chip = self.ident()?;
+        self.expect_symbol('(')?;
+        let mut connections = vec![];
+        if !self.eat_symbol(')') {
+            loop {
+                let inner = self.pin_ref()?;
+                self.expect_symbol('=')?;
+                let outer = match self.peek() {
+                    Some(Token::Ident(s)) if s == "true" => {
+                        self.next();
+                        Wire::Const(true)
+                    }
+                    Some(Token::Ident(s)) if s == "false" => {
+                        self.next();
+                        Wire::Const(false)
+                    }
+                    _ => Wire::Pin(self.pin_ref()?),
+                };
+                connections.push(Connection { inner, outer });
+                if !self.eat_symbol(',') {
+                    break;
+                }
+            }
+            self.expect_symbol(')')?;
+        }
+        self.expect_symbol(';')?;
+        Ok(PartDef {
+            chip,
+            connections,
+            line,
+        })
+    }
+
+    fn pin_ref(&mut self) -> Result<PinRef, HdlError> {
+        let name = self.ident()?;
+        let mut range = None;
+        if self.eat_symbol('[') {
+            let start = self.number()?;
+            let end = match self.next() {
+                Some(Token::Range) => {
+                    let end = self.number()?;
+                    self.expect_symbol(']')?;
+                    end
+                }
+                Some(Token::Symbol(']')) => start,
+                _ => {
+                    self.position -= 1;
+                    return Err(self.error("`..` or `]`"));
+                }
+            };
+            range = Some((start, end));
+        }
+        Ok(PinRef { name, range })
+    }
+}
+
+/// .hdlのソースを1チップ分読む
+pub fn parse(source: &str) -> Result<ChipDef, HdlError> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+    };
+    parser.chip()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pin(name: &str, range: Option<(usize, usize)>) -> PinRef {
+        PinRef {
+            name: name.to_string(),
+            range,
+        }
+    }
+
+    #[test]
+    fn parse_parts() {
+        let source = "
+            /**
+             * 4bitのAnd (コメントも読み飛ばす)
+             */
+            CHIP And4 {
+                IN a[4], b[4], c;
+                OUT out[4], zero;   // 行コメント
+
+                PARTS:
+                And(a=a[0], b=b[0], out=out[0]);
+                And(a=a[1..3], b=true, out=out[1..3], out=x);
+                Not(in=false);
+            }
+        ";
+        let chip = parse(source).unwrap();
+        assert_eq!(chip.name, "And4");
+        assert_eq!(
+            chip.inputs,
+            vec![
+                PinDecl {
+                    name: "a".to_string(),
+                    width: 4
+                },
+                PinDecl {
+                    name: "b".to_string(),
+                    width: 4
+                },
+                PinDecl {
+                    name: "c".to_string(),
+                    width: 1
+                },
+            ]
+        );
+        assert_eq!(chip.outputs[1].name, "zero");
+        assert_eq!(chip.input("c").map(|p| p.width), Some(1));
+        let parts = match chip.body {
+            ChipBody::Parts(parts) => parts,
+            _ => panic!(),
+        };
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0].line, 10);
+        assert_eq!(
+            parts[1].connections,
+            vec![
+                Connection {
+                    inner: pin("a", None),
+                    outer: Wire::Pin(pin("a", Some((1, 3)))),
+                },
+                Connection {
+                    inner: pin("b", None),
+                    outer: Wire::Const(true),
+                },
+                Connection {
+                    inner: pin("out", None),
+                    outer: Wire::Pin(pin("out", Some((1, 3)))),
+                },
+                Connection {
+                    inner: pin("out", None),
+                    outer: Wire::Pin(pin("x", None)),
+                },
+            ]
+        );
+        assert_eq!(parts[2].chip, "Not");
+        assert_eq!(
+            parts[2].connections,
+            vec![Connection {
+                inner: pin("in", None),
+                outer: Wire::Const(false),
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_builtin() {
+        let source =
+            "CHIP Register { IN in[16], load; OUT out[16]; BUILTIN Register; CLOCKED in, load; }";
+        let chip = parse(source).unwrap();
+        assert_eq!(
+            chip.body,
+            ChipBody::BuiltIn {
+                name: "Register".to_string(),
+                clocked: vec!["in".to_string(), "load".to_string()],
+                line: 1,
+            }
+        );
+
+        let chip = parse("CHIP Keyboard { OUT out[16]; BUILTIN Keyboard; }").unwrap();
+        assert!(chip.inputs.is_empty());
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let cases = [
+            ("CHIP { }", 1, "expected a name, found `{`"),
+            (
+                "CHIP A {\n IN a[0];\n OUT b; PARTS: }",
+                2,
+                "invalid width 0 for `a`",
+            ),
+            (
+                "CHIP A {\n IN a;\n OUT b;\n PARTS:\n Not(in=a out=b);\n}",
+                5,
+                "expected `)`, found `out`",
+            ),
+            (
+                "CHIP A { IN a; OUT b; PARTS: Not(in=a[0;1], out=b); }",
+                1,
+                "expected `..` or `]`, found `;`",
+            ),
+            (
+                "CHIP A { IN a; OUT b; PARTS: Not(in=a, out=b) }",
+                1,
+                "expected `;`, found `}`",
+            ),
+            (
+                "CHIP A { IN a; OUT b; PARTS:",
+                1,
+                "expected `}`, found end of file",
+            ),
+            ("CHIP A { IN a#; }", 1, "unexpected character `#`"),
+            ("/* CHIP A {\n}", 1, "unterminated comment"),
+        ];
+        for (source, line, message) in cases {
+            let error = parse(source).unwrap_err();
+            assert_eq!(error.line, line, "{}", source);
+            assert_eq!(
+                error.kind,
+                HdlErrorKind::Syntax(message.to_string()),
+                "{}",
+                source
+            );
+        }
+
+        let mut error = parse("CHIP A { IN a; OUT b; PARTS: Not(in=a out=b); }").unwrap_err();
+        error.file = Some(PathBuf::from("A.hdl"));
+        assert_eq!(error.to_string(), "A.hdl:1: expected `)`, found `out`");
+    }
+}
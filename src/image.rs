@@ -0,0 +1,209 @@
+use std::{fs, io, path::Path};
+
+use crate::display::{pixel, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// バイナリ形式(P4)のPBM。1が黒
+pub fn encode_pbm(frame: &[u16]) -> Vec<u8> {
+    let mut data = format!("P4\n{} {}\n", SCREEN_WIDTH, SCREEN_HEIGHT).into_bytes();
+    for y in 0..SCREEN_HEIGHT {
+        data.extend(pack_row(frame, y, true));
+    }
+    data
+}
+
+/// 1bitグレースケールのPNG。圧縮はせずdeflateの無圧縮ブロックで格納する
+pub fn encode_png(frame: &[u16]) -> Vec<u8> {
+    let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+    let mut ihdr = vec![];
+    ihdr.extend((SCREEN_WIDTH as u32).to_be_bytes());
+    ihdr.extend((SCREEN_HEIGHT as u32).to_be_bytes());
+    // bit depth 1, color type 0(グレースケール), compression, filter, interlace
+    ihdr.extend([1, 0, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &ihdr);
+
+    let mut raw = vec![];
+    for y in 0..SCREEN_HEIGHT {
+        // 各行の先頭はフィルタの種類(0 = なし)。グレースケールでは0が黒なので反転する
+        raw.push(0);
+        raw.extend(pack_row(frame, y, false));
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// 拡張子(.pbmか.png)で形式を決めて保存する
+pub fn save_screenshot<P: AsRef<Path>>(path: P, frame: &[u16]) -> io::Result<()> {
+    let path = path.as_ref();
+    let data = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("pbm") => encode_pbm(frame),
+        Some("png") => encode_png(frame),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: screenshot must be .pbm or .png", path.display()),
+            ))
+        }
+    };
+    fs::write(path, data)
+}
+
+// 左のピクセルが上位bitになるように8ピクセルずつ詰める
+fn pack_row(frame: &[u16], y: usize, black: bool) -> Vec<u8> {
+    (0..SCREEN_WIDTH / 8)
+        .map(|byte| {
+            (0..8).fold(0, |acc, bit| {
+                let on = pixel(frame, byte * 8 + bit, y) == black;
+                acc | ((on as u8) << (7 - bit))
+            })
+        })
+        .collect()
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) -> () {
+    png.extend((data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend(kind);
+    png.extend(data);
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // CMF, FLG (deflate, 32Kのウィンドウ、圧縮レベル最低)
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend([1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        out.push(is_final as u8);
+        let len = block.len() as u16;
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend(block);
+    }
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_frame() -> Vec<u16> {
+        let mut frame = vec![0; 8192];
+        // 左上の1ピクセルと、2行目の先頭16ピクセル
+        frame[0] = 0b1;
+        frame[32] = 0xffff;
+        frame
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn pbm() {
+        let pbm = encode_pbm(&test_frame());
+        let header = b"P4\n512 256\n";
+        assert_eq!(&pbm[..header.len()], header);
+        let body = &pbm[header.len()..];
+        assert_eq!(body.len(), 64 * 256);
+        assert_eq!(&body[0..2], &[0b1000_0000, 0]);
+        assert_eq!(&body[64..67], &[0xff, 0xff, 0]);
+    }
+
+    #[test]
+    fn png() {
+        let png = encode_png(&test_frame());
+        assert_eq!(
+            &png[..8],
+            &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']
+        );
+
+        // チャンクを順に読んでCRCを確かめる
+        let mut chunks = vec![];
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[0..4].try_into().unwrap()) as usize;
+            let kind = &rest[4..8];
+            let data = &rest[8..8 + len];
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32(&rest[4..8 + len]));
+            chunks.push((kind.to_vec(), data.to_vec()));
+            rest = &rest[12 + len..];
+        }
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, vec![&b"IHDR"[..], b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, vec![0, 0, 2, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0]);
+
+        // 無圧縮ブロックをほどいて中身を確かめる
+        let zlib = &chunks[1].1;
+        let mut raw = vec![];
+        let mut block = &zlib[2..zlib.len() - 4];
+        loop {
+            let is_final = block[0] & 1 == 1;
+            let len = u16::from_le_bytes([block[1], block[2]]) as usize;
+            assert_eq!(!len as u16, u16::from_le_bytes([block[3], block[4]]));
+            raw.extend(&block[5..5 + len]);
+            block = &block[5 + len..];
+            if is_final {
+                break;
+            }
+        }
+        assert_eq!(
+            u32::from_be_bytes(zlib[zlib.len() - 4..].try_into().unwrap()),
+            adler32(&raw)
+        );
+        assert_eq!(raw.len(), 65 * 256);
+        // 0が黒
+        assert_eq!(&raw[0..3], &[0, 0b0111_1111, 0xff]);
+        assert_eq!(&raw[65..69], &[0, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn save() {
+        let dir = std::env::temp_dir();
+        let png = dir.join("nand2tetris_my_hs_screenshot.png");
+        let pbm = dir.join("nand2tetris_my_hs_screenshot.pbm");
+        save_screenshot(&png, &test_frame()).unwrap();
+        save_screenshot(&pbm, &test_frame()).unwrap();
+        assert_eq!(fs::read(&png).unwrap(), encode_png(&test_frame()));
+        assert_eq!(fs::read(&pbm).unwrap(), encode_pbm(&test_frame()));
+        fs::remove_file(&png).unwrap();
+        fs::remove_file(&pbm).unwrap();
+
+        let bmp = dir.join("nand2tetris_my_hs_screenshot.bmp");
+        assert!(save_screenshot(&bmp, &test_frame()).is_err());
+    }
+}
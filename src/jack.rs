@@ -0,0 +1,931 @@
+use std::{collections::HashMap, fmt, path::Path};
+
+use crate::vm::read_sources;
+
+const KEYWORDS: [&str; 21] = [
+    "class",
+    "constructor",
+    "function",
+    "method",
+    "field",
+    "static",
+    "var",
+    "int",
+    "char",
+    "boolean",
+    "void",
+    "true",
+    "false",
+    "null",
+    "this",
+    "let",
+    "do",
+    "if",
+    "else",
+    "while",
+    "return",
+];
+
+const SYMBOLS: &str = "{}()[].,;+-*/&|<>=~";
+
+#[derive(Debug, PartialEq)]
+pub enum JackErrorKind {
+    Io(String),
+    NoJackFiles,
+    UnterminatedComment,
+    UnterminatedString,
+    InvalidChar(char),
+    IntegerOutOfRange(String),
+    /// (期待したもの, 実際のトークン)
+    Unexpected {
+        expected: String,
+        found: String,
+    },
+    ClassNameMismatch(String),
+    UndefinedVariable(String),
+    DuplicateVariable(String),
+    ThisInFunction,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct JackError {
+    /// 拡張子なしのファイル名
+    pub file: String,
+    /// 1始まりの行番号。ファイル全体のエラーなら0
+    pub line: usize,
+    pub kind: JackErrorKind,
+}
+
+impl fmt::Display for JackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}.jack:", self.file)?;
+        }
+        if self.line > 0 {
+            write!(f, "{}:", self.line)?;
+        }
+        if !self.file.is_empty() || self.line > 0 {
+            write!(f, " ")?;
+        }
+        match &self.kind {
+            JackErrorKind::Io(e) => write!(f, "{}", e),
+            JackErrorKind::NoJackFiles => write!(f, "no .jack files"),
+            JackErrorKind::UnterminatedComment => write!(f, "unterminated comment"),
+            JackErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            JackErrorKind::InvalidChar(c) => write!(f, "invalid character `{}`", c),
+            JackErrorKind::IntegerOutOfRange(s) => {
+                write!(f, "integer `{}` is out of range (0..=32767)", s)
+            }
+            JackErrorKind::Unexpected { expected, found } => {
+                write!(f, "expected {}, found {}", expected, found)
+            }
+            JackErrorKind::ClassNameMismatch(name) => {
+                write!(f, "class `{}` must be in {}.jack", name, name)
+            }
+            JackErrorKind::UndefinedVariable(s) => write!(f, "undefined variable `{}`", s),
+            JackErrorKind::DuplicateVariable(s) => write!(f, "duplicate variable `{}`", s),
+            JackErrorKind::ThisInFunction => write!(f, "`this` cannot be used in a function"),
+        }
+    }
+}
+
+impl std::error::Error for JackError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Keyword(&'static str),
+    Symbol(char),
+    Int(u16),
+    Str(String),
+    Ident(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Keyword(k) => write!(f, "`{}`", k),
+            Token::Symbol(c) => write!(f, "`{}`", c),
+            Token::Int(n) => write!(f, "`{}`", n),
+            Token::Str(s) => write!(f, "\"{}\"", s),
+            Token::Ident(s) => write!(f, "`{}`", s),
+        }
+    }
+}
+
+// (行, トークン) の列にする
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, (usize, JackErrorKind)> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    chars.next();
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let start = line;
+                let mut last = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if last == '*' => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            last = c;
+                        }
+                        None => return Err((start, JackErrorKind::UnterminatedComment)),
+                    }
+                }
+            }
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\n') | None => return Err((line, JackErrorKind::UnterminatedString)),
+                        Some(c) => s.push(c),
+                    }
+                }
+                tokens.push((line, Token::Str(s)));
+            }
+            c if SYMBOLS.contains(c) => tokens.push((line, Token::Symbol(c))),
+            c if c.is_ascii_digit() => {
+                let mut digits = c.to_string();
+                while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    digits.push(*c);
+                    chars.next();
+                }
+                let n = digits
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|n| *n <= 32767)
+                    .ok_or((line, JackErrorKind::IntegerOutOfRange(digits.clone())))?;
+                tokens.push((line, Token::Int(n)));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some(c) = chars
+                    .peek()
+                    .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+                {
+                    word.push(*c);
+                    chars.next();
+                }
+                match KEYWORDS.iter().find(|k| **k == word) {
+                    Some(keyword) => tokens.push((line, Token::Keyword(keyword))),
+                    None => tokens.push((line, Token::Ident(word))),
+                }
+            }
+            c => return Err((line, JackErrorKind::InvalidChar(c))),
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Static,
+    Field,
+    Argument,
+    Local,
+}
+
+impl Kind {
+    fn segment(&self) -> &'static str {
+        match self {
+            Kind::Static => "static",
+            Kind::Field => "this",
+            Kind::Argument => "argument",
+            Kind::Local => "local",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Symbol {
+    type_name: String,
+    kind: Kind,
+    index: u16,
+}
+
+// クラスのstatic/fieldか、サブルーチンのargument/local
+#[derive(Debug, Default)]
+struct SymbolTable {
+    symbols: HashMap<String, Symbol>,
+}
+
+impl SymbolTable {
+    fn count(&self, kind: Kind) -> u16 {
+        self.symbols.values().filter(|s| s.kind == kind).count() as u16
+    }
+
+    // すでにあればfalse
+    fn define(&mut self, name: &str, type_name: &str, kind: Kind) -> bool {
+        if self.symbols.contains_key(name) {
+            return false;
+        }
+        let index = self.count(kind);
+        let symbol = Symbol {
+            type_name: type_name.to_string(),
+            kind,
+            index,
+        };
+        self.symbols.insert(name.to_string(), symbol);
+        true
+    }
+}
+
+// 字句を読みながらそのままVMコードを出す
+struct Compiler<'a> {
+    file: &'a str,
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    class_name: String,
+    class_symbols: SymbolTable,
+    symbols: SymbolTable,
+    // サブルーチンの種類 (constructor/function/method)
+    subroutine_kind: &'static str,
+    label_count: usize,
+    vm: String,
+}
+
+impl<'a> Compiler<'a> {
+    fn error(&self, kind: JackErrorKind) -> JackError {
+        let line = self
+            .tokens
+            .get(self.pos)
+            .or(self.tokens.last())
+            .map_or(0, |(line, _)| *line);
+        JackError {
+            file: self.file.to_string(),
+            line,
+            kind,
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> JackError {
+        let found = match self.peek() {
+            Some(token) => token.to_string(),
+            None => "end of file".to_string(),
+        };
+        self.error(JackErrorKind::Unexpected {
+            expected: expected.to_string(),
+            found,
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + offset).map(|(_, token)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn is_symbol(&self, c: char) -> bool {
+        self.peek() == Some(&Token::Symbol(c))
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Keyword(k)) if *k == keyword)
+    }
+
+    fn symbol(&mut self, c: char) -> Result<(), JackError> {
+        if !self.is_symbol(c) {
+            return Err(self.unexpected(&format!("`{}`", c)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), JackError> {
+        if !self.is_keyword(keyword) {
+            return Err(self.unexpected(&format!("`{}`", keyword)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn identifier(&mut self) -> Result<String, JackError> {
+        match self.peek() {
+            Some(Token::Ident(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected("an identifier")),
+        }
+    }
+
+    // int/char/boolean かクラス名。allow_voidならvoidも
+    fn type_name(&mut self, allow_void: bool) -> Result<String, JackError> {
+        match self.peek() {
+            Some(Token::Keyword(k @ ("int" | "char" | "boolean"))) => {
+                let k = k.to_string();
+                self.pos += 1;
+                Ok(k)
+            }
+            Some(Token::Keyword("void")) if allow_void => {
+                self.pos += 1;
+                Ok("void".to_string())
+            }
+            Some(Token::Ident(_)) => self.identifier(),
+            _ => Err(self.unexpected("a type")),
+        }
+    }
+
+    fn emit(&mut self, line: &str) -> () {
+        self.vm.push_str(line);
+        self.vm.push('\n');
+    }
+
+    fn new_label(&mut self, kind: &str) -> String {
+        let label = format!("{}{}", kind, self.label_count);
+        self.label_count += 1;
+        label
+    }
+
+    fn lookup(&self, name: &str) -> Option<Symbol> {
+        self.symbols
+            .symbols
+            .get(name)
+            .or(self.class_symbols.symbols.get(name))
+            .cloned()
+    }
+
+    fn variable(&self, name: &str) -> Result<Symbol, JackError> {
+        self.lookup(name)
+            .ok_or_else(|| self.error(JackErrorKind::UndefinedVariable(name.to_string())))
+    }
+
+    fn push_variable(&mut self, symbol: &Symbol) -> () {
+        self.emit(&format!("push {} {}", symbol.kind.segment(), symbol.index));
+    }
+
+    fn compile_class(&mut self) -> Result<(), JackError> {
+        self.keyword("class")?;
+        let name = self.identifier()?;
+        if name != self.file {
+            self.pos -= 1;
+            return Err(self.error(JackErrorKind::ClassNameMismatch(name)));
+        }
+        self.class_name = name;
+        self.symbol('{')?;
+        while self.is_keyword("static") || self.is_keyword("field") {
+            let kind = match self.is_keyword("static") {
+                true => Kind::Static,
+                false => Kind::Field,
+            };
+            self.pos += 1;
+            self.var_names(kind)?;
+        }
+        while self.is_keyword("constructor")
+            || self.is_keyword("function")
+            || self.is_keyword("method")
+        {
+            self.compile_subroutine()?;
+        }
+        self.symbol('}')?;
+        if self.peek().is_some() {
+            return Err(self.unexpected("end of file"));
+        }
+        Ok(())
+    }
+
+    // type name (, name)* ;
+    fn var_names(&mut self, kind: Kind) -> Result<(), JackError> {
+        let type_name = self.type_name(false)?;
+        loop {
+            let name = self.identifier()?;
+            let table = match kind {
+                Kind::Static | Kind::Field => &mut self.class_symbols,
+                _ => &mut self.symbols,
+            };
+            if !table.define(&name, &type_name, kind) {
+                self.pos -= 1;
+                return Err(self.error(JackErrorKind::DuplicateVariable(name)));
+            }
+            if !self.is_symbol(',') {
+                break;
+            }
+            self.pos += 1;
+        }
+        self.symbol(';')
+    }
+
+    fn compile_subroutine(&mut self) -> Result<(), JackError> {
+        self.subroutine_kind = match self.next() {
+            Some(Token::Keyword(k)) => k,
+            _ => unreachable!(),
+        };
+        self.symbols = SymbolTable::default();
+        self.label_count = 0;
+        if self.subroutine_kind == "method" {
+            let class_name = self.class_name.clone();
+            self.symbols.define("this", &class_name, Kind::Argument);
+        }
+        self.type_name(true)?;
+        let name = self.identifier()?;
+
+        self.symbol('(')?;
+        if !self.is_symbol(')') {
+            loop {
+                let type_name = self.type_name(false)?;
+                let arg = self.identifier()?;
+                if !self.symbols.define(&arg, &type_name, Kind::Argument) {
+                    self.pos -= 1;
+                    return Err(self.error(JackErrorKind::DuplicateVariable(arg)));
+                }
+                if !self.is_symbol(',') {
+                    break;
+                }
+                self.pos += 1;
+            }
+        }
+        self.symbol(')')?;
+
+        self.symbol('{')?;
+        while self.is_keyword("var") {
+            self.pos += 1;
+            self.var_names(Kind::Local)?;
+        }
+        let locals = self.symbols.count(Kind::Local);
+        self.emit(&format!("function {}.{} {}", self.class_name, name, locals));
+        match self.subroutine_kind {
+            "constructor" => {
+                let fields = self.class_symbols.count(Kind::Field);
+                self.emit(&format!("push constant {}", fields));
+                self.emit("call Memory.alloc 1");
+                self.emit("pop pointer 0");
+            }
+            "method" => {
+                self.emit("push argument 0");
+                self.emit("pop pointer 0");
+            }
+            _ => {}
+        }
+        self.compile_statements()?;
+        self.symbol('}')
+    }
+
+    fn compile_statements(&mut self) -> Result<(), JackError> {
+        loop {
+            match self.peek() {
+                Some(Token::Keyword("let")) => self.compile_let()?,
+                Some(Token::Keyword("if")) => self.compile_if()?,
+                Some(Token::Keyword("while")) => self.compile_while()?,
+                Some(Token::Keyword("do")) => self.compile_do()?,
+                Some(Token::Keyword("return")) => self.compile_return()?,
+                Some(Token::Symbol('}')) => return Ok(()),
+                _ => return Err(self.unexpected("a statement")),
+            }
+        }
+    }
+
+    fn compile_let(&mut self) -> Result<(), JackError> {
+        self.keyword("let")?;
+        let name = self.identifier()?;
+        self.pos -= 1;
+        let symbol = self.variable(&name)?;
+        self.pos += 1;
+        if self.is_symbol('[') {
+            // 添字の番地を先に積んでおき、右辺を temp 0 に退避してから書き込む
+            self.pos += 1;
+            self.push_variable(&symbol);
+            self.compile_expression()?;
+            self.symbol(']')?;
+            self.emit("add");
+            self.symbol('=')?;
+            self.compile_expression()?;
+            self.emit("pop temp 0");
+            self.emit("pop pointer 1");
+            self.emit("push temp 0");
+            self.emit("pop that 0");
+        } else {
+            self.symbol('=')?;
+            self.compile_expression()?;
+            self.emit(&format!("pop {} {}", symbol.kind.segment(), symbol.index));
+        }
+        self.symbol(';')
+    }
+
+    fn compile_if(&mut self) -> Result<(), JackError> {
+        let else_label = self.new_label("IF_ELSE");
+        let end_label = self.new_label("IF_END");
+        self.keyword("if")?;
+        self.symbol('(')?;
+        self.compile_expression()?;
+        self.symbol(')')?;
+        self.emit("not");
+        self.emit(&format!("if-goto {}", else_label));
+        self.symbol('{')?;
+        self.compile_statements()?;
+        self.symbol('}')?;
+        self.emit(&format!("goto {}", end_label));
+        self.emit(&format!("label {}", else_label));
+        if self.is_keyword("else") {
+            self.pos += 1;
+            self.symbol('{')?;
+            self.compile_statements()?;
+            self.symbol('}')?;
+        }
+        self.emit(&format!("label {}", end_label));
+        Ok(())
+    }
+
+    fn compile_while(&mut self) -> Result<(), JackError> {
+        let loop_label = self.new_label("WHILE_EXP");
+        let end_label = self.new_label("WHILE_END");
+        self.keyword("while")?;
+        self.emit(&format!("label {}", loop_label));
+        self.symbol('(')?;
+        self.compile_expression()?;
+        self.symbol(')')?;
+        self.emit("not");
+        self.emit(&format!("if-goto {}", end_label));
+        self.symbol('{')?;
+        self.compile_statements()?;
+        self.symbol('}')?;
+        self.emit(&format!("goto {}", loop_label));
+        self.emit(&format!("label {}", end_label));
+        Ok(())
+    }
+
+    fn compile_do(&mut self) -> Result<(), JackError> {
+        self.keyword("do")?;
+        let name = self.identifier()?;
+        self.compile_call(name)?;
+        // 戻り値は捨てる
+        self.emit("pop temp 0");
+        self.symbol(';')
+    }
+
+    fn compile_return(&mut self) -> Result<(), JackError> {
+        self.keyword("return")?;
+        if self.is_symbol(';') {
+            self.emit("push constant 0");
+        } else {
+            self.compile_expression()?;
+        }
+        self.emit("return");
+        self.symbol(';')
+    }
+
+    // name(...) / name.sub(...) の name を読んだところから
+    fn compile_call(&mut self, name: String) -> Result<(), JackError> {
+        let (function, mut args) = if self.is_symbol('.') {
+            self.pos += 1;
+            let subroutine = self.identifier()?;
+            match self.lookup(&name) {
+                // 変数ならそのオブジェクトのメソッド
+                Some(symbol) => {
+                    self.push_variable(&symbol);
+                    (format!("{}.{}", symbol.type_name, subroutine), 1)
+                }
+                None => (format!("{}.{}", name, subroutine), 0),
+            }
+        } else {
+            // 同じクラスのメソッド
+            if self.subroutine_kind == "function" {
+                return Err(self.error(JackErrorKind::ThisInFunction));
+            }
+            self.emit("push pointer 0");
+            (format!("{}.{}", self.class_name, name), 1)
+        };
+        self.symbol('(')?;
+        if !self.is_symbol(')') {
+            loop {
+                self.compile_expression()?;
+                args += 1;
+                if !self.is_symbol(',') {
+                    break;
+                }
+                self.pos += 1;
+            }
+        }
+        self.symbol(')')?;
+        self.emit(&format!("call {} {}", function, args));
+        Ok(())
+    }
+
+    fn compile_expression(&mut self) -> Result<(), JackError> {
+        self.compile_term()?;
+        while let Some(Token::Symbol(op @ ('+' | '-' | '*' | '/' | '&' | '|' | '<' | '>' | '='))) =
+            self.peek()
+        {
+            let op = *op;
+            self.pos += 1;
+            self.compile_term()?;
+            self.emit(match op {
+                '+' => "add",
+                '-' => "sub",
+                '*' => "call Math.multiply 2",
+                '/' => "call Math.divide 2",
+                '&' => "and",
+                '|' => "or",
+                '<' => "lt",
+                '>' => "gt",
+                _ => "eq",
+            });
+        }
+        Ok(())
+    }
+
+    fn compile_term(&mut self) -> Result<(), JackError> {
+        match self.peek().cloned() {
+            Some(Token::Int(n)) => {
+                self.pos += 1;
+                self.emit(&format!("push constant {}", n));
+            }
+            Some(Token::Str(s)) => {
+                self.pos += 1;
+                self.emit(&format!("push constant {}", s.chars().count()));
+                self.emit("call String.new 1");
+                for c in s.chars() {
+                    self.emit(&format!("push constant {}", c as u32));
+                    self.emit("call String.appendChar 2");
+                }
+            }
+            Some(Token::Keyword("true")) => {
+                self.pos += 1;
+                self.emit("push constant 0");
+                self.emit("not");
+            }
+            Some(Token::Keyword("false")) | Some(Token::Keyword("null")) => {
+                self.pos += 1;
+                self.emit("push constant 0");
+            }
+            Some(Token::Keyword("this")) => {
+                if self.subroutine_kind == "function" {
+                    return Err(self.error(JackErrorKind::ThisInFunction));
+                }
+                self.pos += 1;
+                self.emit("push pointer 0");
+            }
+            Some(Token::Symbol('(')) => {
+                self.pos += 1;
+                self.compile_expression()?;
+                self.symbol(')')?;
+            }
+            Some(Token::Symbol(op @ ('-' | '~'))) => {
+                self.pos += 1;
+                self.compile_term()?;
+                self.emit(if op == '-' { "neg" } else { "not" });
+            }
+            Some(Token::Ident(name)) => match self.peek_at(1) {
+                Some(Token::Symbol('(')) | Some(Token::Symbol('.')) => {
+                    self.pos += 1;
+                    self.compile_call(name)?;
+                }
+                Some(Token::Symbol('[')) => {
+                    let symbol = self.variable(&name)?;
+                    self.pos += 2;
+                    self.push_variable(&symbol);
+                    self.compile_expression()?;
+                    self.symbol(']')?;
+                    self.emit("add");
+                    self.emit("pop pointer 1");
+                    self.emit("push that 0");
+                }
+                _ => {
+                    let symbol = self.variable(&name)?;
+                    self.pos += 1;
+                    self.push_variable(&symbol);
+                }
+            },
+            _ => return Err(self.unexpected("an expression")),
+        }
+        Ok(())
+    }
+}
+
+/// 1クラス分の.jackをVMコードにする。fileは拡張子なしのファイル名で、クラス名と同じでないといけない
+pub fn compile(file: &str, source: &str) -> Result<String, JackError> {
+    let tokens = tokenize(source).map_err(|(line, kind)| JackError {
+        file: file.to_string(),
+        line,
+        kind,
+    })?;
+    let mut compiler = Compiler {
+        file,
+        tokens,
+        pos: 0,
+        class_name: String::new(),
+        class_symbols: SymbolTable::default(),
+        symbols: SymbolTable::default(),
+        subroutine_kind: "function",
+        label_count: 0,
+        vm: String::new(),
+    };
+    compiler.compile_class()?;
+    Ok(compiler.vm)
+}
+
+/// .jackファイル1つか、ディレクトリの中の.jackを全部コンパイルして (ファイル名, VMコード) を返す
+pub fn compile_path<P: AsRef<Path>>(path: P) -> Result<Vec<(String, String)>, JackError> {
+    let path = path.as_ref();
+    let sources = read_sources(path, "jack").map_err(|e| JackError {
+        file: String::new(),
+        line: 0,
+        kind: JackErrorKind::Io(format!("{}: {}", path.display(), e)),
+    })?;
+    if sources.is_empty() {
+        return Err(JackError {
+            file: String::new(),
+            line: 0,
+            kind: JackErrorKind::NoJackFiles,
+        });
+    }
+    sources
+        .iter()
+        .map(|(name, source)| Ok((name.clone(), compile(name, source)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assembler::assemble,
+        computer::{Computer, ROM32KBuiltIn},
+        gate::*,
+        vm::translate,
+    };
+
+    #[test]
+    fn tokens() {
+        let source = "/** doc\n */ let s = \"a b\"; // x\nlet x[12] = -_y1;";
+        let tokens: Vec<(usize, Token)> = tokenize(source).unwrap();
+        assert_eq!(tokens[0], (2, Token::Keyword("let")));
+        assert_eq!(tokens[3], (2, Token::Str("a b".to_string())));
+        assert_eq!(tokens[7], (3, Token::Symbol('[')));
+        assert_eq!(tokens[8], (3, Token::Int(12)));
+        assert_eq!(tokens[12], (3, Token::Ident("_y1".to_string())));
+        assert_eq!(
+            tokenize("\nlet x = 32768;"),
+            Err((2, JackErrorKind::IntegerOutOfRange("32768".to_string())))
+        );
+        assert_eq!(
+            tokenize("/* ..."),
+            Err((1, JackErrorKind::UnterminatedComment))
+        );
+        assert_eq!(tokenize("x # y"), Err((1, JackErrorKind::InvalidChar('#'))));
+    }
+
+    #[test]
+    fn code_generation() {
+        let source = "class Main {
+            static int count;
+            function void main() {
+                var Array a;
+                let a[count] = \"Hi\";
+                do Output.printInt(1 + (2 * 3));
+                if (~(count < 0)) { let count = -1; } else { let count = true; }
+                return;
+            }
+        }";
+        let expected = "function Main.main 1
+push local 0\npush static 0\nadd
+push constant 2\ncall String.new 1\npush constant 72\ncall String.appendChar 2
+push constant 105\ncall String.appendChar 2
+pop temp 0\npop pointer 1\npush temp 0\npop that 0
+push constant 1\npush constant 2\npush constant 3\ncall Math.multiply 2\nadd
+call Output.printInt 1\npop temp 0
+push static 0\npush constant 0\nlt\nnot\nnot\nif-goto IF_ELSE0
+push constant 1\nneg\npop static 0\ngoto IF_END1
+label IF_ELSE0\npush constant 0\nnot\npop static 0\nlabel IF_END1
+push constant 0\nreturn
+";
+        assert_eq!(compile("Main", source).unwrap(), expected);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source: &str| compile("Main", source).unwrap_err();
+        assert_eq!(
+            error("class Main {\n function void f() {\n let x = 1;\n return;\n }\n}"),
+            JackError {
+                file: "Main".to_string(),
+                line: 3,
+                kind: JackErrorKind::UndefinedVariable("x".to_string()),
+            }
+        );
+        assert_eq!(
+            error("class Main {\n function int f() {\n return 1\n }\n}").to_string(),
+            "Main.jack:4: expected `;`, found `}`"
+        );
+        assert_eq!(
+            error("class Foo {}").kind,
+            JackErrorKind::ClassNameMismatch("Foo".to_string())
+        );
+        assert_eq!(
+            error("class Main { field int x, x; }").kind,
+            JackErrorKind::DuplicateVariable("x".to_string())
+        );
+        assert_eq!(
+            error("class Main { function int f() { return this; } }").kind,
+            JackErrorKind::ThisInFunction
+        );
+        assert_eq!(
+            error("class Main { function void f() { do g(); return; } }").kind,
+            JackErrorKind::ThisInFunction
+        );
+        assert_eq!(
+            error("class Main { method void f() { let } }").to_string(),
+            "Main.jack:1: expected an identifier, found `}`"
+        );
+    }
+
+    // Memory/Math/ArrayはテストのためだけのJack実装、SysはVMコード
+    const MEMORY: &str = "class Memory {
+        static int free;
+        function int alloc(int size) {
+            var int p;
+            if (free = 0) { let free = 2048; }
+            let p = free;
+            let free = free + size;
+            return p;
+        }
+    }";
+    const MATH: &str = "class Math {
+        function int multiply(int x, int y) {
+            var int sum;
+            if (y < 0) { return -Math.multiply(x, -y); }
+            while (y > 0) { let sum = sum + x; let y = y - 1; }
+            return sum;
+        }
+    }";
+    const ARRAY: &str = "class Array {
+        function Array new(int size) { return Memory.alloc(size); }
+    }";
+    const SYS: &str = "function Sys.init 0\ncall Main.main 0\npop temp 0\nlabel HALT\ngoto HALT\n";
+
+    #[test]
+    fn run_on_computer() {
+        let point = "class Point {
+            field int x, y;
+            static int created;
+            constructor Point new(int ax, int ay) {
+                let x = ax; let y = ay;
+                let created = created + 1;
+                return this;
+            }
+            method int dot(Point other) { return (x * other.getX()) + (y * other.getY()); }
+            method int getX() { return x; }
+            method int getY() { return y; }
+            function int created() { return created; }
+        }";
+        let main = "class Main {
+            function void main() {
+                var Array out, squares;
+                var Point p, q;
+                var int i;
+                let out = 100;
+                let p = Point.new(3, -4);
+                let q = Point.new(2, 5);
+                let out[0] = p.dot(q);
+                let squares = Array.new(5);
+                while (i < 5) { let squares[i] = i * i; let i = i + 1; }
+                let out[1] = squares[4] - squares[2];
+                let out[2] = Point.created();
+                if ((out[0] < 0) & ~(out[1] = 0)) { let out[3] = 1; } else { let out[3] = 2; }
+                return;
+            }
+        }";
+        let mut files = vec![];
+        for (name, source) in [
+            ("Main", main),
+            ("Point", point),
+            ("Memory", MEMORY),
+            ("Math", MATH),
+            ("Array", ARRAY),
+        ] {
+            files.push((name.to_string(), compile(name, source).unwrap()));
+        }
+        files.push(("Sys".to_string(), SYS.to_string()));
+        let files: Vec<(&str, &str)> = files
+            .iter()
+            .map(|(name, vm)| (name.as_str(), vm.as_str()))
+            .collect();
+        let asm = translate(&files, true).unwrap();
+        let rom = ROM32KBuiltIn::from_words(&assemble(&asm).unwrap(), Bus::all0().to_shared_bus());
+        let computer = Computer::new(Bus::all0().to_shared_bus(), rom);
+        computer.run_until_halt().unwrap();
+
+        let out: Vec<i16> = (100..104).map(|a| computer.peek(a) as i16).collect();
+        // 3*2 + -4*5 = -14、16-4 = 12
+        assert_eq!(out, vec![-14, 12, 2, 1]);
+    }
+}
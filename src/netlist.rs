@@ -0,0 +1,1475 @@
+use std::{
+    any::type_name,
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    fmt,
+    rc::Rc,
+};
+
+use crate::gate::*;
+
+/// Netlistに変換できなかった理由
+#[derive(Debug, PartialEq)]
+pub enum NetlistError {
+    /// NandとDFFに展開できないチップ (組み込みのRAMなど)
+    Unsupported(String),
+    /// ひとつのnetを複数のノードが出力している
+    DrivenTwice(usize),
+    /// DFFを挟まずに自分自身に戻ってくるnet
+    CombinationalLoop(Vec<usize>),
+}
+
+impl NetlistError {
+    pub fn unsupported<T: ?Sized>() -> NetlistError {
+        let name = chip_name::<T>();
+        let name = name.split('<').next().unwrap_or(&name);
+        NetlistError::Unsupported(name.to_string())
+    }
+}
+
+// パスを外してチップ名だけにする。Mux<16>のような型引数は残す
+fn chip_name<T: ?Sized>() -> String {
+    let name = type_name::<T>();
+    let base = name.split('<').next().unwrap_or(name);
+    let start = base.rfind("::").map_or(0, |i| i + 2);
+    name[start..].to_string()
+}
+
+impl fmt::Display for NetlistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetlistError::Unsupported(name) => {
+                write!(f, "`{}` cannot be flattened into Nand and DFF", name)
+            }
+            NetlistError::DrivenTwice(net) => write!(f, "net {} is driven more than once", net),
+            NetlistError::CombinationalLoop(nets) => {
+                let nets: Vec<String> = nets.iter().map(|net| net.to_string()).collect();
+                write!(f, "combinational loop through nets {}", nets.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for NetlistError {}
+
+/// Nand。a, b, outはnetの番号
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NandNode {
+    pub a: usize,
+    pub b: usize,
+    pub out: usize,
+}
+
+/// DFF。input, outはnetの番号
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DffNode {
+    pub input: usize,
+    pub out: usize,
+}
+
+/// NandとDFFに展開できない組み込みのチップ (ROMやRAM) をそのまま置いたノード
+/// 入力のnetの値をチップのbitに入れてre_computeし、出力のbitの値をnetに戻す
+struct BuiltInNode {
+    chip: Rc<dyn Gate>,
+    // 値が変わったら計算し直す入力
+    inputs: Vec<(usize, SharedBit)>,
+    // クロックのときだけ読む入力 (RAMのinやloadなど)。出力はこれに依存しない
+    clocked: Vec<(usize, SharedBit)>,
+    outputs: Vec<(usize, SharedBit)>,
+    // Netlistのscheduleでの位置
+    rank: usize,
+}
+
+impl BuiltInNode {
+    fn renumber(self, id: impl Fn(usize) -> usize) -> BuiltInNode {
+        let renumber = |pins: Vec<(usize, SharedBit)>| {
+            pins.into_iter().map(|(net, bit)| (id(net), bit)).collect()
+        };
+        BuiltInNode {
+            inputs: renumber(self.inputs),
+            clocked: renumber(self.clocked),
+            outputs: renumber(self.outputs),
+            ..self
+        }
+    }
+}
+
+impl fmt::Debug for BuiltInNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nets = |pins: &[(usize, SharedBit)]| -> Vec<usize> {
+            pins.iter().map(|(net, _)| *net).collect()
+        };
+        f.debug_struct("BuiltInNode")
+            .field("inputs", &nets(&self.inputs))
+            .field("clocked", &nets(&self.clocked))
+            .field("outputs", &nets(&self.outputs))
+            .finish()
+    }
+}
+
+/// Gate::flattenで回路をたどりながらNandとDFFを集める
+/// SharedBitの同じものは同じnetになる
+#[derive(Debug, Default)]
+pub struct NetlistBuilder {
+    ids: HashMap<*const Cell<Bit>, usize>,
+    bits: Vec<SharedBit>,
+    // union-find。connectでつないだnetをまとめる
+    parent: Vec<usize>,
+    nands: Vec<NandNode>,
+    dffs: Vec<DffNode>,
+    states: Vec<bool>,
+    builtins: Vec<BuiltInNode>,
+    // countのときだけ使う。partでたどっている途中の部品で、最後が今flattenしている部品
+    counting: Vec<GateCount>,
+    // 部品ごとの "alu.add16.full_adder3" のような名前と、その中のNandとDFFの数
+    scopes: Vec<(String, usize)>,
+    // partでたどっている途中の部品のscopesでの番号
+    path: Vec<usize>,
+    // netごとに、出力しているNandかDFFが (どの部品の, 何番目か)
+    origins: Vec<Option<(usize, usize)>>,
+}
+
+impl NetlistBuilder {
+    pub fn new() -> NetlistBuilder {
+        NetlistBuilder::default()
+    }
+
+    /// 部品をflattenする。nameは部品のフィールド名
+    pub fn part<G: Gate>(&mut self, name: &str, gate: &G) -> Result<(), NetlistError> {
+        self.named_part(name, &chip_name::<G>(), gate)
+    }
+
+    /// HDLの組み込みチップのように型の分からない部品をflattenする
+    pub fn named_part(
+        &mut self,
+        name: &str,
+        chip: &str,
+        gate: &dyn Gate,
+    ) -> Result<(), NetlistError> {
+        self.enter(name);
+        if self.counting.is_empty() {
+            let result = gate.flatten(self);
+            self.path.pop();
+            return result;
+        }
+        self.counting.push(GateCount::new(name, chip));
+        let result = gate.flatten(self);
+        self.path.pop();
+        let mut count = self.counting.pop().unwrap();
+        // 数えるときは展開できない部品を組み込みとして数えて続ける
+        let result = match result {
+            Err(NetlistError::Unsupported(_)) => {
+                count.builtin = true;
+                Ok(())
+            }
+            result => result,
+        };
+        if let Some(parent) = self.counting.last_mut() {
+            parent.nands += count.nands;
+            parent.dffs += count.dffs;
+            parent.parts.push(count);
+        }
+        result
+    }
+
+    /// 組み込みのチップをひとつのノードとして加える。chipはNetlistが持っておいて計算に使う
+    /// inputsが変わるとchipのre_computeを呼び、clockedはクロックのときだけchipに渡す
+    pub fn builtin(
+        &mut self,
+        name: &str,
+        chip: Rc<dyn Gate>,
+        inputs: &[SharedBit],
+        clocked: &[SharedBit],
+        outputs: &[SharedBit],
+    ) -> () {
+        self.enter(name);
+        let mut pins = |bits: &[SharedBit], output: bool| -> Vec<(usize, SharedBit)> {
+            bits.iter()
+                .map(|bit| {
+                    let net = self.net(bit);
+                    if output {
+                        self.name_output(net);
+                    }
+                    (net, bit.clone())
+                })
+                .collect()
+        };
+        let node = BuiltInNode {
+            chip,
+            inputs: pins(inputs, false),
+            clocked: pins(clocked, false),
+            outputs: pins(outputs, true),
+            rank: 0,
+        };
+        self.path.pop();
+        self.builtins.push(node);
+    }
+
+    // nameの部品に入る。scopesに "alu.add16" のような名前を加える
+    fn enter(&mut self, name: &str) -> () {
+        let scope = match self.path.last() {
+            Some(parent) => format!("{}.{}", self.scopes[*parent].0, name),
+            None => name.to_string(),
+        };
+        self.path.push(self.scopes.len());
+        self.scopes.push((scope, 0));
+    }
+
+    /// GateCountを作るためにたどっているところか。
+    /// ComputerのようにROMやRAMを含むチップは、そのときだけ部品をたどる
+    pub fn is_counting(&self) -> bool {
+        !self.counting.is_empty()
+    }
+
+    pub fn net(&mut self, bit: &SharedBit) -> usize {
+        if let Some(net) = self.ids.get(&Rc::as_ptr(bit)) {
+            return *net;
+        }
+        let net = self.bits.len();
+        self.ids.insert(Rc::as_ptr(bit), net);
+        self.bits.push(bit.clone());
+        self.parent.push(net);
+        self.origins.push(None);
+        net
+    }
+
+    // 今flattenしている部品の何番目のノードがnetを出力しているかを覚える
+    fn name_output(&mut self, net: usize) -> () {
+        if let Some(scope) = self.path.last() {
+            let count = &mut self.scopes[*scope].1;
+            self.origins[net] = Some((*scope, *count));
+            *count += 1;
+        }
+    }
+
+    pub fn nand(&mut self, a: &SharedBit, b: &SharedBit, out: &SharedBit) -> () {
+        let node = NandNode {
+            a: self.net(a),
+            b: self.net(b),
+            out: self.net(out),
+        };
+        self.nands.push(node);
+        self.name_output(node.out);
+        if let Some(count) = self.counting.last_mut() {
+            count.nands += 1;
+        }
+    }
+
+    pub fn dff(&mut self, input: &SharedBit, out: &SharedBit, state: Bit) -> () {
+        let node = DffNode {
+            input: self.net(input),
+            out: self.net(out),
+        };
+        self.dffs.push(node);
+        self.states.push(state == I);
+        self.name_output(node.out);
+        if let Some(count) = self.counting.last_mut() {
+            count.dffs += 1;
+        }
+    }
+
+    /// re_computeでfromの値をtoに写しているところ。ただの配線として扱う
+    pub fn connect(&mut self, from: &SharedBit, to: &SharedBit) -> () {
+        let from = self.net(from);
+        let to = self.net(to);
+        let (from, to) = (self.find(from), self.find(to));
+        if from != to {
+            self.parent[to] = from;
+        }
+    }
+
+    fn find(&mut self, net: usize) -> usize {
+        let mut root = net;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut net = net;
+        while self.parent[net] != root {
+            let next = self.parent[net];
+            self.parent[net] = root;
+            net = next;
+        }
+        root
+    }
+
+    // bitを出力しているNandかDFFの名前。connectでつないだ先のbitでもよい
+    fn bit_name(&mut self, bit: &SharedBit) -> Option<String> {
+        let net = *self.ids.get(&Rc::as_ptr(bit))?;
+        let root = self.find(net);
+        for n in 0..self.origins.len() {
+            if let Some(origin) = self.origins[n] {
+                if self.find(n) == root {
+                    return Some(scope_name(&self.scopes, origin));
+                }
+            }
+        }
+        None
+    }
+
+    /// 入出力ピンを決めてNetlistにする
+    pub fn finish(
+        mut self,
+        inputs: &[(&str, Vec<SharedBit>)],
+        outputs: &[(&str, Vec<SharedBit>)],
+    ) -> Result<Netlist, NetlistError> {
+        let mut pins = |pins: &[(&str, Vec<SharedBit>)]| -> Vec<(String, Vec<usize>)> {
+            pins.iter()
+                .map(|(name, bits)| {
+                    (
+                        name.to_string(),
+                        bits.iter().map(|bit| self.net(bit)).collect(),
+                    )
+                })
+                .collect()
+        };
+        let inputs = pins(inputs);
+        let outputs = pins(outputs);
+
+        // 代表のnetだけを0から番号を振り直す
+        let roots: Vec<usize> = (0..self.parent.len()).map(|net| self.find(net)).collect();
+        let mut ids = vec![usize::MAX; roots.len()];
+        let mut values = vec![];
+        for net in 0..roots.len() {
+            if roots[net] == net {
+                ids[net] = values.len();
+                values.push(self.bits[net].get() == I);
+            }
+        }
+        let id = |net: usize| ids[roots[net]];
+        let mut origins = vec![None; values.len()];
+        for (net, origin) in self.origins.iter().enumerate() {
+            if origin.is_some() {
+                origins[id(net)] = *origin;
+            }
+        }
+        let nands: Vec<NandNode> = self
+            .nands
+            .iter()
+            .map(|n| NandNode {
+                a: id(n.a),
+                b: id(n.b),
+                out: id(n.out),
+            })
+            .collect();
+        let dffs: Vec<DffNode> = self
+            .dffs
+            .iter()
+            .map(|d| DffNode {
+                input: id(d.input),
+                out: id(d.out),
+            })
+            .collect();
+        let pins = |pins: Vec<(String, Vec<usize>)>| {
+            pins.into_iter()
+                .map(|(name, nets)| (name, nets.into_iter().map(id).collect()))
+                .collect()
+        };
+
+        let mut bits: Vec<Vec<SharedBit>> = vec![vec![]; values.len()];
+        for (net, bit) in self.bits.iter().enumerate() {
+            bits[id(net)].push(bit.clone());
+        }
+        let mut builtins: Vec<BuiltInNode> = self
+            .builtins
+            .into_iter()
+            .map(|node| node.renumber(id))
+            .collect();
+
+        // Nandは0..nands.len()、組み込みのチップはその後ろの番号のノードにする
+        let node_count = nands.len() + builtins.len();
+        let mut driver: Vec<Option<usize>> = vec![None; values.len()];
+        let mut driven_by_dff = vec![false; values.len()];
+        let outputs_of = |node: usize| -> Vec<usize> {
+            match node.checked_sub(nands.len()) {
+                None => vec![nands[node].out],
+                Some(i) => builtins[i].outputs.iter().map(|(net, _)| *net).collect(),
+            }
+        };
+        for node in 0..node_count {
+            for out in outputs_of(node) {
+                if driver[out].is_some() {
+                    return Err(NetlistError::DrivenTwice(out));
+                }
+                driver[out] = Some(node);
+            }
+        }
+        for dff in dffs.iter() {
+            if driver[dff.out].is_some() || driven_by_dff[dff.out] {
+                return Err(NetlistError::DrivenTwice(dff.out));
+            }
+            driven_by_dff[dff.out] = true;
+        }
+
+        // Nandと組み込みのチップを依存順に並べる
+        let inputs_of = |node: usize| -> Vec<usize> {
+            match node.checked_sub(nands.len()) {
+                None => vec![nands[node].a, nands[node].b],
+                Some(i) => builtins[i].inputs.iter().map(|(net, _)| *net).collect(),
+            }
+        };
+        let mut dependents: Vec<Vec<usize>> = vec![vec![]; node_count];
+        let mut in_degree = vec![0; node_count];
+        for (node, degree) in in_degree.iter_mut().enumerate() {
+            for net in inputs_of(node) {
+                if let Some(from) = driver[net] {
+                    dependents[from].push(node);
+                    *degree += 1;
+                }
+            }
+        }
+        let mut queue: VecDeque<usize> = (0..node_count).filter(|i| in_degree[*i] == 0).collect();
+        let mut order = vec![];
+        while let Some(node) = queue.pop_front() {
+            order.push(node);
+            for dependent in dependents[node].iter() {
+                in_degree[*dependent] -= 1;
+                if in_degree[*dependent] == 0 {
+                    queue.push_back(*dependent);
+                }
+            }
+        }
+        if order.len() < node_count {
+            let nets = (0..node_count)
+                .filter(|i| in_degree[*i] > 0)
+                .flat_map(|i| outputs_of(i).into_iter().take(1))
+                .take(8)
+                .collect();
+            return Err(NetlistError::CombinationalLoop(nets));
+        }
+
+        let mut sorted = vec![];
+        let mut schedule = vec![];
+        for node in order.iter().copied() {
+            match node.checked_sub(nands.len()) {
+                None => {
+                    schedule.push(Node::Nand(sorted.len()));
+                    sorted.push(nands[node]);
+                }
+                Some(i) => {
+                    builtins[i].rank = schedule.len();
+                    schedule.push(Node::BuiltIn(i));
+                }
+            }
+        }
+        let mut fanout: Vec<Vec<usize>> = vec![vec![]; values.len()];
+        for (rank, node) in schedule.iter().enumerate() {
+            let mut nets = match node {
+                Node::Nand(i) => vec![sorted[*i].a, sorted[*i].b],
+                Node::BuiltIn(i) => builtins[*i].inputs.iter().map(|(net, _)| *net).collect(),
+            };
+            nets.sort();
+            nets.dedup();
+            for net in nets {
+                fanout[net].push(rank);
+            }
+        }
+        let netlist = Netlist {
+            values: RefCell::new(values),
+            states: RefCell::new(self.states),
+            queue: RefCell::new(Queue::new(schedule.len())),
+            stats: Cell::new(Stats::default()),
+            nands: sorted,
+            dffs,
+            builtins,
+            schedule,
+            fanout,
+            bits,
+            write_back: false,
+            scopes: self.scopes,
+            origins,
+            inputs: pins(inputs),
+            outputs: pins(outputs),
+        };
+        netlist.re_compute_all();
+        Ok(netlist)
+    }
+}
+
+/// re_computeでNandを計算した回数
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// re_computeを呼んだ回数
+    pub passes: usize,
+    /// 実際に計算したNandの数
+    pub evaluations: usize,
+    /// 毎回すべてのNandを計算していた場合の数
+    pub full_evaluations: usize,
+}
+
+impl Stats {
+    /// 計算しないで済んだNandの数
+    pub fn saved(&self) -> usize {
+        self.full_evaluations - self.evaluations
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ratio = match self.full_evaluations {
+            0 => 0.0,
+            full => self.saved() as f64 * 100.0 / full as f64,
+        };
+        write!(
+            f,
+            "nand evaluations: {} of {} in {} passes ({:.1}% saved)",
+            self.evaluations, self.full_evaluations, self.passes, ratio
+        )
+    }
+}
+
+/// 部品ごとのNandとDFFの数。部品の中の部品も含めて数える
+#[derive(Debug, Clone, PartialEq)]
+pub struct GateCount {
+    /// 部品のフィールド名
+    pub name: String,
+    /// 部品の型 (Mux<16> など)
+    pub chip: String,
+    pub nands: usize,
+    pub dffs: usize,
+    /// NandとDFFに展開できない組み込みのチップ
+    pub builtin: bool,
+    pub parts: Vec<GateCount>,
+}
+
+impl GateCount {
+    fn new(name: &str, chip: &str) -> GateCount {
+        GateCount {
+            name: name.to_string(),
+            chip: chip.to_string(),
+            nands: 0,
+            dffs: 0,
+            builtin: false,
+            parts: vec![],
+        }
+    }
+
+    /// gateを部品までたどって数える。chipはgateの名前
+    pub fn of(chip: &str, gate: &dyn Gate) -> Result<GateCount, NetlistError> {
+        let mut builder = NetlistBuilder::new();
+        builder.counting.push(GateCount::new("", "(root)"));
+        builder.named_part(chip, chip, gate)?;
+        let mut root = builder.counting.pop().unwrap();
+        Ok(root.parts.remove(0))
+    }
+
+    /// 中で使っている組み込みのチップの名前。重ならないように並べる
+    pub fn builtins(&self) -> Vec<&str> {
+        let mut names = vec![];
+        self.collect_builtins(&mut names);
+        names
+    }
+
+    fn collect_builtins<'a>(&'a self, names: &mut Vec<&'a str>) -> () {
+        if self.builtin && !names.contains(&self.chip.as_str()) {
+            names.push(&self.chip);
+        }
+        for part in self.parts.iter() {
+            part.collect_builtins(names);
+        }
+    }
+
+    /// 同じ型の部品をまとめて木にする。depthより深い部品は表示しない
+    pub fn tree(&self, depth: usize) -> String {
+        let mut lines = vec![format!("{:<40} {:>8} {:>6}", "chip", "nand", "dff")];
+        self.write_tree(1, 0, depth, &mut lines);
+        lines.join("\n")
+    }
+
+    fn write_tree(
+        &self,
+        instances: usize,
+        level: usize,
+        depth: usize,
+        lines: &mut Vec<String>,
+    ) -> () {
+        let mut name = format!("{}{}", "  ".repeat(level), self.chip);
+        if instances > 1 {
+            name.push_str(&format!(" x{}", instances));
+        }
+        match self.builtin {
+            true => lines.push(format!("{:<40} {:>15}", name, "built-in")),
+            false => lines.push(format!("{:<40} {:>8} {:>6}", name, self.nands, self.dffs)),
+        }
+        if level >= depth {
+            return;
+        }
+        // 同じ型の部品は中身も同じなので、最初のものだけ表示して数を付ける
+        let mut groups: Vec<(&GateCount, usize)> = vec![];
+        for part in self.parts.iter() {
+            match groups.iter_mut().find(|(first, _)| first.chip == part.chip) {
+                Some((_, count)) => *count += 1,
+                None => groups.push((part, 1)),
+            }
+        }
+        for (part, count) in groups {
+            part.write_tree(count, level + 1, depth, lines);
+        }
+    }
+}
+
+// (部品, 何番目のノードか) を "alu.add16.full_adder3.or.not.nand" のような名前にする
+fn scope_name(scopes: &[(String, usize)], (scope, index): (usize, usize)) -> String {
+    let (name, size) = &scopes[scope];
+    match size {
+        1 => name.clone(),
+        _ => format!("{}[{}]", name, index),
+    }
+}
+
+/// gateの中のbitの名前。Netlist::net_nameと同じように出力している部品の名前にする
+/// ROMのように展開できない部品は飛ばしてたどり、名前が分からないbitは "?"
+pub fn bit_names<G: Gate + ?Sized>(gate: &G, bits: &[SharedBit]) -> Vec<String> {
+    let mut builder = NetlistBuilder::new();
+    builder.counting.push(GateCount::new("", "(root)"));
+    // 途中で展開できなくても、そこまでにたどった部品の名前は使える
+    let _ = gate.flatten(&mut builder);
+    bits.iter()
+        .map(|bit| builder.bit_name(bit).unwrap_or_else(|| "?".to_string()))
+        .collect()
+}
+
+/// Netlist::critical_pathの結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct CriticalPath {
+    /// 通るNandの数
+    pub levels: usize,
+    /// 始まりのnetから順に、通るnetの名前
+    pub nets: Vec<String>,
+}
+
+impl fmt::Display for CriticalPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "critical path: {} nand levels", self.levels)?;
+        for (level, net) in self.nets.iter().enumerate() {
+            writeln!(f, "{:>4}  {}", level, net)?;
+        }
+        Ok(())
+    }
+}
+
+// scheduleの何番目か
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    Nand(usize),
+    BuiltIn(usize),
+}
+
+// 入力が変わったノード。scheduleの番号の小さい順(依存順)に取り出す
+// 番号ごとに1bitを立てておき、firstより前のwordはすべて0
+#[derive(Debug)]
+struct Queue {
+    words: Vec<u64>,
+    first: usize,
+}
+
+impl Queue {
+    fn new(len: usize) -> Self {
+        Queue {
+            words: vec![0; len.div_ceil(64)],
+            first: 0,
+        }
+    }
+
+    fn push(&mut self, rank: usize) -> () {
+        self.words[rank / 64] |= 1 << (rank % 64);
+        self.first = self.first.min(rank / 64);
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        while let Some(word) = self.words.get_mut(self.first) {
+            if *word != 0 {
+                let bit = word.trailing_zeros() as usize;
+                *word &= *word - 1;
+                return Some(self.first * 64 + bit);
+            }
+            self.first += 1;
+        }
+        None
+    }
+
+    fn clear(&mut self) -> () {
+        self.words.fill(0);
+        self.first = self.words.len();
+    }
+}
+
+/// NandとDFFだけに展開した回路。netの値は配列で持ち、Nandは依存順に並んでいる
+/// re_computeは値が変わったnetの先にあるNandだけを計算する
+/// ComputerのROMやメモリのような組み込みのチップは、そのままひとつのノードとして計算する
+#[derive(Debug)]
+pub struct Netlist {
+    values: RefCell<Vec<bool>>,
+    // dffsと同じ並び。clock_upで取り込んだ値
+    states: RefCell<Vec<bool>>,
+    queue: RefCell<Queue>,
+    stats: Cell<Stats>,
+    nands: Vec<NandNode>,
+    dffs: Vec<DffNode>,
+    builtins: Vec<BuiltInNode>,
+    // Nandと組み込みのチップを合わせて依存順に並べたもの
+    schedule: Vec<Node>,
+    // netごとに、それを入力にしているノードのscheduleでの番号
+    fanout: Vec<Vec<usize>>,
+    // netごとの、flattenでたどったゲートのbit
+    bits: Vec<Vec<SharedBit>>,
+    // trueならnetの値が変わるたびにbitsにも書き込む
+    write_back: bool,
+    // net_nameで使う。NetlistBuilderと同じもの
+    scopes: Vec<(String, usize)>,
+    origins: Vec<Option<(usize, usize)>>,
+    inputs: Vec<(String, Vec<usize>)>,
+    outputs: Vec<(String, Vec<usize>)>,
+}
+
+impl Netlist {
+    /// gateをNandとDFFまで展開する。inputs/outputsはピン名と、そのピンのbit(下位から)
+    pub fn from_gate(
+        gate: &dyn Gate,
+        inputs: &[(&str, Vec<SharedBit>)],
+        outputs: &[(&str, Vec<SharedBit>)],
+    ) -> Result<Netlist, NetlistError> {
+        let mut builder = NetlistBuilder::new();
+        gate.flatten(&mut builder)?;
+        builder.finish(inputs, outputs)
+    }
+
+    pub fn net_count(&self) -> usize {
+        self.values.borrow().len()
+    }
+
+    pub fn nands(&self) -> &[NandNode] {
+        &self.nands
+    }
+
+    pub fn dffs(&self) -> &[DffNode] {
+        &self.dffs
+    }
+
+    /// (ピン名, netの番号)
+    pub fn input_pins(&self) -> &[(String, Vec<usize>)] {
+        &self.inputs
+    }
+
+    pub fn output_pins(&self) -> &[(String, Vec<usize>)] {
+        &self.outputs
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.get()
+    }
+
+    /// netの名前。出力している部品の "alu.add16.full_adder3.or.not.nand" のような名前で、
+    /// 部品の中に複数のNandやDFFがあれば [番号] を付ける。部品の外のnetは入出力ピン名か net番号
+    pub fn net_name(&self, net: usize) -> String {
+        if let Some(origin) = self.origins[net] {
+            return scope_name(&self.scopes, origin);
+        }
+        let pin = self
+            .inputs
+            .iter()
+            .chain(self.outputs.iter())
+            .find_map(|(name, nets)| {
+                Some((name, nets.iter().position(|n| *n == net)?, nets.len()))
+            });
+        match pin {
+            Some((name, _, 1)) => name.clone(),
+            Some((name, i, _)) => format!("{}[{}]", name, i),
+            None => format!("net{}", net),
+        }
+    }
+
+    /// 入力ピンかDFFの出力から、出力ピンかDFFの入力まで、Nandを一番多く通る経路
+    pub fn critical_path(&self) -> CriticalPath {
+        self.longest_path(false)
+    }
+
+    /// DFFの出力からDFFの入力まで、Nandを一番多く通る経路。クロックの周期を決めるのはこちら
+    /// DFFのない回路なら段数0で空になる
+    pub fn register_path(&self) -> CriticalPath {
+        self.longest_path(true)
+    }
+
+    fn longest_path(&self, registers_only: bool) -> CriticalPath {
+        // 始まりにできるnetだけSome。registers_onlyでなければ入力ピンや定数からも始まる
+        let mut levels = vec![(!registers_only).then_some(0); self.net_count()];
+        for dff in self.dffs.iter() {
+            levels[dff.out] = Some(0);
+        }
+        // Nandは依存順に並んでいるので前から段数を決めていける
+        let mut from = vec![None; self.net_count()];
+        for nand in self.nands.iter() {
+            let input = match levels[nand.a] >= levels[nand.b] {
+                true => nand.a,
+                false => nand.b,
+            };
+            if let Some(level) = levels[input] {
+                levels[nand.out] = Some(level + 1);
+                from[nand.out] = Some(input);
+            }
+        }
+        let outputs = self
+            .outputs
+            .iter()
+            .flat_map(|(_, nets)| nets.iter().copied())
+            .filter(|_| !registers_only);
+        let ends = self.dffs.iter().map(|dff| dff.input).chain(outputs);
+        let mut end = None;
+        for net in ends.filter(|net| levels[*net].is_some()) {
+            if end.is_none_or(|end: usize| levels[net] > levels[end]) {
+                end = Some(net);
+            }
+        }
+        let mut nets = vec![];
+        let mut net = end;
+        while let Some(n) = net {
+            nets.push(self.net_name(n));
+            net = from[n];
+        }
+        nets.reverse();
+        CriticalPath {
+            levels: end.and_then(|end| levels[end]).unwrap_or(0),
+            nets,
+        }
+    }
+
+    /// 入力ピンに値を入れる。そのような入力ピンがなければfalse
+    /// 出力に反映するにはre_compute()を呼ぶ
+    pub fn set(&self, pin: &str, value: u16) -> bool {
+        match self.inputs.iter().find(|(name, _)| name == pin) {
+            Some((_, nets)) => {
+                for (i, net) in nets.iter().enumerate() {
+                    self.write(*net, (value >> i) & 1 == 1);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// これより後はnetの値が変わるたびに、flattenでたどったゲートのbitにも書き込む
+    /// re_computeのはじめには入力ピンの値をゲートのbitから読む
+    /// ゲートを外から見たまま、計算だけをNetlistで変わったところに絞るときに使う
+    pub fn write_back(&mut self) -> () {
+        self.write_back = true;
+        let values = self.values.borrow();
+        for (net, bits) in self.bits.iter().enumerate() {
+            for bit in bits.iter() {
+                drive(bit, if values[net] { I } else { O });
+            }
+        }
+    }
+
+    /// write_backのとき、レジスタのようにDFFの出力のbitを直接書き換えたあとに呼ぶ
+    pub fn reload(&self) -> () {
+        for dff in self.dffs.iter() {
+            let value = self.values.borrow()[dff.out];
+            let bits = &self.bits[dff.out];
+            if let Some(bit) = bits.iter().find(|bit| (bit.get() == I) != value) {
+                self.write(dff.out, bit.get() == I);
+            }
+        }
+    }
+
+    // 値が変わったらその先のノードを計算待ちにする
+    fn write(&self, net: usize, value: bool) -> () {
+        {
+            let mut values = self.values.borrow_mut();
+            if values[net] == value {
+                return;
+            }
+            values[net] = value;
+        }
+        if self.write_back {
+            let bit = if value { I } else { O };
+            for b in self.bits[net].iter() {
+                drive(b, bit);
+            }
+        }
+        for rank in self.fanout[net].iter() {
+            self.enqueue(*rank);
+        }
+    }
+
+    fn enqueue(&self, rank: usize) -> () {
+        self.queue.borrow_mut().push(rank);
+    }
+
+    // 組み込みのチップに入力のnetの値を渡す
+    fn load_inputs(&self, node: &BuiltInNode) -> () {
+        let values = self.values.borrow();
+        for (net, bit) in node.inputs.iter().chain(node.clocked.iter()) {
+            bit.set(if values[*net] { I } else { O });
+        }
+    }
+
+    fn evaluate(&self, node: &BuiltInNode) -> () {
+        self.load_inputs(node);
+        node.chip.re_compute();
+        for (net, bit) in node.outputs.iter() {
+            self.write(*net, bit.get() == I);
+        }
+    }
+
+    // 変化を追わずにすべてのノードを計算する
+    fn re_compute_all(&self) -> () {
+        for node in self.schedule.iter() {
+            match *node {
+                Node::Nand(i) => {
+                    let nand = self.nands[i];
+                    let mut values = self.values.borrow_mut();
+                    values[nand.out] = !(values[nand.a] && values[nand.b]);
+                }
+                Node::BuiltIn(i) => self.evaluate(&self.builtins[i]),
+            }
+        }
+        // 組み込みのチップの出力で計算待ちになったものは、もう計算してある
+        self.queue.borrow_mut().clear();
+    }
+
+    /// 入出力ピンの値
+    pub fn get(&self, pin: &str) -> Option<u16> {
+        let values = self.values.borrow();
+        self.inputs
+            .iter()
+            .chain(self.outputs.iter())
+            .find(|(name, _)| name == pin)
+            .map(|(_, nets)| {
+                nets.iter()
+                    .enumerate()
+                    .map(|(i, net)| (values[*net] as u16) << i)
+                    .sum()
+            })
+    }
+}
+
+impl Gate for Netlist {
+    fn re_compute(&self) -> () {
+        if self.write_back {
+            for (_, nets) in self.inputs.iter() {
+                for net in nets.iter() {
+                    let value = self.bits[*net][0].get() == I;
+                    self.write(*net, value);
+                }
+            }
+        }
+        // 組み込みのチップはKeyboardやpokeで外から中身が変わるので毎回計算する
+        for node in self.builtins.iter() {
+            self.enqueue(node.rank);
+        }
+
+        let mut evaluations = 0;
+        loop {
+            let rank = match self.queue.borrow_mut().pop() {
+                Some(rank) => rank,
+                None => break,
+            };
+            // 依存順に並んでいるので、ここから計算待ちになるのは後ろのノードだけ
+            let nand = match self.schedule[rank] {
+                Node::Nand(i) => self.nands[i],
+                Node::BuiltIn(i) => {
+                    self.evaluate(&self.builtins[i]);
+                    continue;
+                }
+            };
+            let out = {
+                let values = self.values.borrow();
+                !(values[nand.a] && values[nand.b])
+            };
+            self.write(nand.out, out);
+            evaluations += 1;
+        }
+
+        let mut stats = self.stats.get();
+        stats.passes += 1;
+        stats.evaluations += evaluations;
+        stats.full_evaluations += self.nands.len();
+        self.stats.set(stats);
+    }
+
+    fn clock_up(&self) -> () {
+        {
+            let values = self.values.borrow();
+            let mut states = self.states.borrow_mut();
+            for (state, dff) in states.iter_mut().zip(self.dffs.iter()) {
+                *state = values[dff.input];
+            }
+        }
+        // clockedの入力はre_computeでは渡していないので、ここで渡して中の部品も計算しておく
+        for node in self.builtins.iter() {
+            self.load_inputs(node);
+            node.chip.re_compute();
+            node.chip.clock_up();
+        }
+    }
+
+    fn clock_down(&self) -> () {
+        let states = self.states.borrow();
+        for (state, dff) in states.iter().zip(self.dffs.iter()) {
+            self.write(dff.out, *state);
+        }
+        for node in self.builtins.iter() {
+            node.chip.clock_down();
+        }
+    }
+}
+
+/// テスト用。同じ入力でゲートとNetlistを動かし、re_computeのたびに出力が同じか確かめる
+/// 入力はゲートのbitに入れておけば、re_computeでNetlistにも写す
+#[cfg(test)]
+pub(crate) struct Twin<'a> {
+    gate: &'a dyn Gate,
+    netlist: Netlist,
+    inputs: Vec<(String, Vec<SharedBit>)>,
+    outputs: Vec<(String, Vec<SharedBit>)>,
+}
+
+#[cfg(test)]
+impl<'a> Twin<'a> {
+    /// ピン名は "in0"、"out0" のように並び順で付ける
+    pub fn new(
+        gate: &'a dyn Gate,
+        inputs: &[Vec<SharedBit>],
+        outputs: &[Vec<SharedBit>],
+    ) -> Twin<'a> {
+        let pins = |prefix: &str, pins: &[Vec<SharedBit>]| -> Vec<(String, Vec<SharedBit>)> {
+            pins.iter()
+                .enumerate()
+                .map(|(i, bits)| (format!("{}{}", prefix, i), bits.clone()))
+                .collect()
+        };
+        let inputs = pins("in", inputs);
+        let outputs = pins("out", outputs);
+        let netlist = Netlist::from_gate(gate, &pin_refs(&inputs), &pin_refs(&outputs)).unwrap();
+        Twin {
+            gate,
+            netlist,
+            inputs,
+            outputs,
+        }
+    }
+}
+
+#[cfg(test)]
+fn pin_refs(pins: &[(String, Vec<SharedBit>)]) -> Vec<(&str, Vec<SharedBit>)> {
+    pins.iter()
+        .map(|(name, bits)| (name.as_str(), bits.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+impl Gate for Twin<'_> {
+    fn re_compute(&self) -> () {
+        for ((_, bits), (_, nets)) in self.inputs.iter().zip(self.netlist.inputs.iter()) {
+            for (bit, net) in bits.iter().zip(nets.iter()) {
+                self.netlist.write(*net, bit.get() == I);
+            }
+        }
+        self.gate.re_compute();
+        self.netlist.re_compute();
+        let values = self.netlist.values.borrow();
+        for ((pin, bits), (_, nets)) in self.outputs.iter().zip(self.netlist.outputs.iter()) {
+            let gate: Vec<bool> = bits.iter().map(|bit| bit.get() == I).collect();
+            let netlist: Vec<bool> = nets.iter().map(|net| values[*net]).collect();
+            assert_eq!(gate, netlist, "{} (gate, netlist)", pin);
+        }
+    }
+
+    fn clock_up(&self) -> () {
+        self.gate.clock_up();
+        self.netlist.clock_up();
+    }
+
+    fn clock_down(&self) -> () {
+        self.gate.clock_down();
+        self.netlist.clock_down();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        arithmetic::{Add16, ALU},
+        computer::{Computer, ROM32KBuiltIn, CPU},
+        sequential::{PC, RAM8},
+    };
+
+    // 適当な値を順に作る (xorshift)
+    fn random(seed: &mut u32) -> u16 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        *seed as u16
+    }
+
+    fn set(bits: &[SharedBit], value: u16) -> () {
+        for (i, bit) in bits.iter().enumerate() {
+            bit.set(if (value >> i) & 1 == 1 { I } else { O });
+        }
+    }
+
+    // 同じ値を入れて、ゲートとNetlistの出力を比べる
+    fn assert_same(netlist: &Netlist, outputs: &[(&str, Vec<SharedBit>)], message: &str) -> () {
+        for (pin, bits) in outputs.iter() {
+            let expected: u16 = bits
+                .iter()
+                .enumerate()
+                .map(|(i, bit)| ((bit.get() == I) as u16) << i)
+                .sum();
+            assert_eq!(netlist.get(pin), Some(expected), "{} {}", pin, message);
+        }
+    }
+
+    #[test]
+    fn alu() {
+        let x = Bus::<16>::all0().to_shared_bus();
+        let y = Bus::<16>::all0().to_shared_bus();
+        let control: Vec<SharedBus<1>> = (0..6).map(|_| Bus::all0().to_shared_bus()).collect();
+        let alu = ALU::new(
+            x.clone(),
+            y.clone(),
+            control[0].clone(),
+            control[1].clone(),
+            control[2].clone(),
+            control[3].clone(),
+            control[4].clone(),
+            control[5].clone(),
+        );
+        let names = ["zx", "nx", "zy", "ny", "f", "no"];
+        let mut inputs = vec![("x", x.shared_bits()), ("y", y.shared_bits())];
+        for (name, bus) in names.iter().zip(control.iter()) {
+            inputs.push((name, bus.shared_bits()));
+        }
+        let outputs = [
+            ("out", alu.out.shared_bits()),
+            ("zr", alu.zr.shared_bits()),
+            ("ng", alu.ng.shared_bits()),
+        ];
+        let netlist = Netlist::from_gate(&alu, &inputs, &outputs).unwrap();
+        assert_eq!(netlist.dffs().len(), 0);
+
+        let mut seed = 1;
+        for i in 0..256 {
+            let values = [random(&mut seed), random(&mut seed)]
+                .into_iter()
+                .chain((0..6).map(|j| (i >> j) & 1));
+            for ((pin, bits), value) in inputs.iter().zip(values) {
+                set(bits, value);
+                netlist.set(pin, value);
+            }
+            alu.re_compute();
+            netlist.re_compute();
+            assert_same(&netlist, &outputs, &i.to_string());
+        }
+    }
+
+    #[test]
+    fn event_driven() {
+        let a = Bus::<16>::all0().to_shared_bus();
+        let b = Bus::<16>::all0().to_shared_bus();
+        let add16 = Add16::new(a.clone(), b.clone());
+        let netlist = Netlist::from_gate(
+            &add16,
+            &[("a", a.shared_bits()), ("b", b.shared_bits())],
+            &[("out", add16.out.shared_bits())],
+        )
+        .unwrap();
+        let nands = netlist.nands().len();
+
+        // 何も変わっていなければ計算しない
+        netlist.re_compute();
+        assert_eq!(
+            netlist.stats(),
+            Stats {
+                passes: 1,
+                evaluations: 0,
+                full_evaluations: nands,
+            }
+        );
+
+        // 最上位bitだけなら最後の全加算器の中だけ
+        netlist.set("a", 0x8000);
+        netlist.re_compute();
+        assert_eq!(netlist.get("out"), Some(0x8000));
+        let top = netlist.stats().evaluations;
+        assert!(0 < top && top < 10, "{}", top);
+
+        // 繰り上がりは下から上まで伝わる
+        netlist.set("a", 0xffff);
+        netlist.set("b", 1);
+        netlist.re_compute();
+        assert_eq!(netlist.get("out"), Some(0));
+        let stats = netlist.stats();
+        assert!(stats.evaluations - top > nands / 2);
+        assert_eq!(stats.passes, 3);
+        assert_eq!(stats.saved(), 3 * nands - stats.evaluations);
+        assert!(stats.to_string().starts_with(&format!(
+            "nand evaluations: {} of {} in 3 passes (",
+            stats.evaluations,
+            3 * nands
+        )));
+    }
+
+    #[test]
+    fn sequential() {
+        let input = Bus::<16>::all0().to_shared_bus();
+        let load = Bus::<1>::all0().to_shared_bus();
+        let inc = Bus::<1>::all0().to_shared_bus();
+        let reset = Bus::<1>::all0().to_shared_bus();
+        let address = Bus::<3>::all0().to_shared_bus();
+        let pc = PC::new(input.clone(), load.clone(), inc.clone(), reset.clone());
+        let ram8 = RAM8::new(input.clone(), load.clone(), address.clone());
+        let inputs = [
+            ("in", input.shared_bits()),
+            ("load", load.shared_bits()),
+            ("inc", inc.shared_bits()),
+            ("reset", reset.shared_bits()),
+            ("address", address.shared_bits()),
+        ];
+
+        for (gate, out) in [(&pc as &dyn Gate, &pc.out), (&ram8, &ram8.out)] {
+            let outputs = [("out", out.shared_bits())];
+            let netlist = Netlist::from_gate(gate, &inputs, &outputs).unwrap();
+            let mut seed = 7;
+            for cycle in 0..300 {
+                for (pin, bits) in inputs.iter() {
+                    // loadやresetはたまにだけ立てる
+                    let value = match bits.len() {
+                        1 => random(&mut seed).is_multiple_of(5) as u16,
+                        _ => random(&mut seed),
+                    };
+                    set(bits, value);
+                    netlist.set(pin, value);
+                }
+                for g in [gate, &netlist] {
+                    g.re_compute();
+                    g.clock_up();
+                }
+                assert_same(&netlist, &outputs, &format!("tick {}", cycle));
+                for g in [gate, &netlist] {
+                    g.clock_down();
+                    g.re_compute();
+                }
+                assert_same(&netlist, &outputs, &format!("tock {}", cycle));
+            }
+        }
+    }
+
+    #[test]
+    fn cpu() {
+        let in_m = Bus::<16>::all0().to_shared_bus();
+        let instruction = Bus::<16>::all0().to_shared_bus();
+        let reset = Bus::<1>::all0().to_shared_bus();
+        let cpu = CPU::new(in_m.clone(), instruction.clone(), reset.clone());
+        cpu.set_a_register_value(1234);
+        cpu.set_d_register_value(0x8000);
+        cpu.set_pc(99);
+        let inputs = [
+            ("inM", in_m.shared_bits()),
+            ("instruction", instruction.shared_bits()),
+            ("reset", reset.shared_bits()),
+        ];
+        let outputs = [
+            ("outM", cpu.out_m.shared_bits()),
+            ("writeM", cpu.write_m.shared_bits()),
+            ("addressM", cpu.address_m.shared_bits()),
+            ("pc", cpu.pc.shared_bits()),
+        ];
+        let netlist = Netlist::from_gate(&cpu, &inputs, &outputs).unwrap();
+        // A、D、PCの16bitずつ
+        assert_eq!(netlist.dffs().len(), 48);
+        assert_eq!(netlist.get("pc"), Some(99));
+
+        let mut seed = 3;
+        for cycle in 0..500 {
+            let values = [
+                random(&mut seed),
+                random(&mut seed),
+                random(&mut seed).is_multiple_of(50) as u16,
+            ];
+            for ((pin, bits), value) in inputs.iter().zip(values) {
+                set(bits, value);
+                netlist.set(pin, value);
+            }
+            cpu.settle().unwrap();
+            netlist.re_compute();
+            assert_same(&netlist, &outputs, &format!("cycle {}", cycle));
+            for gate in [&cpu as &dyn Gate, &netlist] {
+                gate.clock_up();
+                gate.clock_down();
+                gate.settle().unwrap();
+            }
+            assert_same(&netlist, &outputs, &format!("cycle {}", cycle));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let rom = ROM32KBuiltIn::from_words(&[], Bus::all0().to_shared_bus());
+        let computer = Computer::new(Bus::all0().to_shared_bus(), rom);
+        // メモリだけでは展開できない。Computerの中ならノードになる
+        let error = Netlist::from_gate(computer.memory(), &[], &[]).unwrap_err();
+        assert_eq!(
+            error,
+            NetlistError::Unsupported("MemoryBuiltIn".to_string())
+        );
+        assert!(Netlist::from_gate(&computer, &[], &[]).is_ok());
+
+        // Nandの出力を自分の入力に戻す
+        let a = Bus::<1>::all0().to_shared_bus();
+        let nand = Nand::new(a.clone(), Bus::all0().to_shared_bus());
+        let loop_back = Nand::new(nand.out.clone(), Bus::all0().to_shared_bus());
+        let mut builder = NetlistBuilder::new();
+        nand.flatten(&mut builder).unwrap();
+        loop_back.flatten(&mut builder).unwrap();
+        builder.connect(&loop_back.out.get_shared_bit(0), &a.get_shared_bit(0));
+        assert!(matches!(
+            builder.finish(&[], &[]),
+            Err(NetlistError::CombinationalLoop(_))
+        ));
+
+        let mut builder = NetlistBuilder::new();
+        nand.flatten(&mut builder).unwrap();
+        nand.flatten(&mut builder).unwrap();
+        assert!(matches!(
+            builder.finish(&[], &[]),
+            Err(NetlistError::DrivenTwice(_))
+        ));
+    }
+
+    #[test]
+    fn gate_count() {
+        let rom = ROM32KBuiltIn::from_words(&[], Bus::all0().to_shared_bus());
+        let computer = Computer::new(Bus::all0().to_shared_bus(), rom);
+        let count = GateCount::of("Computer", &computer).unwrap();
+        // CPUはNandとDFFだけでできていて、組み込みはROM/RAM/Screen/Keyboardだけ
+        let cpu = count.parts.iter().find(|part| part.name == "cpu").unwrap();
+        assert_eq!((cpu.chip.as_str(), cpu.dffs), ("CPU", 48));
+        assert!(cpu.builtins().is_empty());
+        assert_eq!(
+            count.builtins(),
+            vec![
+                "ROM32KBuiltIn",
+                "RAM16KBuiltIn",
+                "ScreenBuiltIn",
+                "KeyboardBuiltIn"
+            ]
+        );
+        // 展開したNetlistと同じ数になる
+        let netlist = Netlist::from_gate(&computer.cpu, &[], &[]).unwrap();
+        assert_eq!(cpu.nands, netlist.nands().len());
+        assert_eq!(count.nands, cpu.nands + count.parts[2].nands);
+
+        let tree = count.tree(2);
+        let lines: Vec<&str> = tree.lines().collect();
+        assert_eq!(
+            lines[1].split_whitespace().collect::<Vec<_>>(),
+            ["Computer", "2908", "48"]
+        );
+        assert_eq!(
+            lines[2].split_whitespace().collect::<Vec<_>>(),
+            ["ROM32KBuiltIn", "built-in"]
+        );
+        assert!(lines.contains(&"    Register x2                               128     16"));
+        assert!(!tree.contains("Nand<1>"));
+
+        // Nandそのものは部品を持たない
+        let nand = Nand::<16>::new(Bus::all0().to_shared_bus(), Bus::all0().to_shared_bus());
+        let count = GateCount::of("Nand16", &nand).unwrap();
+        assert_eq!((count.nands, count.parts.len()), (16, 0));
+    }
+
+    #[test]
+    fn critical_path() {
+        let a = Bus::<16>::all0().to_shared_bus();
+        let b = Bus::<16>::all0().to_shared_bus();
+        let add16 = Add16::new(a.clone(), b.clone());
+        let netlist = Netlist::from_gate(
+            &add16,
+            &[("a", a.shared_bits()), ("b", b.shared_bits())],
+            &[("out", add16.out.shared_bits())],
+        )
+        .unwrap();
+        // 繰り上がりが全加算器を1つずつ4段で通っていく
+        let path = netlist.critical_path();
+        assert_eq!(path.levels, 62);
+        assert_eq!(path.nets.len(), 63);
+        assert_eq!(path.nets[0], "a[1]");
+        assert_eq!(path.nets[62], "full_adder15.half_adder2.xor.nand4");
+        for i in 1..15 {
+            let carry = format!("full_adder{}.or.nand3", i);
+            assert_eq!(path.nets[3 + 4 * i], carry);
+        }
+        assert!(path
+            .to_string()
+            .starts_with("critical path: 62 nand levels\n   0  a[1]\n   1  full_adder1."));
+
+        let x = Bus::<16>::all0().to_shared_bus();
+        let y = Bus::<16>::all0().to_shared_bus();
+        let control: Vec<SharedBus<1>> = (0..6).map(|_| Bus::all0().to_shared_bus()).collect();
+        let alu = ALU::new(
+            x.clone(),
+            y.clone(),
+            control[0].clone(),
+            control[1].clone(),
+            control[2].clone(),
+            control[3].clone(),
+            control[4].clone(),
+            control[5].clone(),
+        );
+        let mut inputs = vec![("x", x.shared_bits()), ("y", y.shared_bits())];
+        for (name, bus) in ["zx", "nx", "zy", "ny", "f", "no"]
+            .iter()
+            .zip(control.iter())
+        {
+            inputs.push((name, bus.shared_bits()));
+        }
+        let outputs = [("zr", alu.zr.shared_bits()), ("ng", alu.ng.shared_bits())];
+        let netlist = Netlist::from_gate(&alu, &inputs, &outputs).unwrap();
+        let path = netlist.critical_path();
+        assert_eq!(path.levels, 90);
+        assert_eq!(path.nets[0], "zx");
+        assert_eq!(path.nets[13], "add1.full_adder1.half_adder1.xor.nand4");
+        assert_eq!(path.nets[90], "not4.nand");
+
+        let in_m = Bus::<16>::all0().to_shared_bus();
+        let instruction = Bus::<16>::all0().to_shared_bus();
+        let reset = Bus::<1>::all0().to_shared_bus();
+        let cpu = CPU::new(in_m.clone(), instruction.clone(), reset.clone());
+        let inputs = [
+            ("inM", in_m.shared_bits()),
+            ("instruction", instruction.shared_bits()),
+            ("reset", reset.shared_bits()),
+        ];
+        let outputs = [("writeM", cpu.write_m.shared_bits())];
+        let netlist = Netlist::from_gate(&cpu, &inputs, &outputs).unwrap();
+        // instructionからALUを通ってPCのレジスタの入力まで。半分はAdd16の中
+        let path = netlist.critical_path();
+        assert_eq!(path.levels, 121);
+        assert_eq!(path.nets[0], "instruction[12]");
+        assert_eq!(path.nets[121], "pc_gate.reg.one_bit0.mux.or.nand3");
+        let in_add16 = path
+            .nets
+            .iter()
+            .filter(|net| net.starts_with("alu.add1."))
+            .count();
+        assert_eq!(in_add16, 62);
+
+        // レジスタの間だけなら、Aレジスタの出力からALUを通ってPCのレジスタの入力まで
+        let path = netlist.register_path();
+        assert_eq!(path.levels, 120);
+        assert_eq!(path.nets[0], "a_register.one_bit1.dff");
+        assert_eq!(path.nets[120], "pc_gate.reg.one_bit0.mux.or.nand3");
+        assert!(path.nets.iter().all(|net| !net.starts_with("instruction")));
+
+        // DFFのない回路にはレジスタ間の経路がない
+        let netlist = Netlist::from_gate(&add16, &[], &[]).unwrap();
+        assert_eq!(
+            netlist.register_path(),
+            CriticalPath {
+                levels: 0,
+                nets: vec![]
+            }
+        );
+    }
+}
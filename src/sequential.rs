@@ -1,13 +1,18 @@
 use std::{
-    borrow::Borrow,
     cell::{Cell, RefCell},
+    rc::Rc,
 };
 
-use crate::{arithmetic::Inc16, gate::*};
+use crate::{
+    arithmetic::Inc16,
+    gate::*,
+    netlist::{NetlistBuilder, NetlistError},
+};
 
 /// 順序回路を使う回路ですべてclock_up(), clock_down()をちゃんと呼ぶ必要がある
 
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)] // nand2tetrisのチップ名に合わせる
 pub struct DFF {
     pub out: SharedBus<1>,
     input: SharedBus<1>,
@@ -16,10 +21,20 @@ pub struct DFF {
 
 impl DFF {
     pub fn new(input: SharedBus<1>) -> DFF {
-        let state = Bus::all0().to_shared_bus();
-        let out = Bus::all0().to_shared_bus();
+        DFF::with_out(input, Bus::all0().to_shared_bus())
+    }
+
+    // 先に作っておいたoutに出力する。DFFより前に作る部品がoutを入力にできる
+    pub fn with_out(input: SharedBus<1>, out: SharedBus<1>) -> DFF {
+        let state = Bus::new([Rc::new(Cell::new(out.get_shared_bit(0).get()))]).to_shared_bus();
         DFF { out, input, state }
     }
+
+    // clockを介さずに記憶している値を書き換える
+    pub fn set(&self, bit: Bit) -> () {
+        self.state.get_shared_bit(0).set(bit);
+        self.out.get_shared_bit(0).set(bit);
+    }
 }
 
 impl Gate for DFF {
@@ -35,6 +50,15 @@ impl Gate for DFF {
         let state_bit = self.state.get_shared_bit(0).get();
         self.out.get_shared_bit(0).set(state_bit);
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.dff(
+            &self.input.get_shared_bit(0),
+            &self.out.get_shared_bit(0),
+            self.state.get_shared_bit(0).get(),
+        );
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -42,21 +66,23 @@ pub struct OneBitRegister {
     pub out: SharedBus<1>,
     mux: Mux<1>,
     dff: DFF,
-    feedback: SharedBus<1>,
 }
 
 impl OneBitRegister {
     pub fn new(input: SharedBus<1>, load: SharedBus<1>) -> OneBitRegister {
-        let feedback = Bus::<1>::all0().to_shared_bus();
-        let mux = Mux::<1>::new(feedback.clone(), input.clone(), load.clone());
-        let dff = DFF::new(mux.out.clone());
-
-        OneBitRegister {
-            out: dff.out.clone(),
-            mux,
-            dff,
-            feedback,
-        }
+        OneBitRegister::with_out(input, load, Bus::all0().to_shared_bus())
+    }
+
+    // DFFの出力を先に作っておき、そのままmuxに戻す
+    pub fn with_out(input: SharedBus<1>, load: SharedBus<1>, out: SharedBus<1>) -> OneBitRegister {
+        let mux = Mux::<1>::new(out.clone(), input.clone(), load.clone());
+        let dff = DFF::with_out(mux.out.clone(), out.clone());
+
+        OneBitRegister { out, mux, dff }
+    }
+
+    pub fn set(&self, bit: Bit) -> () {
+        self.dff.set(bit);
     }
 }
 
@@ -70,724 +96,263 @@ impl Gate for OneBitRegister {
     }
 
     fn re_compute(&self) -> () {
-        let dff_out_bit = self.dff.out.get_shared_bit(0).get();
-        self.feedback.borrow().get_shared_bit(0).set(dff_out_bit);
-
         self.mux.re_compute();
     }
-}
-
-#[derive(Debug)]
-pub struct Register {
-    pub out: SharedBus<16>,
-    one_bit0: OneBitRegister,
-    one_bit1: OneBitRegister,
-    one_bit2: OneBitRegister,
-    one_bit3: OneBitRegister,
-    one_bit4: OneBitRegister,
-    one_bit5: OneBitRegister,
-    one_bit6: OneBitRegister,
-    one_bit7: OneBitRegister,
-    one_bit8: OneBitRegister,
-    one_bit9: OneBitRegister,
-    one_bit10: OneBitRegister,
-    one_bit11: OneBitRegister,
-    one_bit12: OneBitRegister,
-    one_bit13: OneBitRegister,
-    one_bit14: OneBitRegister,
-    one_bit15: OneBitRegister,
-}
 
-impl Register {
-    pub fn new(input: SharedBus<16>, load: SharedBus<1>) -> Register {
-        let one_bit0 = OneBitRegister::new(input.reconnect([0]), load.clone());
-        let one_bit1 = OneBitRegister::new(input.reconnect([1]), load.clone());
-        let one_bit2 = OneBitRegister::new(input.reconnect([2]), load.clone());
-        let one_bit3 = OneBitRegister::new(input.reconnect([3]), load.clone());
-        let one_bit4 = OneBitRegister::new(input.reconnect([4]), load.clone());
-        let one_bit5 = OneBitRegister::new(input.reconnect([5]), load.clone());
-        let one_bit6 = OneBitRegister::new(input.reconnect([6]), load.clone());
-        let one_bit7 = OneBitRegister::new(input.reconnect([7]), load.clone());
-        let one_bit8 = OneBitRegister::new(input.reconnect([8]), load.clone());
-        let one_bit9 = OneBitRegister::new(input.reconnect([9]), load.clone());
-        let one_bit10 = OneBitRegister::new(input.reconnect([10]), load.clone());
-        let one_bit11 = OneBitRegister::new(input.reconnect([11]), load.clone());
-        let one_bit12 = OneBitRegister::new(input.reconnect([12]), load.clone());
-        let one_bit13 = OneBitRegister::new(input.reconnect([13]), load.clone());
-        let one_bit14 = OneBitRegister::new(input.reconnect([14]), load.clone());
-        let one_bit15 = OneBitRegister::new(input.reconnect([15]), load.clone());
-
-        let out = Bus::new([
-            one_bit0.out.get_shared_bit(0),
-            one_bit1.out.get_shared_bit(0),
-            one_bit2.out.get_shared_bit(0),
-            one_bit3.out.get_shared_bit(0),
-            one_bit4.out.get_shared_bit(0),
-            one_bit5.out.get_shared_bit(0),
-            one_bit6.out.get_shared_bit(0),
-            one_bit7.out.get_shared_bit(0),
-            one_bit8.out.get_shared_bit(0),
-            one_bit9.out.get_shared_bit(0),
-            one_bit10.out.get_shared_bit(0),
-            one_bit11.out.get_shared_bit(0),
-            one_bit12.out.get_shared_bit(0),
-            one_bit13.out.get_shared_bit(0),
-            one_bit14.out.get_shared_bit(0),
-            one_bit15.out.get_shared_bit(0),
-        ])
-        .to_shared_bus();
-
-        Register {
-            out,
-            one_bit0,
-            one_bit1,
-            one_bit2,
-            one_bit3,
-            one_bit4,
-            one_bit5,
-            one_bit6,
-            one_bit7,
-            one_bit8,
-            one_bit9,
-            one_bit10,
-            one_bit11,
-            one_bit12,
-            one_bit13,
-            one_bit14,
-            one_bit15,
-        }
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("mux", &self.mux)?;
+        builder.part("dff", &self.dff)?;
+        Ok(())
     }
 }
 
-impl Gate for Register {
-    fn clock_up(&self) -> () {
-        self.one_bit0.clock_up();
-        self.one_bit1.clock_up();
-        self.one_bit2.clock_up();
-        self.one_bit3.clock_up();
-        self.one_bit4.clock_up();
-        self.one_bit5.clock_up();
-        self.one_bit6.clock_up();
-        self.one_bit7.clock_up();
-        self.one_bit8.clock_up();
-        self.one_bit9.clock_up();
-        self.one_bit10.clock_up();
-        self.one_bit11.clock_up();
-        self.one_bit12.clock_up();
-        self.one_bit13.clock_up();
-        self.one_bit14.clock_up();
-        self.one_bit15.clock_up();
-    }
+/// NbitのRegister。OneBitRegisterをN個並べる
+#[derive(Debug)]
+pub struct Register<const N: usize = 16> {
+    pub out: SharedBus<N>,
+    one_bits: Vec<OneBitRegister>,
+}
 
-    fn clock_down(&self) -> () {
-        self.one_bit0.clock_down();
-        self.one_bit1.clock_down();
-        self.one_bit2.clock_down();
-        self.one_bit3.clock_down();
-        self.one_bit4.clock_down();
-        self.one_bit5.clock_down();
-        self.one_bit6.clock_down();
-        self.one_bit7.clock_down();
-        self.one_bit8.clock_down();
-        self.one_bit9.clock_down();
-        self.one_bit10.clock_down();
-        self.one_bit11.clock_down();
-        self.one_bit12.clock_down();
-        self.one_bit13.clock_down();
-        self.one_bit14.clock_down();
-        self.one_bit15.clock_down();
+impl<const N: usize> Register<N> {
+    pub fn new(input: SharedBus<N>, load: SharedBus<1>) -> Register<N> {
+        Register::with_out(input, load, Bus::all0().to_shared_bus())
     }
 
-    fn re_compute(&self) -> () {
-        self.one_bit0.re_compute();
-        self.one_bit1.re_compute();
-        self.one_bit2.re_compute();
-        self.one_bit3.re_compute();
-        self.one_bit4.re_compute();
-        self.one_bit5.re_compute();
-        self.one_bit6.re_compute();
-        self.one_bit7.re_compute();
-        self.one_bit8.re_compute();
-        self.one_bit9.re_compute();
-        self.one_bit10.re_compute();
-        self.one_bit11.re_compute();
-        self.one_bit12.re_compute();
-        self.one_bit13.re_compute();
-        self.one_bit14.re_compute();
-        self.one_bit15.re_compute();
+    // 先に作っておいたoutに出力する。Registerより前に作る部品がoutを入力にできる
+    pub fn with_out(input: SharedBus<N>, load: SharedBus<1>, out: SharedBus<N>) -> Register<N> {
+        let one_bits: Vec<OneBitRegister> = (0..N)
+            .map(|i| {
+                OneBitRegister::with_out(input.reconnect([i]), load.clone(), out.reconnect([i]))
+            })
+            .collect();
+        Register { out, one_bits }
     }
-}
-
-#[derive(Debug)]
-pub struct RAM8 {
-    pub out: SharedBus<16>,
-    dmux8way: DMux8Way,
-    reg1: Register,
-    reg2: Register,
-    reg3: Register,
-    reg4: Register,
-    reg5: Register,
-    reg6: Register,
-    reg7: Register,
-    reg8: Register,
-    mux8way16: Mux8Way16,
-}
-
-#[allow(dead_code)]
-impl RAM8 {
-    pub fn new(input: SharedBus<16>, load: SharedBus<1>, address: SharedBus<3>) -> RAM8 {
-        let dmux8way = DMux8Way::new(load.clone(), address.clone());
-        let reg1 = Register::new(input.clone(), dmux8way.out1.clone());
-        let reg2 = Register::new(input.clone(), dmux8way.out2.clone());
-        let reg3 = Register::new(input.clone(), dmux8way.out3.clone());
-        let reg4 = Register::new(input.clone(), dmux8way.out4.clone());
-        let reg5 = Register::new(input.clone(), dmux8way.out5.clone());
-        let reg6 = Register::new(input.clone(), dmux8way.out6.clone());
-        let reg7 = Register::new(input.clone(), dmux8way.out7.clone());
-        let reg8 = Register::new(input.clone(), dmux8way.out8.clone());
-        let mux8way16 = Mux8Way16::new(
-            reg1.out.clone(),
-            reg2.out.clone(),
-            reg3.out.clone(),
-            reg4.out.clone(),
-            reg5.out.clone(),
-            reg6.out.clone(),
-            reg7.out.clone(),
-            reg8.out.clone(),
-            address.clone(),
-        );
 
-        RAM8 {
-            out: mux8way16.out.clone(),
-            dmux8way,
-            reg1,
-            reg2,
-            reg3,
-            reg4,
-            reg5,
-            reg6,
-            reg7,
-            reg8,
-            mux8way16,
+    // clockを介さずに記憶している値を書き換える。u64に収まらない幅だとコンパイル時にエラーになる
+    pub fn set(&self, value: u64) -> () {
+        const { assert!(N <= 64, "Register::set supports at most 64 bits") };
+        for (i, one_bit) in self.one_bits.iter().enumerate() {
+            one_bit.set(if (value >> i) & 1 == 1 { I } else { O });
         }
     }
 }
 
-impl Gate for RAM8 {
+impl<const N: usize> Gate for Register<N> {
     fn clock_up(&self) -> () {
-        self.dmux8way.clock_up();
-        self.reg1.clock_up();
-        self.reg2.clock_up();
-        self.reg3.clock_up();
-        self.reg4.clock_up();
-        self.reg5.clock_up();
-        self.reg6.clock_up();
-        self.reg7.clock_up();
-        self.reg8.clock_up();
-        self.mux8way16.clock_up();
+        for one_bit in self.one_bits.iter() {
+            one_bit.clock_up();
+        }
     }
 
     fn clock_down(&self) -> () {
-        self.dmux8way.clock_down();
-        self.reg1.clock_down();
-        self.reg2.clock_down();
-        self.reg3.clock_down();
-        self.reg4.clock_down();
-        self.reg5.clock_down();
-        self.reg6.clock_down();
-        self.reg7.clock_down();
-        self.reg8.clock_down();
-        self.mux8way16.clock_down();
+        for one_bit in self.one_bits.iter() {
+            one_bit.clock_down();
+        }
     }
 
     fn re_compute(&self) -> () {
-        self.dmux8way.re_compute();
-        self.reg1.re_compute();
-        self.reg2.re_compute();
-        self.reg3.re_compute();
-        self.reg4.re_compute();
-        self.reg5.re_compute();
-        self.reg6.re_compute();
-        self.reg7.re_compute();
-        self.reg8.re_compute();
-        self.mux8way16.re_compute();
+        for one_bit in self.one_bits.iter() {
+            one_bit.re_compute();
+        }
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        for (i, one_bit) in self.one_bits.iter().enumerate() {
+            builder.part(&format!("one_bit{}", i), one_bit)?;
+        }
+        Ok(())
     }
 }
 
+pub type RAM8 = Ram<3>;
+pub type RAM64 = Ram<6>;
+pub type RAM512 = Ram<9>;
+pub type RAM4K = Ram<12>;
+#[allow(dead_code)]
+pub type RAM16K = Ram<14>;
+
+/// 2^ADDR_BITS 個のNbitのRegisterを持つRAM
+/// addressの上位bitでどの部品に書くかを選び、残りのbitはそのまま部品に渡す。
+/// 3bitずつ8個の部品に分けていくので、RAM64はRAM8を8個、RAM16KはRAM4Kを4個並べたものになる
 #[derive(Debug)]
-pub struct RAM64 {
-    pub out: SharedBus<16>,
-    dmux8way: Box<DMux8Way>,
-    ram8_1: Box<RAM8>,
-    ram8_2: Box<RAM8>,
-    ram8_3: Box<RAM8>,
-    ram8_4: Box<RAM8>,
-    ram8_5: Box<RAM8>,
-    ram8_6: Box<RAM8>,
-    ram8_7: Box<RAM8>,
-    ram8_8: Box<RAM8>,
-    mux8way16: Box<Mux8Way16>,
+pub struct Ram<const ADDR_BITS: usize, const N: usize = 16> {
+    pub out: SharedBus<N>,
+    bank: RamBank<N>,
 }
 
-#[allow(dead_code)]
-impl RAM64 {
-    pub fn new(input: SharedBus<16>, load: SharedBus<1>, address: SharedBus<6>) -> RAM64 {
-        let low_address = address.reconnect([0, 1, 2]);
-        let high_address = address.reconnect([3, 4, 5]);
-        let dmux8way = Box::new(DMux8Way::new(load.clone(), high_address.clone()));
-        let ram8_1 = Box::new(RAM8::new(
-            input.clone(),
-            dmux8way.out1.clone(),
-            low_address.clone(),
-        ));
-        let ram8_2 = Box::new(RAM8::new(
-            input.clone(),
-            dmux8way.out2.clone(),
-            low_address.clone(),
-        ));
-        let ram8_3 = Box::new(RAM8::new(
-            input.clone(),
-            dmux8way.out3.clone(),
-            low_address.clone(),
-        ));
-        let ram8_4 = Box::new(RAM8::new(
-            input.clone(),
-            dmux8way.out4.clone(),
-            low_address.clone(),
-        ));
-        let ram8_5 = Box::new(RAM8::new(
-            input.clone(),
-            dmux8way.out5.clone(),
-            low_address.clone(),
-        ));
-        let ram8_6 = Box::new(RAM8::new(
-            input.clone(),
-            dmux8way.out6.clone(),
-            low_address.clone(),
-        ));
-        let ram8_7 = Box::new(RAM8::new(
-            input.clone(),
-            dmux8way.out7.clone(),
-            low_address.clone(),
-        ));
-        let ram8_8 = Box::new(RAM8::new(
-            input.clone(),
-            dmux8way.out8.clone(),
-            low_address.clone(),
-        ));
-        let mux8way16 = Box::new(Mux8Way16::new(
-            ram8_1.out.clone(),
-            ram8_2.out.clone(),
-            ram8_3.out.clone(),
-            ram8_4.out.clone(),
-            ram8_5.out.clone(),
-            ram8_6.out.clone(),
-            ram8_7.out.clone(),
-            ram8_8.out.clone(),
-            high_address.clone(),
-        ));
-
-        RAM64 {
-            out: mux8way16.out.clone(),
-            dmux8way,
-            ram8_1,
-            ram8_2,
-            ram8_3,
-            ram8_4,
-            ram8_5,
-            ram8_6,
-            ram8_7,
-            ram8_8,
-            mux8way16,
+impl<const ADDR_BITS: usize, const N: usize> Ram<ADDR_BITS, N> {
+    pub fn new(
+        input: SharedBus<N>,
+        load: SharedBus<1>,
+        address: SharedBus<ADDR_BITS>,
+    ) -> Ram<ADDR_BITS, N> {
+        const { assert!(ADDR_BITS > 0, "Ram needs at least one address bit") };
+        let address: Vec<SharedBus<1>> = (0..ADDR_BITS).map(|i| address.reconnect([i])).collect();
+        let bank = RamBank::new(input, load, &address);
+        Ram {
+            out: bank.out.clone(),
+            bank,
         }
     }
 }
 
-impl Gate for RAM64 {
+impl<const ADDR_BITS: usize, const N: usize> Gate for Ram<ADDR_BITS, N> {
     fn clock_up(&self) -> () {
-        self.dmux8way.clock_up();
-        self.ram8_1.clock_up();
-        self.ram8_2.clock_up();
-        self.ram8_3.clock_up();
-        self.ram8_4.clock_up();
-        self.ram8_5.clock_up();
-        self.ram8_6.clock_up();
-        self.ram8_7.clock_up();
-        self.ram8_8.clock_up();
-        self.mux8way16.clock_up();
+        self.bank.clock_up();
     }
 
     fn clock_down(&self) -> () {
-        self.dmux8way.clock_down();
-        self.ram8_1.clock_down();
-        self.ram8_2.clock_down();
-        self.ram8_3.clock_down();
-        self.ram8_4.clock_down();
-        self.ram8_5.clock_down();
-        self.ram8_6.clock_down();
-        self.ram8_7.clock_down();
-        self.ram8_8.clock_down();
-        self.mux8way16.clock_down();
+        self.bank.clock_down();
     }
 
     fn re_compute(&self) -> () {
-        self.dmux8way.re_compute();
-        self.ram8_1.re_compute();
-        self.ram8_2.re_compute();
-        self.ram8_3.re_compute();
-        self.ram8_4.re_compute();
-        self.ram8_5.re_compute();
-        self.ram8_6.re_compute();
-        self.ram8_7.re_compute();
-        self.ram8_8.re_compute();
-        self.mux8way16.re_compute();
+        self.bank.re_compute();
+    }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        self.bank.flatten(builder)
     }
 }
 
+// Ramの中身。const genericsではaddressの幅を減らしながら型を作れないので、幅は実行時に決める
 #[derive(Debug)]
-pub struct RAM512 {
-    pub out: SharedBus<16>,
-    dmux8way: Box<DMux8Way>,
-    ram64_1: Box<RAM64>,
-    ram64_2: Box<RAM64>,
-    ram64_3: Box<RAM64>,
-    ram64_4: Box<RAM64>,
-    ram64_5: Box<RAM64>,
-    ram64_6: Box<RAM64>,
-    ram64_7: Box<RAM64>,
-    ram64_8: Box<RAM64>,
-    mux8way16: Box<Mux8Way16>,
+struct RamBank<const N: usize> {
+    out: SharedBus<N>,
+    address_bits: usize,
+    // loadを部品ごとに分けるDMuxの木と、部品の出力を選ぶMuxの木
+    dmuxes: Vec<DMux>,
+    parts: Vec<RamPart<N>>,
+    muxes: Vec<Mux<N>>,
 }
 
-#[allow(dead_code)]
-impl RAM512 {
-    pub fn new(input: SharedBus<16>, load: SharedBus<1>, address: SharedBus<9>) -> RAM512 {
-        let low_address = address.reconnect([0, 1, 2, 3, 4, 5]);
-        let high_address = address.reconnect([6, 7, 8]);
-        let dmux8way = Box::new(DMux8Way::new(load.clone(), high_address.clone()));
-        let ram64_1 = Box::new(RAM64::new(
-            input.clone(),
-            dmux8way.out1.clone(),
-            low_address.clone(),
-        ));
-        let ram64_2 = Box::new(RAM64::new(
-            input.clone(),
-            dmux8way.out2.clone(),
-            low_address.clone(),
-        ));
-        let ram64_3 = Box::new(RAM64::new(
-            input.clone(),
-            dmux8way.out3.clone(),
-            low_address.clone(),
-        ));
-        let ram64_4 = Box::new(RAM64::new(
-            input.clone(),
-            dmux8way.out4.clone(),
-            low_address.clone(),
-        ));
-        let ram64_5 = Box::new(RAM64::new(
-            input.clone(),
-            dmux8way.out5.clone(),
-            low_address.clone(),
-        ));
-        let ram64_6 = Box::new(RAM64::new(
-            input.clone(),
-            dmux8way.out6.clone(),
-            low_address.clone(),
-        ));
-        let ram64_7 = Box::new(RAM64::new(
-            input.clone(),
-            dmux8way.out7.clone(),
-            low_address.clone(),
-        ));
-        let ram64_8 = Box::new(RAM64::new(
-            input.clone(),
-            dmux8way.out8.clone(),
-            low_address.clone(),
-        ));
-        let mux8way16 = Box::new(Mux8Way16::new(
-            ram64_1.out.clone(),
-            ram64_2.out.clone(),
-            ram64_3.out.clone(),
-            ram64_4.out.clone(),
-            ram64_5.out.clone(),
-            ram64_6.out.clone(),
-            ram64_7.out.clone(),
-            ram64_8.out.clone(),
-            high_address.clone(),
-        ));
-
-        RAM512 {
-            out: mux8way16.out.clone(),
-            dmux8way,
-            ram64_1,
-            ram64_2,
-            ram64_3,
-            ram64_4,
-            ram64_5,
-            ram64_6,
-            ram64_7,
-            ram64_8,
-            mux8way16,
-        }
-    }
+#[derive(Debug)]
+enum RamPart<const N: usize> {
+    Register(Register<N>),
+    Bank(Box<RamBank<N>>),
 }
 
-impl Gate for RAM512 {
-    fn clock_up(&self) -> () {
-        self.dmux8way.clock_up();
-        self.ram64_1.clock_up();
-        self.ram64_2.clock_up();
-        self.ram64_3.clock_up();
-        self.ram64_4.clock_up();
-        self.ram64_5.clock_up();
-        self.ram64_6.clock_up();
-        self.ram64_7.clock_up();
-        self.ram64_8.clock_up();
-        self.mux8way16.clock_up();
-    }
-
-    fn clock_down(&self) -> () {
-        self.dmux8way.clock_down();
-        self.ram64_1.clock_down();
-        self.ram64_2.clock_down();
-        self.ram64_3.clock_down();
-        self.ram64_4.clock_down();
-        self.ram64_5.clock_down();
-        self.ram64_6.clock_down();
-        self.ram64_7.clock_down();
-        self.ram64_8.clock_down();
-        self.mux8way16.clock_down();
-    }
-
-    fn re_compute(&self) -> () {
-        self.dmux8way.re_compute();
-        self.ram64_1.re_compute();
-        self.ram64_2.re_compute();
-        self.ram64_3.re_compute();
-        self.ram64_4.re_compute();
-        self.ram64_5.re_compute();
-        self.ram64_6.re_compute();
-        self.ram64_7.re_compute();
-        self.ram64_8.re_compute();
-        self.mux8way16.re_compute();
+impl<const N: usize> RamBank<N> {
+    // addressは下位bitから。空でないことはRam::newで確かめている
+    fn new(input: SharedBus<N>, load: SharedBus<1>, address: &[SharedBus<1>]) -> RamBank<N> {
+        // 上位から1〜3bitで部品を選び、残りが3の倍数になるようにする
+        let (low, high) = address.split_at((address.len() - 1) / 3 * 3);
+        let mut dmuxes = vec![];
+        let loads = decode(load, high, &mut dmuxes);
+        let parts: Vec<RamPart<N>> = loads
+            .into_iter()
+            .map(|load| match low.is_empty() {
+                true => RamPart::Register(Register::new(input.clone(), load)),
+                false => RamPart::Bank(Box::new(RamBank::new(input.clone(), load, low))),
+            })
+            .collect();
+        let mut muxes = vec![];
+        let outs = parts.iter().map(|part| part.out()).collect();
+        let out = select(outs, high, &mut muxes);
+        RamBank {
+            out,
+            address_bits: address.len(),
+            dmuxes,
+            parts,
+            muxes,
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct RAM4K {
-    pub out: SharedBus<16>,
-    dmux8way: Box<DMux8Way>,
-    ram512_1: Box<RAM512>,
-    ram512_2: Box<RAM512>,
-    ram512_3: Box<RAM512>,
-    ram512_4: Box<RAM512>,
-    ram512_5: Box<RAM512>,
-    ram512_6: Box<RAM512>,
-    ram512_7: Box<RAM512>,
-    ram512_8: Box<RAM512>,
-    mux8way16: Box<Mux8Way16>,
-}
+impl<const N: usize> RamPart<N> {
+    fn out(&self) -> SharedBus<N> {
+        match self {
+            RamPart::Register(register) => register.out.clone(),
+            RamPart::Bank(bank) => bank.out.clone(),
+        }
+    }
 
-#[allow(dead_code)]
-impl RAM4K {
-    pub fn new(input: SharedBus<16>, load: SharedBus<1>, address: SharedBus<12>) -> RAM4K {
-        let low_address = address.reconnect([0, 1, 2, 3, 4, 5, 6, 7, 8]);
-        let high_address = address.reconnect([9, 10, 11]);
-        let dmux8way = Box::new(DMux8Way::new(load.clone(), high_address.clone()));
-        let ram512_1 = Box::new(RAM512::new(
-            input.clone(),
-            dmux8way.out1.clone(),
-            low_address.clone(),
-        ));
-        let ram512_2 = Box::new(RAM512::new(
-            input.clone(),
-            dmux8way.out2.clone(),
-            low_address.clone(),
-        ));
-        let ram512_3 = Box::new(RAM512::new(
-            input.clone(),
-            dmux8way.out3.clone(),
-            low_address.clone(),
-        ));
-        let ram512_4 = Box::new(RAM512::new(
-            input.clone(),
-            dmux8way.out4.clone(),
-            low_address.clone(),
-        ));
-        let ram512_5 = Box::new(RAM512::new(
-            input.clone(),
-            dmux8way.out5.clone(),
-            low_address.clone(),
-        ));
-        let ram512_6 = Box::new(RAM512::new(
-            input.clone(),
-            dmux8way.out6.clone(),
-            low_address.clone(),
-        ));
-        let ram512_7 = Box::new(RAM512::new(
-            input.clone(),
-            dmux8way.out7.clone(),
-            low_address.clone(),
-        ));
-        let ram512_8 = Box::new(RAM512::new(
-            input.clone(),
-            dmux8way.out8.clone(),
-            low_address.clone(),
-        ));
-        let mux8way16 = Box::new(Mux8Way16::new(
-            ram512_1.out.clone(),
-            ram512_2.out.clone(),
-            ram512_3.out.clone(),
-            ram512_4.out.clone(),
-            ram512_5.out.clone(),
-            ram512_6.out.clone(),
-            ram512_7.out.clone(),
-            ram512_8.out.clone(),
-            high_address.clone(),
-        ));
-
-        RAM4K {
-            out: mux8way16.out.clone(),
-            dmux8way,
-            ram512_1,
-            ram512_2,
-            ram512_3,
-            ram512_4,
-            ram512_5,
-            ram512_6,
-            ram512_7,
-            ram512_8,
-            mux8way16,
+    fn gate(&self) -> &dyn Gate {
+        match self {
+            RamPart::Register(register) => register,
+            RamPart::Bank(bank) => bank.as_ref(),
         }
     }
 }
 
-impl Gate for RAM4K {
+impl<const N: usize> Gate for RamBank<N> {
     fn clock_up(&self) -> () {
-        self.dmux8way.clock_up();
-        self.ram512_1.clock_up();
-        self.ram512_2.clock_up();
-        self.ram512_3.clock_up();
-        self.ram512_4.clock_up();
-        self.ram512_5.clock_up();
-        self.ram512_6.clock_up();
-        self.ram512_7.clock_up();
-        self.ram512_8.clock_up();
-        self.mux8way16.clock_up();
+        for part in self.parts.iter() {
+            part.gate().clock_up();
+        }
     }
 
     fn clock_down(&self) -> () {
-        self.dmux8way.clock_down();
-        self.ram512_1.clock_down();
-        self.ram512_2.clock_down();
-        self.ram512_3.clock_down();
-        self.ram512_4.clock_down();
-        self.ram512_5.clock_down();
-        self.ram512_6.clock_down();
-        self.ram512_7.clock_down();
-        self.ram512_8.clock_down();
-        self.mux8way16.clock_down();
+        for part in self.parts.iter() {
+            part.gate().clock_down();
+        }
     }
 
     fn re_compute(&self) -> () {
-        self.dmux8way.re_compute();
-        self.ram512_1.re_compute();
-        self.ram512_2.re_compute();
-        self.ram512_3.re_compute();
-        self.ram512_4.re_compute();
-        self.ram512_5.re_compute();
-        self.ram512_6.re_compute();
-        self.ram512_7.re_compute();
-        self.ram512_8.re_compute();
-        self.mux8way16.re_compute();
+        for dmux in self.dmuxes.iter() {
+            dmux.re_compute();
+        }
+        for part in self.parts.iter() {
+            part.gate().re_compute();
+        }
+        for mux in self.muxes.iter() {
+            mux.re_compute();
+        }
     }
-}
-
-#[derive(Debug)]
-pub struct RAM16K {
-    pub out: SharedBus<16>,
-    dmux4way: Box<DMux4Way>,
-    ram4k_1: Box<RAM4K>,
-    ram4k_2: Box<RAM4K>,
-    ram4k_3: Box<RAM4K>,
-    ram4k_4: Box<RAM4K>,
-    mux4way16: Box<Mux4Way16>,
-}
 
-#[allow(dead_code)]
-impl RAM16K {
-    pub fn new(input: SharedBus<16>, load: SharedBus<1>, address: SharedBus<14>) -> RAM16K {
-        let low_address = address.reconnect([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
-        let high_address = address.reconnect([12, 13]);
-        let dmux4way = Box::new(DMux4Way::new(load.clone(), high_address.clone()));
-        let ram4k_1 = Box::new(RAM4K::new(
-            input.clone(),
-            dmux4way.out1.clone(),
-            low_address.clone(),
-        ));
-        let ram4k_2 = Box::new(RAM4K::new(
-            input.clone(),
-            dmux4way.out2.clone(),
-            low_address.clone(),
-        ));
-        let ram4k_3 = Box::new(RAM4K::new(
-            input.clone(),
-            dmux4way.out3.clone(),
-            low_address.clone(),
-        ));
-        let ram4k_4 = Box::new(RAM4K::new(
-            input.clone(),
-            dmux4way.out4.clone(),
-            low_address.clone(),
-        ));
-        let mux4way16 = Box::new(Mux4Way16::new(
-            ram4k_1.out.clone(),
-            ram4k_2.out.clone(),
-            ram4k_3.out.clone(),
-            ram4k_4.out.clone(),
-            high_address.clone(),
-        ));
-
-        RAM16K {
-            out: mux4way16.out.clone(),
-            dmux4way,
-            ram4k_1,
-            ram4k_2,
-            ram4k_3,
-            ram4k_4,
-            mux4way16,
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        for (i, dmux) in self.dmuxes.iter().enumerate() {
+            builder.part(&format!("dmux{}", i + 1), dmux)?;
+        }
+        for (i, part) in self.parts.iter().enumerate() {
+            match part {
+                RamPart::Register(register) => builder.part(&format!("reg{}", i + 1), register)?,
+                RamPart::Bank(bank) => builder.named_part(
+                    &format!("ram{}", i + 1),
+                    &format!("Ram<{}, {}>", bank.address_bits, N),
+                    bank.as_ref(),
+                )?,
+            }
         }
+        for (i, mux) in self.muxes.iter().enumerate() {
+            builder.part(&format!("mux{}", i + 1), mux)?;
+        }
+        Ok(())
     }
 }
 
-impl Gate for RAM16K {
-    fn clock_up(&self) -> () {
-        self.dmux4way.clock_up();
-        self.ram4k_1.clock_up();
-        self.ram4k_2.clock_up();
-        self.ram4k_3.clock_up();
-        self.ram4k_4.clock_up();
-        self.mux4way16.clock_up();
-    }
-
-    fn clock_down(&self) -> () {
-        self.dmux4way.clock_down();
-        self.ram4k_1.clock_down();
-        self.ram4k_2.clock_down();
-        self.ram4k_3.clock_down();
-        self.ram4k_4.clock_down();
-        self.mux4way16.clock_down();
+// loadをselの値で2^k本に分ける。DMux8Wayと同じく上位bitから分けていく
+fn decode(input: SharedBus<1>, sel: &[SharedBus<1>], dmuxes: &mut Vec<DMux>) -> Vec<SharedBus<1>> {
+    match sel.split_last() {
+        None => vec![input],
+        Some((top, rest)) => {
+            let dmux = DMux::new(input, top.clone());
+            let (out1, out2) = (dmux.out1.clone(), dmux.out2.clone());
+            dmuxes.push(dmux);
+            let mut outs = decode(out1, rest, dmuxes);
+            outs.extend(decode(out2, rest, dmuxes));
+            outs
+        }
     }
+}
 
-    fn re_compute(&self) -> () {
-        self.dmux4way.re_compute();
-        self.ram4k_1.re_compute();
-        self.ram4k_2.re_compute();
-        self.ram4k_3.re_compute();
-        self.ram4k_4.re_compute();
-        self.mux4way16.re_compute();
+// selの値番目を選ぶ。Mux8Way16と同じく下位bitから2つずつ選んでいく
+fn select<const N: usize>(
+    mut inputs: Vec<SharedBus<N>>,
+    sel: &[SharedBus<1>],
+    muxes: &mut Vec<Mux<N>>,
+) -> SharedBus<N> {
+    for bit in sel {
+        inputs = inputs
+            .chunks(2)
+            .map(|pair| {
+                let mux = Mux::new(pair[0].clone(), pair[1].clone(), bit.clone());
+                let out = mux.out.clone();
+                muxes.push(mux);
+                out
+            })
+            .collect();
     }
+    inputs.pop().unwrap()
 }
 
 #[derive(Debug)]
@@ -853,16 +418,17 @@ impl Gate for RAM16KBuiltIn {
                 1 => I,
                 _ => O,
             });
-            value = value / 2;
+            value /= 2;
             if value < 1 {
                 break;
             }
         }
 
         for i in 0..16 {
-            self.out
-                .get_shared_bit(i)
-                .set(bits.get(i).map(|b| b.clone()).unwrap_or(O));
+            drive(
+                &self.out.get_shared_bit(i),
+                bits.get(i).copied().unwrap_or(O),
+            );
         }
     }
 }
@@ -870,7 +436,6 @@ impl Gate for RAM16KBuiltIn {
 #[derive(Debug)]
 pub struct PC {
     pub out: SharedBus<16>,
-    feedback: SharedBus<16>,
     inc16: Inc16,
     mux16_1: Mux<16>,
     mux16_2: Mux<16>,
@@ -885,20 +450,24 @@ impl PC {
         inc: SharedBus<1>,
         reset: SharedBus<1>,
     ) -> PC {
-        let feedback = Bus::<16>::all0().to_shared_bus();
-        let inc16 = Inc16::new(feedback.clone());
-        let mux16_1 = Mux::new(feedback.clone(), inc16.out.clone(), inc.clone());
+        // regの出力をincとmuxに戻す
+        let out = Bus::<16>::all0().to_shared_bus();
+        let inc16 = Inc16::new(out.clone());
+        let mux16_1 = Mux::new(out.clone(), inc16.out.clone(), inc.clone());
         let mux16_2 = Mux::new(mux16_1.out.clone(), input.clone(), load.clone());
         let mux16_3 = Mux::new(
             mux16_2.out.clone(),
             Bus::all0().to_shared_bus(),
             reset.clone(),
         );
-        let reg = Register::new(mux16_3.out.clone(), Bus::all1().to_shared_bus());
+        let reg = Register::with_out(
+            mux16_3.out.clone(),
+            Bus::all1().to_shared_bus(),
+            out.clone(),
+        );
 
         PC {
-            out: reg.out.clone(),
-            feedback,
+            out,
             inc16,
             mux16_1,
             mux16_2,
@@ -906,6 +475,10 @@ impl PC {
             reg,
         }
     }
+
+    pub fn set(&self, value: u16) -> () {
+        self.reg.set(value.into());
+    }
 }
 
 impl Gate for PC {
@@ -926,23 +499,27 @@ impl Gate for PC {
     }
 
     fn re_compute(&self) -> () {
-        // regのoutをmuxのaに反映
-        for i in 0..16 {
-            let reg_out_bit = self.reg.out.get_shared_bit(i).get();
-            self.feedback.borrow().get_shared_bit(i).set(reg_out_bit);
-        }
-
         self.inc16.re_compute();
         self.mux16_1.re_compute();
         self.mux16_2.re_compute();
         self.mux16_3.re_compute();
         self.reg.re_compute();
     }
+
+    fn flatten(&self, builder: &mut NetlistBuilder) -> Result<(), NetlistError> {
+        builder.part("inc16", &self.inc16)?;
+        builder.part("mux16_1", &self.mux16_1)?;
+        builder.part("mux16_2", &self.mux16_2)?;
+        builder.part("mux16_3", &self.mux16_3)?;
+        builder.part("reg", &self.reg)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::netlist::Twin;
 
     #[test]
     fn dff() {
@@ -971,6 +548,11 @@ mod tests {
         let input = Bus::<1>::all0().to_shared_bus();
         let load = Bus::<1>::all0().to_shared_bus();
         let one_bit_register = OneBitRegister::new(input.clone(), load.clone());
+        let chip = Twin::new(
+            &one_bit_register,
+            &[input.shared_bits(), load.shared_bits()],
+            &[one_bit_register.out.shared_bits()],
+        );
 
         assert_eq!(
             one_bit_register.out.clone(),
@@ -982,8 +564,8 @@ mod tests {
         load.get_shared_bit(0).set(I);
 
         // tick
-        one_bit_register.re_compute();
-        one_bit_register.clock_up();
+        chip.re_compute();
+        chip.clock_up();
         assert_eq!(
             one_bit_register.out.clone(),
             Bus::<1>::all0().to_shared_bus()
@@ -1000,8 +582,8 @@ mod tests {
         );
 
         // tock
-        one_bit_register.clock_down();
-        one_bit_register.re_compute();
+        chip.clock_down();
+        chip.re_compute();
         // ここで1が記憶されているはず
         assert_eq!(
             one_bit_register.out.clone(),
@@ -1028,8 +610,8 @@ mod tests {
         );
 
         // tick
-        one_bit_register.re_compute();
-        one_bit_register.clock_up();
+        chip.re_compute();
+        chip.clock_up();
         // まだ1が記憶されているはず
         assert_eq!(
             one_bit_register.out.clone(),
@@ -1049,8 +631,8 @@ mod tests {
         );
 
         // tock
-        one_bit_register.clock_down();
-        one_bit_register.re_compute();
+        chip.clock_down();
+        chip.re_compute();
         // まだ1が記憶されているはず
         assert_eq!(
             one_bit_register.out.clone(),
@@ -1058,11 +640,11 @@ mod tests {
         );
 
         // tick
-        one_bit_register.re_compute();
-        one_bit_register.clock_up();
+        chip.re_compute();
+        chip.clock_up();
         // tock
-        one_bit_register.clock_down();
-        one_bit_register.re_compute();
+        chip.clock_down();
+        chip.re_compute();
         // まだ1が記憶されているはず
         assert_eq!(
             one_bit_register.out.clone(),
@@ -1074,8 +656,8 @@ mod tests {
         load.get_shared_bit(0).set(I);
 
         // tick
-        one_bit_register.re_compute();
-        one_bit_register.clock_up();
+        chip.re_compute();
+        chip.clock_up();
         // tickの直後ではまだ1
         assert_eq!(
             one_bit_register.out.clone(),
@@ -1083,8 +665,8 @@ mod tests {
         );
 
         // tock
-        one_bit_register.clock_down();
-        one_bit_register.re_compute();
+        chip.clock_down();
+        chip.re_compute();
         // tockの直後に0になる
         assert_eq!(
             one_bit_register.out.clone(),
@@ -1092,11 +674,11 @@ mod tests {
         );
 
         // tick
-        one_bit_register.re_compute();
-        one_bit_register.clock_up();
+        chip.re_compute();
+        chip.clock_up();
         // tock
-        one_bit_register.clock_down();
-        one_bit_register.re_compute();
+        chip.clock_down();
+        chip.re_compute();
         // まだ0が記憶されているはず
         assert_eq!(
             one_bit_register.out.clone(),
@@ -1262,6 +844,11 @@ mod tests {
         let input: SharedBus<16> = Bus::all0().to_shared_bus();
         let load: SharedBus<1> = Bus::all0().to_shared_bus();
         let register = Register::new(input.clone(), load.clone());
+        let chip = Twin::new(
+            &register,
+            &[input.shared_bits(), load.shared_bits()],
+            &[register.out.shared_bits()],
+        );
 
         cases.chunks(2).for_each(|case| {
             let _input = i16_to_bus16(case[0].0);
@@ -1274,14 +861,14 @@ mod tests {
             load.overwrite(&_sel);
 
             // tick
-            register.re_compute();
-            register.clock_up();
+            chip.re_compute();
+            chip.clock_up();
 
             assert_eq!(register.out.clone(), out.clone());
 
             // tock
-            register.clock_down();
-            register.re_compute();
+            chip.clock_down();
+            chip.re_compute();
 
             let out = i16_to_bus16(case[1].2).to_shared_bus();
             assert_eq!(register.out.clone(), out.clone());
@@ -1471,6 +1058,15 @@ mod tests {
         let load: SharedBus<1> = Bus::all0().to_shared_bus();
         let address: SharedBus<3> = Bus::all0().to_shared_bus();
         let ram8 = RAM8::new(input.clone(), load.clone(), address.clone());
+        let chip = Twin::new(
+            &ram8,
+            &[
+                input.shared_bits(),
+                load.shared_bits(),
+                address.shared_bits(),
+            ],
+            &[ram8.out.shared_bits()],
+        );
 
         for case in cases {
             let _input = i16_to_bus16(case.0);
@@ -1486,13 +1082,13 @@ mod tests {
 
             // tick
             if case.4 {
-                ram8.re_compute();
-                ram8.clock_up();
+                chip.re_compute();
+                chip.clock_up();
                 assert_eq!(ram8.out.clone(), out.clone());
             } else {
                 // tock
-                ram8.clock_down();
-                ram8.re_compute();
+                chip.clock_down();
+                chip.re_compute();
                 assert_eq!(ram8.out.clone(), out.clone());
             }
         }
@@ -1828,6 +1424,15 @@ mod tests {
         let load: SharedBus<1> = Bus::all0().to_shared_bus();
         let address: SharedBus<6> = Bus::all0().to_shared_bus();
         let ram64 = RAM64::new(input.clone(), load.clone(), address.clone());
+        let chip = Twin::new(
+            &ram64,
+            &[
+                input.shared_bits(),
+                load.shared_bits(),
+                address.shared_bits(),
+            ],
+            &[ram64.out.shared_bits()],
+        );
 
         for case in cases {
             println!(
@@ -1847,19 +1452,84 @@ mod tests {
 
             // tick
             if case.4 {
-                ram64.re_compute();
-                ram64.clock_up();
+                chip.re_compute();
+                chip.clock_up();
 
                 assert_eq!(ram64.out.clone(), out.clone());
             } else {
                 // tock
-                ram64.clock_down();
-                ram64.re_compute();
+                chip.clock_down();
+                chip.re_compute();
                 assert_eq!(ram64.out.clone(), out.clone());
             }
         }
     }
 
+    // 8bitと32bitのRegisterとRAM
+    #[test]
+    fn register_and_ram_n() {
+        fn set<const N: usize>(bus: &SharedBus<N>, value: u64) -> () {
+            for i in 0..N {
+                let bit = if (value >> i) & 1 == 1 { I } else { O };
+                bus.get_shared_bit(i).set(bit);
+            }
+        }
+        fn value<const N: usize>(bus: &SharedBus<N>) -> u64 {
+            (0..N)
+                .map(|i| ((bus.get_shared_bit(i).get() == I) as u64) << i)
+                .sum()
+        }
+        fn tick_tock(gate: &dyn Gate) -> () {
+            gate.re_compute();
+            gate.clock_up();
+            gate.clock_down();
+            gate.re_compute();
+        }
+
+        let input = Bus::<32>::all0().to_shared_bus();
+        let load = Bus::<1>::all1().to_shared_bus();
+        let register = Register::<32>::new(input.clone(), load.clone());
+        let chip = Twin::new(
+            &register,
+            &[input.shared_bits(), load.shared_bits()],
+            &[register.out.shared_bits()],
+        );
+        set(&input, 0xdead_beef);
+        tick_tock(&chip);
+        assert_eq!(value(&register.out), 0xdead_beef);
+        register.set(0x8000_0001);
+        assert_eq!(value(&register.out), 0x8000_0001);
+
+        // 16ワードの8bit RAM。上位1bitでRAM8を選ぶ
+        let input = Bus::<8>::all0().to_shared_bus();
+        let address = Bus::<4>::all0().to_shared_bus();
+        let ram = Ram::<4, 8>::new(input.clone(), load.clone(), address.clone());
+        let chip = Twin::new(
+            &ram,
+            &[
+                input.shared_bits(),
+                load.shared_bits(),
+                address.shared_bits(),
+            ],
+            &[ram.out.shared_bits()],
+        );
+        for word in 0..16 {
+            set(&address, word);
+            set(&input, word * 16 + 1);
+            tick_tock(&chip);
+        }
+        load.get_shared_bit(0).set(O);
+        set(&input, 0);
+        for word in (0..16).rev() {
+            set(&address, word);
+            tick_tock(&chip);
+            assert_eq!(value(&ram.out), word * 16 + 1);
+        }
+        let count = crate::netlist::GateCount::of("Ram<4, 8>", &ram).unwrap();
+        assert_eq!(count.dffs, 16 * 8);
+        assert_eq!(count.parts[1].chip, "Ram<3, 8>");
+    }
+
     #[test]
     fn pc() {
         // 順番に依存している
@@ -1902,6 +1572,16 @@ mod tests {
         let inc: SharedBus<1> = Bus::all0().to_shared_bus();
         let reset: SharedBus<1> = Bus::all0().to_shared_bus();
         let pc = PC::new(input.clone(), load.clone(), inc.clone(), reset.clone());
+        let chip = Twin::new(
+            &pc,
+            &[
+                input.shared_bits(),
+                load.shared_bits(),
+                inc.shared_bits(),
+                reset.shared_bits(),
+            ],
+            &[pc.out.shared_bits()],
+        );
 
         for case in cases {
             println!(
@@ -1923,13 +1603,13 @@ mod tests {
 
             // tick
             if case.5 {
-                pc.re_compute();
-                pc.clock_up();
+                chip.re_compute();
+                chip.clock_up();
                 assert_eq!(pc.out.clone(), out.clone());
             } else {
                 // tock
-                pc.clock_down();
-                pc.re_compute();
+                chip.clock_down();
+                chip.re_compute();
                 assert_eq!(pc.out.clone(), out.clone());
             }
         }
@@ -2266,6 +1946,15 @@ mod tests {
         let load: SharedBus<1> = Bus::all0().to_shared_bus();
         let address: SharedBus<9> = Bus::all0().to_shared_bus();
         let ram512 = RAM512::new(input.clone(), load.clone(), address.clone());
+        let chip = Twin::new(
+            &ram512,
+            &[
+                input.shared_bits(),
+                load.shared_bits(),
+                address.shared_bits(),
+            ],
+            &[ram512.out.shared_bits()],
+        );
 
         for case in cases {
             println!(
@@ -2285,13 +1974,13 @@ mod tests {
 
             // tick
             if case.4 {
-                ram512.re_compute();
-                ram512.clock_up();
+                chip.re_compute();
+                chip.clock_up();
                 assert_eq!(ram512.out.clone(), out.clone());
             } else {
                 // tock
-                ram512.clock_down();
-                ram512.re_compute();
+                chip.clock_down();
+                chip.re_compute();
                 assert_eq!(ram512.out.clone(), out.clone());
             }
         }
@@ -2628,6 +2317,15 @@ mod tests {
         let load: SharedBus<1> = Bus::all0().to_shared_bus();
         let address: SharedBus<12> = Bus::all0().to_shared_bus();
         let ram4k = RAM4K::new(input.clone(), load.clone(), address.clone());
+        let chip = Twin::new(
+            &ram4k,
+            &[
+                input.shared_bits(),
+                load.shared_bits(),
+                address.shared_bits(),
+            ],
+            &[ram4k.out.shared_bits()],
+        );
 
         for case in cases {
             println!(
@@ -2647,13 +2345,13 @@ mod tests {
 
             // tick
             if case.4 {
-                ram4k.re_compute();
-                ram4k.clock_up();
+                chip.re_compute();
+                chip.clock_up();
                 assert_eq!(ram4k.out.clone(), out.clone());
             } else {
                 // tock
-                ram4k.clock_down();
-                ram4k.re_compute();
+                chip.clock_down();
+                chip.re_compute();
                 assert_eq!(ram4k.out.clone(), out.clone());
             }
         }
@@ -2989,6 +2687,15 @@ mod tests {
         let load: SharedBus<1> = Bus::all0().to_shared_bus();
         let address: SharedBus<14> = Bus::all0().to_shared_bus();
         let ram16k = RAM16K::new(input.clone(), load.clone(), address.clone());
+        let chip = Twin::new(
+            &ram16k,
+            &[
+                input.shared_bits(),
+                load.shared_bits(),
+                address.shared_bits(),
+            ],
+            &[ram16k.out.shared_bits()],
+        );
 
         for case in cases {
             println!(
@@ -3008,13 +2715,13 @@ mod tests {
 
             // tick
             if case.4 {
-                ram16k.re_compute();
-                ram16k.clock_up();
+                chip.re_compute();
+                chip.clock_up();
                 assert_eq!(ram16k.out.clone(), out.clone());
             } else {
                 // tock
-                ram16k.clock_down();
-                ram16k.re_compute();
+                chip.clock_down();
+                chip.re_compute();
                 assert_eq!(ram16k.out.clone(), out.clone());
             }
         }
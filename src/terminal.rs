@@ -0,0 +1,229 @@
+use std::{
+    io::{self, Read},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Hackのキーコード
+pub const NEWLINE: u16 = 128;
+pub const BACKSPACE: u16 = 129;
+pub const LEFT_ARROW: u16 = 130;
+pub const UP_ARROW: u16 = 131;
+pub const RIGHT_ARROW: u16 = 132;
+pub const DOWN_ARROW: u16 = 133;
+pub const HOME: u16 = 134;
+pub const END: u16 = 135;
+pub const PAGE_UP: u16 = 136;
+pub const PAGE_DOWN: u16 = 137;
+pub const INSERT: u16 = 138;
+pub const DELETE: u16 = 139;
+pub const ESC: u16 = 140;
+/// F1..F12 は 141..152
+pub const F1: u16 = 141;
+
+const CTRL_C: u8 = 0x03;
+
+/// 端末はキーを離したことを通知してくれないので、入力が途切れてからこの時間で離したとみなす
+const RELEASE_AFTER: Duration = Duration::from_millis(200);
+
+/// 端末から読んだバイト列の先頭を1キー分デコードする
+/// 戻り値は (Hackのキーコード, 消費したバイト数)。対応するキーがなければキーコードは0
+pub fn decode_key(bytes: &[u8]) -> (u16, usize) {
+    match bytes {
+        [] => (0, 0),
+        [b'\r' | b'\n', ..] => (NEWLINE, 1),
+        [0x7f | 0x08, ..] => (BACKSPACE, 1),
+        [0x1b, b'[', rest @ ..] => decode_csi(rest),
+        [0x1b, b'O', c @ b'P'..=b'S', ..] => (F1 + (c - b'P') as u16, 3),
+        [0x1b, b'O', c @ (b'H' | b'F'), ..] => (if *c == b'H' { HOME } else { END }, 3),
+        [0x1b, ..] => (ESC, 1),
+        [c @ 0x20..=0x7e, ..] => (*c as u16, 1),
+        _ => (0, 1),
+    }
+}
+
+// ESC [ の後ろ
+fn decode_csi(rest: &[u8]) -> (u16, usize) {
+    match rest {
+        [b'A', ..] => (UP_ARROW, 3),
+        [b'B', ..] => (DOWN_ARROW, 3),
+        [b'C', ..] => (RIGHT_ARROW, 3),
+        [b'D', ..] => (LEFT_ARROW, 3),
+        [b'H', ..] => (HOME, 3),
+        [b'F', ..] => (END, 3),
+        _ => {
+            // ESC [ 数字 ~
+            let digits = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+            if digits == 0 || rest.get(digits) != Some(&b'~') {
+                return (ESC, 1);
+            }
+            let number: u32 = std::str::from_utf8(&rest[..digits])
+                .unwrap()
+                .parse()
+                .unwrap_or(0);
+            let key = match number {
+                1 | 7 => HOME,
+                2 => INSERT,
+                3 => DELETE,
+                4 | 8 => END,
+                5 => PAGE_UP,
+                6 => PAGE_DOWN,
+                11..=15 => F1 + (number - 11) as u16,
+                17..=21 => F1 + 5 + (number - 17) as u16,
+                23 | 24 => F1 + 10 + (number - 23) as u16,
+                _ => 0,
+            };
+            (key, 2 + digits + 1)
+        }
+    }
+}
+
+/// 端末をrawモードにして、裏のスレッドでキー入力を読み続ける
+/// dropするとスレッドを止めて待ち、端末の設定を元に戻す
+pub struct TerminalKeyboard {
+    key: Arc<AtomicU16>,
+    pressed_at: Arc<Mutex<Instant>>,
+    quit: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    reader: Option<thread::JoinHandle<()>>,
+    saved_mode: String,
+}
+
+impl TerminalKeyboard {
+    pub fn start() -> io::Result<TerminalKeyboard> {
+        let saved_mode = stty(&["-g"])?;
+        // 行バッファとエコー、Ctrl-Cでのシグナルを止める。出力の改行変換はそのまま
+        // readは入力がなくても0.1秒で戻るようにして、stopを見られるようにする
+        stty(&["-icanon", "-echo", "-isig", "min", "0", "time", "1"])?;
+
+        let key = Arc::new(AtomicU16::new(0));
+        let pressed_at = Arc::new(Mutex::new(Instant::now()));
+        let quit = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let key = key.clone();
+            let pressed_at = pressed_at.clone();
+            let quit = quit.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut stdin = io::stdin();
+                let mut buf = [0; 64];
+                while !stop.load(Ordering::Relaxed) {
+                    // 0は入力がないまま時間切れになっただけ
+                    let n = match stdin.read(&mut buf) {
+                        Ok(n) => n,
+                        Err(_) => break,
+                    };
+                    let mut bytes = &buf[..n];
+                    while !bytes.is_empty() {
+                        if bytes[0] == CTRL_C {
+                            quit.store(true, Ordering::Relaxed);
+                            return;
+                        }
+                        let (code, consumed) = decode_key(bytes);
+                        if code != 0 {
+                            key.store(code, Ordering::Relaxed);
+                            *pressed_at.lock().unwrap() = Instant::now();
+                        }
+                        bytes = &bytes[consumed..];
+                    }
+                }
+            })
+        };
+
+        Ok(TerminalKeyboard {
+            key,
+            pressed_at,
+            quit,
+            stop,
+            reader: Some(reader),
+            saved_mode,
+        })
+    }
+
+    /// 今押されているキーのHackキーコード。押されていなければ0
+    pub fn current_key(&self) -> u16 {
+        if self.pressed_at.lock().unwrap().elapsed() > RELEASE_AFTER {
+            return 0;
+        }
+        self.key.load(Ordering::Relaxed)
+    }
+
+    /// Ctrl-Cが押されたか
+    pub fn quit_requested(&self) -> bool {
+        self.quit.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for TerminalKeyboard {
+    fn drop(&mut self) {
+        // 端末を戻す前にスレッドを止めて、あとの入力を読まれないようにする
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+        let _ = stty(&[self.saved_mode.as_str()]);
+    }
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stty failed (stdin is not a terminal?)"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        let cases: Vec<(&[u8], u16, usize)> = vec![
+            // bytes, key, consumed
+            (b"a", 97, 1),
+            (b"K", 75, 1),
+            (b" ", 32, 1),
+            (b"\r", NEWLINE, 1),
+            (b"\n", NEWLINE, 1),
+            (b"\x7f", BACKSPACE, 1),
+            (b"\x1b[D", LEFT_ARROW, 3),
+            (b"\x1b[A", UP_ARROW, 3),
+            (b"\x1b[C", RIGHT_ARROW, 3),
+            (b"\x1b[B", DOWN_ARROW, 3),
+            (b"\x1b[H", HOME, 3),
+            (b"\x1b[F", END, 3),
+            (b"\x1b[1~", HOME, 4),
+            (b"\x1b[4~", END, 4),
+            (b"\x1b[5~", PAGE_UP, 4),
+            (b"\x1b[6~", PAGE_DOWN, 4),
+            (b"\x1b[2~", INSERT, 4),
+            (b"\x1b[3~", DELETE, 4),
+            (b"\x1b", ESC, 1),
+            (b"\x1bOP", 141, 3),
+            (b"\x1bOS", 144, 3),
+            (b"\x1b[15~", 145, 5),
+            (b"\x1b[17~", 146, 5),
+            (b"\x1b[21~", 150, 5),
+            (b"\x1b[23~", 151, 5),
+            (b"\x1b[24~", 152, 5),
+            (b"\x01", 0, 1),
+        ];
+        for (bytes, key, consumed) in cases {
+            assert_eq!(decode_key(bytes), (key, consumed), "{:?}", bytes);
+        }
+
+        // 続けて入力されたものは1キーずつ読む
+        assert_eq!(decode_key(b"\x1b[Aab"), (UP_ARROW, 3));
+    }
+}
@@ -0,0 +1,1183 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    builtin,
+    circuit::{Chip, Library},
+    cli::load_rom,
+    computer::{Computer, MemoryBuiltIn, ROM32KBuiltIn, CPU},
+    gate::*,
+    hdl::HdlError,
+    netlist::{Netlist, NetlistError, Stats},
+    sequential::{OneBitRegister, RAM16KBuiltIn, Register, PC},
+    vcd::Vcd,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum TstErrorKind {
+    Io(String),
+    Syntax(String),
+    Hdl(HdlError),
+    Netlist(NetlistError),
+    NoChip,
+    UnknownPin(String),
+    UnknownState(String),
+    Rom(String),
+    Oscillation(String),
+}
+
+/// テストスクリプトのエラー。lineは.tstの行
+#[derive(Debug, PartialEq)]
+pub struct TstError {
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub kind: TstErrorKind,
+}
+
+impl TstError {
+    fn new(line: usize, kind: TstErrorKind) -> TstError {
+        TstError {
+            file: None,
+            line,
+            kind,
+        }
+    }
+
+    fn syntax(line: usize, message: String) -> TstError {
+        TstError::new(line, TstErrorKind::Syntax(message))
+    }
+}
+
+impl fmt::Display for TstError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        if self.line > 0 {
+            write!(f, "{}:", self.line)?;
+        }
+        if self.file.is_some() || self.line > 0 {
+            write!(f, " ")?;
+        }
+        match &self.kind {
+            TstErrorKind::Io(e) => write!(f, "{}", e),
+            TstErrorKind::Syntax(message) => write!(f, "{}", message),
+            TstErrorKind::Hdl(e) => write!(f, "{}", e),
+            TstErrorKind::Netlist(e) => write!(f, "{}", e),
+            TstErrorKind::NoChip => write!(f, "no chip is loaded"),
+            TstErrorKind::UnknownPin(pin) => write!(f, "unknown pin `{}`", pin),
+            TstErrorKind::UnknownState(name) => write!(f, "unknown chip state `{}`", name),
+            TstErrorKind::Rom(e) => write!(f, "{}", e),
+            TstErrorKind::Oscillation(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TstError {}
+
+/// 出力と.cmpが最初に食い違った行 (1始まり)
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// スクリプトを最後まで(または最初の不一致まで)実行した結果
+#[derive(Debug)]
+pub struct Outcome {
+    pub lines: Vec<String>,
+    pub compared: bool,
+    pub mismatch: Option<Mismatch>,
+    /// use_netlistのとき、最後にloadしたチップのNandの計算回数
+    pub stats: Option<Stats>,
+}
+
+// 値を読み書きする先
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    Time,
+    Pin(String),
+    // ARegister[] や RAM16K[3] のような組み込みチップの中身
+    State(String, Option<u16>),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Time => write!(f, "time"),
+            Target::Pin(pin) => write!(f, "{}", pin),
+            Target::State(name, None) => write!(f, "{}[]", name),
+            Target::State(name, Some(index)) => write!(f, "{}[{}]", name, index),
+        }
+    }
+}
+
+// output-list の1列。a%B3.1.3 なら format='B', left=3, width=1, right=3
+#[derive(Debug, Clone, PartialEq)]
+struct Column {
+    target: Target,
+    format: char,
+    left: usize,
+    width: usize,
+    right: usize,
+}
+
+#[derive(Debug, PartialEq)]
+enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+#[derive(Debug, PartialEq)]
+struct Condition {
+    target: Target,
+    compare: Compare,
+    value: u16,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Load(String),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<Column>),
+    Set(Target, u16),
+    Eval,
+    Tick,
+    Tock,
+    TickTock,
+    Output,
+    RomLoad(String),
+    Repeat(usize, Vec<Step>),
+    While(Condition, Vec<Step>),
+    // echo や breakpoint など、結果に関係しないもの
+    Ignored,
+}
+
+#[derive(Debug, PartialEq)]
+struct Step {
+    line: usize,
+    command: Command,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    // , ; ! はどれもコマンドの区切りとして扱う
+    Separator,
+    Open,
+    Close,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, TstError> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    chars.next();
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let start = line;
+                let mut last = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if last == '*' => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            last = c;
+                        }
+                        None => return Err(TstError::syntax(start, "unclosed comment".into())),
+                    }
+                }
+            }
+            ',' | ';' | '!' => tokens.push((line, Token::Separator)),
+            '{' => tokens.push((line, Token::Open)),
+            '}' => tokens.push((line, Token::Close)),
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\n') | None => {
+                            return Err(TstError::syntax(line, "unclosed string".into()))
+                        }
+                        Some(c) => s.push(c),
+                    }
+                }
+                tokens.push((line, Token::Str(s)));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.peek() {
+                    if c.is_whitespace() || ",;!{}\"".contains(*c) {
+                        break;
+                    }
+                    word.push(*c);
+                    chars.next();
+                }
+                tokens.push((line, Token::Word(word)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or(self.tokens.last())
+            .map_or(0, |(line, _)| *line)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn word(&mut self, what: &str) -> Result<String, TstError> {
+        match self.peek() {
+            Some(Token::Word(word)) => {
+                let word = word.clone();
+                self.pos += 1;
+                Ok(word)
+            }
+            _ => Err(TstError::syntax(self.line(), format!("expected {}", what))),
+        }
+    }
+
+    // 区切りでも}でもない次の単語
+    fn next_is_word(&self) -> bool {
+        matches!(self.peek(), Some(Token::Word(_)))
+    }
+
+    // } か終わりまでのコマンドを読む
+    fn steps(&mut self, in_block: bool) -> Result<Vec<Step>, TstError> {
+        let mut steps = vec![];
+        loop {
+            match self.peek() {
+                None if in_block => {
+                    return Err(TstError::syntax(self.line(), "missing `}`".into()))
+                }
+                None => return Ok(steps),
+                Some(Token::Close) if in_block => {
+                    self.pos += 1;
+                    return Ok(steps);
+                }
+                Some(Token::Separator) => self.pos += 1,
+                _ => {
+                    let line = self.line();
+                    let command = self.command()?;
+                    steps.push(Step { line, command });
+                }
+            }
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Step>, TstError> {
+        match self.peek() {
+            Some(Token::Open) => {
+                self.pos += 1;
+                self.steps(true)
+            }
+            _ => Err(TstError::syntax(self.line(), "expected `{`".into())),
+        }
+    }
+
+    fn command(&mut self) -> Result<Command, TstError> {
+        let line = self.line();
+        let name = self.word("a command")?;
+        let command = match name.as_str() {
+            "load" => match self.next_is_word() {
+                true => Command::Load(self.word("a file name")?),
+                false => Command::Ignored,
+            },
+            "output-file" => Command::OutputFile(self.word("a file name")?),
+            "compare-to" => Command::CompareTo(self.word("a file name")?),
+            "output-list" => {
+                let mut columns = vec![];
+                while self.next_is_word() {
+                    let word = self.word("a column")?;
+                    columns.push(parse_column(&word).map_err(|e| TstError::syntax(line, e))?);
+                }
+                Command::OutputList(columns)
+            }
+            "set" => {
+                let target = parse_target(&self.word("a pin")?);
+                let value = self.word("a value")?;
+                let value = parse_value(&value).map_err(|e| TstError::syntax(line, e))?;
+                Command::Set(target, value)
+            }
+            "eval" => Command::Eval,
+            "tick" => Command::Tick,
+            "tock" => Command::Tock,
+            "ticktock" => Command::TickTock,
+            "output" => Command::Output,
+            "ROM32K" => {
+                if self.word("`load`")? != "load" {
+                    return Err(TstError::syntax(line, "expected `load`".into()));
+                }
+                Command::RomLoad(self.word("a file name")?)
+            }
+            "repeat" => {
+                if !self.next_is_word() {
+                    return Err(TstError::syntax(line, "repeat without count".into()));
+                }
+                let count = self.word("a count")?;
+                let count = count
+                    .parse()
+                    .map_err(|_| TstError::syntax(line, format!("invalid count `{}`", count)))?;
+                Command::Repeat(count, self.block()?)
+            }
+            "while" => {
+                let target = parse_target(&self.word("a pin")?);
+                let compare = match self.word("a comparison")?.as_str() {
+                    "=" => Compare::Eq,
+                    "<>" => Compare::Ne,
+                    "<" => Compare::Lt,
+                    ">" => Compare::Gt,
+                    "<=" => Compare::Le,
+                    ">=" => Compare::Ge,
+                    op => {
+                        return Err(TstError::syntax(
+                            line,
+                            format!("unknown comparison `{}`", op),
+                        ))
+                    }
+                };
+                let value = self.word("a value")?;
+                let value = parse_value(&value).map_err(|e| TstError::syntax(line, e))?;
+                let condition = Condition {
+                    target,
+                    compare,
+                    value,
+                };
+                Command::While(condition, self.block()?)
+            }
+            "echo" => match self.peek() {
+                Some(Token::Str(_)) | Some(Token::Word(_)) => {
+                    self.pos += 1;
+                    Command::Ignored
+                }
+                _ => return Err(TstError::syntax(line, "expected a message".into())),
+            },
+            "clear-echo" | "clear-breakpoints" => Command::Ignored,
+            "breakpoint" => {
+                self.word("a pin")?;
+                self.word("a value")?;
+                Command::Ignored
+            }
+            _ => {
+                return Err(TstError::syntax(
+                    line,
+                    format!("unknown command `{}`", name),
+                ))
+            }
+        };
+        Ok(command)
+    }
+}
+
+fn parse(source: &str) -> Result<Vec<Step>, TstError> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    parser.steps(false)
+}
+
+// "time"、"a"、"RAM16K[3]"、"ARegister[]"
+fn parse_target(word: &str) -> Target {
+    if word == "time" {
+        return Target::Time;
+    }
+    match word.split_once('[') {
+        Some((name, rest)) => {
+            let index = rest.trim_end_matches(']');
+            Target::State(name.to_string(), index.parse().ok())
+        }
+        None => Target::Pin(word.to_string()),
+    }
+}
+
+// 10進(負も可)、%B0101、%XFF、%D-3
+fn parse_value(s: &str) -> Result<u16, String> {
+    let error = || format!("invalid value `{}`", s);
+    let (radix, digits) = match s.get(..2) {
+        Some("%B") => (2, &s[2..]),
+        Some("%X") => (16, &s[2..]),
+        Some("%D") => (10, &s[2..]),
+        _ => (10, s),
+    };
+    if radix == 10 {
+        return match digits.parse::<u16>() {
+            Ok(value) => Ok(value),
+            Err(_) => digits.parse::<i16>().map(|v| v as u16).map_err(|_| error()),
+        };
+    }
+    u16::from_str_radix(digits, radix).map_err(|_| error())
+}
+
+// "a%B3.1.3"。%以降がなければ %B1.1.1
+fn parse_column(word: &str) -> Result<Column, String> {
+    let error = || format!("invalid output column `{}`", word);
+    let (name, format) = word.split_once('%').unwrap_or((word, "B1.1.1"));
+    let mut chars = format.chars();
+    let format = chars
+        .next()
+        .filter(|c| "BDXS".contains(*c))
+        .ok_or_else(error)?;
+    let sizes = chars
+        .as_str()
+        .split('.')
+        .map(|n| n.parse::<usize>().map_err(|_| error()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if sizes.len() != 3 {
+        return Err(error());
+    }
+    Ok(Column {
+        target: parse_target(name),
+        format,
+        left: sizes[0],
+        width: sizes[1],
+        right: sizes[2],
+    })
+}
+
+// .cmpの*はどの文字にも一致する
+fn line_matches(expected: &str, actual: &str) -> bool {
+    expected.chars().count() == actual.chars().count()
+        && expected
+            .chars()
+            .zip(actual.chars())
+            .all(|(e, a)| e == '*' || e == a)
+}
+
+// 組み込みチップの中身を読む。writeがあれば書き込む
+fn access_state(chip: &Chip, name: &str, index: Option<u16>, write: Option<u16>) -> Option<u16> {
+    chip.builtins().find_map(|(node, state)| {
+        if let Some(computer) = state.downcast_ref::<Computer>() {
+            computer_state(computer, name, index, write)
+        } else if let Some(cpu) = state.downcast_ref::<CPU>() {
+            cpu_state(cpu, name, index, write)
+        } else if let Some(memory) = state.downcast_ref::<MemoryBuiltIn>() {
+            let address = memory_address(name, index)?;
+            if let Some(value) = write {
+                memory.poke(address, value);
+            }
+            Some(memory.peek(address))
+        } else if let Some(ram) = state.downcast_ref::<RAM16KBuiltIn>() {
+            let index = index.filter(|i| *i < 16384 && name == node)? as usize;
+            if let Some(value) = write {
+                ram.ram.borrow_mut()[index] = value;
+            }
+            let value = ram.ram.borrow()[index];
+            Some(value)
+        } else if let Some(rom) = state.downcast_ref::<ROM32KBuiltIn>() {
+            let index = index.filter(|i| *i < 32768 && name == node)? as usize;
+            if let Some(value) = write {
+                rom.rom.borrow_mut()[index] = value;
+            }
+            let value = rom.rom.borrow()[index];
+            Some(value)
+        } else if name != node || index.is_some() {
+            None
+        } else if let Some(register) = state.downcast_ref::<Register>() {
+            if let Some(value) = write {
+                register.set(value.into());
+            }
+            Some(register.out.to_u16())
+        } else if let Some(pc) = state.downcast_ref::<PC>() {
+            if let Some(value) = write {
+                pc.set(value);
+            }
+            Some(pc.out.to_u16())
+        } else if let Some(bit) = state.downcast_ref::<OneBitRegister>() {
+            if let Some(value) = write {
+                bit.set(if value & 1 == 1 { I } else { O });
+            }
+            Some(bit.out.to_u16())
+        } else {
+            None
+        }
+    })
+}
+
+// Memory の中の RAM16K[i]、Screen[i]、Keyboard[] のアドレス
+fn memory_address(name: &str, index: Option<u16>) -> Option<u16> {
+    match (name, index) {
+        ("Memory", Some(i)) if i <= 24576 => Some(i),
+        ("RAM16K", Some(i)) if i < 16384 => Some(i),
+        ("Screen", Some(i)) if i < 8192 => Some(16384 + i),
+        ("Keyboard", None) => Some(24576),
+        _ => None,
+    }
+}
+
+fn cpu_state(cpu: &CPU, name: &str, index: Option<u16>, write: Option<u16>) -> Option<u16> {
+    if index.is_some() {
+        return None;
+    }
+    match name {
+        "ARegister" => {
+            if let Some(value) = write {
+                cpu.set_a_register_value(value);
+            }
+            Some(cpu.get_a_register_value())
+        }
+        "DRegister" => {
+            if let Some(value) = write {
+                cpu.set_d_register_value(value);
+            }
+            Some(cpu.get_d_register_value())
+        }
+        "PC" => {
+            if let Some(value) = write {
+                cpu.set_pc(value);
+            }
+            Some(cpu.pc.to_u16())
+        }
+        _ => None,
+    }
+}
+
+fn computer_state(
+    computer: &Computer,
+    name: &str,
+    index: Option<u16>,
+    write: Option<u16>,
+) -> Option<u16> {
+    // 書き込んだ値はほかの組み込みチップと同じく、次のevalやtickで出力に反映される
+    if let Some(address) = memory_address(name, index) {
+        if let Some(value) = write {
+            computer.memory().poke(address, value);
+        }
+        return Some(computer.peek(address));
+    }
+    match (name, index, write) {
+        ("ROM32K", Some(i), None) if i < 32768 => Some(computer.get_instruction(i)),
+        (_, None, _) => cpu_state(&computer.cpu, name, None, write),
+        _ => None,
+    }
+}
+
+struct Runner<'a> {
+    dir: &'a Path,
+    use_hdl: bool,
+    use_netlist: bool,
+    chip: Option<Chip>,
+    // use_netlistのときはピンとclockをこちらで扱う
+    netlist: Option<Netlist>,
+    vcd_path: Option<&'a Path>,
+    vcd: Option<Vcd>,
+    time: usize,
+    // tickしてまだtockしていない
+    ticked: bool,
+    columns: Vec<Column>,
+    lines: Vec<String>,
+    output_file: Option<PathBuf>,
+    compare: Option<Vec<String>>,
+    mismatch: Option<Mismatch>,
+}
+
+impl<'a> Runner<'a> {
+    fn chip(&self, line: usize) -> Result<&Chip, TstError> {
+        self.chip
+            .as_ref()
+            .ok_or(TstError::new(line, TstErrorKind::NoChip))
+    }
+
+    fn gate(&self, line: usize) -> Result<&dyn Gate, TstError> {
+        match &self.netlist {
+            Some(netlist) => Ok(netlist),
+            None => Ok(self.chip(line)?),
+        }
+    }
+
+    fn load(&mut self, line: usize, file: &str) -> Result<(), TstError> {
+        let name = Path::new(file)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or(file);
+        // 組み込みにあるチップはRustの実装を使う。--hdlのときは.hdlを優先する
+        let mut library = match self.use_hdl || builtin::find(name).is_none() {
+            true => Library::new(&[self.dir]),
+            false => Library::new::<&Path>(&[]),
+        };
+        let chip = library
+            .build(name)
+            .map_err(|e| TstError::new(line, TstErrorKind::Hdl(e)))?;
+        self.netlist = match self.use_netlist {
+            true => Some(
+                chip.to_netlist()
+                    .map_err(|e| TstError::new(line, TstErrorKind::Netlist(e)))?,
+            ),
+            false => None,
+        };
+        self.finish_vcd(line)?;
+        self.vcd = match (self.vcd_path, &self.netlist) {
+            (Some(path), None) => {
+                let mut vcd = Vcd::create(chip.name(), path)
+                    .map_err(|e| TstError::new(line, TstErrorKind::Io(e.to_string())))?;
+                for (pin, bits) in chip.probes() {
+                    vcd.probe(pin, bits);
+                }
+                vcd.sample(O);
+                Some(vcd)
+            }
+            _ => None,
+        };
+        self.chip = Some(chip);
+        self.time = 0;
+        self.ticked = false;
+        Ok(())
+    }
+
+    // (値, ビット幅)
+    fn read(&self, line: usize, target: &Target) -> Result<(u16, usize), TstError> {
+        let chip = self.chip(line)?;
+        match target {
+            Target::Time => Ok((self.time as u16, 16)),
+            Target::Pin(pin) => {
+                let width = chip
+                    .input_pins()
+                    .into_iter()
+                    .chain(chip.output_pins())
+                    .find(|(name, _)| name == pin)
+                    .map(|(_, width)| width)
+                    .ok_or(TstError::new(line, TstErrorKind::UnknownPin(pin.clone())))?;
+                let value = match &self.netlist {
+                    Some(netlist) => netlist.get(pin),
+                    None => chip.get(pin),
+                };
+                Ok((value.unwrap(), width))
+            }
+            // netlistにはレジスタやRAMの中身を取り出す方法がない
+            Target::State(..) if self.netlist.is_some() => Err(TstError::new(
+                line,
+                TstErrorKind::UnknownState(target.to_string()),
+            )),
+            Target::State(name, index) => access_state(chip, name, *index, None)
+                .map(|value| (value, 16))
+                .ok_or(TstError::new(
+                    line,
+                    TstErrorKind::UnknownState(target.to_string()),
+                )),
+        }
+    }
+
+    fn set(&self, line: usize, target: &Target, value: u16) -> Result<(), TstError> {
+        let chip = self.chip(line)?;
+        let ok = match target {
+            Target::Time => false,
+            Target::Pin(pin) => match &self.netlist {
+                Some(netlist) => netlist.set(pin, value),
+                None => chip.set(pin, value),
+            },
+            Target::State(..) if self.netlist.is_some() => false,
+            Target::State(name, index) => access_state(chip, name, *index, Some(value)).is_some(),
+        };
+        match (ok, target) {
+            (true, _) => Ok(()),
+            (false, Target::State(..)) => Err(TstError::new(
+                line,
+                TstErrorKind::UnknownState(target.to_string()),
+            )),
+            (false, _) => Err(TstError::new(
+                line,
+                TstErrorKind::UnknownPin(target.to_string()),
+            )),
+        }
+    }
+
+    fn format(&self, line: usize, column: &Column) -> Result<String, TstError> {
+        let width = column.width;
+        let s = match (&column.target, column.format) {
+            (Target::Time, _) => {
+                let time = format!("{}{}", self.time, if self.ticked { "+" } else { "" });
+                format!("{:<width$}", time)
+            }
+            (target, format) => {
+                let (value, bits) = self.read(line, target)?;
+                let s = match format {
+                    'B' => format!("{:0width$b}", value),
+                    'X' => format!("{:0width$X}", value),
+                    'D' if bits == 16 => format!("{:>width$}", value as i16),
+                    'D' => format!("{:>width$}", value),
+                    _ => format!("{:<width$}", value),
+                };
+                // 幅を超える分は上位を捨てる
+                s[s.len() - width.min(s.len())..].to_string()
+            }
+        };
+        Ok(format!(
+            "{}{}{}",
+            " ".repeat(column.left),
+            s,
+            " ".repeat(column.right)
+        ))
+    }
+
+    // 列名を列の幅の中央に置く。入り切らなければ切る
+    fn header(column: &Column) -> String {
+        let total = column.left + column.width + column.right;
+        let name: String = column.target.to_string().chars().take(total).collect();
+        let left = (total - name.len()) / 2;
+        format!(
+            "{}{}{}",
+            " ".repeat(left),
+            name,
+            " ".repeat(total - left - name.len())
+        )
+    }
+
+    // 1行出力して.cmpの同じ行と比べる。食い違ったらfalse
+    fn emit(&mut self, text: String) -> bool {
+        self.lines.push(text);
+        let line = self.lines.len();
+        let actual = self.lines.last().unwrap();
+        if let Some(compare) = &self.compare {
+            let expected = compare.get(line - 1).map_or("", |s| s.as_str());
+            if !line_matches(expected, actual) {
+                self.mismatch = Some(Mismatch {
+                    line,
+                    expected: expected.to_string(),
+                    actual: actual.clone(),
+                });
+                return false;
+            }
+        }
+        true
+    }
+
+    fn output(&mut self, line: usize) -> Result<bool, TstError> {
+        let cells = self
+            .columns
+            .iter()
+            .map(|column| self.format(line, column))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(self.emit(format!("|{}|", cells.join("|"))))
+    }
+
+    fn read_file(&self, line: usize, file: &str) -> Result<String, TstError> {
+        fs::read_to_string(self.dir.join(file))
+            .map_err(|e| TstError::new(line, TstErrorKind::Io(format!("{}: {}", file, e))))
+    }
+
+    fn condition(&self, line: usize, condition: &Condition) -> Result<bool, TstError> {
+        let (value, bits) = self.read(line, &condition.target)?;
+        let signed = |v: u16| {
+            if bits == 16 {
+                v as i16 as i32
+            } else {
+                v as i32
+            }
+        };
+        let (a, b) = (signed(value), signed(condition.value));
+        Ok(match condition.compare {
+            Compare::Eq => a == b,
+            Compare::Ne => a != b,
+            Compare::Lt => a < b,
+            Compare::Gt => a > b,
+            Compare::Le => a <= b,
+            Compare::Ge => a >= b,
+        })
+    }
+
+    // 不一致で止めるときはfalse
+    fn run(&mut self, steps: &[Step]) -> Result<bool, TstError> {
+        for step in steps {
+            if !self.step(step)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn step(&mut self, step: &Step) -> Result<bool, TstError> {
+        let line = step.line;
+        match &step.command {
+            Command::Load(file) => self.load(line, file)?,
+            Command::OutputFile(file) => self.output_file = Some(self.dir.join(file)),
+            Command::CompareTo(file) => {
+                let source = self.read_file(line, file)?;
+                let lines = source.lines().map(|l| l.trim_end().to_string()).collect();
+                self.compare = Some(lines);
+            }
+            Command::OutputList(columns) => {
+                self.columns = columns.clone();
+                let headers: Vec<String> = self.columns.iter().map(Self::header).collect();
+                return Ok(self.emit(format!("|{}|", headers.join("|"))));
+            }
+            Command::Set(target, value) => self.set(line, target, *value)?,
+            Command::Eval => self.settle(line)?,
+            Command::Tick => self.tick(line)?,
+            Command::Tock => self.tock(line)?,
+            Command::TickTock => {
+                self.tick(line)?;
+                self.tock(line)?;
+            }
+            Command::Output => return self.output(line),
+            Command::RomLoad(file) => {
+                let path = self.dir.join(file);
+                let rom = load_rom(path.to_str().unwrap_or(file), Bus::all0().to_shared_bus())
+                    .map_err(|e| TstError::new(line, TstErrorKind::Rom(e.to_string())))?;
+                let words = rom.rom.into_inner();
+                let chip = self.chip(line)?;
+                let mut loaded = false;
+                for (_, state) in chip.builtins() {
+                    if let Some(computer) = state.downcast_ref::<Computer>() {
+                        computer.load_program(&words[..]).map_err(|e| {
+                            TstError::new(line, TstErrorKind::Oscillation(e.to_string()))
+                        })?;
+                    } else if let Some(rom) = state.downcast_ref::<ROM32KBuiltIn>() {
+                        rom.load(&words[..]);
+                    } else {
+                        continue;
+                    }
+                    loaded = true;
+                    break;
+                }
+                if !loaded {
+                    return Err(TstError::new(
+                        line,
+                        TstErrorKind::UnknownState("ROM32K".into()),
+                    ));
+                }
+                self.settle(line)?;
+            }
+            Command::Repeat(count, steps) => {
+                for _ in 0..*count {
+                    if !self.run(steps)? {
+                        return Ok(false);
+                    }
+                }
+            }
+            Command::While(condition, steps) => {
+                while self.condition(line, condition)? {
+                    if !self.run(steps)? {
+                        return Ok(false);
+                    }
+                }
+            }
+            Command::Ignored => {}
+        }
+        Ok(true)
+    }
+
+    fn settle(&self, line: usize) -> Result<(), TstError> {
+        self.gate(line)?
+            .settle()
+            .map_err(|e| TstError::new(line, TstErrorKind::Oscillation(e.to_string())))?;
+        Ok(())
+    }
+
+    // 書き出していた波形を閉じる
+    fn finish_vcd(&mut self, line: usize) -> Result<(), TstError> {
+        if let Some(vcd) = self.vcd.take() {
+            vcd.finish()
+                .map_err(|e| TstError::new(line, TstErrorKind::Io(e.to_string())))?;
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, line: usize) -> Result<(), TstError> {
+        self.settle(line)?;
+        self.gate(line)?.clock_up();
+        if let Some(vcd) = self.vcd.as_mut() {
+            vcd.sample(I);
+        }
+        self.ticked = true;
+        Ok(())
+    }
+
+    fn tock(&mut self, line: usize) -> Result<(), TstError> {
+        self.gate(line)?.clock_down();
+        self.settle(line)?;
+        if let Some(vcd) = self.vcd.as_mut() {
+            vcd.sample(O);
+        }
+        self.time += 1;
+        self.ticked = false;
+        Ok(())
+    }
+}
+
+/// .tstを実行する。loadしたチップは組み込みのRust実装を使い、
+/// use_hdlなら同じディレクトリの.hdlから組み立てる。use_netlistならNetlistに展開して動かす
+/// vcd_pathがあればtick/tockごとに入出力ピンをそこへ書き出す (use_netlistのときは記録しない)
+/// 何度もloadしたときは最後にloadしたチップの波形が残る
+pub fn run_script<P: AsRef<Path>>(
+    path: P,
+    use_hdl: bool,
+    use_netlist: bool,
+    vcd_path: Option<&Path>,
+) -> Result<Outcome, TstError> {
+    let path = path.as_ref();
+    let file = Some(path.to_path_buf());
+    let in_file = |mut e: TstError| {
+        e.file = file.clone();
+        e
+    };
+    let source = fs::read_to_string(path)
+        .map_err(|e| in_file(TstError::new(0, TstErrorKind::Io(e.to_string()))))?;
+    let steps = parse(&source).map_err(in_file)?;
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut runner = Runner {
+        dir,
+        use_hdl,
+        use_netlist,
+        chip: None,
+        netlist: None,
+        vcd_path,
+        vcd: None,
+        time: 0,
+        ticked: false,
+        columns: vec![],
+        lines: vec![],
+        output_file: None,
+        compare: None,
+        mismatch: None,
+    };
+    // 食い違っていても、そこまでの波形は書き出す
+    let result = runner.run(&steps);
+    let finished = runner.finish_vcd(0);
+    if let Some(out) = &runner.output_file {
+        let mut text = runner.lines.join("\n");
+        text.push('\n');
+        fs::write(out, text)
+            .map_err(|e| in_file(TstError::new(0, TstErrorKind::Io(e.to_string()))))?;
+    }
+    result.and(finished).map_err(in_file)?;
+    Ok(Outcome {
+        lines: runner.lines,
+        compared: runner.compare.is_some(),
+        mismatch: runner.mismatch,
+        stats: runner.netlist.as_ref().map(|netlist| netlist.stats()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // テストごとのディレクトリにファイルを書く
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("nand2tetris-my-hs-tst-{}", test));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    const XOR_TST: &str = "// Xor
+load Xor.hdl,
+output-file Xor.out,
+compare-to Xor.cmp,
+output-list a%B3.1.3 b%B3.1.3 out%B3.1.3;
+
+set a 0, set b 0, eval, output;
+set a 0, set b 1, eval, output;
+set a 1, set b 0, eval, output;
+set a 1, set b 1, eval, output;
+";
+
+    const XOR_CMP: &str = "|   a   |   b   |  out  |
+|   0   |   0   |   0   |
+|   0   |   1   |   1   |
+|   1   |   0   |   1   |
+|   1   |   1   |   0   |
+";
+
+    #[test]
+    fn parse_script() {
+        let steps =
+            parse("set in %B0101, /* c */ repeat 2 {\n tick, tock;\n}\nwhile out <> -1 {eval;}")
+                .unwrap();
+        assert_eq!(steps[0].command, Command::Set(Target::Pin("in".into()), 5));
+        assert_eq!(steps[1].line, 1);
+        assert!(
+            matches!(&steps[1].command, Command::Repeat(2, body) if body.len() == 2 && body[0].line == 2)
+        );
+        assert!(matches!(
+            &steps[2].command,
+            Command::While(
+                Condition {
+                    compare: Compare::Ne,
+                    value: 0xffff,
+                    ..
+                },
+                _
+            )
+        ));
+
+        assert_eq!(parse_value("%XFF"), Ok(255));
+        assert_eq!(parse_value("%D-2"), Ok(0xfffe));
+        assert!(parse_value("%B102").is_err());
+        assert_eq!(
+            parse_target("RAM16K[3]"),
+            Target::State("RAM16K".into(), Some(3))
+        );
+        assert_eq!(parse_target("PC[]"), Target::State("PC".into(), None));
+        let column = parse_column("time%S1.4.1").unwrap();
+        assert_eq!(
+            (column.target, column.format, column.width),
+            (Target::Time, 'S', 4)
+        );
+
+        let error = parse("set a 0,\nfoo;").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.to_string(), "2: unknown command `foo`");
+        assert_eq!(parse("repeat 3 { tick;").unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn compare_gate() {
+        let dir = write_files("xor", &[("Xor.tst", XOR_TST), ("Xor.cmp", XOR_CMP)]);
+        let outcome = run_script(dir.join("Xor.tst"), false, false, None).unwrap();
+        assert!(outcome.compared);
+        assert_eq!(outcome.mismatch, None);
+        assert_eq!(fs::read_to_string(dir.join("Xor.out")).unwrap(), XOR_CMP);
+
+        // .hdlから組み立てても同じ
+        let hdl = "CHIP Xor { IN a, b; OUT out;
+            PARTS: Nand(a=a, b=b, out=n); Nand(a=a, b=n, out=x); Nand(a=n, b=b, out=y);
+            Nand(a=x, b=y, out=out); }";
+        let dir = write_files(
+            "xor-hdl",
+            &[("Xor.tst", XOR_TST), ("Xor.cmp", XOR_CMP), ("Xor.hdl", hdl)],
+        );
+        assert_eq!(
+            run_script(dir.join("Xor.tst"), true, false, None)
+                .unwrap()
+                .mismatch,
+            None
+        );
+
+        // 最初に食い違った行で止まる
+        let wrong = XOR_CMP.replace("|   1   |   1   |   0   |", "|   1   |   1   |   1   |");
+        let dir = write_files("xor-wrong", &[("Xor.tst", XOR_TST), ("Xor.cmp", &wrong)]);
+        let outcome = run_script(dir.join("Xor.tst"), false, false, None).unwrap();
+        assert_eq!(
+            outcome.mismatch,
+            Some(Mismatch {
+                line: 5,
+                expected: "|   1   |   1   |   1   |".into(),
+                actual: "|   1   |   1   |   0   |".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn sequential_and_arithmetic() {
+        let tst = "load Register.hdl, output-list time%S1.4.1 in%D1.6.1 load%B2.1.2 out%D1.6.1;
+set in -32123, set load 0, tick, output; tock, output;
+set load 1, tick, output; tock, output;
+repeat 2 { set in %X0010, ticktock; }
+while out < 100 { set in 200, tick, tock; }
+output;";
+        let cmp = "| time |   in   |load |  out   |
+| 0+   | -32123 |  0  |      0 |
+| 1    | -32123 |  0  |      0 |
+| 1+   | -32123 |  1  |      0 |
+| 2    | -32123 |  1  | -32123 |
+| 5    |    200 |  1  |    200 |
+";
+        let dir = write_files("register", &[("Register.tst", tst), ("Register.cmp", cmp)]);
+        let vcd_path = dir.join("Register.vcd");
+        let outcome = run_script(dir.join("Register.tst"), false, false, Some(&vcd_path)).unwrap();
+        assert_eq!(outcome.mismatch, None, "{:?}", outcome.lines);
+        // 半サイクルごとの波形。loadが1になった次のtockでoutが変わる
+        let vcd = fs::read_to_string(&vcd_path).unwrap();
+        let vars = "$scope module Register $end\n$var wire 1 ! clk $end\n\
+            $var wire 16 \" in $end\n$var wire 1 # load $end\n$var wire 16 $ out $end\n";
+        assert!(vcd.contains(vars), "{}", vcd);
+        assert!(
+            vcd.contains("#3\n1!\n1#\n#4\n0!\nb1000001010000101 $\n"),
+            "{}",
+            vcd
+        );
+        // Netlistに展開しても同じ
+        let outcome = run_script(dir.join("Register.tst"), false, true, None).unwrap();
+        assert_eq!(outcome.mismatch, None, "{:?}", outcome.lines);
+        let stats = outcome.stats.unwrap();
+        assert!(0 < stats.evaluations && stats.evaluations < stats.full_evaluations);
+
+        // ALUの出力と*の列
+        let tst = "load Add16.hdl, output-list a%X1.4.1 b%D1.6.1 out%B1.16.1;
+set a %X7FFF, set b 1, eval, output;";
+        let cmp = "|  a   |   b    |       out        |\n| 7FFF |      1 | 1000000000****** |\n";
+        let dir = write_files("add16", &[("Add16.tst", tst), ("Add16.cmp", cmp)]);
+        for use_netlist in [false, true] {
+            let outcome = run_script(dir.join("Add16.tst"), false, use_netlist, None).unwrap();
+            assert_eq!(outcome.mismatch, None);
+        }
+    }
+
+    #[test]
+    fn computer() {
+        let tst = "load Computer.hdl, ROM32K load Max.asm,
+output-list time%S1.4.1 reset%B2.1.2 ARegister[]%D1.7.1 DRegister[]%D1.7.1 PC[]%D0.4.0 RAM16K[2]%D1.7.1;
+set RAM16K[0] 3, set RAM16K[1] 5, output;
+repeat 14 { tick, tock; }
+output;
+set reset 1, tick, tock, output;";
+        let asm =
+            "@0\nD=M\n@1\nD=D-M\n@10\nD;JGT\n@1\nD=M\n@12\n0;JMP\n@0\nD=M\n@2\nM=D\n@14\n0;JMP\n";
+        let cmp = "| time |reset|ARegister|DRegister|PC[]|RAM16K[2]|
+| 0    |  0  |       0 |       0 |   0|       0 |
+| 14   |  0  |      14 |       5 |  14|       5 |
+| 15   |  1  |      14 |       5 |   0|       5 |
+";
+        let dir = write_files(
+            "computer",
+            &[
+                ("Computer.tst", tst),
+                ("Max.asm", asm),
+                ("Computer.cmp", cmp),
+            ],
+        );
+        let outcome = run_script(dir.join("Computer.tst"), false, false, None).unwrap();
+        assert_eq!(outcome.mismatch, None, "{:?}", outcome.lines);
+        // ROMやRAMはノードとして展開できるが、netlistからはRAMの中身を触れない
+        let error = run_script(dir.join("Computer.tst"), false, true, None).unwrap_err();
+        assert_eq!(error.kind, TstErrorKind::UnknownState("RAM16K[0]".into()));
+
+        let dir = write_files(
+            "errors",
+            &[
+                ("Bad.tst", "load Xor.hdl, set c 1;"),
+                ("Cpu.tst", "load CPU.hdl, set RAM16K[0] 1;"),
+            ],
+        );
+        let error = run_script(dir.join("Bad.tst"), false, false, None).unwrap_err();
+        assert_eq!(error.kind, TstErrorKind::UnknownPin("c".into()));
+        let error = run_script(dir.join("Cpu.tst"), false, false, None).unwrap_err();
+        assert_eq!(error.kind, TstErrorKind::UnknownState("RAM16K[0]".into()));
+        assert!(error
+            .to_string()
+            .ends_with("Cpu.tst:1: unknown chip state `RAM16K[0]`"));
+    }
+}
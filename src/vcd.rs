@@ -0,0 +1,254 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use crate::gate::*;
+
+/// 名前を付けたbitの値を半サイクルごとに記録して、GTKWaveで見られるVCDにする
+/// 名前を "cpu.alu.zr" のように . で区切るとscopeに分かれる
+/// 記録した値はためずにoutへ書き出していくので、長く動かしてもメモリは増えない
+#[derive(Debug)]
+pub struct Vcd<W: Write = BufWriter<File>> {
+    top: String,
+    probes: Vec<Probe>,
+    // 1回目のsampleでヘッダと全部の値を書き出すまではNone
+    last: Option<Vec<Vec<Bit>>>,
+    time: usize,
+    out: W,
+    // 書き出しで最初に起きたエラー。finishで返す
+    error: Option<io::Error>,
+}
+
+#[derive(Debug)]
+struct Probe {
+    name: String,
+    id: String,
+    // 下位bitから
+    bits: Vec<SharedBit>,
+}
+
+impl Vcd {
+    /// pathのファイルに書き出す
+    pub fn create<P: AsRef<Path>>(top: &str, path: P) -> io::Result<Vcd> {
+        Ok(Vcd::new(top, BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> Vcd<W> {
+    /// 最初から clk が入っている
+    pub fn new(top: &str, out: W) -> Vcd<W> {
+        let mut vcd = Vcd {
+            top: top.to_string(),
+            probes: vec![],
+            last: None,
+            time: 0,
+            out,
+            error: None,
+        };
+        vcd.probe("clk", vec![]);
+        vcd
+    }
+
+    /// 記録するbitを加える。sampleを始める前に呼ぶ
+    pub fn probe(&mut self, name: &str, bits: Vec<SharedBit>) -> () {
+        assert!(self.last.is_none(), "probe after sample: {}", name);
+        let id = identifier(self.probes.len());
+        self.probes.push(Probe {
+            name: name.to_string(),
+            id,
+            bits,
+        });
+    }
+
+    /// clockの値と今の値を記録して、時刻を半サイクル進める
+    /// clock_upのあとはI、clock_downのあとはOで呼ぶ
+    pub fn sample(&mut self, clock: Bit) -> () {
+        let values: Vec<Vec<Bit>> = self
+            .probes
+            .iter()
+            .map(|probe| match probe.bits.is_empty() {
+                true => vec![clock],
+                false => probe.bits.iter().map(|bit| bit.get()).collect(),
+            })
+            .collect();
+        let mut changes = vec![];
+        for (i, (probe, value)) in self.probes.iter().zip(values.iter()).enumerate() {
+            let changed = match &self.last {
+                Some(last) => last[i] != *value,
+                None => true,
+            };
+            if changed {
+                changes.push(format_value(value, &probe.id));
+            }
+        }
+        if let (Err(e), None) = (self.write_changes(&changes), &self.error) {
+            self.error = Some(e);
+        }
+        self.last = Some(values);
+        self.time += 1;
+    }
+
+    /// 残りを書き出してoutを返す。途中で書き出しに失敗していればそのエラーを返す
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if self.last.is_none() {
+            self.write_header()?;
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_changes(&mut self, changes: &[String]) -> io::Result<()> {
+        if self.last.is_none() {
+            self.write_header()?;
+        }
+        if !changes.is_empty() {
+            writeln!(self.out, "#{}", self.time)?;
+            for change in changes {
+                writeln!(self.out, "{}", change)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let f = &mut self.out;
+        writeln!(f, "$version nand2tetris-my-hs $end")?;
+        writeln!(f, "$timescale 1ns $end")?;
+        writeln!(f, "$scope module {} $end", self.top)?;
+        let mut scopes: Vec<&str> = vec![];
+        for probe in self.probes.iter() {
+            let mut path: Vec<&str> = probe.name.split('.').collect();
+            let name = path.pop().unwrap_or_default();
+            // 前のprobeと共通のscopeまで戻ってから入り直す
+            let common = scopes
+                .iter()
+                .zip(path.iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            for _ in common..scopes.len() {
+                writeln!(f, "$upscope $end")?;
+            }
+            for scope in path[common..].iter() {
+                writeln!(f, "$scope module {} $end", scope)?;
+            }
+            scopes = path;
+            let width = probe.bits.len().max(1);
+            writeln!(f, "$var wire {} {} {} $end", width, probe.id, name)?;
+        }
+        for _ in 0..scopes.len() {
+            writeln!(f, "$upscope $end")?;
+        }
+        writeln!(f, "$upscope $end")?;
+        writeln!(f, "$enddefinitions $end")
+    }
+}
+
+// VCDの識別子は ! から ~ までの文字を並べたもの
+fn identifier(mut index: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return id;
+        }
+        index -= 1;
+    }
+}
+
+fn format_value(value: &[Bit], id: &str) -> String {
+    let bits: String = value
+        .iter()
+        .rev()
+        .map(|bit| match bit {
+            O => '0',
+            I => '1',
+        })
+        .collect();
+    match value.len() {
+        1 => format!("{}{}", bits, id),
+        _ => format!("b{} {}", bits, id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::assemble, computer::Computer, computer::ROM32KBuiltIn};
+
+    #[test]
+    fn computer() {
+        let words = assemble("@7\nD=A\n@3\nD=D-A\n@END\nD;JGT\n(END)\n@END\n0;JMP\n").unwrap();
+        let rom = ROM32KBuiltIn::from_words(&words, Bus::all0().to_shared_bus());
+        let computer = Computer::new(Bus::all0().to_shared_bus(), rom);
+        computer.re_compute();
+        let mut vcd = Vcd::new("Computer", vec![]);
+        for (name, bits) in computer.probes() {
+            vcd.probe(&name, bits);
+        }
+        vcd.sample(O);
+        for _ in 0..4 {
+            computer.tick().unwrap();
+            vcd.sample(I);
+            computer.tock().unwrap();
+            vcd.sample(O);
+        }
+
+        let text = String::from_utf8(vcd.finish().unwrap()).unwrap();
+        let header = "$version nand2tetris-my-hs $end
+$timescale 1ns $end
+$scope module Computer $end
+$var wire 1 ! clk $end
+$var wire 1 \" reset $end
+$var wire 16 # instruction $end
+$var wire 16 $ inM $end
+$scope module cpu $end
+$var wire 16 % A $end
+$var wire 16 & D $end
+$var wire 15 ' PC $end
+$var wire 1 ( writeM $end
+$var wire 15 ) addressM $end
+$var wire 16 * outM $end
+$scope module alu $end
+$var wire 1 + zr $end
+$var wire 1 , ng $end
+$upscope $end
+$upscope $end
+$upscope $end
+$enddefinitions $end
+#0
+0!
+0\"
+b0000000000000111 #
+";
+        assert!(text.starts_with(header), "{}", text);
+        // 変わらなかった信号は書かない
+        assert!(text.contains("#1\n1!\n#2\n0!\n"), "{}", text);
+        // D=D-A (7-3) のあいだoutMは4で、clock_downのあとDが4になりPCが進む
+        assert!(
+            text.contains("b0000000000000100 *\n#7\n1!\n#8\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("#8\n0!\nb0000000000000110 #\nb0000000000000100 &\nb000000000000100 '\n"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn identifiers() {
+        assert_eq!(identifier(0), "!");
+        assert_eq!(identifier(93), "~");
+        assert_eq!(identifier(94), "!!");
+        assert_eq!(identifier(95), "\"!");
+        assert_eq!(format_value(&[I, O, O], "#"), "b001 #");
+        assert_eq!(format_value(&[I], "#"), "1#");
+    }
+}
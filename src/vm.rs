@@ -0,0 +1,567 @@
+use std::{fmt, fs, io, path::Path};
+
+/// スタックの始まり
+const STACK_BASE: u16 = 256;
+
+#[derive(Debug, PartialEq)]
+pub enum VmErrorKind {
+    Io(String),
+    NoVmFiles,
+    UnknownCommand(String),
+    MissingArgument(String),
+    InvalidSegment(String),
+    InvalidIndex(String),
+    IndexOutOfRange { segment: String, index: u16 },
+    PopConstant,
+    InvalidLabel(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VmError {
+    /// 拡張子なしのファイル名
+    pub file: String,
+    /// 1始まりの行番号。ファイル全体のエラーなら0
+    pub line: usize,
+    pub kind: VmErrorKind,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}.vm:", self.file)?;
+        }
+        if self.line > 0 {
+            write!(f, "{}:", self.line)?;
+        }
+        if !self.file.is_empty() || self.line > 0 {
+            write!(f, " ")?;
+        }
+        match &self.kind {
+            VmErrorKind::Io(e) => write!(f, "{}", e),
+            VmErrorKind::NoVmFiles => write!(f, "no .vm files"),
+            VmErrorKind::UnknownCommand(s) => write!(f, "unknown command `{}`", s),
+            VmErrorKind::MissingArgument(s) => write!(f, "`{}` requires more arguments", s),
+            VmErrorKind::InvalidSegment(s) => write!(f, "invalid segment `{}`", s),
+            VmErrorKind::InvalidIndex(s) => write!(f, "invalid index `{}`", s),
+            VmErrorKind::IndexOutOfRange { segment, index } => {
+                write!(f, "index {} is out of range for `{}`", index, segment)
+            }
+            VmErrorKind::PopConstant => write!(f, "cannot pop to `constant`"),
+            VmErrorKind::InvalidLabel(s) => write!(f, "invalid label `{}`", s),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Segment {
+    Constant,
+    Local,
+    Argument,
+    This,
+    That,
+    Pointer,
+    Temp,
+    Static,
+}
+
+impl Segment {
+    fn parse(s: &str) -> Option<Segment> {
+        match s {
+            "constant" => Some(Segment::Constant),
+            "local" => Some(Segment::Local),
+            "argument" => Some(Segment::Argument),
+            "this" => Some(Segment::This),
+            "that" => Some(Segment::That),
+            "pointer" => Some(Segment::Pointer),
+            "temp" => Some(Segment::Temp),
+            "static" => Some(Segment::Static),
+            _ => None,
+        }
+    }
+
+    // ベースアドレスを持つレジスタ
+    fn base(&self) -> Option<&'static str> {
+        match self {
+            Segment::Local => Some("LCL"),
+            Segment::Argument => Some("ARG"),
+            Segment::This => Some("THIS"),
+            Segment::That => Some("THAT"),
+            _ => None,
+        }
+    }
+
+    fn max_index(&self) -> u16 {
+        match self {
+            Segment::Pointer => 1,
+            Segment::Temp => 7,
+            Segment::Static => 239,
+            _ => 32767,
+        }
+    }
+}
+
+// 関数の中のラベルは Function$label、それ以外はファイル名で区切る
+struct CodeWriter {
+    asm: String,
+    file: String,
+    function: String,
+    count: usize,
+}
+
+impl CodeWriter {
+    fn emit(&mut self, lines: &[&str]) -> () {
+        for line in lines {
+            self.asm.push_str(line);
+            self.asm.push('\n');
+        }
+    }
+
+    fn comment(&mut self, s: &str) -> () {
+        self.asm.push_str(&format!("// {}\n", s));
+    }
+
+    fn push_d(&mut self) -> () {
+        self.emit(&["@SP", "A=M", "M=D", "@SP", "M=M+1"]);
+    }
+
+    fn pop_d(&mut self) -> () {
+        self.emit(&["@SP", "AM=M-1", "D=M"]);
+    }
+
+    // 比較やcallで使う、プログラム全体で重ならないラベル
+    fn unique_label(&mut self, kind: &str) -> String {
+        self.count += 1;
+        let scope = match self.function.is_empty() {
+            true => &self.file,
+            false => &self.function,
+        };
+        format!("{}${}.{}", scope, kind, self.count)
+    }
+
+    fn scoped_label(&self, label: &str) -> String {
+        match self.function.is_empty() {
+            true => format!("{}${}", self.file, label),
+            false => format!("{}${}", self.function, label),
+        }
+    }
+
+    // pointer/temp/staticの番地
+    fn fixed_address(&self, segment: Segment, index: u16) -> String {
+        match segment {
+            Segment::Pointer => format!("@R{}", 3 + index),
+            Segment::Temp => format!("@R{}", 5 + index),
+            _ => format!("@{}.{}", self.file, index),
+        }
+    }
+
+    fn push(&mut self, segment: Segment, index: u16) -> () {
+        let at_index = format!("@{}", index);
+        match segment.base() {
+            Some(base) => self.emit(&[&format!("@{}", base), "D=M", &at_index, "A=D+A", "D=M"]),
+            None if segment == Segment::Constant => self.emit(&[&at_index, "D=A"]),
+            None => {
+                let address = self.fixed_address(segment, index);
+                self.emit(&[&address, "D=M"]);
+            }
+        }
+        self.push_d();
+    }
+
+    fn pop(&mut self, segment: Segment, index: u16) -> () {
+        match segment.base() {
+            Some(base) => {
+                let at_index = format!("@{}", index);
+                self.emit(&[
+                    &format!("@{}", base),
+                    "D=M",
+                    &at_index,
+                    "D=D+A",
+                    "@R13",
+                    "M=D",
+                ]);
+                self.pop_d();
+                self.emit(&["@R13", "A=M", "M=D"]);
+            }
+            None => {
+                self.pop_d();
+                let address = self.fixed_address(segment, index);
+                self.emit(&[&address, "M=D"]);
+            }
+        }
+    }
+
+    fn arithmetic(&mut self, command: &str) -> bool {
+        match command {
+            "add" | "sub" | "and" | "or" => {
+                let comp = match command {
+                    "add" => "M=D+M",
+                    "sub" => "M=M-D",
+                    "and" => "M=D&M",
+                    _ => "M=D|M",
+                };
+                self.pop_d();
+                self.emit(&["A=A-1", comp]);
+            }
+            "neg" => self.emit(&["@SP", "A=M-1", "M=-M"]),
+            "not" => self.emit(&["@SP", "A=M-1", "M=!M"]),
+            "eq" | "gt" | "lt" => {
+                let jump = match command {
+                    "eq" => "D;JEQ",
+                    "gt" => "D;JGT",
+                    _ => "D;JLT",
+                };
+                // 先にtrue(-1)を入れておき、条件が成り立たなければ0にする
+                let label = self.unique_label(command);
+                self.pop_d();
+                self.emit(&["A=A-1", "D=M-D", "M=-1", &format!("@{}", label), jump]);
+                self.emit(&["@SP", "A=M-1", "M=0", &format!("({})", label)]);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn function(&mut self, name: &str, locals: u16) -> () {
+        self.function = name.to_string();
+        self.emit(&[&format!("({})", name)]);
+        for _ in 0..locals {
+            self.emit(&["@SP", "A=M", "M=0", "@SP", "M=M+1"]);
+        }
+    }
+
+    fn call(&mut self, name: &str, args: u16) -> () {
+        let ret = self.unique_label("ret");
+        self.emit(&[&format!("@{}", ret), "D=A"]);
+        self.push_d();
+        for base in ["@LCL", "@ARG", "@THIS", "@THAT"] {
+            self.emit(&[base, "D=M"]);
+            self.push_d();
+        }
+        // ARG = SP - 5 - args、LCL = SP
+        let offset = format!("@{}", 5 + args as u32);
+        self.emit(&["@SP", "D=M", &offset, "D=D-A", "@ARG", "M=D"]);
+        self.emit(&["@SP", "D=M", "@LCL", "M=D"]);
+        self.emit(&[&format!("@{}", name), "0;JMP", &format!("({})", ret)]);
+    }
+
+    fn return_(&mut self) -> () {
+        // R13 = FRAME = LCL、R14 = 戻り先 = *(FRAME-5)
+        self.emit(&["@LCL", "D=M", "@R13", "M=D"]);
+        self.emit(&["@5", "A=D-A", "D=M", "@R14", "M=D"]);
+        // *ARG = pop()、SP = ARG + 1
+        self.pop_d();
+        self.emit(&["@ARG", "A=M", "M=D", "@ARG", "D=M+1", "@SP", "M=D"]);
+        for base in ["@THAT", "@THIS", "@ARG", "@LCL"] {
+            self.emit(&["@R13", "AM=M-1", "D=M", base, "M=D"]);
+        }
+        self.emit(&["@R14", "A=M", "0;JMP"]);
+    }
+
+    fn bootstrap(&mut self) -> () {
+        self.comment("bootstrap");
+        self.file = "Bootstrap".to_string();
+        self.emit(&[&format!("@{}", STACK_BASE), "D=A", "@SP", "M=D"]);
+        self.call("Sys.init", 0);
+    }
+}
+
+// アセンブラのシンボルに使える文字だけか
+fn is_valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && !label.starts_with(|c: char| c.is_ascii_digit())
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.:$".contains(c))
+}
+
+fn translate_file(writer: &mut CodeWriter, source: &str) -> Result<(), VmError> {
+    let file = writer.file.clone();
+    for (index, raw) in source.lines().enumerate() {
+        let line = index + 1;
+        let error = |kind| VmError {
+            file: file.clone(),
+            line,
+            kind,
+        };
+        let code = raw.split("//").next().unwrap_or("").trim();
+        if code.is_empty() {
+            continue;
+        }
+        let words: Vec<&str> = code.split_whitespace().collect();
+        let command = words[0];
+        let arg = |i: usize| {
+            words
+                .get(i)
+                .copied()
+                .ok_or_else(|| error(VmErrorKind::MissingArgument(command.to_string())))
+        };
+        let number = |i: usize| {
+            let s = arg(i)?;
+            s.parse::<u16>()
+                .ok()
+                .filter(|n| *n <= 32767)
+                .ok_or_else(|| error(VmErrorKind::InvalidIndex(s.to_string())))
+        };
+        let label = |i: usize| {
+            let s = arg(i)?;
+            match is_valid_label(s) {
+                true => Ok(s),
+                false => Err(error(VmErrorKind::InvalidLabel(s.to_string()))),
+            }
+        };
+
+        writer.comment(code);
+        match command {
+            "push" | "pop" => {
+                let name = arg(1)?;
+                let segment = Segment::parse(name)
+                    .ok_or_else(|| error(VmErrorKind::InvalidSegment(name.to_string())))?;
+                let index = number(2)?;
+                if index > segment.max_index() {
+                    return Err(error(VmErrorKind::IndexOutOfRange {
+                        segment: name.to_string(),
+                        index,
+                    }));
+                }
+                match command {
+                    "push" => writer.push(segment, index),
+                    _ if segment == Segment::Constant => {
+                        return Err(error(VmErrorKind::PopConstant))
+                    }
+                    _ => writer.pop(segment, index),
+                }
+            }
+            "label" => {
+                let label = writer.scoped_label(label(1)?);
+                writer.emit(&[&format!("({})", label)]);
+            }
+            "goto" => {
+                let label = writer.scoped_label(label(1)?);
+                writer.emit(&[&format!("@{}", label), "0;JMP"]);
+            }
+            "if-goto" => {
+                let label = writer.scoped_label(label(1)?);
+                writer.pop_d();
+                writer.emit(&[&format!("@{}", label), "D;JNE"]);
+            }
+            "function" => writer.function(label(1)?, number(2)?),
+            "call" => writer.call(label(1)?, number(2)?),
+            "return" => writer.return_(),
+            _ => {
+                if !writer.arithmetic(command) {
+                    return Err(error(VmErrorKind::UnknownCommand(command.to_string())));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// (拡張子なしのファイル名, 中身) の.vmをまとめてHackアセンブリにする
+/// static変数はファイルごとに別になる。bootstrapならSP=256にしてSys.initを呼ぶ
+pub fn translate(files: &[(&str, &str)], bootstrap: bool) -> Result<String, VmError> {
+    let mut writer = CodeWriter {
+        asm: String::new(),
+        file: String::new(),
+        function: String::new(),
+        count: 0,
+    };
+    if bootstrap {
+        writer.bootstrap();
+    }
+    for (name, source) in files {
+        writer.file = name.to_string();
+        writer.function = String::new();
+        translate_file(&mut writer, source)?;
+    }
+    Ok(writer.asm)
+}
+
+/// (拡張子なしのファイル名, 中身) を読む。ディレクトリなら中の拡張子extensionのファイルを名前順に読む
+pub(crate) fn read_sources(path: &Path, extension: &str) -> io::Result<Vec<(String, String)>> {
+    let mut paths = vec![];
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let file = entry?.path();
+            if file.extension().is_some_and(|ext| ext == extension) {
+                paths.push(file);
+            }
+        }
+        paths.sort();
+    } else {
+        paths.push(path.to_path_buf());
+    }
+
+    let mut files = vec![];
+    for file in paths.iter() {
+        let name = file
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("")
+            .to_string();
+        files.push((name, fs::read_to_string(file)?));
+    }
+    Ok(files)
+}
+
+/// read_sourcesで読んだ.vmをまとめて変換する。ディレクトリから読んでSysがあればbootstrapを付ける
+pub fn translate_sources(files: &[(String, String)], is_dir: bool) -> Result<String, VmError> {
+    if files.is_empty() {
+        return Err(VmError {
+            file: String::new(),
+            line: 0,
+            kind: VmErrorKind::NoVmFiles,
+        });
+    }
+    let bootstrap = is_dir && files.iter().any(|(name, _)| name == "Sys");
+    let files: Vec<(&str, &str)> = files
+        .iter()
+        .map(|(name, source)| (name.as_str(), source.as_str()))
+        .collect();
+    translate(&files, bootstrap)
+}
+
+/// .vmファイル1つか、.vmファイルの入ったディレクトリを変換する
+pub fn translate_path<P: AsRef<Path>>(path: P) -> Result<String, VmError> {
+    let path = path.as_ref();
+    let files = read_sources(path, "vm").map_err(|e| VmError {
+        file: String::new(),
+        line: 0,
+        kind: VmErrorKind::Io(format!("{}: {}", path.display(), e)),
+    })?;
+    translate_sources(&files, path.is_dir())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assembler::assemble,
+        computer::{Computer, ROM32KBuiltIn},
+        gate::*,
+    };
+
+    fn run(files: &[(&str, &str)], bootstrap: bool, setup: &[(u16, u16)]) -> Computer {
+        let asm = translate(files, bootstrap).unwrap();
+        let words = assemble(&asm).unwrap();
+        let rom = ROM32KBuiltIn::from_words(&words, Bus::all0().to_shared_bus());
+        let computer = Computer::new(Bus::all0().to_shared_bus(), rom);
+        for (address, value) in setup {
+            computer.poke(*address, *value).unwrap();
+        }
+        // どのテストも最後は自分自身へのループで止まる
+        computer.run_until_halt().unwrap();
+        computer
+    }
+
+    #[test]
+    fn stack_arithmetic() {
+        let source = "push constant 17\npush constant 17\neq
+push constant 892\npush constant 891\nlt
+push constant 32767\npush constant 32766\ngt
+push constant 57\npush constant 31\npush constant 53\nadd\npush constant 112\nsub\nneg
+push constant 82\nand\npush constant 0\nnot\nor // 最後は -1
+label END\ngoto END";
+        let computer = run(&[("StackTest", source)], false, &[(0, 256)]);
+        assert_eq!(computer.peek(0), 261);
+        let stack: Vec<i16> = (256..261).map(|a| computer.peek(a) as i16).collect();
+        assert_eq!(stack, vec![-1, 0, -1, 57, -1]);
+    }
+
+    #[test]
+    fn memory_segments() {
+        let source = "push constant 10\npop local 0\npush constant 21\npop argument 2
+push constant 3030\npop pointer 0\npush constant 3040\npop pointer 1
+push constant 36\npop this 6\npush constant 42\npop that 5\npush constant 510\npop temp 6
+push constant 7\npop static 3
+push local 0\npush that 5\nadd\npush argument 2\nsub\npush this 6\npush this 6\nadd\nsub
+push temp 6\nadd\npush pointer 0\npush pointer 1\nadd\npush static 3\nadd
+label END\ngoto END";
+        let setup = [(0, 256), (1, 300), (2, 400)];
+        let computer = run(&[("Basic", source)], false, &setup);
+        assert_eq!(computer.peek(300), 10);
+        assert_eq!(computer.peek(402), 21);
+        assert_eq!(computer.peek(3036), 36);
+        assert_eq!(computer.peek(3045), 42);
+        assert_eq!(computer.peek(11), 510);
+        // 10+42-21-72+510 = 469、3030+3040+7 = 6077
+        assert_eq!(computer.peek(256), 469);
+        assert_eq!(computer.peek(257), 6077);
+    }
+
+    #[test]
+    fn functions_and_statics() {
+        // Class1/Class2 のstaticは別々
+        let sys = "function Sys.init 0
+push constant 6\npush constant 8\ncall Class1.set 2\npop temp 0
+push constant 23\npush constant 15\ncall Class2.set 2\npop temp 0
+call Class1.get 0\ncall Class2.get 0\npush constant 4\ncall Sys.fib 1
+label WHILE\ngoto WHILE
+function Sys.fib 0
+push argument 0\npush constant 2\nlt\nif-goto BASE
+push argument 0\npush constant 1\nsub\ncall Sys.fib 1
+push argument 0\npush constant 2\nsub\ncall Sys.fib 1\nadd\nreturn
+label BASE\npush argument 0\nreturn";
+        let class = |name: &str| {
+            format!(
+                "function {0}.set 0\npush argument 0\npop static 0\npush argument 1\npop static 1
+push constant 0\nreturn\nfunction {0}.get 0\npush static 0\npush static 1\nsub\nreturn",
+                name
+            )
+        };
+        let (class1, class2) = (class("Class1"), class("Class2"));
+        let files = [
+            ("Class1", &class1[..]),
+            ("Class2", &class2[..]),
+            ("Sys", sys),
+        ];
+        let computer = run(&files, true, &[]);
+        let result: Vec<i16> = (261..264).map(|a| computer.peek(a) as i16).collect();
+        assert_eq!(result, vec![-2, 8, 3]);
+        assert_eq!(computer.peek(16), 6);
+        assert_eq!(computer.peek(19), 15);
+        assert!(computer.is_halted());
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source: &str| translate(&[("Main", source)], false).unwrap_err();
+        assert_eq!(
+            error("push constant 1\npop constant 0"),
+            VmError {
+                file: "Main".to_string(),
+                line: 2,
+                kind: VmErrorKind::PopConstant
+            }
+        );
+        assert_eq!(
+            error("push temp 8").kind,
+            VmErrorKind::IndexOutOfRange {
+                segment: "temp".to_string(),
+                index: 8
+            }
+        );
+        assert_eq!(
+            error("push heap 0").kind,
+            VmErrorKind::InvalidSegment("heap".to_string())
+        );
+        assert_eq!(
+            error("push local x").kind,
+            VmErrorKind::InvalidIndex("x".to_string())
+        );
+        assert_eq!(
+            error("mul").kind,
+            VmErrorKind::UnknownCommand("mul".to_string())
+        );
+        assert_eq!(
+            error("goto 1abc").kind,
+            VmErrorKind::InvalidLabel("1abc".to_string())
+        );
+        assert_eq!(
+            error("\n\ncall Foo").to_string(),
+            "Main.vm:3: `call` requires more arguments"
+        );
+    }
+}